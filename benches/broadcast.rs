@@ -0,0 +1,19 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use paxos::{MemoryNetwork, Network, NodeId, PaxosMsg};
+
+/// Broadcasts to the other 49 replicas of a group of 50, which never connected to the network,
+/// so that every send returns right away and iterating over the peers dominates.
+fn broadcast(c: &mut Criterion) {
+    let network = MemoryNetwork::<u32>::new();
+    let mut node = network.connect(NodeId(0));
+    let group: Vec<NodeId> = (0..50).map(NodeId).collect();
+    node.discover(&group);
+    let msg = PaxosMsg::Applied { index: 0 };
+    c.bench_function("broadcast 50", |b| b.iter(|| node.broadcast(black_box(&msg))));
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
@@ -4,11 +4,13 @@
 use std::collections::HashMap;
 use std::{io, thread, time::Duration};
 
-use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use tracing::{info, info_span, Level};
+use tracing::{info, Level};
 
-use paxos::{PaxosReplica, ReplicatedStateMachine, UdpNetworkNode};
+use paxos::{ClusterBuilder, PaxosClient, PaxosError, ReplicatedStateMachine};
+
+/// How long operations may take to be applied, including finding the leader.
+const TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Operation {
@@ -29,41 +31,32 @@ impl ReplicatedStateMachine for KeyValueStore {
     fn execute(&mut self, action: Self::Command) -> Result<String, ()> {
         match action {
             Operation::Put { key, value } => self.store.insert(key, value).ok_or(()),
-            Operation::Get { key } => self.store.get(&key).map(|v| v.clone()).ok_or(()),
+            Operation::Get { key } => self.store.get(&key).cloned().ok_or(()),
         }
     }
 }
 
-pub fn start_kv_stores(group_size: usize) {
-    // create the network nodes
-    let mut nodes = Vec::new();
-    for _ in 0..group_size {
-        nodes.push(UdpNetworkNode::<Operation>::new());
+/// Submits operations to the key value store and returns their results.
+struct KeyValueClient<'a>(&'a PaxosClient<Operation>);
+
+impl KeyValueClient<'_> {
+    /// Stores the value under the key and returns the value it replaced.
+    /// Fails with `PaxosError::CommandFailed` if there was none, after storing the value.
+    fn put(&self, key: &str, value: &str) -> Result<String, PaxosError> {
+        let put = Operation::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+        };
+        self.0.execute(put, TIMEOUT)
     }
 
-    // start the replicas and make them know about everyone else
-    let node_ids = nodes.iter().map(|n| n.id()).collect();
-    for mut node in nodes {
-        node.discover(&node_ids);
-        let node_id = node.id();
-        let mut replica = PaxosReplica::<Operation>::new(node, node_id, group_size);
-        thread::spawn(move || {
-            // configure a span to associate tracing output with this replica
-            let tracing_span = info_span!("Replica", id = node_id);
-            let _guard = tracing_span.enter();
-            info!("Starting Paxos Replica with ID {}", node_id);
-
-            // main loop
-            loop {
-                replica.tick();
-                if thread_rng().gen_range(0..=500) == 0 {
-                    replica.submit_value(Operation::Put {
-                        key: "Hello".to_string(),
-                        value: "World".to_string(),
-                    });
-                }
-            }
-        });
+    /// Returns the value stored under the key.
+    /// Fails with `PaxosError::CommandFailed` if there is none.
+    fn get(&self, key: &str) -> Result<String, PaxosError> {
+        let get = Operation::Get {
+            key: key.to_string(),
+        };
+        self.0.execute(get, TIMEOUT)
     }
 }
 
@@ -77,8 +70,36 @@ fn main() -> io::Result<()> {
         .init();
 
     // create and connect a number of Paxos replicas maintaining the key value store
-    start_kv_stores(5);
-    thread::sleep(Duration::new(6, 0));
+    let cluster = ClusterBuilder::new(5, |_| KeyValueStore::default()).spawn_udp();
+
+    // keep submitting operations for a while, the client finds the leader on its own
+    let client = KeyValueClient(cluster.client());
+    for i in 0..60 {
+        match client.put("Hello", &format!("World {}", i)) {
+            Ok(previous) => info!("Replaced {:?}.", previous),
+            Err(PaxosError::CommandFailed) => info!("Stored the first value."),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+        let value = client.get("Hello").expect("value just stored");
+        info!("Read {:?}.", value);
+        thread::sleep(Duration::from_millis(100));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_then_get() {
+        let cluster = ClusterBuilder::new(3, |_| KeyValueStore::default()).spawn_in_memory();
+        let client = KeyValueClient(cluster.client());
+        assert_eq!(client.put("a", "1"), Err(PaxosError::CommandFailed));
+        assert_eq!(client.get("a"), Ok("1".to_string()));
+        assert_eq!(client.put("a", "2"), Ok("1".to_string()));
+        assert_eq!(client.get("a"), Ok("2".to_string()));
+        assert_eq!(client.get("b"), Err(PaxosError::CommandFailed));
+    }
+}
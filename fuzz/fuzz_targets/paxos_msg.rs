@@ -0,0 +1,63 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Decodes arbitrary bytes as a sequence of messages, the way replicas decode datagrams, and
+//! has a replica handle the ones which decode. Malformed input has to be rejected cleanly,
+//! every panic or abort is a bug.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use paxos::{MemoryNetwork, NodeId, PaxosMsg, PaxosReplica, ReplicatedStateMachine};
+
+/// The number of messages decoded from one input at most, which bounds the time and memory
+/// one input can take while still allowing short exchanges, e.g. a Prepare and a Propose.
+const MAX_MESSAGES: usize = 4;
+
+/// Keeps all executed commands, so that snapshots carry data which needs to be validated.
+#[derive(Default)]
+struct History(Vec<String>);
+
+impl ReplicatedStateMachine for History {
+    type Command = String;
+
+    fn execute(&mut self, cmd: String) -> Result<String, ()> {
+        self.0.push(cmd.clone());
+        Ok(cmd)
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        bincode::serialize(&self.0).ok()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), ()> {
+        self.0 = bincode::deserialize(snapshot).map_err(|_| ())?;
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let network = MemoryNetwork::new();
+    let members = vec![NodeId(1), NodeId(2), NodeId(3)];
+    let _peers: Vec<_> = members[1..].iter().map(|&id| network.connect(id)).collect();
+    let node = network.connect(NodeId(1));
+    let mut replica =
+        PaxosReplica::with_members(node, NodeId(1), members, History::default()).unwrap();
+
+    // each message is preceded by a byte choosing its sender, which might be no member at all
+    let mut input = data;
+    for _ in 0..MAX_MESSAGES {
+        let (&src, rest) = match input.split_first() {
+            Some(split) => split,
+            None => break,
+        };
+        let msg = match bincode::deserialize::<PaxosMsg<String>>(rest) {
+            Ok(msg) => msg,
+            Err(_) => break,
+        };
+        // the encoding is canonical, so the message took exactly as many bytes as it encodes to
+        let len = bincode::serialized_size(&msg).unwrap() as usize;
+        input = &rest[len..];
+        replica.handle_message(NodeId(usize::from(src % 5)), msg);
+    }
+});
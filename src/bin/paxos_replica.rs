@@ -0,0 +1,209 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Command line interface for running a Paxos replica and interacting with a running group.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches, Command};
+use tracing::Level;
+
+use paxos::{
+    LogExport, Network, NodeId, PaxosClient, PaxosError, PaxosReplica, UdpNetworkNode,
+};
+
+fn cli() -> Command<'static> {
+    Command::new("paxos_replica")
+        .about("Runs a Multi-Paxos replica or interacts with a running group")
+        .subcommand(
+            Command::new("run")
+                .about("Launches a replica (default)")
+                .arg(
+                    Arg::new("group-size")
+                        .long("group-size")
+                        .takes_value(true)
+                        .default_value("1")
+                        .help("Number of replicas in the group"),
+                )
+                .arg(
+                    Arg::new("peers")
+                        .long("peers")
+                        .takes_value(true)
+                        .multiple_values(true)
+                        .help("IDs of the other replicas in the group"),
+                )
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .takes_value(true)
+                        .help("IP address of the interface to bind to [default: 127.0.0.1]"),
+                )
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .takes_value(true)
+                        .help("UDP port to bind to [default: any free port]"),
+                )
+                .arg(
+                    Arg::new("data-dir")
+                        .long("data-dir")
+                        .takes_value(true)
+                        .help("Directory to persist the state in [default: keep it in memory]"),
+                ),
+        )
+        .subcommand(
+            Command::new("submit")
+                .about("Submits a value to the replicated log")
+                .arg(Arg::new("node").required(true).help("ID of the target replica"))
+                .arg(Arg::new("value").required(true).help("Value to append to the log")),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Queries a replica for its leader, progress and other internal state")
+                .arg(Arg::new("node").required(true).help("ID of the queried replica")),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("Prints a log exported with `PaxosReplica::export_log`")
+                .arg(Arg::new("file").required(true).help("Path of the exported log")),
+        )
+}
+
+/// Parses an argument value, exiting with a usage error naming `what` if it is invalid.
+fn parse_value<T: FromStr>(value: &str, what: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        cli()
+            .error(
+                clap::ErrorKind::InvalidValue,
+                format!("invalid {}: {}", what, value),
+            )
+            .exit()
+    })
+}
+
+fn parse_node_id(matches: &ArgMatches, name: &str) -> NodeId {
+    parse_value(matches.value_of(name).unwrap(), "node ID")
+}
+
+fn run(matches: Option<&ArgMatches>) {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::INFO)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let group_size = matches
+        .and_then(|m| m.value_of("group-size"))
+        .map_or(1, |s| parse_value(s, "group size"));
+    let peers: Vec<NodeId> = matches
+        .and_then(|m| m.values_of("peers"))
+        .map(|values| values.map(|p| parse_value(p, "peer ID")).collect())
+        .unwrap_or_default();
+
+    let host = matches
+        .and_then(|m| m.value_of("host"))
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |s| parse_value(s, "host"));
+    let port = matches
+        .and_then(|m| m.value_of("port"))
+        .map_or(0, |s| parse_value(s, "port"));
+    let mut node = UdpNetworkNode::<String>::new_on(host, port).unwrap_or_else(|e| {
+        eprintln!("Failed to bind to {}:{}: {}", host, port, e);
+        std::process::exit(1);
+    });
+    node.discover(&peers);
+    let node_id = node.id();
+    // the members are the given peers, of which there must be one less than the group size
+    let known = node.peers().len() + 1;
+    let replica = if group_size == 0 {
+        Err(PaxosError::InvalidConfig("group size must be at least 1".to_owned()))
+    } else if known != group_size {
+        Err(PaxosError::GroupSizeMismatch {
+            expected: group_size,
+            known,
+        })
+    } else {
+        PaxosReplica::with_members(node, node_id, peers, Vec::new())
+    };
+    let replica = replica.unwrap_or_else(|e| match e {
+        PaxosError::InvalidConfig(_) | PaxosError::GroupSizeMismatch { .. } => {
+            cli().error(clap::ErrorKind::InvalidValue, e.to_string()).exit()
+        }
+        _ => {
+            eprintln!("Failed to start replica: {}", e);
+            std::process::exit(1);
+        }
+    });
+    let mut replica = match matches.and_then(|m| m.value_of("data-dir")) {
+        Some(dir) => replica.with_data_dir(dir).unwrap_or_else(|e| {
+            eprintln!("Failed to start replica: {}", e);
+            std::process::exit(1);
+        }),
+        None => replica,
+    };
+
+    // stop on SIGINT or SIGTERM, so that the final state is persisted before exiting
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_signal = stop.clone();
+    if let Err(e) = ctrlc::set_handler(move || stop_signal.store(true, Ordering::Relaxed)) {
+        eprintln!("Failed to install signal handler: {}", e);
+        std::process::exit(1);
+    }
+    println!("Started replica with ID {}", node_id);
+    while !stop.load(Ordering::Relaxed) {
+        let timeout = replica.tick();
+        replica.wait(timeout);
+    }
+    if let Err(e) = replica.close() {
+        eprintln!("Failed to shut down replica: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    match matches.subcommand() {
+        Some(("submit", m)) => {
+            let node_id = parse_node_id(m, "node");
+            let value = m.value_of("value").unwrap().to_owned();
+            if let Err(e) = PaxosClient::new().submit_to(node_id, value) {
+                eprintln!("Failed to submit value: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(("status", m)) => {
+            let node_id = parse_node_id(m, "node");
+            match PaxosClient::<String>::new().state_dump(node_id, Duration::from_secs(1)) {
+                Ok(dump) => {
+                    match dump.leader {
+                        Some(leader) => println!("leader: {}", leader),
+                        None => println!("leader: unknown"),
+                    }
+                    println!("role: {:?}", dump.role);
+                    println!("ballot: {:?}", dump.ballot);
+                    println!("commit index: {}", dump.commit_index);
+                    println!("apply index: {}", dump.apply_index);
+                    println!("log length: {}", dump.log_len);
+                }
+                Err(e) => {
+                    eprintln!("Failed to query status: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("inspect", m)) => {
+            let file = m.value_of("file").unwrap();
+            match LogExport::<String>::load(file) {
+                Ok(export) => print!("{}", export),
+                Err(e) => {
+                    eprintln!("Failed to read exported log: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("run", m)) => run(Some(m)),
+        _ => run(None),
+    }
+}
@@ -0,0 +1,889 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Lets applications outside of a group submit commands to its replicas.
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use rand::random;
+use tracing::debug;
+
+use crate::network::{Network, NodeId};
+use crate::protocol::{PaxosMsg, ReplicaStatus, RequestId, StateDump, DEFAULT_PRIORITY};
+use crate::udp_network::UdpNetworkNode;
+use crate::{AppCommand, PaxosError};
+
+/// Default for the time `submit` keeps trying to reach the leader.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time a single replica has to answer, before the client moves on to the next one.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Time between two status requests while waiting for a commit.
+const STATUS_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Time after which `submit_exactly_once` submits a command again, if it hasn't been applied,
+/// e.g. because the leader lost its leadership before proposing it.
+const RESUBMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bounds of the exponential backoff between unsuccessful attempts to reach the leader.
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How far a submitted command has to get, before `submit_and_wait` reports success.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acknowledgement {
+    /// The command is chosen, i.e. it is durably committed to the log.
+    Chosen,
+    /// A quorum of replicas has applied the command to their state machines.
+    /// Requires all replicas to run with `PaxosReplica::with_apply_acks`.
+    Applied,
+}
+
+/// Lets another thread stop `PaxosClient::submit_and_wait_cancellable` from waiting, e.g. when
+/// the application shuts down. All clones of a token are cancelled together.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Talks to the replicas of a group through a single node, which is reused for all requests.
+/// Remembers the last known leader, to send commands there directly.
+#[derive(Debug)]
+pub struct PaxosClient<V: AppCommand> {
+    node: Box<dyn Network<V>>,
+    /// The replicas tried in turn while the leader is unknown.
+    replicas: Vec<NodeId>,
+    /// Position in `replicas` of the next replica to try.
+    next_replica: Cell<usize>,
+    leader: Cell<Option<NodeId>>,
+    timeout: Duration,
+    ack: Acknowledgement,
+    /// Identifies this client in the request IDs of `submit_exactly_once`.
+    client_id: u64,
+    /// Sequence number of the next command submitted with `submit_exactly_once`.
+    next_seq: Cell<u64>,
+    /// The last request a replica rejected, received while waiting for another reply.
+    rejected: RefCell<Option<(RequestId, PaxosError)>>,
+}
+
+impl<V: AppCommand> Default for PaxosClient<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: AppCommand> PaxosClient<V> {
+    /// Creates a client talking to replicas via UDP on localhost.
+    pub fn new() -> Self {
+        Self::with_node(UdpNetworkNode::new())
+    }
+
+    /// Creates a client sending and receiving through the node, e.g. a `MemoryNode`.
+    pub fn with_node(node: impl Network<V> + 'static) -> Self {
+        Self {
+            node: Box::new(node),
+            replicas: Vec::new(),
+            next_replica: Cell::new(0),
+            leader: Cell::new(None),
+            timeout: DEFAULT_TIMEOUT,
+            ack: Acknowledgement::Chosen,
+            client_id: random(),
+            next_seq: Cell::new(1),
+            rejected: RefCell::new(None),
+        }
+    }
+
+    /// Makes `submit` look for the leader among these replicas.
+    pub fn with_replicas(mut self, replicas: &[NodeId]) -> Self {
+        self.replicas = replicas.to_vec();
+        self
+    }
+
+    /// Sets the time `submit` keeps trying to reach the leader, before giving up.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how far commands have to get before `submit_and_wait` reports success.
+    /// Defaults to `Acknowledgement::Chosen`.
+    pub fn with_acknowledgement(mut self, ack: Acknowledgement) -> Self {
+        self.ack = ack;
+        self
+    }
+
+    /// The replica this client believes to be the leader, if any.
+    /// Takes into account the leader hints replicas sent in reply to earlier submissions.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.take_leader_hints();
+        self.leader.get()
+    }
+
+    /// Sends the value to the leader, to be appended to the replicated log.
+    /// Follows replicas pointing to another leader, and tries all known replicas in turn
+    /// (backing off in between) if the leader doesn't respond, e.g. because it is down.
+    /// Returns the ID of the leader, or fails with `PaxosError::Timeout` if none was found.
+    pub fn submit(&self, value: V) -> Result<NodeId, PaxosError> {
+        self.submit_with_priority(value, DEFAULT_PRIORITY)
+    }
+
+    /// Submits the value like `submit`, but lets the leader propose it ahead of queued commands
+    /// of lower priority, e.g. to get a configuration change in ahead of bulk writes.
+    pub fn submit_with_priority(&self, value: V, priority: u8) -> Result<NodeId, PaxosError> {
+        self.node.check_command_size(&value)?;
+        let (leader, _) = self.find_leader(Instant::now() + self.timeout, None)?;
+        self.send_request(leader, value, priority, None)?;
+        Ok(leader)
+    }
+
+    /// Submits the value like `submit`, but it is never proposed after the `deadline`, e.g.
+    /// because a response after that would be worthless anyway. The leader drops the value
+    /// instead of proposing it once the deadline has passed, see `Command::Expiring` for the
+    /// caveats.
+    pub fn submit_with_deadline(
+        &self,
+        value: V,
+        deadline: SystemTime,
+    ) -> Result<NodeId, PaxosError> {
+        self.node.check_command_size(&value)?;
+        let deadline = deadline.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let (leader, _) = self.find_leader(Instant::now() + self.timeout, None)?;
+        self.send_request(leader, value, DEFAULT_PRIORITY, Some(deadline as u64))?;
+        Ok(leader)
+    }
+
+    /// Submits the value like `submit`, then waits until it is chosen, which the leader confirms
+    /// once it has applied it, and returns the leader's status from then on. The value is
+    /// submitted with a request ID, so that the leader reports about this value specifically.
+    /// With `Acknowledgement::Applied` it waits until a quorum has applied the value as well.
+    ///
+    /// Fails with `PaxosError::CommitTimeout` if that takes longer than `timeout`, telling
+    /// whether the leader proposed the value, so that callers can decide whether submitting it
+    /// again might commit it twice.
+    pub fn submit_and_wait(
+        &self,
+        value: V,
+        timeout: Duration,
+    ) -> Result<ReplicaStatus, PaxosError> {
+        self.submit_and_wait_cancellable(value, timeout, &CancelToken::new())
+    }
+
+    /// Like `submit_and_wait`, but fails with `PaxosError::Cancelled` as soon as the token is
+    /// cancelled. The leader is told to drop the command if it hasn't proposed it yet, but
+    /// cancelling doesn't withdraw a proposal, so that the command might still be committed.
+    pub fn submit_and_wait_cancellable(
+        &self,
+        value: V,
+        timeout: Duration,
+        cancel: &CancelToken,
+    ) -> Result<ReplicaStatus, PaxosError> {
+        self.node.check_command_size(&value)?;
+        let deadline = Instant::now() + timeout;
+        let (leader, _) = match self.find_leader(deadline, Some(cancel)) {
+            Err(PaxosError::Timeout) => return Err(PaxosError::CommitTimeout { index: None }),
+            found => found?,
+        };
+        let id = self.next_request_id();
+        self.send_tracked_request(leader, id, value)?;
+        loop {
+            if cancel.is_cancelled() {
+                debug!("Request {:?} cancelled, no longer waiting for it.", id);
+                let _ = self.send(leader, &PaxosMsg::CancelRequest(id));
+                return Err(PaxosError::Cancelled);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                let index = self
+                    .request_status(leader, id, REQUEST_TIMEOUT)
+                    .ok()
+                    .and_then(|(_, index, _)| index);
+                return Err(PaxosError::CommitTimeout { index });
+            }
+            let wait = remaining.min(REQUEST_TIMEOUT);
+            let polled = self.request_status(leader, id, wait);
+            self.check_rejected(id)?;
+            match polled {
+                Ok((true, index, _)) => match self.status(leader, wait) {
+                    Ok(status) if self.is_acknowledged(&status, index) => return Ok(status),
+                    Ok(_) | Err(PaxosError::Timeout) => thread::sleep(STATUS_INTERVAL),
+                    Err(e) => return Err(e),
+                },
+                Ok(_) | Err(PaxosError::Timeout) => thread::sleep(STATUS_INTERVAL),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Submits the value with a request ID and waits until the leader has applied it.
+    /// Whenever the leader fails or doesn't apply the value in time, it is submitted again,
+    /// possibly to a newly elected leader. The request ID keeps replicas from applying the
+    /// value more than once, even if several of the submissions are chosen.
+    /// Returns the ID of the leader which applied it, or fails with `PaxosError::Timeout` if that
+    /// takes longer than `timeout`, in which case the value might still be applied later.
+    ///
+    /// Replicas only remember the latest request applied per client, so commands submitted
+    /// through the same client are applied exactly once only if they are submitted one by one.
+    pub fn submit_exactly_once(&self, value: V, timeout: Duration) -> Result<NodeId, PaxosError> {
+        self.node.check_command_size(&value)?;
+        let id = self.next_request_id();
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (leader, _) = self.find_leader(deadline, None)?;
+            let applied = self
+                .send_tracked_request(leader, id, value.clone())
+                .and_then(|_| self.wait_until_applied(leader, id, deadline));
+            match applied {
+                Ok(true) => return Ok(leader),
+                Ok(false) | Err(PaxosError::Timeout) | Err(PaxosError::SendFailed { .. }) => {
+                    debug!("Request {:?} not applied by {}, submitting it again.", id, leader);
+                }
+                Err(e) => return Err(e),
+            }
+            if Instant::now() >= deadline {
+                return Err(PaxosError::Timeout);
+            }
+        }
+    }
+
+    /// Submits the value like `submit_and_wait`, but waits until the leader has applied it and
+    /// returns the result of executing it, e.g. to read a value with a command. The leader sends
+    /// the result directly to this client, even if another replica relayed the command to it.
+    ///
+    /// Fails with `PaxosError::CommandFailed` if the state machine failed to execute the value,
+    /// and with `PaxosError::CommitTimeout` like `submit_and_wait` if it isn't applied in time.
+    pub fn execute(&self, value: V, timeout: Duration) -> Result<String, PaxosError> {
+        self.node.check_command_size(&value)?;
+        let deadline = Instant::now() + timeout;
+        let (leader, _) = match self.find_leader(deadline, None) {
+            Err(PaxosError::Timeout) => return Err(PaxosError::CommitTimeout { index: None }),
+            found => found?,
+        };
+        let id = self.next_request_id();
+        self.send_tracked_request(leader, id, value)?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.node.recv(remaining) {
+                Ok((_, PaxosMsg::RequestApplied { id: r, result, .. })) if r == id => {
+                    return result.map_err(|()| PaxosError::CommandFailed);
+                }
+                Ok((_, PaxosMsg::Rejected { id: Some(r), reason })) if r == id => {
+                    return Err(reason);
+                }
+                Ok(_) if remaining > Duration::from_secs(0) => continue,
+                _ => {
+                    let index = self
+                        .request_status(leader, id, REQUEST_TIMEOUT)
+                        .ok()
+                        .and_then(|(_, index, _)| index);
+                    return Err(PaxosError::CommitTimeout { index });
+                }
+            }
+        }
+    }
+
+    /// Polls the replica until it has applied the request, or fails with `PaxosError::Timeout`
+    /// if it stops answering. Gives up after `RESUBMIT_INTERVAL` or at the deadline, returning
+    /// false, or right away if the replica reports that another leader superseded it.
+    fn wait_until_applied(
+        &self,
+        node_id: NodeId,
+        id: RequestId,
+        deadline: Instant,
+    ) -> Result<bool, PaxosError> {
+        let give_up = deadline.min(Instant::now() + RESUBMIT_INTERVAL);
+        while Instant::now() < give_up {
+            let (applied, _, superseded) = self.request_status(node_id, id, REQUEST_TIMEOUT)?;
+            self.check_rejected(id)?;
+            if applied || superseded {
+                return Ok(applied);
+            }
+            thread::sleep(STATUS_INTERVAL);
+        }
+        Ok(false)
+    }
+
+    /// Asks the replica whether it has applied the request, which log entry holds it, and
+    /// whether the replica gave up proposing it.
+    fn request_status(
+        &self,
+        node_id: NodeId,
+        id: RequestId,
+        timeout: Duration,
+    ) -> Result<(bool, Option<usize>, bool), PaxosError> {
+        self.request(node_id, PaxosMsg::RequestStatus(id), timeout, |reply| match reply {
+            PaxosMsg::RequestStatusReply {
+                id: r,
+                applied,
+                index,
+                superseded,
+            } if r == id => Some((applied, index, superseded)),
+            // the leader's notification that it applied the request answers the status as well
+            PaxosMsg::RequestApplied { id: r, index, .. } if r == id => {
+                Some((true, Some(index), false))
+            }
+            _ => None,
+        })
+    }
+
+    /// Fails with the reason a replica gave for rejecting the request, if it did so meanwhile.
+    fn check_rejected(&self, id: RequestId) -> Result<(), PaxosError> {
+        match self.rejected.take() {
+            Some((r, reason)) if r == id => Err(reason),
+            _ => Ok(()),
+        }
+    }
+
+    /// The ID for the next command submitted with one, see `RequestId`.
+    fn next_request_id(&self) -> RequestId {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq + 1);
+        RequestId {
+            client: self.client_id,
+            seq,
+        }
+    }
+
+    /// Whether the leader's status shows that the request it applied at `index` got as far as
+    /// `ack` requires. Without an index, as the entry is part of a snapshot already, all entries
+    /// the leader applied so far have to get that far.
+    fn is_acknowledged(&self, status: &ReplicaStatus, index: Option<usize>) -> bool {
+        let len = index.map_or(status.commit_index, |index| index + 1);
+        match self.ack {
+            Acknowledgement::Chosen => status.commit_index >= len,
+            Acknowledgement::Applied => status.quorum_applied.is_some_and(|n| n >= len),
+        }
+    }
+
+    /// Sends the value to the replica with the given ID, to be appended to the replicated log.
+    /// Commands which are too large to ever be proposed are rejected before sending.
+    pub fn submit_to(&self, node_id: NodeId, value: V) -> Result<(), PaxosError> {
+        self.node.check_command_size(&value)?;
+        self.send_request(node_id, value, DEFAULT_PRIORITY, None)
+    }
+
+    /// Sends the commands to the replica with the given ID, to be appended to the replicated log
+    /// as a single entry, which is applied all or nothing, see `PaxosReplica::submit_batch`.
+    /// Batches which are too large to ever be proposed are rejected before sending.
+    pub fn submit_batch_to(&self, node_id: NodeId, cmds: Vec<V>) -> Result<(), PaxosError> {
+        self.node.check_batch_size(&cmds)?;
+        let msg = PaxosMsg::ClientBatch {
+            values: cmds,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+        };
+        self.send(node_id, &msg)
+    }
+
+    /// Asks the replica with the given ID for its current leader and progress.
+    /// Fails with `PaxosError::Timeout` if no answer arrives in time.
+    pub fn status(&self, node_id: NodeId, timeout: Duration) -> Result<ReplicaStatus, PaxosError> {
+        self.request(node_id, PaxosMsg::StatusRequest, timeout, |reply| match reply {
+            PaxosMsg::StatusReply(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    /// Asks the replica with the given ID for a summary of its internal state, for debugging.
+    /// Fails with `PaxosError::Timeout` if no answer arrives in time.
+    pub fn state_dump(&self, node_id: NodeId, timeout: Duration) -> Result<StateDump, PaxosError> {
+        self.request(node_id, PaxosMsg::StateDumpRequest, timeout, |reply| match reply {
+            PaxosMsg::StateDumpReply(dump) => Some(dump),
+            _ => None,
+        })
+    }
+
+    /// Checks that the group can still commit entries, not just that its replicas are up, by
+    /// asking the replica with the given ID to have the leader commit a NoOp.
+    /// Returns the time the leader took for it, or fails with `PaxosError::Timeout` if no answer
+    /// arrives in time, e.g. because there is no leader or it can't reach a quorum.
+    pub fn probe_health(&self, node_id: NodeId, timeout: Duration) -> Result<Duration, PaxosError> {
+        let prober = self.node.id();
+        self.send(node_id, &PaxosMsg::HealthProbe { prober, hops: 0 })?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.node.recv(remaining) {
+                // the answer comes from the leader, which needn't be the replica asked
+                Ok((_, PaxosMsg::HealthOk { latency_ms })) => {
+                    return Ok(Duration::from_millis(latency_ms))
+                }
+                Ok(_) if remaining > Duration::from_secs(0) => continue,
+                _ => return Err(PaxosError::Timeout),
+            }
+        }
+    }
+
+    /// Sends the request to the replica and waits for the first reply `extract` accepts.
+    fn request<T>(
+        &self,
+        node_id: NodeId,
+        request: PaxosMsg<V>,
+        timeout: Duration,
+        extract: impl Fn(PaxosMsg<V>) -> Option<T>,
+    ) -> Result<T, PaxosError> {
+        self.send(node_id, &request)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.node.recv(remaining) {
+                // rejections come from the leader, which needn't be the replica asked
+                Ok((_, PaxosMsg::Rejected { id: Some(id), reason })) => {
+                    *self.rejected.borrow_mut() = Some((id, reason));
+                }
+                Ok((src, reply)) if src == node_id => {
+                    if let Some(reply) = extract(reply) {
+                        return Ok(reply);
+                    }
+                }
+                Ok((_, PaxosMsg::NotLeader { leader_hint })) if leader_hint.is_some() => {
+                    self.leader.set(leader_hint);
+                }
+                Ok(_) if remaining > Duration::from_secs(0) => continue,
+                _ => return Err(PaxosError::Timeout),
+            }
+        }
+    }
+
+    /// Looks for a replica which confirms that it is the leader, starting with the cached one.
+    /// Gives up with `PaxosError::Cancelled` once the token, if any, is cancelled.
+    fn find_leader(
+        &self,
+        deadline: Instant,
+        cancel: Option<&CancelToken>,
+    ) -> Result<(NodeId, ReplicaStatus), PaxosError> {
+        self.take_leader_hints();
+        let mut backoff = MIN_BACKOFF;
+        let mut redirects = 0;
+        loop {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                return Err(PaxosError::Cancelled);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let target = match self.leader.take().or_else(|| self.next_replica()) {
+                Some(target) if remaining > Duration::from_secs(0) => target,
+                _ => return Err(PaxosError::Timeout),
+            };
+            match self.status(target, remaining.min(REQUEST_TIMEOUT)) {
+                Ok(status) if status.leader == Some(target) => {
+                    self.leader.set(Some(target));
+                    return Ok((target, status));
+                }
+                // replicas might point at each other while their knowledge is stale
+                Ok(ReplicaStatus {
+                    leader: Some(leader),
+                    ..
+                }) if redirects < self.replicas.len() => {
+                    debug!("Redirected from {} to {}.", target, leader);
+                    self.leader.set(Some(leader));
+                    redirects += 1;
+                    continue;
+                }
+                Ok(_) | Err(PaxosError::Timeout) | Err(PaxosError::SendFailed { .. }) => {
+                    debug!("No leader found at {}, retrying in {:?}.", target, backoff);
+                }
+                Err(e) => return Err(e),
+            }
+            redirects = 0;
+            thread::sleep(backoff.min(deadline.saturating_duration_since(Instant::now())));
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Updates the cached leader from the hints replicas sent back, when they received one of
+    /// our requests without being the leader. Other pending messages are stale by now.
+    fn take_leader_hints(&self) {
+        while let Ok((src, msg)) = self.node.try_recv() {
+            if let PaxosMsg::NotLeader {
+                leader_hint: Some(leader),
+            } = msg
+            {
+                debug!("Replica {} hinted at leader {}.", src, leader);
+                self.leader.set(Some(leader));
+            }
+        }
+    }
+
+    /// The next replica to ask for the leader, cycling through all known replicas.
+    fn next_replica(&self) -> Option<NodeId> {
+        if self.replicas.is_empty() {
+            return None;
+        }
+        let i = self.next_replica.get() % self.replicas.len();
+        self.next_replica.set(i + 1);
+        Some(self.replicas[i])
+    }
+
+    fn send_request(
+        &self,
+        dst: NodeId,
+        value: V,
+        priority: u8,
+        deadline: Option<u64>,
+    ) -> Result<(), PaxosError> {
+        let msg = PaxosMsg::ClientRequest {
+            value,
+            hops: 0,
+            priority,
+            deadline,
+        };
+        self.send(dst, &msg)
+    }
+
+    fn send_tracked_request(&self, dst: NodeId, id: RequestId, value: V) -> Result<(), PaxosError> {
+        let msg = PaxosMsg::TrackedRequest {
+            id,
+            value,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+            origin: None,
+        };
+        self.send(dst, &msg)
+    }
+
+    fn send(&self, dst: NodeId, msg: &PaxosMsg<V>) -> Result<(), PaxosError> {
+        if !self.node.send(dst, msg) {
+            return Err(PaxosError::SendFailed { dst });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::{ClusterBuilder, ClusterConfig, ReplicaHandle};
+    use crate::network::MemoryNetwork;
+    use crate::{PaxosReplica, ReplicatedStateMachine};
+
+    #[test]
+    fn submit_to_invalid_node() {
+        let client = PaxosClient::<u32>::new();
+        assert_eq!(
+            client.submit_to(NodeId(usize::MAX), 42),
+            Err(PaxosError::SendFailed { dst: NodeId(usize::MAX) })
+        );
+        assert_eq!(
+            client.status(NodeId(usize::MAX), Duration::from_millis(10)),
+            Err(PaxosError::SendFailed { dst: NodeId(usize::MAX) })
+        );
+        // without any known replicas there is nobody to ask for the leader
+        assert_eq!(client.submit(42), Err(PaxosError::Timeout));
+    }
+
+    #[test]
+    fn follow_leader_change() {
+        let mut cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let timeout = Duration::from_secs(10);
+        let status = cluster.client().submit_and_wait(1, timeout).unwrap();
+        let old_leader = cluster.client().leader().unwrap();
+        assert_eq!(status.leader, Some(old_leader));
+
+        // the cached leader crashes, so the client has to find the newly elected one
+        drop(cluster.stop(old_leader));
+        let status = cluster.client().submit_and_wait(2, timeout).unwrap();
+        let new_leader = cluster.client().leader().unwrap();
+        assert_ne!(new_leader, old_leader);
+        assert_eq!(status.leader, Some(new_leader));
+        for replica in cluster.shutdown() {
+            let applied: Vec<u32> = replica.history().map(|(_, &v)| v).collect();
+            assert_eq!(applied, vec![1, 2]);
+        }
+    }
+
+    #[test]
+    fn resubmit_after_leader_crash() {
+        let mut cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let timeout = Duration::from_secs(10);
+        let old_leader = cluster.client().submit_exactly_once(1, timeout).unwrap();
+
+        // the leader gets the command chosen, but crashes before the client learns about it
+        let client = cluster.client();
+        let id = RequestId {
+            client: client.client_id,
+            seq: client.next_seq.get(),
+        };
+        let request = PaxosMsg::TrackedRequest {
+            id,
+            value: 2,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+            origin: None,
+        };
+        client.send(old_leader, &request).unwrap();
+        let deadline = Instant::now() + timeout;
+        for &follower in cluster.ids().iter().filter(|&&id| id != old_leader) {
+            while !client.wait_until_applied(follower, id, deadline).unwrap() {
+                assert!(Instant::now() < deadline, "command not applied in time");
+            }
+        }
+        drop(cluster.stop(old_leader));
+
+        // the client submits it again to the new leader, which applies it only once
+        let new_leader = cluster.client().submit_exactly_once(2, timeout).unwrap();
+        assert_ne!(new_leader, old_leader);
+        assert_eq!(cluster.client().submit_exactly_once(3, timeout), Ok(new_leader));
+        for replica in cluster.shutdown() {
+            let applied: Vec<u32> = replica.history().map(|(_, &v)| v).collect();
+            assert_eq!(applied, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn follow_leader_hint() {
+        let cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let client = cluster.client();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let leader = loop {
+            assert!(Instant::now() < deadline, "no leader elected in time");
+            let leaders: Vec<_> = cluster
+                .ids()
+                .iter()
+                .map(|&id| client.status(id, REQUEST_TIMEOUT).ok().and_then(|s| s.leader))
+                .collect();
+            if leaders[0].is_some() && leaders.iter().all(|&l| l == leaders[0]) {
+                break leaders[0].unwrap();
+            }
+            thread::sleep(STATUS_INTERVAL);
+        };
+        assert_eq!(client.leader(), None);
+
+        // the follower relays the command and tells the client where to send the next one
+        let follower = *cluster.ids().iter().find(|&&id| id != leader).unwrap();
+        client.submit_to(follower, 1).unwrap();
+        while client.leader().is_none() {
+            assert!(Instant::now() < deadline, "no leader hint received in time");
+            thread::sleep(STATUS_INTERVAL);
+        }
+        assert_eq!(client.leader(), Some(leader));
+        assert_eq!(client.submit(2), Ok(leader));
+    }
+
+    #[test]
+    fn wait_for_quorum_application() {
+        let config = ClusterConfig {
+            apply_acks: true,
+            ..ClusterConfig::default()
+        };
+        let cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new())
+            .with_config(config)
+            .spawn_udp();
+        let client = PaxosClient::<u32>::new()
+            .with_replicas(cluster.ids())
+            .with_acknowledgement(Acknowledgement::Applied);
+        let status = client.submit_and_wait(1, Duration::from_secs(10)).unwrap();
+        assert!(status.quorum_applied >= Some(1));
+
+        // without reports from the followers only the leader is known to have applied it
+        let cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_udp();
+        let client = PaxosClient::<u32>::new()
+            .with_replicas(cluster.ids())
+            .with_acknowledgement(Acknowledgement::Applied);
+        assert!(cluster.client().submit_and_wait(1, Duration::from_secs(10)).is_ok());
+        let result = client.submit_and_wait(2, Duration::from_secs(2));
+        assert_eq!(result, Err(PaxosError::CommitTimeout { index: Some(2) }));
+    }
+
+    #[test]
+    fn wait_for_the_submitted_command() {
+        // a leader which commits other entries all the time, but never proposes the command
+        let network = MemoryNetwork::new();
+        let leader = network.connect(NodeId(1));
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let fake_leader = thread::spawn(move || {
+            let mut commit_index = 1;
+            while !stopped.load(Ordering::Relaxed) {
+                let (src, reply) = match leader.recv(STATUS_INTERVAL) {
+                    Ok((src, PaxosMsg::StatusRequest)) => {
+                        commit_index += 1;
+                        let status = ReplicaStatus {
+                            leader: Some(NodeId(1)),
+                            commit_index,
+                            quorum_applied: None,
+                        };
+                        (src, PaxosMsg::StatusReply(status))
+                    }
+                    Ok((src, PaxosMsg::RequestStatus(id))) => {
+                        let reply = PaxosMsg::RequestStatusReply {
+                            id,
+                            applied: false,
+                            index: None,
+                            superseded: false,
+                        };
+                        (src, reply)
+                    }
+                    _ => continue,
+                };
+                leader.send(src, &reply);
+            }
+        });
+        let client = PaxosClient::<u32>::with_node(network.connect(NodeId(0)))
+            .with_replicas(&[NodeId(1)]);
+        let result = client.submit_and_wait(1, Duration::from_millis(300));
+        assert_eq!(result, Err(PaxosError::CommitTimeout { index: None }));
+        stop.store(true, Ordering::Relaxed);
+        fake_leader.join().unwrap();
+    }
+
+    #[test]
+    fn time_out_without_leader() {
+        // a single replica of a group of three can't get elected
+        let network = MemoryNetwork::new();
+        let node = network.connect(NodeId(1));
+        let members = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let replica =
+            PaxosReplica::with_members(node, NodeId(1), members, Vec::<u32>::new()).unwrap();
+        let _replica = ReplicaHandle::spawn(replica);
+        let client = PaxosClient::with_node(network.connect(NodeId(0))).with_replicas(&[NodeId(1)]);
+        let result = client.submit_and_wait(1, Duration::from_millis(500));
+        assert_eq!(result, Err(PaxosError::CommitTimeout { index: None }));
+    }
+
+    /// Records commands like `Vec`, but rejects 0 before it is proposed.
+    #[derive(Default)]
+    struct NonZero(Vec<u32>);
+
+    impl ReplicatedStateMachine for NonZero {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            self.0.push(v);
+            Ok(v.to_string())
+        }
+
+        fn validate(&self, v: &u32) -> Result<(), String> {
+            if *v == 0 {
+                return Err("zero".to_owned());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn report_rejected_commands() {
+        let network = MemoryNetwork::new();
+        let node = network.connect(NodeId(1));
+        let replica =
+            PaxosReplica::with_members(node, NodeId(1), vec![NodeId(1)], NonZero(vec![])).unwrap();
+        let _replica = ReplicaHandle::spawn(replica);
+        let client = PaxosClient::with_node(network.connect(NodeId(0))).with_replicas(&[NodeId(1)]);
+        let timeout = Duration::from_secs(10);
+        let start = Instant::now();
+
+        // the client learns about the rejection right away, instead of waiting for the timeout
+        let rejected = Err(PaxosError::CommandRejected("zero".to_owned()));
+        assert_eq!(client.submit_and_wait(0, timeout).map(|_| ()), rejected);
+        assert_eq!(client.execute(0, timeout).map(|_| ()), rejected);
+        assert_eq!(client.submit_exactly_once(0, timeout).map(|_| ()), rejected);
+        assert!(start.elapsed() < timeout / 2);
+        assert_eq!(client.execute(1, timeout), Ok("1".to_owned()));
+    }
+
+    #[test]
+    fn cancel_waiting_for_leader() {
+        let network = MemoryNetwork::new();
+        let node = network.connect(NodeId(1));
+        let members = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let replica =
+            PaxosReplica::with_members(node, NodeId(1), members, Vec::<u32>::new()).unwrap();
+        let _replica = ReplicaHandle::spawn(replica);
+        let client = PaxosClient::with_node(network.connect(NodeId(0))).with_replicas(&[NodeId(1)]);
+        let cancel = CancelToken::new();
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                cancel.cancel();
+            })
+        };
+        let start = Instant::now();
+        let result = client.submit_and_wait_cancellable(1, Duration::from_secs(10), &cancel);
+        assert_eq!(result, Err(PaxosError::Cancelled));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+    }
+
+    #[test]
+    fn time_out_in_partitioned_group() {
+        let mut cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let timeout = Duration::from_secs(10);
+        cluster.client().submit_and_wait(1, timeout).unwrap();
+        let leader = cluster.client().leader().unwrap();
+
+        // the leader still believes in its leadership and proposes the value, but no quorum is
+        // left to accept it
+        for id in cluster.ids().to_vec() {
+            if id != leader {
+                drop(cluster.stop(id));
+            }
+        }
+        let result = cluster.client().submit_and_wait(2, Duration::from_millis(500));
+        assert_eq!(result, Err(PaxosError::CommitTimeout { index: Some(2) }));
+    }
+
+    #[test]
+    fn probe_health() {
+        let cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let timeout = Duration::from_secs(10);
+        cluster.client().submit_and_wait(1, timeout).unwrap();
+        let leader = cluster.client().leader().unwrap();
+        let before = cluster.client().status(leader, timeout).unwrap();
+        for &id in cluster.ids() {
+            // followers relay the probe to the leader
+            let latency = cluster.client().probe_health(id, timeout).unwrap();
+            assert!(latency < timeout);
+        }
+        // each probe had the leader commit an entry
+        let after = cluster.client().status(leader, timeout).unwrap();
+        assert!(after.commit_index >= before.commit_index + cluster.ids().len());
+
+        // a single replica of a group of three has no quorum to commit anything
+        let network = MemoryNetwork::new();
+        let node = network.connect(NodeId(1));
+        let members = vec![NodeId(1), NodeId(2), NodeId(3)];
+        let replica =
+            PaxosReplica::with_members(node, NodeId(1), members, Vec::<u32>::new()).unwrap();
+        let _replica = ReplicaHandle::spawn(replica);
+        let client = PaxosClient::with_node(network.connect(NodeId(0)));
+        let result = client.probe_health(NodeId(1), Duration::from_millis(500));
+        assert_eq!(result, Err(PaxosError::Timeout));
+    }
+
+    #[test]
+    fn dump_leader_state() {
+        let cluster = ClusterBuilder::new(3, |_| Vec::<u32>::new()).spawn_in_memory();
+        let timeout = Duration::from_secs(10);
+        for v in 1..=3 {
+            cluster.client().submit_and_wait(v, timeout).unwrap();
+        }
+        let leader = cluster.client().leader().unwrap();
+        let dump = cluster.client().state_dump(leader, timeout).unwrap();
+        assert_eq!(dump.role, crate::Role::Voter);
+        assert_eq!(dump.leader, Some(leader));
+        // the leader applied everything it committed, which includes the submitted values
+        assert!(dump.commit_index >= 3);
+        assert_eq!(dump.apply_index, dump.commit_index);
+        assert!(dump.log_len >= dump.apply_index);
+        for replica in cluster.shutdown() {
+            let applied: Vec<u32> = replica.history().map(|(_, &v)| v).collect();
+            assert_eq!(applied, vec![1, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Sources of time used by replicas for their leases and timeouts.
+//! Tests can use the `ManualClock` to control the passing of time.
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of time. Leases and timeouts only ever use the monotonic `now`, so that adjustments
+/// of the wall clock, e.g. by NTP, can't make a lease seem to last longer than it does.
+///
+/// The monotonic clock may stand still while the machine is suspended, however. A replica
+/// resumed after more than `LEASE_DURATION` still believes in its lease, although the others
+/// may have elected a new leader meanwhile, so that leases are only safe as long as replicas
+/// aren't suspended, or their clocks are otherwise paused, for that long.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current point in time on a monotonic clock, which never goes backwards.
+    fn now(&self) -> Instant;
+
+    /// Returns the time passed since the given point in time, or zero if it lies in the future.
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    /// Returns the current wall-clock time, which unlike `now` can be compared between nodes,
+    /// though only as precisely as their clocks are synchronized. As it may jump in either
+    /// direction, it must never decide about leases.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Uses the operating system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only moves forward when it is explicitly advanced.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<Instant>,
+    /// A point in time together with the wall-clock time at that point.
+    started: Mutex<(Instant, SystemTime)>,
+}
+
+impl ManualClock {
+    /// Creates a new clock, which initially stands still at the current point in time.
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            now: Mutex::new(now),
+            started: Mutex::new((now, SystemTime::now())),
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Sets the wall-clock time, e.g. to simulate an adjustment by NTP. Unlike `advance`, this
+    /// can also move backwards, and it leaves the monotonic time unaffected.
+    pub fn set_system_time(&self, time: SystemTime) {
+        *self.started.lock().unwrap() = (self.now(), time);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    /// Advances together with `now`, starting at the wall-clock time of the clock's creation.
+    fn system_time(&self) -> SystemTime {
+        let (instant, system_time) = *self.started.lock().unwrap();
+        system_time + self.elapsed(instant)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_advances() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.elapsed(start), Duration::from_secs(0));
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.elapsed(start), Duration::from_millis(1500));
+        assert_eq!(clock.elapsed(clock.now() + Duration::from_secs(1)), Duration::from_secs(0));
+
+        let time = clock.system_time();
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.system_time().duration_since(time).unwrap(), Duration::from_secs(3));
+
+        let now = clock.now();
+        clock.set_system_time(time - Duration::from_secs(60));
+        assert_eq!(clock.now(), now);
+        assert_eq!(time.duration_since(clock.system_time()).unwrap(), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Assembles groups of replicas running on threads of the local process,
+//! e.g. for examples, tests and experiments.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tracing::{info, info_span};
+
+use crate::client::PaxosClient;
+use crate::codec::BincodeCodec;
+use crate::network::{MemoryNetwork, Network, NodeId};
+use crate::protocol::{CATCH_UP_WINDOW, POLL_INTERVAL, PROPOSAL_WINDOW};
+use crate::replica::PaxosReplica;
+use crate::udp_network::{SocketOptions, UdpNetworkNode};
+use crate::{AppCommand, PaxosError, ReplicatedStateMachine};
+
+/// Settings applied to every replica of a cluster.
+#[derive(Clone, Debug)]
+pub struct ClusterConfig {
+    /// See `PaxosReplica::with_poll_interval`.
+    pub poll_interval: Duration,
+    /// See `PaxosReplica::with_latency_tracking`.
+    pub track_latency: bool,
+    /// See `PaxosReplica::with_proposal_window`.
+    pub proposal_window: usize,
+    /// See `PaxosReplica::with_apply_acks`.
+    pub apply_acks: bool,
+    /// Base directory the replicas persist their state in, see `PaxosReplica::with_data_dir`.
+    /// Without one, replicas keep their state in memory only.
+    pub data_dir: Option<PathBuf>,
+    /// Encoding of both the messages sent over UDP and the state persisted to disk.
+    pub codec: BincodeCodec,
+    /// Address of the interface replicas communicating over UDP bind to, localhost by default.
+    pub bind_addr: IpAddr,
+    /// Bytes per second and burst size each replica communicating over UDP may send,
+    /// see `UdpNetworkNode::with_rate_limit`. Unlimited by default.
+    pub rate_limit: Option<(u64, usize)>,
+    /// Buffer sizes of the sockets of replicas communicating over UDP, see `SocketOptions`.
+    /// The operating system's defaults are kept by default.
+    pub socket_options: SocketOptions,
+    /// Seed of the replicas' random number generators, offset by their IDs so that they differ,
+    /// see `PaxosReplica::with_rng`. Seeded by the operating system by default.
+    pub seed: Option<u64>,
+    /// Window in which replicas persist the values they accept together, see
+    /// `PaxosReplica::with_group_commit`. Each accepted value is persisted on its own by default.
+    pub group_commit: Option<Duration>,
+    /// Number of applied entries replicas keep for subscribers which haven't acknowledged them,
+    /// see `PaxosReplica::with_subscriber_retention`. Entries aren't kept beyond snapshots
+    /// by default.
+    pub subscriber_retention: Option<usize>,
+    /// Whether leaders skip Heartbeats to followers they recently sent other messages to, see
+    /// `PaxosReplica::with_heartbeat_suppression`. Heartbeats are sent to all followers by default.
+    pub heartbeat_suppression: bool,
+    /// How many chosen values a replica sends per request of a replica catching up, see
+    /// `PaxosReplica::with_catch_up_window`.
+    pub catch_up_window: usize,
+    /// Group the replicas communicating over UDP and the cluster's client are part of, so that
+    /// they drop messages of other groups, see `UdpNetworkNode::with_group`. Group 0 by default.
+    pub group: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: POLL_INTERVAL,
+            track_latency: false,
+            proposal_window: PROPOSAL_WINDOW,
+            apply_acks: false,
+            data_dir: None,
+            codec: BincodeCodec::default(),
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            rate_limit: None,
+            socket_options: SocketOptions::default(),
+            seed: None,
+            group_commit: None,
+            subscriber_retention: None,
+            heartbeat_suppression: false,
+            catch_up_window: CATCH_UP_WINDOW,
+            group: 0,
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Applies the settings to the replica.
+    /// Fails if the replica's data directory can't be created.
+    pub(crate) fn configure<V: AppCommand>(
+        &self,
+        replica: PaxosReplica<V>,
+    ) -> Result<PaxosReplica<V>, PaxosError> {
+        let mut replica = replica
+            .with_poll_interval(self.poll_interval)
+            .with_proposal_window(self.proposal_window)
+            .with_catch_up_window(self.catch_up_window)
+            .with_storage_codec(self.codec);
+        if self.track_latency {
+            replica = replica.with_latency_tracking();
+        }
+        if self.apply_acks {
+            replica = replica.with_apply_acks();
+        }
+        if let Some(data_dir) = &self.data_dir {
+            replica = replica.with_data_dir(data_dir)?;
+        }
+        if let Some(seed) = self.seed {
+            let seed = seed.wrapping_add(replica.id().0 as u64);
+            replica = replica.with_rng(StdRng::seed_from_u64(seed));
+        }
+        if let Some(window) = self.group_commit {
+            replica = replica.with_group_commit(window);
+        }
+        if let Some(max_entries) = self.subscriber_retention {
+            replica = replica.with_subscriber_retention(max_entries);
+        }
+        if self.heartbeat_suppression {
+            replica = replica.with_heartbeat_suppression();
+        }
+        Ok(replica)
+    }
+
+    /// Creates a node on the configured interface, which a replica communicates over UDP with.
+    pub(crate) fn bind_udp<V: AppCommand>(&self) -> io::Result<UdpNetworkNode<V>> {
+        let mut node = UdpNetworkNode::new_on(self.bind_addr, 0)?
+            .with_codec(self.codec)
+            .with_group(self.group)
+            .with_socket_options(&self.socket_options)?;
+        if let Some((rate, burst)) = self.rate_limit {
+            node = node.with_rate_limit(rate, burst);
+        }
+        Ok(node)
+    }
+}
+
+/// Creates a group of replicas which know each other, each running on its own thread.
+///
+/// ```
+/// # use paxos::{ClusterBuilder, NodeId};
+/// let cluster = ClusterBuilder::new(3, |_| Vec::<String>::new()).spawn_in_memory();
+/// assert_eq!(cluster.ids(), &[NodeId(1), NodeId(2), NodeId(3)]);
+/// ```
+pub struct ClusterBuilder<S> {
+    group_size: usize,
+    state_machine: Box<dyn FnMut(NodeId) -> S>,
+    config: ClusterConfig,
+}
+
+impl<S> ClusterBuilder<S>
+where
+    S: ReplicatedStateMachine + Send + 'static,
+{
+    /// Prepares a group of `group_size` replicas.
+    /// The state machine of each replica is created by calling `state_machine` with its ID.
+    pub fn new(group_size: usize, state_machine: impl FnMut(NodeId) -> S + 'static) -> Self {
+        Self {
+            group_size,
+            state_machine: Box::new(state_machine),
+            config: ClusterConfig::default(),
+        }
+    }
+
+    /// Applies the settings to all replicas of the cluster.
+    pub fn with_config(mut self, config: ClusterConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Starts the replicas, which communicate over UDP on the configured interface.
+    /// Panics if no socket can be bound or a data directory can't be created, see `start_replica`
+    /// for reporting this instead.
+    pub fn spawn_udp(self) -> Cluster<S::Command> {
+        let config = self.config.clone();
+        let bind = || config.bind_udp().expect("failed to bind UDP socket");
+        let nodes: Vec<_> = (0..self.group_size).map(|_| bind()).collect();
+        self.spawn(nodes, bind())
+    }
+
+    /// Starts the replicas, which communicate over a `MemoryNetwork` and get the IDs 1 to n.
+    /// Panics if a data directory can't be created.
+    pub fn spawn_in_memory(self) -> Cluster<S::Command> {
+        let network = MemoryNetwork::new();
+        let nodes: Vec<_> = (1..=self.group_size)
+            .map(|id| network.connect(NodeId(id)))
+            .collect();
+        self.spawn(nodes, network.connect(NodeId(0)))
+    }
+
+    fn spawn(
+        mut self,
+        nodes: Vec<impl Network<S::Command> + 'static>,
+        client: impl Network<S::Command> + 'static,
+    ) -> Cluster<S::Command> {
+        let ids: Vec<NodeId> = nodes.iter().map(|n| n.id()).collect();
+        let replicas = nodes
+            .into_iter()
+            .map(|node| {
+                let node_id = node.id();
+                let state_machine = (self.state_machine)(node_id);
+                let replica = PaxosReplica::with_members(node, node_id, ids.clone(), state_machine)
+                    .and_then(|replica| self.config.configure(replica))
+                    .expect("failed to configure replica");
+                Some(ReplicaHandle::spawn(replica))
+            })
+            .collect();
+        Cluster {
+            client: PaxosClient::with_node(client).with_replicas(&ids),
+            ids,
+            replicas,
+        }
+    }
+}
+
+/// Handle to a replica running on its own thread, which is stopped once it is dropped.
+#[derive(Debug)]
+pub struct ReplicaHandle<V: AppCommand> {
+    node_id: NodeId,
+    /// Tells the thread to stop, which then returns the replica.
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<PaxosReplica<V>>>,
+}
+
+impl<V: AppCommand> ReplicaHandle<V> {
+    /// Runs the replica's main loop on a new thread, until it is told to stop.
+    pub(crate) fn spawn(mut replica: PaxosReplica<V>) -> Self {
+        let node_id = replica.id();
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = stop.clone();
+        let thread = thread::spawn(move || {
+            // configure a span to associate tracing output with this replica
+            let tracing_span = info_span!("Replica", id = %node_id);
+            let _guard = tracing_span.enter();
+            info!("Starting Paxos Replica with ID {}", node_id);
+            while !flag.load(Ordering::Relaxed) {
+                let timeout = replica.tick();
+                replica.wait(timeout);
+            }
+            replica
+        });
+        Self {
+            node_id,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// The ID of the replica, which clients send their requests to.
+    pub fn id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Stops the replica and hands it back, e.g. to inspect its state.
+    pub fn stop(mut self) -> PaxosReplica<V> {
+        self.stop.store(true, Ordering::Relaxed);
+        let thread = self.thread.take().expect("replica thread already joined");
+        thread.join().expect("replica thread panicked")
+    }
+
+    /// Stops the replica and closes it, persisting its final state and releasing its network
+    /// node, see `PaxosReplica::close`.
+    pub fn shutdown(self) -> Result<(), PaxosError> {
+        self.stop().close()
+    }
+}
+
+impl<V: AppCommand> Drop for ReplicaHandle<V> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Handle to a running group of replicas, which are stopped once it is dropped.
+#[derive(Debug)]
+pub struct Cluster<V: AppCommand> {
+    ids: Vec<NodeId>,
+    client: PaxosClient<V>,
+    /// The replicas still running, in the same order as `ids`.
+    replicas: Vec<Option<ReplicaHandle<V>>>,
+}
+
+impl<V: AppCommand> Cluster<V> {
+    /// The IDs of all replicas, in the order they were created.
+    pub fn ids(&self) -> &[NodeId] {
+        &self.ids
+    }
+
+    /// A client for talking to the replicas from outside the group, which knows all of them.
+    pub fn client(&self) -> &PaxosClient<V> {
+        &self.client
+    }
+
+    /// Stops a single replica and hands it back, e.g. to simulate a crash by dropping it.
+    /// Returns `None` if there is no such replica or it was already stopped.
+    pub fn stop(&mut self, node_id: NodeId) -> Option<PaxosReplica<V>> {
+        let i = self.ids.iter().position(|&id| id == node_id)?;
+        Some(self.replicas[i].take()?.stop())
+    }
+
+    /// Stops all replicas and hands back the ones still running, e.g. to inspect their state.
+    pub fn shutdown(mut self) -> Vec<PaxosReplica<V>> {
+        let ids = self.ids.clone();
+        ids.into_iter().filter_map(|id| self.stop(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ReplicaStatus;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+    struct Put(String, String);
+
+    impl AppCommand for Put {}
+
+    #[derive(Default)]
+    struct KeyValueStore(HashMap<String, String>);
+
+    impl ReplicatedStateMachine for KeyValueStore {
+        type Command = Put;
+
+        fn execute(&mut self, Put(key, value): Put) -> Result<String, ()> {
+            Ok(self.0.insert(key, value).unwrap_or_default())
+        }
+    }
+
+    /// Polls the replica's status until the condition holds, or panics after a while.
+    fn wait_for<V: AppCommand>(
+        cluster: &Cluster<V>,
+        node_id: NodeId,
+        condition: impl Fn(&ReplicaStatus) -> bool,
+    ) -> ReplicaStatus {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if let Ok(status) = cluster.client().status(node_id, Duration::from_millis(100)) {
+                if condition(&status) {
+                    return status;
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("replica {} didn't reach the expected state in time", node_id);
+    }
+
+    #[test]
+    fn build_kv_cluster() {
+        let cluster = ClusterBuilder::new(5, |_| KeyValueStore::default()).spawn_in_memory();
+        cluster.client().submit(Put("Hello".to_owned(), "World".to_owned())).unwrap();
+
+        for &id in cluster.ids() {
+            wait_for(&cluster, id, |s| s.commit_index == 2);
+        }
+        for replica in cluster.shutdown() {
+            assert_eq!(replica.committed_len(), 2);
+        }
+    }
+
+    #[test]
+    fn make_progress_under_rate_limit() {
+        let config = ClusterConfig {
+            rate_limit: Some((20_000, 2_000)),
+            ..ClusterConfig::default()
+        };
+        let cluster = ClusterBuilder::new(3, |_| KeyValueStore::default())
+            .with_config(config)
+            .spawn_udp();
+        for i in 0..10 {
+            cluster.client().submit(Put(i.to_string(), "x".repeat(500))).unwrap();
+        }
+        for &id in cluster.ids() {
+            wait_for(&cluster, id, |s| s.commit_index == 11);
+        }
+        let replicas = cluster.shutdown();
+        let leader = replicas.iter().find(|r| r.is_leader()).unwrap();
+        assert_eq!(leader.committed_len(), 11);
+    }
+}
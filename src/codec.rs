@@ -0,0 +1,509 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Encodings used for messages on the wire and for state on disk.
+//!
+//! `BincodeCodec` is the default, as it is compact and fast. Its integer encoding and byte
+//! order can be configured, e.g. to shrink messages with variable length integers.
+//! `JsonCodec` produces human readable output, which helps with debugging and allows
+//! replicas to interoperate with tools written in other languages.
+//! Not all types survive a round-trip through JSON like they do through bincode, though:
+//! maps need string keys, floats can't be NaN or infinite, and byte vectors turn into
+//! (rather large) arrays of numbers. All types used by the protocol itself are fine,
+//! so this only restricts the choice of commands.
+
+use std::fmt::Debug;
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Errors reported by a codec, e.g. when decoding corrupted data.
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Converts values of type `T` to bytes and back.
+pub trait Codec<T>: Debug + Send + Sync {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Decodes a value encoded with bincode's default configuration, reading at most `bytes.len()`
+/// bytes. Without this limit a corrupted or malicious length prefix, e.g. of a string, makes
+/// bincode allocate as much memory as the prefix claims, before noticing the missing data.
+pub(crate) fn bincode_deserialize<T: DeserializeOwned>(bytes: &[u8]) -> bincode::Result<T> {
+    bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(bytes.len() as u64)
+        .deserialize(bytes)
+}
+
+/// Runs `$body` with `$options` bound to the bincode options configured by the `BincodeCodec`.
+/// Each combination of settings has a distinct type, hence this can't be a function.
+macro_rules! with_bincode_options {
+    ($codec:expr, $options:ident => $body:expr) => {{
+        let base = bincode::options().allow_trailing_bytes();
+        match ($codec.varint, $codec.big_endian) {
+            (false, false) => {
+                let $options = base.with_fixint_encoding().with_little_endian();
+                $body
+            }
+            (false, true) => {
+                let $options = base.with_fixint_encoding().with_big_endian();
+                $body
+            }
+            (true, false) => {
+                let $options = base.with_varint_encoding().with_little_endian();
+                $body
+            }
+            (true, true) => {
+                let $options = base.with_varint_encoding().with_big_endian();
+                $body
+            }
+        }
+    }};
+}
+
+/// Encodes values with bincode, by default with fixed length integers in little endian order.
+/// Decoding never allocates more memory than the encoded data could possibly describe.
+///
+/// The encoded bytes start with a tag identifying the configuration. Data encoded with another
+/// configuration is thus rejected, instead of being silently decoded into garbage.
+/// Use the same configuration for network and storage, e.g. via `ClusterConfig::codec`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BincodeCodec {
+    varint: bool,
+    big_endian: bool,
+}
+
+impl BincodeCodec {
+    /// Encodes integers with a variable length, which makes small numbers take up less space.
+    pub fn with_varint_encoding(mut self) -> Self {
+        self.varint = true;
+        self
+    }
+
+    /// Encodes integers in big endian byte order, i.e. network byte order.
+    pub fn with_big_endian(mut self) -> Self {
+        self.big_endian = true;
+        self
+    }
+
+    /// The tag preceding all data encoded with this configuration.
+    fn tag(&self) -> u8 {
+        u8::from(self.varint) | u8::from(self.big_endian) << 1
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for BincodeCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = vec![self.tag()];
+        with_bincode_options!(self, options => options.serialize_into(&mut bytes, value))?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match bytes.split_first() {
+            Some((&tag, payload)) if tag == self.tag() => {
+                let limit = payload.len() as u64;
+                Ok(with_bincode_options!(self, options => {
+                    options.with_limit(limit).deserialize(payload)
+                })?)
+            }
+            Some((&tag, _)) => Err(format!("encoded with other bincode options ({})", tag).into()),
+            None => Err("missing bincode options tag".into()),
+        }
+    }
+}
+
+/// Encodes values as JSON text.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for JsonCodec {
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NodeId;
+    use crate::protocol::{
+        AppliedRequests, Ballot, Command, Membership, PaxosMsg, ReplicaStatus, RequestId,
+        StateDump, PROTOCOL_VERSION,
+    };
+    use crate::replica::Role;
+    use crate::storage::{load_from_disk_file, store_in_disk_file, StorageError};
+    use crate::PaxosError;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    enum Operation {
+        Put { key: String, value: Vec<u8> },
+        Delete(String),
+        Clear,
+    }
+
+    impl crate::AppCommand for Operation {}
+
+    fn messages<V: Clone + Debug>(value: V) -> Vec<PaxosMsg<V>> {
+        let ballot = Ballot::default();
+        let id = RequestId {
+            client: u64::MAX,
+            seq: 3,
+        };
+        let mut requests = AppliedRequests::new();
+        requests.record(id, 1);
+        vec![
+            PaxosMsg::Prepare {
+                ballot,
+                holes: vec![0, 5, 7],
+                sent_at: None,
+            },
+            PaxosMsg::Promise {
+                ballot,
+                accepted: vec![
+                    (3, ballot, Command::App(value.clone())),
+                    (4, ballot, Command::NoOp),
+                    (5, ballot, Command::Batch(vec![value.clone(), value.clone()])),
+                    (6, ballot, Command::Tracked(id, value.clone())),
+                    (7, ballot, Command::Expiring { deadline: 0, value: value.clone() }),
+                ],
+                sent_at: Some(u64::MAX),
+            },
+            PaxosMsg::Propose {
+                index: usize::MAX,
+                ballot,
+                value: Command::Reconfigure(Membership {
+                    current: vec![NodeId(1), NodeId(2), NodeId(3)],
+                    next: Some(vec![NodeId(2), NodeId(3), NodeId(4)]),
+                }),
+                sent_at: Some(12_345),
+            },
+            PaxosMsg::Accept {
+                index: 41,
+                ballot,
+                sent_at: Some(12_345),
+            },
+            PaxosMsg::Learn {
+                index: 42,
+                ballot,
+                value: Command::App(value.clone()),
+            },
+            PaxosMsg::CatchUp { from: 40 },
+            PaxosMsg::InstallSnapshot {
+                snapshot_index: 39,
+                membership: Membership::new(vec![NodeId(1), NodeId(2)]),
+                data: vec![0, 1, 255],
+                requests,
+            },
+            PaxosMsg::Nack {
+                ballot,
+                promised: ballot,
+            },
+            PaxosMsg::Heartbeat {
+                ballot,
+                seq: u64::MAX,
+                applied: 7,
+            },
+            PaxosMsg::HeartbeatAck {
+                ballot,
+                seq: u64::MAX,
+            },
+            PaxosMsg::TimeoutNow {
+                ballot,
+                target: NodeId(3),
+            },
+            PaxosMsg::Applied { index: 42 },
+            PaxosMsg::ClientRequest {
+                value: value.clone(),
+                hops: 3,
+                priority: 200,
+                deadline: Some(u64::MAX),
+            },
+            PaxosMsg::TrackedRequest {
+                id,
+                value: value.clone(),
+                hops: 1,
+                priority: 0,
+                origin: Some(NodeId(9)),
+            },
+            PaxosMsg::ClientBatch {
+                values: vec![value],
+                hops: 0,
+                priority: 1,
+            },
+            PaxosMsg::NotLeader {
+                leader_hint: Some(NodeId(3)),
+            },
+            PaxosMsg::StatusRequest,
+            PaxosMsg::StatusReply(ReplicaStatus {
+                leader: Some(NodeId(281_474_976_710_655)),
+                commit_index: 7,
+                quorum_applied: Some(5),
+            }),
+            PaxosMsg::StateDumpRequest,
+            PaxosMsg::StateDumpReply(StateDump {
+                ballot,
+                role: Role::Learner,
+                leader: None,
+                commit_index: 7,
+                apply_index: 5,
+                log_len: 9,
+            }),
+            PaxosMsg::HealthProbe {
+                prober: NodeId(7),
+                hops: 2,
+            },
+            PaxosMsg::HealthOk { latency_ms: 12 },
+            PaxosMsg::RequestStatus(id),
+            PaxosMsg::RequestStatusReply {
+                id,
+                applied: true,
+                index: Some(9),
+                superseded: false,
+            },
+            PaxosMsg::RequestApplied {
+                id,
+                index: 9,
+                result: Ok("done".to_owned()),
+            },
+            PaxosMsg::CancelRequest(id),
+            PaxosMsg::LearnAck { index: 42 },
+            PaxosMsg::Rejected {
+                id: Some(id),
+                reason: PaxosError::CommandRejected("invalid".to_owned()),
+            },
+        ]
+    }
+
+    /// All variants of `PaxosMsg`, each of which `messages` must contain.
+    const VARIANTS: &[&str] = &[
+        "Prepare",
+        "Promise",
+        "Propose",
+        "Accept",
+        "Learn",
+        "CatchUp",
+        "InstallSnapshot",
+        "Nack",
+        "Heartbeat",
+        "HeartbeatAck",
+        "TimeoutNow",
+        "Applied",
+        "ClientRequest",
+        "ClientBatch",
+        "TrackedRequest",
+        "NotLeader",
+        "StatusRequest",
+        "StatusReply",
+        "StateDumpRequest",
+        "StateDumpReply",
+        "HealthProbe",
+        "HealthOk",
+        "RequestStatus",
+        "RequestStatusReply",
+        "RequestApplied",
+        "CancelRequest",
+        "LearnAck",
+        "Rejected",
+    ];
+
+    /// The name of the message's variant. The match is exhaustive, so that no variant can be
+    /// added without being listed in `VARIANTS`, and thus covered by the wire format fixtures.
+    fn variant<V: Debug>(msg: &PaxosMsg<V>) -> &'static str {
+        match msg {
+            PaxosMsg::Prepare { .. } => "Prepare",
+            PaxosMsg::Promise { .. } => "Promise",
+            PaxosMsg::Propose { .. } => "Propose",
+            PaxosMsg::Accept { .. } => "Accept",
+            PaxosMsg::Learn { .. } => "Learn",
+            PaxosMsg::CatchUp { .. } => "CatchUp",
+            PaxosMsg::InstallSnapshot { .. } => "InstallSnapshot",
+            PaxosMsg::Nack { .. } => "Nack",
+            PaxosMsg::Heartbeat { .. } => "Heartbeat",
+            PaxosMsg::HeartbeatAck { .. } => "HeartbeatAck",
+            PaxosMsg::TimeoutNow { .. } => "TimeoutNow",
+            PaxosMsg::Applied { .. } => "Applied",
+            PaxosMsg::ClientRequest { .. } => "ClientRequest",
+            PaxosMsg::ClientBatch { .. } => "ClientBatch",
+            PaxosMsg::TrackedRequest { .. } => "TrackedRequest",
+            PaxosMsg::NotLeader { .. } => "NotLeader",
+            PaxosMsg::StatusRequest => "StatusRequest",
+            PaxosMsg::StatusReply(_) => "StatusReply",
+            PaxosMsg::StateDumpRequest => "StateDumpRequest",
+            PaxosMsg::StateDumpReply(_) => "StateDumpReply",
+            PaxosMsg::HealthProbe { .. } => "HealthProbe",
+            PaxosMsg::HealthOk { .. } => "HealthOk",
+            PaxosMsg::RequestStatus(_) => "RequestStatus",
+            PaxosMsg::RequestStatusReply { .. } => "RequestStatusReply",
+            PaxosMsg::RequestApplied { .. } => "RequestApplied",
+            PaxosMsg::CancelRequest(_) => "CancelRequest",
+            PaxosMsg::LearnAck { .. } => "LearnAck",
+            PaxosMsg::Rejected { .. } => "Rejected",
+        }
+    }
+
+    /// The checked-in encoding of `messages` for the current `PROTOCOL_VERSION`.
+    fn wire_fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(format!("wire-v{}.txt", PROTOCOL_VERSION))
+    }
+
+    /// Checks that all messages decode to what was encoded, by comparing their debug output.
+    fn assert_round_trip<V, C>(codec: C, value: V)
+    where
+        V: crate::AppCommand,
+        C: Codec<PaxosMsg<V>>,
+    {
+        for msg in messages(value) {
+            let bytes = codec.encode(&msg).unwrap();
+            let decoded = codec.decode(&bytes).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", msg));
+        }
+    }
+
+    #[test]
+    fn round_trip_string_messages() {
+        assert_round_trip(BincodeCodec::default(), "Hello 🦀 \"World\"".to_owned());
+        assert_round_trip(JsonCodec, "Hello 🦀 \"World\"".to_owned());
+    }
+
+    #[test]
+    fn messages_cover_all_variants() {
+        let mut covered: Vec<_> = messages(()).iter().map(variant).collect();
+        covered.sort_unstable();
+        covered.dedup();
+        let mut all = VARIANTS.to_vec();
+        all.sort_unstable();
+        assert_eq!(covered, all);
+    }
+
+    /// Compares the encoding of every message with the fixture, to catch accidental changes
+    /// which would break rolling upgrades, e.g. reordered variants or added fields.
+    /// An intentional change must bump `PROTOCOL_VERSION`; the fixture for the new version
+    /// is then written by running this test with `UPDATE_WIRE_FIXTURES` set.
+    #[test]
+    fn wire_format_is_stable() {
+        let codec = BincodeCodec::default();
+        let encoded: String = messages("value".to_owned())
+            .iter()
+            .map(|msg| {
+                let bytes = codec.encode(msg).unwrap();
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("{} {}\n", variant(msg), hex)
+            })
+            .collect();
+        let path = wire_fixture();
+        if std::env::var_os("UPDATE_WIRE_FIXTURES").is_some() {
+            std::fs::write(&path, &encoded).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| {
+            panic!("no wire format fixture for protocol version {}", PROTOCOL_VERSION)
+        });
+        for (actual, expected) in encoded.lines().zip(expected.lines()) {
+            assert_eq!(actual, expected, "wire format changed, bump PROTOCOL_VERSION");
+        }
+        assert_eq!(encoded.lines().count(), expected.lines().count());
+    }
+
+    #[test]
+    fn round_trip_custom_command() {
+        let commands = vec![
+            Operation::Put {
+                key: "key".to_owned(),
+                value: vec![0, 1, 255],
+            },
+            Operation::Delete("key".to_owned()),
+            Operation::Clear,
+        ];
+        for cmd in commands {
+            assert_round_trip(BincodeCodec::default(), cmd.clone());
+            assert_round_trip(JsonCodec, cmd);
+        }
+    }
+
+    #[test]
+    fn configure_bincode_options() {
+        let varint = BincodeCodec::default().with_varint_encoding();
+        assert_round_trip(varint, "Hello".to_owned());
+        assert_round_trip(varint.with_big_endian(), "Hello".to_owned());
+        assert_round_trip(BincodeCodec::default().with_big_endian(), "Hello".to_owned());
+
+        let filename = std::env::temp_dir().join("paxos-varint-codec.bin");
+        let filename = filename.to_str().unwrap();
+        let squares: Vec<u64> = (0..10).map(|i| i * i).collect();
+        store_in_disk_file(&varint, filename, &squares).unwrap();
+        assert_eq!(load_from_disk_file(&varint, filename).ok(), Some(squares.clone()));
+
+        // data encoded with other options is rejected, instead of decoding into garbage
+        let fixint = BincodeCodec::default();
+        let bytes = Codec::encode(&varint, &squares).unwrap();
+        assert!(bytes.len() < Codec::encode(&fixint, &squares).unwrap().len());
+        assert!(Codec::<Vec<u64>>::decode(&fixint, &bytes).is_err());
+        assert!(Codec::<Vec<u64>>::decode(&varint.with_big_endian(), &bytes).is_err());
+        assert!(matches!(
+            load_from_disk_file::<Vec<u64>>(&fixint, filename),
+            Err(StorageError::Codec(_))
+        ));
+        std::fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn json_is_readable() {
+        let msg = PaxosMsg::ClientRequest {
+            value: Operation::Delete("key".to_owned()),
+            hops: 1,
+            priority: 0,
+            deadline: None,
+        };
+        let bytes = Codec::encode(&JsonCodec, &msg).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            r#"{"ClientRequest":{"value":{"Delete":"key"},"hops":1,"priority":0,"deadline":null}}"#
+        );
+    }
+
+    #[test]
+    fn json_limitations() {
+        // maps with non-string keys can't be encoded as JSON objects
+        let map: BTreeMap<(u32, u32), u32> = vec![((1, 2), 3)].into_iter().collect();
+        assert!(Codec::encode(&BincodeCodec::default(), &map).is_ok());
+        assert!(Codec::encode(&JsonCodec, &map).is_err());
+
+        // non-finite floats become null, which doesn't decode back into a float
+        let bytes = Codec::encode(&JsonCodec, &f64::NAN).unwrap();
+        assert!(Codec::<f64>::decode(&JsonCodec, &bytes).is_err());
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let bincode = BincodeCodec::default();
+        assert!(Codec::<PaxosMsg<String>>::decode(&bincode, &[]).is_err());
+        assert!(Codec::<PaxosMsg<String>>::decode(&bincode, &[0, 255, 255]).is_err());
+        assert!(Codec::<PaxosMsg<String>>::decode(&JsonCodec, b"{not json").is_err());
+    }
+
+    #[test]
+    fn reject_huge_length_prefix() {
+        let msg = PaxosMsg::ClientRequest {
+            value: "abc".to_owned(),
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        };
+        let mut bytes = Codec::encode(&BincodeCodec::default(), &msg).unwrap();
+        // the string's length follows the tag and the variant index
+        bytes[5..13].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(Codec::<PaxosMsg<String>>::decode(&BincodeCodec::default(), &bytes).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Errors reported to users of the Paxos replicated log.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::network::NodeId;
+
+/// Errors which can occur when interacting with a Paxos replica or group.
+/// Replicas send them to clients whose requests they reject, see `PaxosMsg::Rejected`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PaxosError {
+    /// The serialized command would not fit into a single network message.
+    CommandTooLarge { size: usize, max: usize },
+    /// The operation can only be performed by the leader.
+    NotLeader,
+    /// Another membership change has not completed yet.
+    ReconfigurationInProgress,
+    /// The message could not be sent to the node, e.g. because its ID isn't a valid address.
+    SendFailed { dst: NodeId },
+    /// The operation could not be completed in time.
+    Timeout,
+    /// The state machine failed to execute the command.
+    CommandFailed,
+    /// The state machine rejected the command before it was proposed, for the given reason.
+    CommandRejected(String),
+    /// No higher ballot can be generated anymore, as its round number would overflow.
+    BallotExhausted,
+    /// The replica is draining and doesn't accept new commands, try another replica.
+    Draining,
+    /// The configured group size doesn't match the number of replicas known to the node.
+    GroupSizeMismatch { expected: usize, known: usize },
+    /// The state machine can't produce snapshots of its state.
+    SnapshotUnsupported,
+    /// Another snapshot is still being written.
+    SnapshotInProgress,
+    /// The leader's lease expired, as no quorum has confirmed its leadership recently.
+    LeaseExpired,
+    /// The newly elected leader hasn't committed an entry of its own yet, try again shortly.
+    NotReady,
+    /// The replica's state may be staler than the caller allows, try a fresher replica.
+    TooStale,
+    /// The replica's state machine lags too far behind the log, try again later.
+    Overloaded,
+    /// The leader has lost contact with a quorum, so that nothing can be committed until it is
+    /// restored, try again later.
+    NoQuorum,
+    /// An I/O operation failed, e.g. binding a socket, for the given reason.
+    Io(String),
+    /// The replica can't be created with the given configuration, for the given reason.
+    InvalidConfig(String),
+    /// The learner can't be promoted yet, as it has applied too few of the leader's entries.
+    LearnerBehind { applied: usize, leader: usize },
+    /// The command wasn't committed in time. `index` is the log entry the leader proposed it
+    /// for. Without one, the command most likely never made it into the log, e.g. because no
+    /// leader was found, so that submitting it again is unlikely to commit it twice.
+    CommitTimeout { index: Option<usize> },
+    /// The requested log entries were replaced by a snapshot, which covers the log up to
+    /// `snapshot_index`.
+    Truncated { snapshot_index: usize },
+    /// The log entry is chosen already, so that its value can't be changed anymore.
+    AlreadyChosen { index: usize },
+    /// The subscription was dropped, as it left too many entries unacknowledged from log index
+    /// `acked` onwards, see `PaxosReplica::with_subscriber_retention`.
+    SubscriberLagging { acked: usize },
+    /// The caller stopped waiting through its `CancelToken`. A command submitted before might
+    /// still be committed, as cancelling doesn't withdraw proposals.
+    Cancelled,
+    /// The snapshot would not fit into a single network message, so that replicas which are
+    /// behind it couldn't catch up anymore.
+    SnapshotTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for PaxosError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandTooLarge { size, max } => {
+                write!(f, "command too large: {} bytes (max. {} bytes)", size, max)
+            }
+            Self::NotLeader => write!(f, "replica is not the leader"),
+            Self::ReconfigurationInProgress => write!(f, "reconfiguration already in progress"),
+            Self::SendFailed { dst } => write!(f, "failed to send message to node {}", dst),
+            Self::Timeout => write!(f, "operation timed out"),
+            Self::CommandFailed => write!(f, "state machine failed to execute command"),
+            Self::CommandRejected(reason) => write!(f, "command rejected: {}", reason),
+            Self::BallotExhausted => write!(f, "ballot round numbers are exhausted"),
+            Self::Draining => write!(f, "replica is draining"),
+            Self::GroupSizeMismatch { expected, known } => write!(
+                f,
+                "group of {} replicas configured, but {} are known",
+                expected, known
+            ),
+            Self::SnapshotUnsupported => write!(f, "state machine doesn't support snapshots"),
+            Self::SnapshotInProgress => write!(f, "snapshot already in progress"),
+            Self::LeaseExpired => write!(f, "leader's lease expired"),
+            Self::Overloaded => write!(f, "state machine lags behind, try again later"),
+            Self::NoQuorum => write!(f, "no quorum of replicas reachable"),
+            Self::NotReady => write!(f, "leader is not ready yet"),
+            Self::TooStale => write!(f, "replica state too stale"),
+            Self::Io(reason) => write!(f, "I/O error: {}", reason),
+            Self::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            Self::LearnerBehind { applied, leader } => write!(
+                f,
+                "learner has applied {} entries, but the leader {}",
+                applied, leader
+            ),
+            Self::CommitTimeout { index: Some(index) } => {
+                write!(f, "command proposed at index {} but not committed in time", index)
+            }
+            Self::CommitTimeout { index: None } => write!(f, "command not proposed in time"),
+            Self::Truncated { snapshot_index } => write!(
+                f,
+                "log entries before index {} were replaced by a snapshot",
+                snapshot_index
+            ),
+            Self::AlreadyChosen { index } => write!(f, "log entry {} is chosen already", index),
+            Self::SubscriberLagging { acked } => write!(
+                f,
+                "subscriber dropped for not acknowledging entries from index {}",
+                acked
+            ),
+            Self::Cancelled => write!(f, "operation cancelled"),
+            Self::SnapshotTooLarge { size, max } => {
+                write!(f, "snapshot too large: {} bytes (max. {} bytes)", size, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaxosError {}
@@ -0,0 +1,51 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Policies steering which replica of a group is likely to become leader.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::network::NodeId;
+
+/// How much longer than the preferred replica all others wait before standing for election.
+/// This exceeds the spread of the random election timeouts, so the preferred one goes first.
+const PREFERENCE_MARGIN: Duration = Duration::from_millis(200);
+
+/// Decides how eagerly a replica stands for election, once the leader's lease has expired.
+///
+/// Whoever stands first usually wins, so policies can give some replicas precedence, e.g. the
+/// ones in the primary datacenter. Policies only affect the timing of elections: every replica
+/// with a vote still stands eventually, so that a group can elect a leader as long as a quorum
+/// is up, and safety never depends on the policy.
+pub trait LeaderPolicy: Debug + Send {
+    /// How long the replica `node_id` waits after the leader's lease expired, or after its own
+    /// unsuccessful election, before standing for election. `base` is the (usually random)
+    /// time it would wait without a policy.
+    fn election_delay(&self, node_id: NodeId, base: Duration) -> Duration;
+}
+
+/// Treats all replicas alike, leaving the outcome of elections to chance.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultPolicy;
+
+impl LeaderPolicy for DefaultPolicy {
+    fn election_delay(&self, _node_id: NodeId, base: Duration) -> Duration {
+        base
+    }
+}
+
+/// Makes the given replica stand for election before all others, so that it becomes leader
+/// whenever it is up and reachable. Once it is down, one of the others takes over.
+#[derive(Clone, Copy, Debug)]
+pub struct PreferNode(pub NodeId);
+
+impl LeaderPolicy for PreferNode {
+    fn election_delay(&self, node_id: NodeId, base: Duration) -> Duration {
+        if node_id == self.0 {
+            base
+        } else {
+            base + PREFERENCE_MARGIN
+        }
+    }
+}
@@ -3,91 +3,540 @@
 
 //! Implementation of a replicated log using the Multi-Paxos consensus protocol.
 
+mod client;
+mod clock;
+mod cluster;
+mod codec;
+mod error;
+mod leader_policy;
+mod metrics;
+mod network;
 mod protocol;
+mod rate_limit;
 mod replica;
+mod sink;
 mod storage;
 mod udp_network;
+mod watchdog;
 
-use std::{fmt::Debug, thread};
+use std::{fmt::Debug, time::Duration};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use protocol::PaxosMsg;
-pub use replica::PaxosReplica;
-pub use udp_network::UdpNetworkNode;
+pub use client::{Acknowledgement, CancelToken, PaxosClient};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use cluster::{Cluster, ClusterBuilder, ClusterConfig, ReplicaHandle};
+pub use codec::{BincodeCodec, Codec, CodecError, JsonCodec};
+pub use error::PaxosError;
+pub use leader_policy::{DefaultPolicy, LeaderPolicy, PreferNode};
+pub use metrics::{LatencyHistogram, Metrics, SizeHistogram};
+pub use network::{
+    command_wire_size, BroadcastResult, MemoryNetwork, MemoryNode, MessageCounts, Network, NodeId,
+    RecvError,
+};
+pub use protocol::{
+    has_quorum, quorum, AppliedRequests, Ballot, Command, LogEntry, Membership, ReplicaStatus,
+    RequestId, Snapshot, StateDump,
+};
+#[cfg(feature = "test-hooks")]
+pub use protocol::PaxosMsg;
+pub use replica::{
+    replay_log, run_until_idle, AppliedCommand, ApplyWatch, FanOut, LeaderState, PaxosReplica,
+    Role, Subscription,
+};
+pub use sink::{CommitSink, FileSink};
+pub use storage::{load_from_disk_file, LogExport, LogFile, PersistentState, StorageError};
+pub use udp_network::{SocketOptions, UdpNetworkNode};
 
+/// Commands which can be stored in the replicated log.
+///
+/// Custom command types only need an empty impl, once they derive the required traits:
+///
+/// ```
+/// #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+/// enum Command {
+///     Increment(u64),
+///     Reset,
+/// }
+///
+/// impl paxos::AppCommand for Command {}
+/// ```
 pub trait AppCommand: Clone + Debug + Serialize + DeserializeOwned + Send + 'static {}
 impl AppCommand for String {}
 impl AppCommand for u32 {}
+impl AppCommand for u64 {}
+impl AppCommand for i64 {}
+impl AppCommand for Vec<u8> {}
 
+/// Serializes a state captured by `ReplicatedStateMachine::snapshot_writer`,
+/// on a thread separate from the replica's.
+pub type SnapshotWriter = Box<dyn FnOnce() -> Option<Vec<u8>> + Send>;
+
+/// The application state replicated by a group, which all replicas keep an identical copy of.
+///
+/// Every replica owns its state machine, which is passed to its constructor, and executes
+/// each chosen command on it exactly once, in log order:
+///
+/// ```
+/// use paxos::{MemoryNetwork, NodeId, PaxosReplica, ReplicatedStateMachine};
+///
+/// #[derive(Default)]
+/// struct Counter(u64);
+///
+/// impl ReplicatedStateMachine for Counter {
+///     type Command = u64;
+///
+///     fn execute(&mut self, v: u64) -> Result<String, ()> {
+///         self.0 += v;
+///         Ok(self.0.to_string())
+///     }
+///
+///     fn query(&self, _: &u64) -> Result<String, ()> {
+///         Ok(self.0.to_string())
+///     }
+/// }
+///
+/// let network = MemoryNetwork::new();
+/// let id = NodeId(1);
+/// let node = network.connect(id);
+/// let mut replica = PaxosReplica::with_members(node, id, vec![id], Counter::default()).unwrap();
+/// replica.tick();
+/// replica.submit_value(40).unwrap();
+/// replica.submit_value(2).unwrap();
+/// assert_eq!(replica.linearizable_read(0), Ok("42".to_owned()));
+/// ```
 pub trait ReplicatedStateMachine {
     type Command: AppCommand;
 
+    #[allow(clippy::result_unit_err)]
     fn execute(&mut self, v: Self::Command) -> Result<String, ()>;
+
+    /// Answers a read-only command from the current state, e.g. for
+    /// `PaxosReplica::linearizable_read`. Reads don't go through the log, so this must not
+    /// change the state, or the replica serving them would diverge from the others.
+    /// Reads aren't supported by default.
+    #[allow(clippy::result_unit_err)]
+    fn query(&self, _cmd: &Self::Command) -> Result<String, ()> {
+        Err(())
+    }
+
+    /// Checks a submitted command before it is proposed, so that commands which are known to be
+    /// invalid don't take up a slot in the log. Rejected commands are reported to the submitter
+    /// as `PaxosError::CommandRejected` with the returned reason, if it waits for a result.
+    ///
+    /// This is only a best-effort pre-check: it sees the state applied so far on the replica
+    /// receiving the command, but commands proposed meanwhile may change the state before this
+    /// one is applied. Accepted commands can thus still fail in `execute`, which remains the
+    /// authoritative check. Accepts all commands by default.
+    fn validate(&self, _cmd: &Self::Command) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Serializes the current state, so that the log entries applied so far can be discarded,
+    /// see `PaxosReplica::take_snapshot`. Returns `None` if snapshots aren't supported.
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Captures the current state, e.g. as a clone or a copy-on-write view, and returns a function
+    /// serializing it like `snapshot`. It runs on a separate thread, so that commands can be
+    /// executed meanwhile, see `PaxosReplica::start_snapshot`.
+    /// By default the state is serialized right away, using `snapshot`.
+    fn snapshot_writer(&self) -> Option<SnapshotWriter> {
+        let data = self.snapshot()?;
+        Some(Box::new(move || Some(data)))
+    }
+
+    /// Replaces the current state with one previously returned by `snapshot`,
+    /// e.g. on a replica which is too far behind to catch up entry by entry.
+    #[allow(clippy::result_unit_err)]
+    fn restore(&mut self, _snapshot: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+
+    /// Executes the commands of a batch, see `PaxosReplica::submit_batch`, all or nothing:
+    /// if any of them fails, the state has to be left as it was before the batch.
+    /// Like `execute`, this must be deterministic, so that all replicas agree on the outcome.
+    ///
+    /// By default the state is captured with `snapshot` before executing the commands in order,
+    /// and put back with `restore` once one of them fails. State machines without `snapshot` or
+    /// `restore` reject all batches without executing any command, unless they override this,
+    /// e.g. with a cheaper way to undo the commands executed so far. Whether `restore` is
+    /// supported is checked by restoring the captured state before executing anything.
+    #[allow(clippy::result_unit_err)]
+    fn execute_batch(&mut self, cmds: Vec<Self::Command>) -> Result<Vec<String>, ()> {
+        let before = self.snapshot().ok_or(())?;
+        self.restore(&before)?;
+        let mut results = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            match self.execute(cmd) {
+                Ok(result) => results.push(result),
+                Err(()) => {
+                    self.restore(&before)?;
+                    return Err(());
+                }
+            }
+        }
+        Ok(results)
+    }
 }
 
-pub fn start_replica<V: AppCommand>(group_size: usize) -> usize {
-    let node = UdpNetworkNode::new();
+/// The simplest state machine, which only keeps a record of all executed commands.
+impl<V: AppCommand> ReplicatedStateMachine for Vec<V> {
+    type Command = V;
+
+    fn execute(&mut self, v: Self::Command) -> Result<String, ()> {
+        self.push(v);
+        Ok(String::new())
+    }
+
+    /// Reports the number of commands recorded so far.
+    fn query(&self, _: &Self::Command) -> Result<String, ()> {
+        Ok(self.len().to_string())
+    }
+
+    fn snapshot(&self) -> Option<Vec<u8>> {
+        bincode::serialize(self).ok()
+    }
+
+    fn snapshot_writer(&self) -> Option<SnapshotWriter> {
+        let state = self.clone();
+        Some(Box::new(move || bincode::serialize(&state).ok()))
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), ()> {
+        *self = codec::bincode_deserialize(snapshot).map_err(|_| ())?;
+        Ok(())
+    }
+}
+
+/// Starts a replica of the group formed with the given peers, which communicates over UDP on
+/// the configured interface and runs on its own thread until the returned handle is dropped.
+/// Commands are applied to the state machine, and the settings are those of a `Cluster`.
+/// Fails if no socket can be bound for the replica, or its data directory can't be created.
+pub fn start_replica<S>(
+    state_machine: S,
+    peers: &[NodeId],
+    config: &ClusterConfig,
+) -> Result<ReplicaHandle<S::Command>, PaxosError>
+where
+    S: ReplicatedStateMachine + Send + 'static,
+{
+    let node = config.bind_udp().map_err(|e| PaxosError::Io(e.to_string()))?;
     let node_id = node.id();
-    let mut replica = PaxosReplica::<V>::new(node, node_id, group_size);
-    thread::spawn(move || {
-        loop {
-            replica.tick();
-        }
-    });
-    node_id
+    let replica = PaxosReplica::with_members(node, node_id, peers.to_vec(), state_machine)?;
+    Ok(ReplicaHandle::spawn(config.configure(replica)?))
+}
+
+/// Sends the value to the replica with the given ID, to be appended to the replicated log.
+/// This sets up a new `PaxosClient` for every call, prefer using one directly instead.
+pub fn submit_value<T: AppCommand>(node_id: NodeId, value: T) -> Result<(), PaxosError> {
+    PaxosClient::new().submit_to(node_id, value)
 }
 
-pub fn submit_value<T: AppCommand>(node_id: usize, value: T) {
-    let node = UdpNetworkNode::new();
-    node.send(node_id, &PaxosMsg::ClientRequest(value));
+/// Sends the commands to the replica with the given ID, to be appended to the replicated log
+/// as a single entry, which is applied all or nothing, see `PaxosReplica::submit_batch`.
+/// This sets up a new `PaxosClient` for every call, prefer using one directly instead.
+pub fn submit_batch<T: AppCommand>(node_id: NodeId, cmds: Vec<T>) -> Result<(), PaxosError> {
+    PaxosClient::new().submit_batch_to(node_id, cmds)
+}
+
+/// Asks the replica with the given ID for its current leader and progress.
+/// This sets up a new `PaxosClient` for every call, prefer using one directly instead.
+pub fn query_status<T: AppCommand>(
+    node_id: NodeId,
+    timeout: Duration,
+) -> Result<ReplicaStatus, PaxosError> {
+    PaxosClient::<T>::new().status(node_id, timeout)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use proptest::prelude::*;
+    use protocol::LEASE_DURATION;
+    use std::sync::Arc;
+    use std::thread;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(25))]
 
-    /// Start a set of testing replicas, all running on localhost and connected to each other.
-    pub fn start_replicas<V: AppCommand>(group_size: usize) -> Vec<usize> {
-        // create the network nodes
+        #[test]
+        fn submit_random_value_test(s in "\\PC*{1,128}") {
+            let cluster = ClusterBuilder::new(3, |_| Vec::<String>::new()).spawn_udp();
+            thread::sleep(std::time::Duration::new(2, 0));
+            submit_value(cluster.ids()[0], s).unwrap();
+            thread::sleep(std::time::Duration::new(1, 0));
+        }
+    }
+
+    /// Create a set of testing replicas, which share the same manually advanced clock.
+    fn create_replicas<V: AppCommand>(
+        group_size: usize,
+        clock: Arc<ManualClock>,
+    ) -> Vec<PaxosReplica<V>> {
         let mut nodes = Vec::new();
         for _ in 0..group_size {
             nodes.push(UdpNetworkNode::<V>::new());
         }
-        // start the replicas and make them know about everyone else
-        let node_ids = nodes.iter().map(|n| n.id()).collect();
-        for mut node in nodes {
-            node.discover(&node_ids);
-            let node_id = node.id();
-            let mut replica = PaxosReplica::<V>::new(node, node_id, group_size);
-            thread::spawn(move || {
-                loop {
-                    replica.tick();
+        let node_ids: Vec<NodeId> = nodes.iter().map(|n| n.id()).collect();
+        nodes
+            .into_iter()
+            .map(|node| {
+                let node_id = node.id();
+                PaxosReplica::with_members(node, node_id, node_ids.clone(), Vec::new())
+                    .unwrap()
+                    .with_clock(clock.clone())
+            })
+            .collect()
+    }
+
+    /// Create the nodes of a group of three replicas with the logical IDs 1 to 3.
+    fn create_group_nodes(group: u64) -> Vec<UdpNetworkNode<u32>> {
+        let mut nodes: Vec<_> = (1..=3)
+            .map(|id| UdpNetworkNode::new().with_group(group).with_id(NodeId(id)))
+            .collect();
+        let addrs: Vec<_> = nodes.iter().map(|n| n.socket.local_addr().unwrap()).collect();
+        for node in &mut nodes {
+            for (i, &addr) in addrs.iter().enumerate() {
+                if NodeId(i + 1) != node.id() {
+                    node.discover_at(NodeId(i + 1), addr);
                 }
-            });
+            }
         }
-        node_ids
+        nodes
     }
 
-    proptest! {
-        #![proptest_config(ProptestConfig::with_cases(25))]
+    /// Tick all replicas for the given number of rounds.
+    fn tick_all<V: AppCommand>(replicas: &mut [PaxosReplica<V>], rounds: usize) {
+        for _ in 0..rounds {
+            for replica in replicas.iter_mut() {
+                replica.tick();
+            }
+        }
+    }
 
-        #[test]
-        fn submit_random_value_test(s in "\\PC*{1,128}") {
-            let nodes = start_replicas::<String>(3);
-            thread::sleep(std::time::Duration::new(2, 0));
-            submit_value(nodes[0], s);
-            thread::sleep(std::time::Duration::new(1, 0));
+    /// Let the initial lease time out, so that the replicas elect a leader.
+    fn elect_leader<V: AppCommand>(replicas: &mut [PaxosReplica<V>], clock: &ManualClock) {
+        clock.advance(LEASE_DURATION * 2);
+        tick_all(replicas, 3);
+        assert_eq!(replicas.iter().filter(|r| r.is_leader()).count(), 1);
+    }
+
+    /// Submit the values to a group of three replicas and check they are all committed.
+    fn commit_values<V: AppCommand>(values: Vec<V>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut replicas = create_replicas::<V>(3, clock.clone());
+        elect_leader(&mut replicas, &clock);
+        let count = values.len();
+        for (i, value) in values.into_iter().enumerate() {
+            submit_value(replicas[i % 3].id(), value).unwrap();
+            tick_all(&mut replicas, 1);
+        }
+        tick_all(&mut replicas, 5);
+        for replica in &replicas {
+            // the leader's NoOp comes first
+            assert_eq!(replica.committed_len(), count + 1);
         }
     }
 
     #[test]
     fn submit_value_test() {
-        let nodes = start_replicas::<String>(2);
-        thread::sleep(std::time::Duration::new(3, 0));
-        submit_value(nodes[0], "Hello".to_owned());
-        submit_value(nodes[1], "World".to_owned());
-        thread::sleep(std::time::Duration::new(3, 0));
+        let clock = Arc::new(ManualClock::new());
+        let network = MemoryNetwork::<String>::with_clock(clock.clone());
+        let ids = [NodeId(1), NodeId(2)];
+        let mut replicas: Vec<_> = ids
+            .iter()
+            .map(|&id| {
+                PaxosReplica::with_members(network.connect(id), id, ids.to_vec(), Vec::new())
+                    .unwrap()
+                    .with_clock(clock.clone())
+            })
+            .collect();
+        let client = PaxosClient::with_node(network.connect(NodeId(0)));
+        clock.advance(LEASE_DURATION * 2);
+        assert!(run_until_idle(&mut replicas, 10));
+        assert_eq!(replicas.iter().filter(|r| r.is_leader()).count(), 1);
+
+        client.submit_to(ids[0], "Hello".to_owned()).unwrap();
+        client.submit_to(ids[1], "World".to_owned()).unwrap();
+        assert!(run_until_idle(&mut replicas, 10));
+        for replica in &replicas {
+            assert_eq!(replica.committed_len(), 3);
+        }
+    }
+
+    #[test]
+    fn idle_group_blocks() {
+        let clock = Arc::new(ManualClock::new());
+        let mut replicas = create_replicas::<u32>(3, clock.clone());
+        elect_leader(&mut replicas, &clock);
+
+        let run_for = std::time::Duration::from_millis(500);
+        let handles: Vec<_> = replicas
+            .into_iter()
+            .map(|mut replica| {
+                thread::spawn(move || {
+                    let start = std::time::Instant::now();
+                    let mut blocked = std::time::Duration::from_secs(0);
+                    let mut iterations = 0;
+                    while start.elapsed() < run_for {
+                        let timeout = replica.tick();
+                        let wait_start = std::time::Instant::now();
+                        replica.wait(timeout);
+                        blocked += wait_start.elapsed();
+                        iterations += 1;
+                    }
+                    (iterations, blocked.as_secs_f64() / start.elapsed().as_secs_f64())
+                })
+            })
+            .collect();
+        for handle in handles {
+            let (iterations, blocked_ratio) = handle.join().unwrap();
+            assert!(iterations < 200, "replica woke up {} times", iterations);
+            assert!(blocked_ratio > 0.5, "replica only blocked {:.0}%", blocked_ratio * 100.0);
+        }
+    }
+
+    #[test]
+    fn isolate_groups_in_one_process() {
+        let clock = Arc::new(ManualClock::new());
+        let ids: Vec<_> = (1..=3).map(NodeId).collect();
+        let start = |nodes: Vec<UdpNetworkNode<u32>>| -> Vec<_> {
+            nodes
+                .into_iter()
+                .zip(&ids)
+                .map(|(node, &id)| {
+                    PaxosReplica::with_members(node, id, ids.clone(), Vec::new())
+                        .unwrap()
+                        .with_clock(clock.clone())
+                })
+                .collect()
+        };
+        let nodes_b = create_group_nodes(2);
+        let addrs_b: Vec<_> = nodes_b.iter().map(|n| n.socket.local_addr().unwrap()).collect();
+        let mut group_a = start(create_group_nodes(1));
+        let mut group_b = start(nodes_b);
+        elect_leader(&mut group_a, &clock);
+        tick_all(&mut group_b, 3);
+        assert_eq!(group_b.iter().filter(|r| r.is_leader()).count(), 1);
+        let leaders_b: Vec<_> = group_b.iter().map(|r| r.leader_state()).collect();
+
+        // a node of group A which reaches group B, e.g. at addresses reused by it,
+        // tries to take over leadership and submits commands
+        let mut misdirected = UdpNetworkNode::<u32>::new().with_group(1);
+        let mut node_b = UdpNetworkNode::<u32>::new().with_group(2);
+        for (&id, &addr) in ids.iter().zip(&addrs_b) {
+            misdirected.discover_at(id, addr);
+            node_b.discover_at(id, addr);
+        }
+        let mut ballot = Ballot::default();
+        ballot.skip_rounds(protocol::FORCE_ROUNDS).unwrap();
+        misdirected.broadcast(&protocol::PaxosMsg::Prepare {
+            ballot,
+            holes: Vec::new(),
+            sent_at: None,
+        });
+        let misdirected = PaxosClient::with_node(misdirected);
+        let client_b = PaxosClient::with_node(node_b);
+        for &id in &ids {
+            misdirected.submit_to(id, 1).unwrap();
+            client_b.submit_to(id, 2).unwrap();
+        }
+        tick_all(&mut group_a, 5);
+        tick_all(&mut group_b, 5);
+        let leaders: Vec<_> = group_b.iter().map(|r| r.leader_state()).collect();
+        assert_eq!(leaders, leaders_b);
+        for replica in &group_b {
+            assert_eq!(replica.history().map(|(_, &v)| v).collect::<Vec<_>>(), [2, 2, 2]);
+        }
+        for replica in &group_a {
+            assert_eq!(replica.history().count(), 0);
+        }
+    }
+
+    #[test]
+    fn start_replica_with_state_machine() {
+        let handle = start_replica(Vec::<u32>::new(), &[], &ClusterConfig::default()).unwrap();
+        let client = PaxosClient::<u32>::new().with_replicas(&[handle.id()]);
+        let status = client.submit_and_wait(42, Duration::from_secs(10)).unwrap();
+        assert_eq!(status.leader, Some(handle.id()));
+
+        let replica = handle.stop();
+        assert_eq!(replica.history().collect::<Vec<_>>(), vec![(1, &42)]);
+    }
+
+    #[test]
+    fn reads_leave_state_unchanged() {
+        let network = MemoryNetwork::new();
+        let id = NodeId(1);
+        let mut replica =
+            PaxosReplica::with_members(network.connect(id), id, vec![id], Vec::new()).unwrap();
+        replica.tick();
+        replica.submit_value(1u32).unwrap();
+        replica.submit_value(2).unwrap();
+        for _ in 0..2 {
+            assert_eq!(replica.linearizable_read(7), Ok("2".to_owned()));
+            assert_eq!(replica.read_local(7), Ok("2".to_owned()));
+            assert_eq!(replica.read_bounded(7, Duration::from_secs(1)), Ok("2".to_owned()));
+        }
+        assert_eq!(replica.history().count(), 2);
+    }
+
+    #[test]
+    fn reject_batches_without_restore() {
+        /// Records commands and takes snapshots, but can't restore them.
+        #[derive(Default)]
+        struct NoRestore(Vec<u32>);
+
+        impl ReplicatedStateMachine for NoRestore {
+            type Command = u32;
+
+            fn execute(&mut self, v: u32) -> Result<String, ()> {
+                self.0.push(v);
+                Ok(String::new())
+            }
+
+            fn snapshot(&self) -> Option<Vec<u8>> {
+                bincode::serialize(&self.0).ok()
+            }
+        }
+
+        // the batch is rejected up front, instead of failing halfway without a way back
+        let mut state_machine = NoRestore::default();
+        assert_eq!(state_machine.execute_batch(vec![1, 2]), Err(()));
+        assert!(state_machine.0.is_empty());
+        let mut state_machine = Vec::new();
+        assert_eq!(state_machine.execute_batch(vec![1u32, 2]), Ok(vec![String::new(); 2]));
+        assert_eq!(state_machine, [1, 2]);
+    }
+
+    #[test]
+    fn submit_builtin_command_types() {
+        commit_values(vec![1u32, 2, u32::MAX]);
+        commit_values(vec![1u64, 2, u64::MAX]);
+        commit_values(vec![-1i64, i64::MIN, i64::MAX]);
+        commit_values(vec![vec![], vec![0u8, 255], vec![42u8; 1000]]);
+        commit_values(vec![String::new(), "Hello".to_owned(), "🦀".to_owned()]);
+    }
+
+    #[test]
+    fn submit_oversized_value_test() {
+        let value = "x".repeat(70_000);
+        let node = UdpNetworkNode::<String>::new();
+        let node_id = node.id();
+        let mut replica =
+            PaxosReplica::with_members(node, node_id, vec![node_id], Vec::new()).unwrap();
+        match replica.submit_value(value.clone()) {
+            Err(PaxosError::CommandTooLarge { size, max }) => {
+                assert!(size > 70_000);
+                assert!(max < size);
+            }
+            other => panic!("expected CommandTooLarge, got {:?}", other),
+        }
+        assert!(matches!(
+            submit_value(node_id, value),
+            Err(PaxosError::CommandTooLarge { .. })
+        ));
     }
 }
@@ -0,0 +1,164 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Measurements a replica collects about itself and its peers, e.g. to diagnose slow commits.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::network::NodeId;
+
+/// Number of samples a `LatencyHistogram` keeps, older ones are discarded.
+const LATENCY_SAMPLES: usize = 128;
+
+/// Number of buckets of a `SizeHistogram`, the last one covering all sizes above 2^30 bytes.
+const SIZE_BUCKETS: usize = 32;
+
+/// Rolling window over the most recent latency samples.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyHistogram {
+    /// Adds a sample, discarding the oldest one if the window is full.
+    pub fn record(&mut self, latency: Duration) {
+        if self.samples.len() == LATENCY_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+    }
+
+    /// The number of samples currently in the window.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The average of all samples in the window, or zero if there are none.
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::from_secs(0);
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    /// The smallest sample such that at least `p` percent of all samples are at most as large.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted.get(rank.clamp(1, sorted.len().max(1)) - 1).copied()
+    }
+}
+
+/// Counts of sizes in bytes, in buckets bounded by powers of two, i.e. the bucket of bound `b`
+/// counts all sizes greater than `b / 2` and at most `b`, the first one also counting zero.
+/// Recording a size never allocates.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [u64; SIZE_BUCKETS],
+    max: usize,
+}
+
+impl SizeHistogram {
+    /// Counts the size in its bucket.
+    pub fn record(&mut self, size: usize) {
+        let bucket = size
+            .checked_next_power_of_two()
+            .map_or(SIZE_BUCKETS, |bound| bound.trailing_zeros() as usize);
+        self.counts[bucket.min(SIZE_BUCKETS - 1)] += 1;
+        self.max = self.max.max(size);
+    }
+
+    /// The number of sizes recorded so far.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The largest size recorded so far, or zero if there is none.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// The upper bound of each bucket holding any sizes, together with their number.
+    pub fn buckets(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| (1usize << bucket, count))
+    }
+}
+
+/// Snapshot of the measurements of a single replica.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Round-trip times to each peer, from Prepare to Promise and from Propose to Accept.
+    /// Only collected if latency tracking is enabled.
+    pub round_trip_times: BTreeMap<NodeId, LatencyHistogram>,
+    /// Times from first proposing an entry to a quorum having accepted it, as the leader,
+    /// i.e. the time spent in the network and on the acceptors.
+    /// Only collected if latency tracking is enabled.
+    pub commit_latency: LatencyHistogram,
+    /// Times from learning that an entry is chosen to having applied it, i.e. the time spent
+    /// waiting for and executing the state machine. Only collected if latency tracking is enabled.
+    pub apply_latency: LatencyHistogram,
+    /// The number of entries up to the last chosen one, which are not applied yet.
+    pub apply_lag: usize,
+    /// Sizes of the commands applied so far, wrapped into a Propose like by
+    /// `command_wire_size`, to show how close they come to the message size limit.
+    pub command_sizes: SizeHistogram,
+    /// The number of messages received which imply that another replica uses this replica's ID,
+    /// e.g. because two replicas were configured with the same address.
+    /// Anything but zero indicates a misconfigured group, whose quorums can't be trusted.
+    pub id_conflicts: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.mean(), Duration::from_secs(0));
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for ms in 1..=10 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.len(), 10);
+        assert_eq!(histogram.mean(), Duration::from_micros(5500));
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.percentile(90.0), Some(Duration::from_millis(9)));
+        assert_eq!(histogram.percentile(100.0), Some(Duration::from_millis(10)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_millis(1)));
+
+        // only the most recent samples are kept
+        for _ in 0..LATENCY_SAMPLES {
+            histogram.record(Duration::from_millis(20));
+        }
+        assert_eq!(histogram.len(), LATENCY_SAMPLES);
+        assert_eq!(histogram.mean(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn size_histogram() {
+        let mut histogram = SizeHistogram::default();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.buckets().next(), None);
+
+        for size in [0, 1, 2, 3, 4, 5, 1000, 1024, 1025, usize::MAX] {
+            histogram.record(size);
+        }
+        let buckets: Vec<_> = histogram.buckets().collect();
+        let expected = [(1, 2), (2, 1), (4, 2), (8, 1), (1024, 2), (2048, 1), (1 << 31, 1)];
+        assert_eq!(buckets, expected);
+        assert_eq!(histogram.count(), 10);
+        assert_eq!(histogram.max(), usize::MAX);
+    }
+}
@@ -0,0 +1,613 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Abstracts over the network replicas use to exchange messages.
+//! Besides the `UdpNetworkNode`, this provides the in-process `MemoryNetwork` for tests.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use bincode::serialized_size;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{Clock, SystemClock};
+use crate::protocol::{Ballot, Command, PaxosMsg, Snapshot};
+use crate::{AppCommand, PaxosError};
+
+/// The size of a bincode-encoded Propose carrying the command, the largest message carrying
+/// commands. This lets applications check up front that their largest commands fit into a
+/// single message, e.g. of at most `UdpNetworkNode::max_msg_size` bytes including its header.
+pub fn command_wire_size<V: AppCommand>(cmd: &V) -> usize {
+    let envelope = PaxosMsg::Propose {
+        index: 0,
+        ballot: Ballot::default(),
+        value: Command::App(()),
+        sent_at: Some(0),
+    };
+    (serialized_size(&envelope).unwrap() + serialized_size(cmd).unwrap()) as usize
+}
+
+/// Identifies a node in a network, and thus the replica or client it belongs to.
+/// On UDP networks it encodes the node's IPv4 address and port, see `from_addr`,
+/// unless the node uses a logical ID, see `UdpNetworkNode::with_id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeId(pub usize);
+
+impl NodeId {
+    /// Converts a socket address (IP + port) into a node ID.
+    /// This transformation can be reversed. IPv6 addresses yield `None`.
+    pub fn from_addr(addr: SocketAddr) -> Option<Self> {
+        let port = addr.port();
+        if let IpAddr::V4(ip) = addr.ip() {
+            let ipv4: u32 = ip.into();
+            Some(Self(ipv4 as usize * 65536 + port as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Converts the node ID into a socket address (IP + port).
+    /// This transformation can be reversed. IDs too large for an IPv4 address yield `None`.
+    pub fn to_addr(self) -> Option<SocketAddr> {
+        let port = (self.0 % 65536) as u16;
+        let ip = u32::try_from(self.0 / 65536).ok()?;
+        Some(SocketAddr::from((Ipv4Addr::from(ip), port)))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// Reasons for not receiving a message.
+#[derive(Debug)]
+pub enum RecvError {
+    /// No message arrived in time, or none is available right now.
+    /// This is expected and only means that there is nothing to do.
+    Timeout,
+    /// Receiving failed, e.g. because of a socket error or a message which couldn't be decoded.
+    Io(io::Error),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "no message received"),
+            Self::Io(e) => write!(f, "failed to receive message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+impl From<io::Error> for RecvError {
+    /// Treats the error kinds sockets report for timeouts as `Timeout`.
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::Timeout,
+            _ => Self::Io(e),
+        }
+    }
+}
+
+/// Outcome of a broadcast: the peers the message was handed to the network for, and those it
+/// couldn't be sent to, e.g. because their node is gone.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BroadcastResult {
+    pub sent: Vec<NodeId>,
+    pub failed: Vec<NodeId>,
+}
+
+impl BroadcastResult {
+    /// Records the outcome of sending the message to `peer`.
+    pub fn record(&mut self, peer: NodeId, sent: bool) {
+        if sent {
+            self.sent.push(peer);
+        } else {
+            self.failed.push(peer);
+        }
+    }
+}
+
+/// A node in a network, which sends and receives Paxos messages on behalf of a replica.
+pub trait Network<V: crate::AppCommand>: Debug + Send {
+    /// The ID identifying this node in the network.
+    fn id(&self) -> NodeId;
+
+    /// The IDs of all other nodes this node knows about, in ascending order.
+    fn peers(&self) -> Vec<NodeId>;
+
+    /// Adds other nodes' IDs to this node's list of known peers.
+    fn discover(&mut self, other_nodes: &[NodeId]);
+
+    /// Sends the Paxos message to another node.
+    /// Returns whether the message could be handed to the network.
+    fn send(&self, dst: NodeId, msg: &PaxosMsg<V>) -> bool;
+
+    /// Try to receive a new Paxos message, blocking until the next one arrives.
+    /// If this takes longer than timeout `RecvError::Timeout` is returned instead.
+    fn recv(&self, timeout: Duration) -> Result<(NodeId, PaxosMsg<V>), RecvError>;
+
+    /// Try to receive a new Paxos message, without blocking.
+    /// If no message is available right now `RecvError::Timeout` is returned instead.
+    fn try_recv(&self) -> Result<(NodeId, PaxosMsg<V>), RecvError>;
+
+    /// Blocks until a message is available to be received, or the timeout has passed.
+    /// Returns whether a message is available.
+    fn wait(&self, timeout: Duration) -> bool;
+
+    /// Sends the Paxos message to all known peers, reporting which of them it could be sent to.
+    /// Implementations should override this to iterate over their peers in place, instead of
+    /// collecting them into a new `Vec` via `peers` with every call.
+    fn broadcast(&self, msg: &PaxosMsg<V>) -> BroadcastResult {
+        let mut result = BroadcastResult::default();
+        for peer in self.peers() {
+            result.record(peer, self.send(peer, msg));
+        }
+        result
+    }
+
+    /// Upper bound for the size of a single serialized message.
+    fn max_msg_size(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Checks whether the command still fits into a single message once it is wrapped into a
+    /// Propose or Learn, which are the largest messages carrying commands.
+    fn check_command_size(&self, cmd: &V) -> Result<(), PaxosError> {
+        check_size(command_wire_size(cmd), self.max_msg_size())
+    }
+
+    /// Checks whether the commands still fit into a single message once they are wrapped into
+    /// a Propose as a batch, like `check_command_size`.
+    fn check_batch_size(&self, cmds: &[V]) -> Result<(), PaxosError> {
+        let envelope = PaxosMsg::Propose {
+            index: 0,
+            ballot: Ballot::default(),
+            value: Command::Batch(Vec::<()>::new()),
+            sent_at: Some(0),
+        };
+        let size = serialized_size(&envelope).unwrap()
+            + cmds.iter().map(|cmd| serialized_size(cmd).unwrap()).sum::<u64>();
+        check_size(size as usize, self.max_msg_size())
+    }
+
+    /// Checks whether the snapshot fits into the single InstallSnapshot message it is sent to
+    /// replicas which are behind it in, failing with `SnapshotTooLarge` otherwise.
+    fn check_snapshot_size(&self, snapshot: &Snapshot) -> Result<(), PaxosError> {
+        let envelope = install_snapshot::<()>(snapshot);
+        let size = serialized_size(&envelope).unwrap() as usize;
+        if size > self.max_msg_size() {
+            let max = self.max_msg_size();
+            return Err(PaxosError::SnapshotTooLarge { size, max });
+        }
+        Ok(())
+    }
+}
+
+/// Wraps the snapshot into the InstallSnapshot message it is sent in to catching-up replicas.
+pub(crate) fn install_snapshot<V: Debug>(snapshot: &Snapshot) -> PaxosMsg<V> {
+    PaxosMsg::InstallSnapshot {
+        snapshot_index: usize::MAX,
+        membership: snapshot.membership.clone(),
+        data: snapshot.data.clone(),
+        requests: snapshot.requests.clone(),
+    }
+}
+
+/// Fails with `CommandTooLarge` if a message of `size` bytes exceeds `max`.
+fn check_size(size: usize, max: usize) -> Result<(), PaxosError> {
+    if size > max {
+        return Err(PaxosError::CommandTooLarge { size, max });
+    }
+    Ok(())
+}
+
+/// A message together with its sender and the point in time it may be received.
+type Envelope<V> = (Instant, NodeId, PaxosMsg<V>);
+
+/// Queue of messages waiting to be received by a single node.
+#[derive(Debug)]
+struct Mailbox<V: Debug> {
+    queue: Mutex<VecDeque<Envelope<V>>>,
+    arrived: Condvar,
+}
+
+/// Whether the first message in the queue may be received at time `now`.
+fn is_due<V: Debug>(queue: &VecDeque<Envelope<V>>, now: Instant) -> bool {
+    queue.front().is_some_and(|(deliver_at, _, _)| *deliver_at <= now)
+}
+
+/// The numbers of messages of each type sent over a `MemoryNetwork`, by all of its nodes.
+/// Broadcasts count once per recipient. Messages to nodes which aren't connected count too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub prepares: u64,
+    pub proposes: u64,
+    pub accepts: u64,
+    pub learns: u64,
+    pub nacks: u64,
+    pub heartbeats: u64,
+    /// All other messages, e.g. Promises, HeartbeatAcks and client requests.
+    pub other: u64,
+}
+
+impl MessageCounts {
+    fn count<V: Debug>(&mut self, msg: &PaxosMsg<V>) {
+        let counter = match msg {
+            PaxosMsg::Prepare { .. } => &mut self.prepares,
+            PaxosMsg::Propose { .. } => &mut self.proposes,
+            PaxosMsg::Accept { .. } => &mut self.accepts,
+            PaxosMsg::Learn { .. } => &mut self.learns,
+            PaxosMsg::Nack { .. } => &mut self.nacks,
+            PaxosMsg::Heartbeat { .. } => &mut self.heartbeats,
+            _ => &mut self.other,
+        };
+        *counter += 1;
+    }
+}
+
+#[derive(Debug)]
+struct Shared<V: Debug> {
+    mailboxes: Mutex<HashMap<NodeId, Arc<Mailbox<V>>>>,
+    /// Additional latency of all messages sent to a node.
+    delays: Mutex<HashMap<NodeId, Duration>>,
+    counts: Mutex<MessageCounts>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Connects nodes living in the same process, delivering messages reliably and in order.
+/// Messages sent to nodes which are not connected (anymore) are dropped, like for a crashed
+/// process. Cloning yields another handle to the same network.
+#[derive(Debug)]
+pub struct MemoryNetwork<V: Debug> {
+    shared: Arc<Shared<V>>,
+}
+
+impl<V: Debug> Clone for MemoryNetwork<V> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<V: Debug> Default for MemoryNetwork<V> {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl<V: Debug> MemoryNetwork<V> {
+    /// Creates a new network without any nodes, which uses the clock to delay messages.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                mailboxes: Mutex::new(HashMap::new()),
+                delays: Mutex::new(HashMap::new()),
+                counts: Mutex::new(MessageCounts::default()),
+                clock,
+            }),
+        }
+    }
+}
+
+impl<V: crate::AppCommand> MemoryNetwork<V> {
+    /// Creates a new network without any nodes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays all messages sent to the node from now on, e.g. to simulate a slow replica.
+    pub fn set_delay(&self, node: NodeId, delay: Duration) {
+        self.shared.delays.lock().unwrap().insert(node, delay);
+    }
+
+    /// The numbers of messages sent over the network since it was created or the counts were
+    /// reset, e.g. to check in tests how many messages an operation takes.
+    pub fn message_counts(&self) -> MessageCounts {
+        *self.shared.counts.lock().unwrap()
+    }
+
+    /// Starts counting messages from zero again.
+    pub fn reset_message_counts(&self) {
+        *self.shared.counts.lock().unwrap() = MessageCounts::default();
+    }
+
+    /// Connects a node with the given ID to the network.
+    /// A node previously connected with the same ID is replaced, losing its pending messages.
+    pub fn connect(&self, id: NodeId) -> MemoryNode<V> {
+        let mailbox = Arc::new(Mailbox {
+            queue: Mutex::new(VecDeque::new()),
+            arrived: Condvar::new(),
+        });
+        self.shared
+            .mailboxes
+            .lock()
+            .unwrap()
+            .insert(id, mailbox.clone());
+        MemoryNode {
+            id,
+            peers: BTreeSet::new(),
+            network: self.clone(),
+            mailbox,
+        }
+    }
+}
+
+/// A node connected to a `MemoryNetwork`, which is disconnected again once it is dropped.
+#[derive(Debug)]
+pub struct MemoryNode<V: Debug> {
+    id: NodeId,
+    peers: BTreeSet<NodeId>,
+    network: MemoryNetwork<V>,
+    mailbox: Arc<Mailbox<V>>,
+}
+
+impl<V: Debug> Drop for MemoryNode<V> {
+    fn drop(&mut self) {
+        let mut mailboxes = self.network.shared.mailboxes.lock().unwrap();
+        if mailboxes
+            .get(&self.id)
+            .is_some_and(|mailbox| Arc::ptr_eq(mailbox, &self.mailbox))
+        {
+            mailboxes.remove(&self.id);
+        }
+    }
+}
+
+impl<V: crate::AppCommand> Network<V> for MemoryNode<V> {
+    fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.peers.iter().copied().collect()
+    }
+
+    fn discover(&mut self, other_nodes: &[NodeId]) {
+        let id = self.id;
+        self.peers
+            .extend(other_nodes.iter().copied().filter(|&node| node != id));
+    }
+
+    fn send(&self, dst: NodeId, msg: &PaxosMsg<V>) -> bool {
+        let shared = &self.network.shared;
+        shared.counts.lock().unwrap().count(msg);
+        let mailbox = match shared.mailboxes.lock().unwrap().get(&dst) {
+            Some(mailbox) => mailbox.clone(),
+            None => return false,
+        };
+        let delay = shared.delays.lock().unwrap().get(&dst).copied();
+        let deliver_at = shared.clock.now() + delay.unwrap_or_default();
+        mailbox
+            .queue
+            .lock()
+            .unwrap()
+            .push_back((deliver_at, self.id, msg.clone()));
+        mailbox.arrived.notify_all();
+        true
+    }
+
+    fn broadcast(&self, msg: &PaxosMsg<V>) -> BroadcastResult {
+        let mut result = BroadcastResult::default();
+        for &peer in &self.peers {
+            result.record(peer, self.send(peer, msg));
+        }
+        result
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<(NodeId, PaxosMsg<V>), RecvError> {
+        let clock = &self.network.shared.clock;
+        let queue = self.mailbox.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .mailbox
+            .arrived
+            .wait_timeout_while(queue, timeout, |queue| !is_due(queue, clock.now()))
+            .unwrap();
+        if !is_due(&queue, clock.now()) {
+            return Err(RecvError::Timeout);
+        }
+        let (_, src, msg) = queue.pop_front().unwrap();
+        Ok((src, msg))
+    }
+
+    fn try_recv(&self) -> Result<(NodeId, PaxosMsg<V>), RecvError> {
+        let mut queue = self.mailbox.queue.lock().unwrap();
+        if !is_due(&queue, self.network.shared.clock.now()) {
+            return Err(RecvError::Timeout);
+        }
+        let (_, src, msg) = queue.pop_front().unwrap();
+        Ok((src, msg))
+    }
+
+    fn wait(&self, timeout: Duration) -> bool {
+        let clock = &self.network.shared.clock;
+        let queue = self.mailbox.queue.lock().unwrap();
+        let (queue, _) = self
+            .mailbox
+            .arrived
+            .wait_timeout_while(queue, timeout, |queue| !is_due(queue, clock.now()))
+            .unwrap();
+        is_due(&queue, clock.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+    enum BankCommand {
+        Deposit { account: u64, amount: u64 },
+        Transfer { from: u64, to: u64, amount: u64 },
+    }
+
+    impl AppCommand for BankCommand {}
+
+    #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+    enum KeyValueCommand {
+        Put(String, String),
+        Delete(String),
+    }
+
+    impl AppCommand for KeyValueCommand {}
+
+    #[test]
+    fn command_wire_size_fits_default_limit() {
+        let transfer = BankCommand::Transfer { from: 1, to: 2, amount: 100 };
+        let propose = PaxosMsg::Propose {
+            index: 17,
+            ballot: Ballot::default(),
+            value: Command::App(transfer.clone()),
+            sent_at: Some(12),
+        };
+        let size = command_wire_size(&transfer);
+        assert_eq!(size as u64, serialized_size(&propose).unwrap());
+
+        let put = KeyValueCommand::Put("key".repeat(100), "value".repeat(1000));
+        let max = crate::UdpNetworkNode::<KeyValueCommand>::new().max_msg_size();
+        assert!(command_wire_size(&put) < max);
+        assert!(command_wire_size(&KeyValueCommand::Delete("key".to_owned())) < max);
+        assert!(command_wire_size(&BankCommand::Deposit { account: 1, amount: 5 }) < size);
+    }
+
+    proptest! {
+        #[test]
+        fn node_id_addr_conversion(ip: u32, port: u16) {
+            let addr = SocketAddr::from((Ipv4Addr::from(ip), port));
+            let node_id = NodeId::from_addr(addr).unwrap();
+            assert_eq!(node_id.to_addr(), Some(addr));
+        }
+    }
+
+    #[test]
+    fn memory_send_and_receive() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut node1 = network.connect(NodeId(1));
+        let node2 = network.connect(NodeId(2));
+        node1.discover(&[NodeId(1), NodeId(2), NodeId(3)]);
+        assert_eq!(node1.peers(), vec![NodeId(2), NodeId(3)]);
+
+        let result = node1.broadcast(&PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
+        // node 3 isn't connected
+        assert_eq!(result.sent, [NodeId(2)]);
+        assert_eq!(result.failed, [NodeId(3)]);
+        assert!(node2.wait(Duration::from_secs(1)));
+        match node2.recv(Duration::from_secs(1)).unwrap() {
+            (NodeId(1), PaxosMsg::ClientRequest {
+                value: 42,
+                hops: 0,
+                priority: 0,
+                deadline: None,
+            }) => {}
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(matches!(node2.try_recv(), Err(RecvError::Timeout)));
+        let request = PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None };
+        assert!(!node1.send(NodeId(3), &request));
+    }
+
+    #[test]
+    fn memory_delay() {
+        let clock = Arc::new(crate::ManualClock::new());
+        let network = MemoryNetwork::<u32>::with_clock(clock.clone());
+        let node1 = network.connect(NodeId(1));
+        let node2 = network.connect(NodeId(2));
+        network.set_delay(NodeId(2), Duration::from_millis(50));
+
+        node1.send(NodeId(2), &PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
+        assert!(node2.try_recv().is_err());
+        clock.advance(Duration::from_millis(49));
+        assert!(!node2.wait(Duration::from_millis(1)));
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            node2.try_recv(),
+            Ok((NodeId(1), PaxosMsg::ClientRequest {
+                value: 42,
+                hops: 0,
+                priority: 0,
+                deadline: None,
+            }))
+        ));
+    }
+
+    #[test]
+    fn broadcast_to_large_group() {
+        let network = MemoryNetwork::<u32>::new();
+        let group: Vec<NodeId> = (0..50).map(NodeId).collect();
+        let mut nodes: Vec<_> = group.iter().map(|&id| network.connect(id)).collect();
+        nodes[0].discover(&group);
+        nodes[0].discover(&group[..10]);
+        assert_eq!(nodes[0].peers(), group[1..]);
+
+        for index in 0..3 {
+            nodes[0].broadcast(&PaxosMsg::Applied { index });
+        }
+        assert_eq!(network.message_counts().other, 3 * 49);
+        for node in &nodes[1..] {
+            for index in 0..3 {
+                match node.try_recv() {
+                    Ok((NodeId(0), PaxosMsg::Applied { index: i })) => assert_eq!(i, index),
+                    other => panic!("unexpected message: {:?}", other),
+                }
+            }
+            assert!(node.try_recv().is_err());
+        }
+        assert!(nodes[0].try_recv().is_err());
+    }
+
+    #[test]
+    fn memory_reconnect() {
+        let network = MemoryNetwork::<u32>::new();
+        let node1 = network.connect(NodeId(1));
+        let node2 = network.connect(NodeId(2));
+        let request = |value| PaxosMsg::ClientRequest {
+            value,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        };
+        node1.send(NodeId(2), &request(1));
+
+        // a crashed node loses its pending messages and doesn't receive anything
+        drop(node2);
+        assert!(!node1.send(NodeId(2), &request(2)));
+        let node2 = network.connect(NodeId(2));
+        assert!(node2.try_recv().is_err());
+        assert!(node1.send(NodeId(2), &request(3)));
+        assert!(matches!(
+            node2.try_recv(),
+            Ok((NodeId(1), PaxosMsg::ClientRequest { value: 3, .. }))
+        ));
+    }
+}
@@ -3,14 +3,98 @@
 
 //! Contains structures, types and constants used by the rest of the Paxos implementation.
 
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Debug;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
+use crate::network::NodeId;
+use crate::replica::Role;
+use crate::PaxosError;
+
+/// Version of the message format, which all replicas of a group need to agree on.
+/// Needs to be raised with every incompatible change to `PaxosMsg` or the types it contains.
+pub const PROTOCOL_VERSION: u16 = 22;
+
 /// Duration until the leader's lease expires after election.
-pub static LEASE_DURATION: u128 = 2000; //2000 ms (= 2 seconds)
+pub const LEASE_DURATION: Duration = Duration::from_millis(2000);
+
+/// Default for the longest time between two ticks of a replica, while no timer is due.
+/// Incoming messages wake a waiting replica right away, so this mostly bounds idle wakeups.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Default for the number of recently processed Prepare and Propose messages a replica
+/// remembers, to ignore duplicates of them.
+pub const DUPLICATE_WINDOW: usize = 64;
+
+/// Default for the number of proposals the leader keeps in flight at most, i.e. entries
+/// proposed but not yet chosen. Further client requests are queued until some are chosen.
+pub const PROPOSAL_WINDOW: usize = 256;
+
+/// Default for the time after which the leader sends a Propose again, if its entry is not
+/// chosen yet, e.g. because the Propose or the Accepts were lost.
+pub const PROPOSAL_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Default for the longest time a candidate waits for its round of Prepares to be answered by
+/// a quorum, before it starts another one, see `PaxosReplica::with_prepare_round_timeout`.
+pub const PREPARE_ROUND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Default for the number of times the leader sends a Propose again, before it gives up
+/// and leaves the entry to the next election.
+pub const MAX_PROPOSAL_RETRIES: u32 = 5;
+
+/// Priority of client commands submitted without one, which is the lowest.
+pub const DEFAULT_PRIORITY: u8 = 0;
+
+/// Bound on the number of commands queued later that a queued command can be overtaken by,
+/// per level of priority they are higher. This keeps low-priority commands from starving.
+pub const PRIORITY_AGING: u64 = 16;
+
+/// How many entries a learner may have applied fewer than the leader, to be promoted to voter.
+pub const MAX_LEARNER_LAG: usize = 16;
+
+/// Minimum time between two catch-up responses to the same replica. Responses can be much
+/// larger than requests, so this bounds the traffic forged requests can direct at a replica.
+pub const CATCH_UP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many chosen values a replica sends per CatchUp request by default,
+/// see `PaxosReplica::with_catch_up_window`.
+pub const CATCH_UP_WINDOW: usize = 256;
+
+/// Time after which a replica which is catching up asks again for the values it is missing,
+/// e.g. because some of the last batch got lost.
+pub const CATCH_UP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Default for the number of clients whose applied requests a replica remembers,
+/// see `AppliedRequests`.
+pub const MAX_TRACKED_CLIENTS: usize = 1000;
+
+/// How long a replica remembers a tracked request it abandoned, for its client to find out.
+/// Clients which haven't asked by then are assumed to have given up on it.
+pub const REQUEST_TTL: Duration = Duration::from_secs(30);
+
+/// The number of rounds `PaxosReplica::force_commit` skips, so that its election wins over
+/// any other one which might be going on at the same time.
+pub const FORCE_ROUNDS: usize = 1 << 16;
 
-/// Unique monotonic increasing ID.
+/// Default for how far beyond the end of its log a replica accepts entries and snapshots from
+/// other replicas. Messages referring to indices further ahead are dropped as malformed, as they
+/// would make the replica fill its log with an unbounded number of placeholder entries.
+pub const MAX_INDEX_GAP: usize = 1 << 20;
+
+/// Unique monotonic increasing ID, consisting of a round number and the ID of its node.
+///
+/// Ballots are ordered by their round first and by their node's ID second, so that no two
+/// nodes ever generate equal ballots. They also decide between conflicting values for the same
+/// log entry: a new leader adopts the value accepted at the highest ballot reported to it.
+/// Values reported with the exact same ballot stem from the same Propose, and thus are equal.
+/// Should they differ anyway, the value reported by the replica with the lowest ID wins, so
+/// that the outcome never depends on the order in which Promises arrive.
+///
+/// Rounds are never allowed to wrap around, as this would break monotonicity.
+/// Once the round reaches `usize::MAX`, e.g. because a faulty peer sent such a ballot,
+/// only nodes with a higher ID than the one holding the ballot can still generate a higher one.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
 pub struct Ballot(usize, usize);
 
@@ -18,75 +102,453 @@ impl Ballot {
     /// Changes this Ballot number to be a higher number than before.
     /// The resulting Ballot number is again in the space of numbers for this peer,
     /// i.e. no other peer could ever generate the same number.
-    pub fn increment_for(&mut self, node_id: usize) {
-        if self.1 > node_id {
-            self.0 += 1;
+    /// Fails with `BallotExhausted`, leaving the ballot unchanged, if the round would overflow.
+    pub fn increment_for(&mut self, node_id: NodeId) -> Result<(), PaxosError> {
+        if self.1 > node_id.0 {
+            self.0 = self.0.checked_add(1).ok_or(PaxosError::BallotExhausted)?;
+        }
+        self.1 = node_id.0;
+        Ok(())
+    }
+
+    /// Raises the round by `rounds`, keeping the node.
+    /// Fails with `BallotExhausted`, leaving the ballot unchanged, if the round would overflow.
+    pub(crate) fn skip_rounds(&mut self, rounds: usize) -> Result<(), PaxosError> {
+        self.0 = self.0.checked_add(rounds).ok_or(PaxosError::BallotExhausted)?;
+        Ok(())
+    }
+
+    /// The ID of the node which generated this ballot.
+    pub(crate) fn node(&self) -> NodeId {
+        NodeId(self.1)
+    }
+}
+
+/// A value which can be chosen for a slot in the replicated log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Command<V> {
+    /// A command submitted by a client, to be executed by the replicated state machine.
+    App(V),
+    /// Changes the set of replicas whose votes count towards a quorum.
+    /// It governs all log entries following the one it is stored in.
+    Reconfigure(Membership),
+    /// Fills a slot for which a newly elected leader found no previously accepted value.
+    NoOp,
+    /// Client commands submitted together, which are applied all or nothing,
+    /// see `ReplicatedStateMachine::execute_batch`.
+    Batch(Vec<V>),
+    /// A client command which is applied only once, even if the client submitted it again
+    /// and it was chosen for several log entries, see `PaxosClient::submit_exactly_once`.
+    Tracked(RequestId, V),
+    /// A client command which must not be proposed after its `deadline`, in milliseconds since
+    /// the UNIX epoch. The leader drops it instead of proposing it once the deadline has passed.
+    ///
+    /// Deadlines are compared to the leader's wall clock, so they are only as precise as the
+    /// clocks of clients and replicas are synchronized. Once proposed, the command is applied
+    /// like any other, even if it is only chosen after its deadline: the deadline is never
+    /// checked again, so that replicas with skewed clocks can't disagree about the outcome.
+    Expiring { deadline: u64, value: V },
+}
+
+impl<V> Command<V> {
+    /// The client commands this value holds, in the order they are applied.
+    pub(crate) fn commands(&self) -> &[V] {
+        match self {
+            Self::App(cmd) | Self::Tracked(_, cmd) | Self::Expiring { value: cmd, .. } => {
+                std::slice::from_ref(cmd)
+            }
+            Self::Batch(cmds) => cmds,
+            Self::Reconfigure(_) | Self::NoOp => &[],
+        }
+    }
+}
+
+/// Identifies a command submitted by a client, so that replicas recognize resubmissions of it.
+/// Each client numbers its commands consecutively, and replicas only remember the highest
+/// `seq` applied per client, so a client must not submit a command before the previous one has
+/// been applied.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId {
+    /// Randomly chosen by the client, to tell apart clients without coordinating them.
+    pub client: u64,
+    pub seq: u64,
+}
+
+/// The highest `RequestId::seq` applied per client, for a bounded number of clients.
+///
+/// Beyond the capacity, the client whose last applied request is the oldest is forgotten, and a
+/// resubmission of one of its requests would be applied again. The capacity, by default
+/// `MAX_TRACKED_CLIENTS`, has to be large enough for this to be unlikely, and small enough for
+/// snapshots to fit into a single message. Clients are evicted in the order of their requests in
+/// the log rather than by time, so that all replicas forget the same clients.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppliedRequests {
+    /// The highest applied `seq` of each client, and when it was recorded, see `recorded`.
+    clients: BTreeMap<u64, (u64, u64)>,
+    /// The clients by when their last request was recorded, least recently active first.
+    recency: BTreeMap<u64, u64>,
+    /// The number of requests recorded so far.
+    recorded: u64,
+}
+
+impl AppliedRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The highest `seq` of the client's requests applied so far, unless it is forgotten.
+    pub fn get(&self, client: u64) -> Option<u64> {
+        self.clients.get(&client).map(|&(seq, _)| seq)
+    }
+
+    /// The number of clients remembered.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Records the request as applied, and forgets the least recently active clients beyond
+    /// `capacity`. Returns false if the request has been applied before, i.e. it is a
+    /// resubmission, in which case nothing changes.
+    pub fn record(&mut self, id: RequestId, capacity: usize) -> bool {
+        let last = self.clients.get(&id.client).copied();
+        if id.seq <= last.map_or(0, |(seq, _)| seq) {
+            return false;
+        }
+        if let Some((_, recorded)) = last {
+            self.recency.remove(&recorded);
+        }
+        self.clients.insert(id.client, (id.seq, self.recorded));
+        self.recency.insert(self.recorded, id.client);
+        self.recorded += 1;
+        while self.clients.len() > capacity {
+            let (_, client) = self.recency.pop_first().unwrap();
+            self.clients.remove(&client);
         }
-        self.1 = node_id;
+        true
     }
 }
 
+/// The number of votes a quorum needs in a group of `group_size` replicas, i.e. a majority.
+///
+/// For an even group size this is one more than half of the group, so a group of 2k replicas
+/// tolerates no more failures than one of 2k - 1, while any two quorums still overlap.
+/// A group of one is its own quorum, and an empty group needs a single vote, which it never gets.
+pub fn quorum(group_size: usize) -> usize {
+    group_size / 2 + 1
+}
+
+/// Whether `count` votes form a quorum in a group of `group_size` replicas, see `quorum`.
+pub fn has_quorum(count: usize, group_size: usize) -> bool {
+    count >= quorum(group_size)
+}
+
+/// The replicas taking part in reaching a quorum.
+///
+/// Membership changes use joint consensus: the group first moves to a joint configuration,
+/// in which a quorum needs a majority in both `current` and `next`.
+/// Only once that has been chosen the group moves on to `next` alone.
+/// This way the old and new configuration can never make decisions independently.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Membership {
+    pub current: Vec<NodeId>,
+    pub next: Option<Vec<NodeId>>,
+}
+
+impl Membership {
+    pub fn new(members: Vec<NodeId>) -> Self {
+        Self {
+            current: members,
+            next: None,
+        }
+    }
+
+    /// Whether the votes form a quorum, i.e. a majority in each active configuration.
+    pub fn is_quorum<'a>(&self, votes: impl Iterator<Item = &'a NodeId> + Clone) -> bool {
+        let is_majority = |members: &Vec<NodeId>| {
+            let count = votes.clone().filter(|v| members.contains(v)).count();
+            has_quorum(count, members.len())
+        };
+        is_majority(&self.current) && self.next.as_ref().is_none_or(is_majority)
+    }
+
+    /// Iterates over all replicas which are part of any active configuration.
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.current.iter().chain(self.next.iter().flatten())
+    }
+}
+
+/// Microseconds since the start of a replica, measured with its own monotonic clock.
+/// Timestamps are only ever compared by the replica which created them.
+pub type Timestamp = u64;
+
 /// Represents a preliminary log entry as (index, ballot, value).
-type PValue<V> = (usize, Ballot, V);
+type PValue<V> = (usize, Ballot, Command<V>);
 pub type Promise<V> = Vec<PValue<V>>;
 
 /// Internal messages for the Paxos protocol.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum PaxosMsg<V: Debug> {
     /// Paxos phase 1a message
+    ///
+    /// `holes` lists the indices of all entries the candidate doesn't know to be chosen, in
+    /// ascending order. The last one is the length of the candidate's log, which stands for all
+    /// indices from there on. Acceptors only report the values they accepted at these indices in
+    /// their Promise, as the candidate already knows the values chosen at all others.
+    /// An empty list stands for the whole log, like `[0]`.
     Prepare {
         ballot: Ballot,
         holes: Vec<usize>,
+        /// The sender's timestamp, if it tracks latencies, which is echoed back in the Promise.
+        sent_at: Option<Timestamp>,
     },
     /// Paxos phase 1b message
     Promise {
         ballot: Ballot,
         accepted: Promise<V>,
+        sent_at: Option<Timestamp>,
     },
 
     /// Paxos phase 2a message
     Propose {
         index: usize,
         ballot: Ballot,
-        value: V,
+        value: Command<V>,
+        /// The sender's timestamp, if it tracks latencies, which is echoed back in the Accept.
+        sent_at: Option<Timestamp>,
     },
     /// Paxos phase 2b message
     Accept {
         index: usize,
         ballot: Ballot,
+        sent_at: Option<Timestamp>,
     },
 
     Learn {
         index: usize,
         ballot: Ballot,
-        value: V,
+        value: Command<V>,
+    },
+    /// Asks for the chosen values starting at `from`, which the sender has missed,
+    /// e.g. because it was down. They are sent back as Learn messages, preceded by an
+    /// InstallSnapshot if some of them are only covered by a snapshot anymore.
+    /// At most a window of values is sent per request, the next request acknowledges them,
+    /// see `PaxosReplica::with_catch_up_window`.
+    CatchUp {
+        from: usize,
+    },
+    /// Brings a replica which is behind the sender's snapshot up to `snapshot_index`,
+    /// replacing the state of its state machine with `data`.
+    InstallSnapshot {
+        snapshot_index: usize,
+        membership: Membership,
+        data: Vec<u8>,
+        requests: AppliedRequests,
     },
 
     /// This message is sent when a Prepare/Propose request is rejected due to a higher Ballot.
+    /// `ballot` is the rejected one, `promised` the highest the sender has promised.
     Nack {
         ballot: Ballot,
+        promised: Ballot,
     },
 
-    ClientRequest(V),
+    /// Sent by the leader to confirm that it still holds the majority's promise.
+    /// `applied` is the number of log entries the leader has applied so far.
+    Heartbeat {
+        ballot: Ballot,
+        seq: u64,
+        applied: usize,
+    },
+    /// Response to a Heartbeat from the leader this replica has promised to.
+    HeartbeatAck {
+        ballot: Ballot,
+        seq: u64,
+    },
+    /// Sent by the leader with `ballot` when handing leadership over to `target`, see
+    /// `PaxosReplica::transfer_leadership_to`. The target starts an election right away,
+    /// the other replicas attribute the leader's lease to it.
+    TimeoutNow {
+        ballot: Ballot,
+        target: NodeId,
+    },
+
+    /// Tells the leader that the sender has applied all log entries before `index`
+    /// to its state machine, if it tracks application, see `PaxosReplica::with_apply_acks`.
+    Applied {
+        index: usize,
+    },
+
+    /// A command submitted by a client, possibly relayed by replicas which aren't the leader.
+    /// `hops` counts how often it was relayed, which breaks forwarding loops between replicas
+    /// with stale beliefs about the leader.
+    /// Queued commands with a higher `priority` are proposed first.
+    /// With a `deadline`, the command is proposed as `Command::Expiring`.
+    ClientRequest {
+        value: V,
+        hops: u32,
+        priority: u8,
+        deadline: Option<u64>,
+    },
+    /// Commands submitted together by a client, to be chosen as a single log entry.
+    /// Relayed and prioritized like a ClientRequest.
+    ClientBatch {
+        values: Vec<V>,
+        hops: u32,
+        priority: u8,
+    },
+    /// A command submitted by a client with its request ID, see `Command::Tracked`.
+    /// Relayed and prioritized like a ClientRequest.
+    /// The first replica receiving it records the client as its `origin`, which the leader tells
+    /// once it has applied the command, no matter which replicas relayed it.
+    TrackedRequest {
+        id: RequestId,
+        value: V,
+        hops: u32,
+        priority: u8,
+        origin: Option<NodeId>,
+    },
+    /// Tells a client which sent a ClientRequest to a replica which isn't the leader, that the
+    /// request was relayed to `leader_hint`, so that it sends further requests there directly.
+    NotLeader {
+        leader_hint: Option<NodeId>,
+    },
+
+    /// Asks a replica to report its `ReplicaStatus`, e.g. for monitoring.
+    StatusRequest,
+    StatusReply(ReplicaStatus),
+
+    /// Asks a replica to report its `StateDump`, for debugging a running replica.
+    StateDumpRequest,
+    StateDumpReply(StateDump),
+
+    /// Asks the leader to commit a NoOp, to check that the group can still make progress.
+    /// Replicas which aren't the leader relay it, counting the `hops` like for a ClientRequest.
+    /// Once the NoOp is applied, the leader answers the `prober` with a HealthOk.
+    HealthProbe {
+        prober: NodeId,
+        hops: u32,
+    },
+    /// Tells the prober how long it took from receiving the HealthProbe to applying the NoOp.
+    HealthOk {
+        latency_ms: u64,
+    },
+
+    /// Asks a replica whether it has applied the command with the given request ID, and which
+    /// log entry holds it, if any. `superseded` tells that the replica gave up proposing it,
+    /// because another leader took over, so that the client should submit it again.
+    RequestStatus(RequestId),
+    RequestStatusReply {
+        id: RequestId,
+        applied: bool,
+        index: Option<usize>,
+        superseded: bool,
+    },
+    /// Tells the client which submitted a TrackedRequest that the leader applied it at log index
+    /// `index`, with the given result.
+    RequestApplied {
+        id: RequestId,
+        index: usize,
+        result: Result<String, ()>,
+    },
+    /// Tells the leader that the client stopped waiting for the request, see `CancelToken`.
+    /// The leader drops it if it is still queued, but can't withdraw it once it was proposed.
+    CancelRequest(RequestId),
+    /// Confirms to the leader that the sender learned the value chosen at `index`, so that it
+    /// doesn't send the Learn again, see `PaxosReplica::with_learn_acks`.
+    LearnAck {
+        index: usize,
+    },
+    /// Tells a client that its request was rejected without entering the log, e.g. because
+    /// the leader is overloaded or the state machine found the command invalid, so that it can
+    /// fail fast or try elsewhere. `id` identifies tracked requests.
+    Rejected {
+        id: Option<RequestId>,
+        reason: PaxosError,
+    },
+}
+
+impl<V: Debug> PaxosMsg<V> {
+    /// Whether the message keeps the leader in office, so that it must not be held up behind
+    /// bulk messages like Proposes and Learns, see `UdpNetworkNode::with_rate_limit`.
+    pub(crate) fn is_urgent(&self) -> bool {
+        matches!(self, Self::Heartbeat { .. } | Self::HeartbeatAck { .. })
+    }
+
+    /// The ballot of the message, if it refreshes the lease of the leader sending it at its
+    /// followers, provided the ballot is the one they promised.
+    pub(crate) fn lease_ballot(&self) -> Option<Ballot> {
+        match self {
+            Self::Propose { ballot, .. }
+            | Self::Learn { ballot, .. }
+            | Self::Heartbeat { ballot, .. } => Some(*ballot),
+            _ => None,
+        }
+    }
+}
+
+/// Leadership and progress of a single replica, as reported by itself.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaStatus {
+    /// The replica this replica believes to be leader, if any.
+    pub leader: Option<NodeId>,
+    /// The number of log entries known to be chosen and applied.
+    pub commit_index: usize,
+    /// The number of log entries a quorum of replicas has applied, if this replica is the
+    /// leader and tracks it, see `PaxosReplica::with_apply_acks`.
+    pub quorum_applied: Option<usize>,
+}
+
+/// Summary of a replica's internal state, as reported by itself.
+/// Leaves out the log itself, so that it always fits into a single message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateDump {
+    /// The highest ballot the replica has seen or promised.
+    pub ballot: Ballot,
+    pub role: Role,
+    /// The replica this replica believes to be leader, if any.
+    pub leader: Option<NodeId>,
+    /// The number of log entries known to be chosen, including ones not applied yet.
+    pub commit_index: usize,
+    /// Index of the next log entry to be applied to the state machine.
+    pub apply_index: usize,
+    /// The number of entries in the log, including ones covered by a snapshot.
+    pub log_len: usize,
 }
 
 /// Holds the state representing a single slot in the log.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LogEntry<V> {
     /// The value this replica currently believes to be the value for this entry.
-    pub value: Option<V>,
+    pub value: Option<Command<V>>,
     /// The `node_id`s of the replicas that have accepted this entry.
-    pub acceptances: Vec<usize>,
+    pub acceptances: HashSet<NodeId>,
     pub accepted_ballot: Ballot,
     pub chosen: bool, // TODO: replace with accepted_id==Ballot(INFINITY, INFINITY)?
 }
 
+/// State machine state which replaces all log entries up to a replica's `snapshot_index`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The membership governing the first entry after the snapshot.
+    pub membership: Membership,
+    /// The state machine's state, as produced by `ReplicatedStateMachine::snapshot`.
+    pub data: Vec<u8>,
+    /// The client commands applied up to the snapshot, which must not be applied again.
+    pub requests: AppliedRequests,
+}
+
 impl<V> LogEntry<V> {
-    pub fn new(value: V) -> Self {
+    /// Create a new entry for a value proposed by the replica `node_id` itself,
+    /// which implicitly accepts its own proposal.
+    pub fn new(value: Command<V>, node_id: NodeId, ballot: Ballot) -> Self {
         Self {
             value: Some(value),
-            acceptances: vec![0], // TODO: actually place own ID here, or don't count it alltogether
-            accepted_ballot: Ballot(0, 0),
+            acceptances: std::iter::once(node_id).collect(),
+            accepted_ballot: ballot,
             chosen: false,
         }
     }
@@ -98,9 +560,114 @@ impl<V> Default for LogEntry<V> {
     fn default() -> Self {
         Self {
             value: None,
-            acceptances: Vec::new(),
+            acceptances: HashSet::new(),
             accepted_ballot: Ballot(0, 0),
             chosen: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The IDs of the nodes with the given numbers.
+    fn nodes(ids: &[usize]) -> Vec<NodeId> {
+        ids.iter().copied().map(NodeId).collect()
+    }
+
+    #[test]
+    fn increment_ballot() {
+        let mut ballot = Ballot::default();
+        ballot.increment_for(NodeId(3)).unwrap();
+        assert_eq!(ballot, Ballot(0, 3));
+        ballot.increment_for(NodeId(5)).unwrap();
+        assert_eq!(ballot, Ballot(0, 5));
+        ballot.increment_for(NodeId(3)).unwrap();
+        assert_eq!(ballot, Ballot(1, 3));
+    }
+
+    #[test]
+    fn increment_near_max_ballot() {
+        let mut ballot = Ballot(usize::MAX - 1, 5);
+        ballot.increment_for(NodeId(3)).unwrap();
+        assert_eq!(ballot, Ballot(usize::MAX, 3));
+
+        // higher node IDs can still take over within the last round
+        ballot.increment_for(NodeId(4)).unwrap();
+        assert_eq!(ballot, Ballot(usize::MAX, 4));
+
+        // but the round never wraps around to lower ballots
+        assert_eq!(ballot.increment_for(NodeId(3)), Err(PaxosError::BallotExhausted));
+        assert_eq!(ballot, Ballot(usize::MAX, 4));
+        assert!(Ballot(usize::MAX, 0) > Ballot(usize::MAX - 1, usize::MAX));
+    }
+
+    #[test]
+    fn evict_least_recently_active_clients() {
+        let mut requests = AppliedRequests::new();
+        let id = |client, seq| RequestId { client, seq };
+        for client in 1..=3 {
+            assert!(requests.record(id(client, 1), 3));
+        }
+        // resubmissions are skipped, and don't count as activity
+        assert!(!requests.record(id(1, 1), 3));
+        assert!(requests.record(id(1, 2), 3));
+
+        // past the capacity, the client which was inactive the longest is forgotten
+        assert!(requests.record(id(4, 1), 3));
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests.get(2), None);
+        assert_eq!(requests.get(1), Some(2));
+        assert_eq!(requests.get(3), Some(1));
+        assert_eq!(requests.get(4), Some(1));
+        assert!(!requests.record(id(1, 2), 3));
+        assert!(!requests.record(id(3, 1), 3));
+
+        // so that its old requests are no longer recognized
+        assert!(requests.record(id(2, 1), 3));
+        assert_eq!(requests.get(3), None);
+    }
+
+    #[test]
+    fn quorum_sizes() {
+        let sizes: Vec<_> = (1..=10).map(quorum).collect();
+        assert_eq!(sizes, vec![1, 2, 2, 3, 3, 4, 4, 5, 5, 6]);
+        for size in 1..=10 {
+            assert!(has_quorum(quorum(size), size));
+            assert!(!has_quorum(quorum(size) - 1, size));
+            // any two quorums share a replica
+            assert!(2 * quorum(size) > size);
+        }
+        assert!(!has_quorum(0, 0));
+    }
+
+    #[test]
+    fn simple_quorum() {
+        let membership = Membership::new(nodes(&[1, 2, 3]));
+        assert!(!membership.is_quorum(nodes(&[1]).iter()));
+        assert!(!membership.is_quorum(nodes(&[1, 4, 5]).iter()));
+        assert!(membership.is_quorum(nodes(&[1, 3]).iter()));
+        assert!(membership.is_quorum(nodes(&[1, 2, 3]).iter()));
+
+        // neither half of an even group is a quorum
+        let membership = Membership::new(nodes(&[1, 2, 3, 4]));
+        assert!(!membership.is_quorum(nodes(&[1, 2]).iter()));
+        assert!(!membership.is_quorum(nodes(&[3, 4]).iter()));
+        assert!(membership.is_quorum(nodes(&[1, 2, 4]).iter()));
+    }
+
+    #[test]
+    fn joint_quorum() {
+        let membership = Membership {
+            current: nodes(&[1, 2, 3]),
+            next: Some(nodes(&[1, 2, 3, 4, 5])),
+        };
+        assert!(!membership.is_quorum(nodes(&[1, 2]).iter()));
+        assert!(!membership.is_quorum(nodes(&[3, 4, 5]).iter()));
+        assert!(!membership.is_quorum(nodes(&[1, 4, 5]).iter()));
+        assert!(membership.is_quorum(nodes(&[1, 2, 4]).iter()));
+        assert!(membership.is_quorum(nodes(&[1, 2, 3]).iter()));
+        assert_eq!(membership.nodes().count(), 8);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Limits the rate at which a node sends datagrams, so that it doesn't congest its own uplink.
+
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{error, warn};
+
+/// The number of datagrams which may wait in each queue, before further ones are dropped.
+const MAX_QUEUED: usize = 1024;
+
+/// How long the sending thread waits for new datagrams, before checking whether it should end.
+const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An encoded message, together with the address it is sent to.
+type Datagram = (SocketAddr, Vec<u8>);
+
+/// Token bucket refilled with `rate` bytes per second, holding at most `burst` bytes.
+/// Datagrams exceeding the budget wait in a queue, with urgent ones always sent first.
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    burst: u64,
+    tokens: u64,
+    refilled: Instant,
+    urgent: VecDeque<Datagram>,
+    bulk: VecDeque<Datagram>,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let added = now.duration_since(self.refilled).as_secs_f64() * self.rate as f64;
+        if added >= 1.0 {
+            self.tokens = (self.tokens + added as u64).min(self.burst);
+            self.refilled = now;
+        }
+    }
+
+    /// Takes tokens for a datagram of `len` bytes, if there are enough.
+    /// Datagrams larger than the bucket only need it to be full.
+    fn try_take(&mut self, len: usize) -> bool {
+        let needed = (len as u64).min(self.burst);
+        if self.tokens < needed {
+            return false;
+        }
+        self.tokens -= needed;
+        true
+    }
+
+    /// The time until enough tokens are available for a datagram of `len` bytes.
+    fn time_until(&self, len: usize) -> Duration {
+        let missing = (len as u64).min(self.burst).saturating_sub(self.tokens);
+        Duration::from_secs_f64(missing as f64 / self.rate as f64)
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    bucket: Mutex<Bucket>,
+    queued: Condvar,
+}
+
+/// Sends datagrams no faster than the configured rate, deferring the ones over budget
+/// instead of dropping them. These are sent from a separate thread, once the budget allows,
+/// which ends once the limiter is dropped, together with the node owning it.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    shared: Arc<Shared>,
+}
+
+impl RateLimiter {
+    /// Starts sending deferred datagrams over the socket, at most `rate` bytes per second
+    /// after an initial burst of up to `burst` bytes.
+    pub(crate) fn spawn(socket: UdpSocket, rate: u64, burst: usize) -> Self {
+        let shared = Arc::new(Shared {
+            bucket: Mutex::new(Bucket {
+                rate: rate.max(1),
+                burst: burst as u64,
+                tokens: burst as u64,
+                refilled: Instant::now(),
+                urgent: VecDeque::new(),
+                bulk: VecDeque::new(),
+            }),
+            queued: Condvar::new(),
+        });
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || send_deferred(&weak, &socket));
+        Self { shared }
+    }
+
+    /// Sends the datagram right away if the budget allows it and nothing is queued before it,
+    /// defers it otherwise. Urgent datagrams, e.g. heartbeats, overtake all others queued.
+    /// Returns false if it had to be dropped, as its queue is full.
+    pub(crate) fn send(&self, socket: &UdpSocket, datagram: Datagram, urgent: bool) -> bool {
+        let mut bucket = self.shared.bucket.lock().unwrap();
+        bucket.refill();
+        let (addr, bytes) = &datagram;
+        let ahead = bucket.urgent.len() + if urgent { 0 } else { bucket.bulk.len() };
+        if ahead == 0 && bucket.try_take(bytes.len()) {
+            drop(bucket);
+            return socket.send_to(bytes, addr).is_ok();
+        }
+        let queue = if urgent { &mut bucket.urgent } else { &mut bucket.bulk };
+        if queue.len() >= MAX_QUEUED {
+            warn!("Send queue full, dropping message to {}.", addr);
+            return false;
+        }
+        queue.push_back(datagram);
+        self.shared.queued.notify_one();
+        true
+    }
+}
+
+/// Sends queued datagrams as the budget allows, until the limiter is dropped.
+fn send_deferred(shared: &Weak<Shared>, socket: &UdpSocket) {
+    while let Some(shared) = shared.upgrade() {
+        let mut bucket = shared.bucket.lock().unwrap();
+        bucket.refill();
+        let next_len = bucket.urgent.front().or_else(|| bucket.bulk.front()).map(|d| d.1.len());
+        let wait = match next_len {
+            Some(len) if bucket.try_take(len) => {
+                let (addr, bytes) = match bucket.urgent.pop_front() {
+                    Some(datagram) => datagram,
+                    None => bucket.bulk.pop_front().unwrap(),
+                };
+                drop(bucket);
+                if let Err(e) = socket.send_to(&bytes, addr) {
+                    error!("Failed to send deferred message to {}: {}", addr, e);
+                }
+                continue;
+            }
+            Some(len) => bucket.time_until(len).min(IDLE_INTERVAL),
+            None => IDLE_INTERVAL,
+        };
+        let _ = shared.queued.wait_timeout(bucket, wait).unwrap();
+    }
+}
@@ -3,36 +3,382 @@
 
 //! Contains the PaxosReplica which implements the main Paxos protocol logic.
 
-use std::collections::HashMap;
-use std::fmt::Debug;
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug};
+use std::io;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::protocol::{Ballot, LogEntry, PaxosMsg, Promise, LEASE_DURATION};
-use crate::storage::{load_from_disk_file, store_in_disk_file};
-use crate::udp_network::UdpNetworkNode;
+use crate::clock::{Clock, SystemClock};
+use crate::codec::{BincodeCodec, Codec};
+use crate::leader_policy::{DefaultPolicy, LeaderPolicy};
+use crate::metrics::Metrics;
+use crate::protocol::{
+    AppliedRequests, Ballot, Command, LogEntry, Membership, PaxosMsg, Promise, ReplicaStatus,
+    RequestId, Snapshot, StateDump, Timestamp, CATCH_UP_INTERVAL, CATCH_UP_TIMEOUT,
+    CATCH_UP_WINDOW, DEFAULT_PRIORITY, DUPLICATE_WINDOW, FORCE_ROUNDS, LEASE_DURATION,
+    MAX_INDEX_GAP, MAX_LEARNER_LAG, MAX_PROPOSAL_RETRIES, MAX_TRACKED_CLIENTS, POLL_INTERVAL,
+    PREPARE_ROUND_TIMEOUT, PRIORITY_AGING, PROPOSAL_TIMEOUT, PROPOSAL_WINDOW, REQUEST_TTL,
+};
+use crate::network::{command_wire_size, BroadcastResult, Network, NodeId, RecvError};
+use crate::sink::CommitSink;
+use crate::storage::{
+    load_from_disk_file, store_in_disk_file, LogExport, LogFile, PersistentState, StorageError,
+};
+use crate::watchdog::Watchdog;
+use crate::{PaxosError, ReplicatedStateMachine};
+
+/// Identifies a processed Prepare (without index) or Propose (with index) by its ballot.
+type MessageKey = (Option<usize>, Ballot);
+
+/// A command applied to the state machine, with the index of its log entry and its result.
+pub type AppliedCommand<V> = (usize, V, Result<String, ()>);
+
+/// Receives the commands applied to the state machine, see `PaxosReplica::subscribe`.
+/// Dereferences to the underlying channel, e.g. to `recv` the next command.
+pub struct Subscription<V> {
+    receiver: Receiver<AppliedCommand<V>>,
+    state: Arc<SubscriberState>,
+}
+
+impl<V> Subscription<V> {
+    /// Acknowledges that all commands up to log index `index` have been processed, so that the
+    /// replica may discard them, see `PaxosReplica::with_subscriber_retention`.
+    ///
+    /// Fails with `PaxosError::SubscriberLagging` once the replica has dropped this subscription
+    /// for falling too far behind. The commands received so far remain available.
+    pub fn ack(&self, index: usize) -> Result<(), PaxosError> {
+        if self.state.dropped.load(Ordering::Relaxed) {
+            return Err(PaxosError::SubscriberLagging {
+                acked: self.state.unacked.load(Ordering::Relaxed),
+            });
+        }
+        self.state.unacked.fetch_max(index + 1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl<V> Deref for Subscription<V> {
+    type Target = Receiver<AppliedCommand<V>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.receiver
+    }
+}
+
+/// Progress of a subscription, shared between the replica and the subscriber.
+#[derive(Default)]
+struct SubscriberState {
+    /// The first log index not acknowledged yet.
+    unacked: AtomicUsize,
+    /// Set once the replica has dropped the subscription for lagging behind.
+    dropped: AtomicBool,
+}
+
+type Subscriber<V> = (Sender<AppliedCommand<V>>, Arc<SubscriberState>);
+
+/// Lets other threads wait for a replica to apply log entries, while it runs elsewhere,
+/// see `PaxosReplica::apply_watch`.
+#[derive(Clone, Debug, Default)]
+pub struct ApplyWatch(Arc<(Mutex<usize>, Condvar)>);
+
+impl ApplyWatch {
+    /// The number of entries at the start of the log which the replica has applied.
+    pub fn applied(&self) -> usize {
+        let (applied, _) = &*self.0;
+        *applied.lock().unwrap()
+    }
+
+    /// Blocks until the replica has applied the log entry at `index`, or fails with
+    /// `PaxosError::Timeout` if that takes longer than `timeout`.
+    pub fn wait_for_apply(&self, index: usize, timeout: Duration) -> Result<(), PaxosError> {
+        let (applied, changed) = &*self.0;
+        let applied = applied.lock().unwrap();
+        let (_applied, result) = changed
+            .wait_timeout_while(applied, timeout, |applied| *applied <= index)
+            .unwrap();
+        if result.timed_out() {
+            return Err(PaxosError::Timeout);
+        }
+        Ok(())
+    }
+
+    /// Wakes up the waiting threads, if the replica has applied more entries.
+    fn update(&self, apply_index: usize) {
+        let (applied, changed) = &*self.0;
+        let mut applied = applied.lock().unwrap();
+        if apply_index > *applied {
+            *applied = apply_index;
+            changed.notify_all();
+        }
+    }
+}
+
+/// A snapshot being written on a separate thread, with the index and membership it covers.
+type PendingSnapshot = (usize, Membership, AppliedRequests, JoinHandle<Option<Vec<u8>>>);
+
+/// The part a replica plays in its group.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Takes part in quorums and can become leader.
+    Voter,
+    /// Only learns chosen values and applies them, e.g. to serve reads.
+    /// Learners are never counted toward quorums and never run for leader.
+    Learner,
+}
+
+/// How chosen values spread from the leader to the other replicas of its group.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FanOut {
+    /// The leader sends each chosen value to all other replicas itself.
+    #[default]
+    Direct,
+    /// The leader sends each chosen value to `fan_out` random replicas, which forward it to
+    /// as many random replicas again when they learn about it first. This takes load off the
+    /// leader at the cost of latency. Replicas missed by chance catch up with the leader, once
+    /// its next Heartbeat shows that they are still behind.
+    /// All other messages, e.g. Prepares and Proposes, are still sent directly.
+    Gossip { fan_out: usize },
+}
+
+/// What a replica believes about the leadership of its group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaderState {
+    /// No leader is known yet.
+    Unknown,
+    /// This replica is the leader.
+    Leader,
+    /// The replica with the given ID is the leader.
+    Follower(NodeId),
+}
+
+/// Called with the old and new `LeaderState` and the ballot of the new leader,
+/// whenever a replica's belief about the leadership changes.
+type LeaderChangeCallback = Box<dyn FnMut(LeaderState, LeaderState, Ballot) + Send>;
 
 /// Handles all Paxos related state for a single replica, acting as proposer, acceptor and learner.
-#[derive(Debug)]
 pub struct PaxosReplica<V: Debug> {
-    node_id: usize,
-    node: UdpNetworkNode<V>,
-    client_cmd_queue: Vec<V>,
+    node_id: NodeId,
+    role: Role,
+    node: Box<dyn Network<V>>,
+    state_machine: Box<dyn ReplicatedStateMachine<Command = V> + Send>,
+    /// Client commands waiting for a leader to be known, or for room in the proposal window.
+    /// They are proposed in the order of their keys, see `enqueue`.
+    client_cmd_queue: BTreeMap<(u64, u64), Command<V>>,
+    /// The number of client commands queued so far, which orders those of equal priority.
+    queued_total: u64,
+    /// The number of entries this replica proposes as leader, before one of them is chosen.
+    proposal_window: usize,
+    /// How chosen values spread to the other replicas.
+    fan_out: FanOut,
+    /// How far beyond the end of the log other replicas may refer to entries, see `in_reach`.
+    max_index_gap: usize,
+    /// Entries this replica proposed as leader and which might not be chosen yet,
+    /// with the time of their last Propose, the number of retries so far, and the timestamp
+    /// of their first Propose if latencies are tracked.
+    proposals: BTreeMap<usize, (Instant, u32, Option<Timestamp>)>,
+    /// Time after which an entry not yet chosen is proposed again.
+    proposal_timeout: Duration,
+    /// The number of times an entry is proposed again at most.
+    max_proposal_retries: u32,
+    /// Time after which the leader sends a Learn again to peers which haven't acknowledged it,
+    /// and the number of times it does so at most, see `with_learn_acks`.
+    learn_acks: Option<(Duration, u32)>,
+    /// Entries this replica got chosen as leader, which some peers haven't acknowledged yet,
+    /// with the time of their last Learn, the number of retries so far, and those peers.
+    unacked_learns: BTreeMap<usize, (Instant, u32, HashSet<NodeId>)>,
     log: Vec<LogEntry<V>>,
-    /// The number of nodes which comprise a quorum (majority).
-    quorum: usize,
-    // TODO: replace with Option<usize> to support the initial state w/o a leader
-    current_leader: usize,
+    /// Index of the next log entry to be applied to the state machine.
+    apply_index: usize,
+    /// Index up to which the log is covered by a snapshot of the state machine.
+    snapshot_index: usize,
+    /// The snapshot covering the log up to `snapshot_index`, whose entries hold no values.
+    snapshot: Option<Snapshot>,
+    /// The highest sequence number of each client's commands applied so far, to skip
+    /// resubmissions of them, see `Command::Tracked`.
+    applied_requests: AppliedRequests,
+    /// The number of clients `applied_requests` remembers, see `with_tracked_clients`.
+    max_tracked_clients: usize,
+    /// The highest sequence number of each client's commands whose proposal this replica
+    /// abandoned, see `abandon_proposal`, and when. Forgotten after `REQUEST_TTL`.
+    superseded_requests: BTreeMap<u64, (u64, Instant)>,
+    /// The clients which submitted the tracked requests this replica queued, to tell them once
+    /// the requests are applied, and since when. Forgotten after `REQUEST_TTL`.
+    request_origins: HashMap<RequestId, (NodeId, Instant)>,
+    /// The snapshot started by `start_snapshot`, until it is written and installed.
+    pending_snapshot: Option<PendingSnapshot>,
+    /// File this replica's persistent state is stored in, if any.
+    state_file: Option<String>,
+    /// Encoding of the persistent state in the `state_file`.
+    storage_codec: Arc<dyn Codec<PersistentState<V>>>,
+    /// Whether transient vote tracking is left out of the state file, see `with_compact_storage`.
+    compact_storage: bool,
+    /// File applied entries are spilled to and the number of applied entries whose values are
+    /// kept in memory, see `with_log_archive`. Cleared once spilling fails, so that all further
+    /// entries are kept in memory.
+    log_archive: Option<(String, usize)>,
+    /// The opened `log_archive`, holding the values of all entries before `archive.len()`.
+    archive: Option<LogFile<V>>,
+    /// Whether the entries left over in the `archive` from an earlier run have been discarded.
+    archive_truncated: bool,
+    /// The first log entry whose value is kept in memory, as those before it were spilled.
+    spilled_index: usize,
+    /// The membership this replica was started with, governing the log up to the first
+    /// `Reconfigure` entry.
+    initial_membership: Membership,
+    /// All memberships stored in the log, keyed by their index.
+    configs: BTreeMap<usize, Membership>,
+    /// The replica this node believes to be leader, if any.
+    current_leader: Option<NodeId>,
+    /// Source of time for all leases and timeouts.
+    clock: Arc<dyn Clock>,
+    /// Point in time all timestamps sent by this replica are relative to.
+    epoch: Instant,
+    /// Whether Prepare and Propose messages carry timestamps, to measure round-trip times.
+    track_latency: bool,
+    /// Timestamps of when entries not applied yet were learned to be chosen, if latencies are
+    /// tracked, see `Metrics::apply_latency`.
+    chosen_at: BTreeMap<usize, Timestamp>,
+    metrics: Metrics,
     /// Point in time when the leader last refreshed his lease with this node.
     /// This happens when the leader is first elected and also upon proposing values.
     leader_lease_start: Instant,
     random_timeout_offset: Duration,
+    /// Source of the random election timeouts and gossip targets, see `with_rng`.
+    rng: Box<dyn RngCore + Send>,
+    /// Replaces the random part of election timeouts, see `with_election_rank`.
+    election_rank: Option<u32>,
+    /// Adjusts election timeouts, to give some replicas precedence in becoming leader.
+    leader_policy: Box<dyn LeaderPolicy>,
+    /// Point in time when this replica last started an election.
+    last_election: Instant,
+    /// The replicas which rejected this replica's Prepares in its current election.
+    prepare_nacks: HashSet<NodeId>,
+    /// How long a round of Prepares may take at most, see `with_prepare_round_timeout`.
+    prepare_round_timeout: Duration,
+    /// Whether this replica learned of another candidate with a higher ballot, since its
+    /// election timer last fired. It then waits one more timeout, see `defers_election`.
+    candidate_seen: bool,
+    /// Point in time when this replica last put off an election, in favor of another candidate.
+    election_deferred: Option<Instant>,
+    /// Upper bound for the time `tick` asks to wait until it is called again.
+    /// Also bounds the time each `tick` spends applying entries to the state machine.
+    poll_interval: Duration,
+    /// Point in time after which no more entries are applied during the current `tick`.
+    apply_deadline: Option<Instant>,
+    /// The apply lag at which a warning is logged, see `with_apply_lag_threshold`.
+    apply_lag_threshold: Option<usize>,
+    /// Whether the apply lag has reached the threshold since the last warning.
+    apply_lag_warned: bool,
+    /// Whether client requests are rejected while the apply lag is at the threshold.
+    backpressure: bool,
     /// Always holds the highest Ballot number seen so far,
     /// including the ones generated by this node itself.
     highest_promised: Ballot,
-    promises: HashMap<usize, (Ballot, Promise<V>)>,
+    promises: BTreeMap<NodeId, (Ballot, Promise<V>)>,
+    /// Sequence number of the last Heartbeat this replica sent as leader.
+    heartbeat_seq: u64,
+    /// Point in time when this replica sent the last Heartbeat.
+    heartbeat_sent: Instant,
+    /// The replicas which have acknowledged the last Heartbeat.
+    heartbeat_acks: HashSet<NodeId>,
+    /// Whether the leader skips Heartbeats to busy followers, see `with_heartbeat_suppression`.
+    suppress_heartbeats: bool,
+    /// When this replica as leader last sent each peer a message which refreshes its lease.
+    lease_refreshed: HashMap<NodeId, Instant>,
+    /// Whether followers report how far they applied the log, see `with_apply_acks`.
+    track_applied: bool,
+    /// The last `apply_index` each follower reported, while this replica is leader.
+    applied: HashMap<NodeId, usize>,
+    /// The learners added by `add_learner`, with the last `apply_index` each reported.
+    learners: HashMap<NodeId, usize>,
+    /// When this replica last answered a CatchUp request of each replica, and the index after
+    /// the last value it sent, from which on the next request acknowledges the batch.
+    catch_ups_served: HashMap<NodeId, (Instant, usize)>,
+    /// The number of chosen values sent per CatchUp request, see `with_catch_up_window`.
+    catch_up_window: usize,
+    /// The replica this one is catching up from, the index up to which it has chosen all values,
+    /// and the index from which on the last batch was requested, and when.
+    catching_up: Option<(NodeId, usize, usize, Instant)>,
+    /// Peers the last broadcast couldn't be sent to, and which haven't been heard from since.
+    suspects: HashSet<NodeId>,
+    /// The number of entries the leader had applied according to its last Heartbeat,
+    /// and the point in time when this Heartbeat arrived.
+    leader_applied: Option<(usize, Instant)>,
+    /// Arrival of the last Heartbeat whose applied entries this replica has applied as well.
+    /// This replica's state is at least as fresh as the leader's state at that point in time.
+    caught_up_at: Option<Instant>,
+    /// Index of the NoOp this replica proposed when it was last elected. Until it is applied,
+    /// the leader doesn't know how far the log is chosen, and doesn't serve reads.
+    ready_index: usize,
+    /// Start of the last Prepare or Heartbeat round answered by a quorum, if any.
+    /// No other leader can be elected until a lease duration after it.
+    quorum_contact: Option<Instant>,
+    /// Set while draining, to signal once all in-flight entries have been chosen and applied.
+    drained: Option<Arc<AtomicBool>>,
+    /// The log entry and value to propose instead of the adopted one, once re-elected,
+    /// see `force_commit`.
+    forced: Option<(usize, Command<V>)>,
+    /// The replica this leader hands leadership over to, and when it started waiting for it to
+    /// catch up, see `transfer_leadership_to`.
+    transfer: Option<(NodeId, Instant)>,
+    /// The replica the leader handed leadership over to with a TimeoutNow. Its Prepares are
+    /// answered even while the leader's lease is still running.
+    handover: Option<NodeId>,
+    /// Notified of leadership changes, e.g. to update service discovery.
+    on_leader_change: Option<LeaderChangeCallback>,
+    /// Reports ticks which don't return in time, see `with_watchdog`.
+    watchdog: Option<Watchdog>,
+    /// The most recently processed Prepare and Propose messages, oldest first.
+    recent_messages: VecDeque<MessageKey>,
+    /// The number of messages kept in `recent_messages`.
+    duplicate_window: usize,
+    /// The results of the entries applied since the snapshot, to replay them to subscribers.
+    applied_results: BTreeMap<usize, Vec<Result<String, ()>>>,
+    /// Receive every command once it has been applied, see `subscribe`.
+    subscribers: Vec<Subscriber<V>>,
+    /// Wakes up the threads waiting for entries to be applied, see `apply_watch`.
+    apply_watch: ApplyWatch,
+    /// How many applied entries are kept for subscribers which haven't acknowledged them,
+    /// see `with_subscriber_retention`.
+    subscriber_retention: Option<usize>,
+    /// The first log entry whose value is still kept, as it is covered by neither the snapshot
+    /// nor the acknowledgements of all subscribers.
+    retained_index: usize,
+    /// The NoOps proposed for HealthProbes, by log index, with the prober and receipt time.
+    health_probes: Vec<(usize, NodeId, Instant)>,
+    /// How long Accepts are held back to persist the values they acknowledge together,
+    /// see `with_group_commit`.
+    group_commit: Option<Duration>,
+    /// Accepts held back until the next group commit, as destination, index, ballot and the
+    /// timestamp to echo, together with the time the first of them was held back.
+    pending_accepts: Vec<(NodeId, usize, Ballot, Option<Timestamp>)>,
+    pending_accepts_since: Instant,
+}
+
+impl<V: Debug> Debug for PaxosReplica<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PaxosReplica")
+            .field("node_id", &self.node_id)
+            .field("role", &self.role)
+            .field("node", &self.node)
+            .field("log", &self.log)
+            .field("apply_index", &self.apply_index)
+            .field("current_leader", &self.current_leader)
+            .field("highest_promised", &self.highest_promised)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<V: crate::AppCommand> PaxosReplica<V> {
@@ -40,292 +386,6665 @@ impl<V: crate::AppCommand> PaxosReplica<V> {
     ///
     /// # Arguments
     ///
-    /// * `node` - The network node used for sending messages to other Paxos replicas.
+    /// * `node` - The network node used for sending messages to other Paxos replicas,
+    ///   e.g. a `UdpNetworkNode`.
     /// * `node_id` - A unique number identifying this Paxos replica.
     /// * `node_count` - The number of Paxos replicas operating in this network.
+    /// * `state_machine` - The state machine chosen commands are applied to, in log order.
     ///
     /// # Remarks
     ///
     /// At the time of creation, this replica has an empty log and doesn't know who the leader is.
-    pub fn new(node: UdpNetworkNode<V>, node_id: usize, node_count: usize) -> Self {
+    /// The membership consists of this replica and all peers the `node` knows about, which must
+    /// be exactly `node_count - 1`. Otherwise the quorum derived from the known replicas could be
+    /// smaller than a majority of the group, and replicas which know different parts of the
+    /// group could make conflicting decisions.
+    /// For groups of replicas prefer `with_members`, which keeps peers and group size consistent.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidConfig` if `node_count` is 0, as a group needs at least one replica,
+    /// or if `node_id` appears among the peers of `node`, as it would then share its ID with one
+    /// of them. Fails with `GroupSizeMismatch` if the `node` doesn't know exactly
+    /// `node_count - 1` peers.
+    #[deprecated(note = "use with_members")]
+    pub fn new(
+        node: impl Network<V> + 'static,
+        node_id: NodeId,
+        node_count: usize,
+        state_machine: impl ReplicatedStateMachine<Command = V> + Send + 'static,
+    ) -> Result<Self, PaxosError> {
+        Self::check_node_count(node_count)?;
+        Self::check_node_id(&node, node_id)?;
+        Self::check_group_size(&node, node_count)?;
+        let members = node.peers();
+        Ok(Self::create(Box::new(node), node_id, members, Box::new(state_machine)))
+    }
+
+    fn check_node_count(node_count: usize) -> Result<(), PaxosError> {
+        if node_count == 0 {
+            let reason = "group size must be at least 1".to_owned();
+            return Err(PaxosError::InvalidConfig(reason));
+        }
+        Ok(())
+    }
+
+    fn check_node_id(node: &impl Network<V>, node_id: NodeId) -> Result<(), PaxosError> {
+        if node.peers().contains(&node_id) {
+            let reason = format!("node id {} appears among its own peers", node_id);
+            return Err(PaxosError::InvalidConfig(reason));
+        }
+        Ok(())
+    }
+
+    fn check_group_size(node: &impl Network<V>, node_count: usize) -> Result<(), PaxosError> {
+        let known = node.peers().len() + 1;
+        if known != node_count {
+            return Err(PaxosError::GroupSizeMismatch {
+                expected: node_count,
+                known,
+            });
+        }
+        Ok(())
+    }
+
+    /// Creates a new Paxos replica, which is a member of the group consisting of `members`.
+    /// The network node learns about all other members, and the quorum size is derived from
+    /// the number of members, so that both are always consistent.
+    /// It doesn't matter whether `members` contains `node_id` itself or not.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidConfig` if `node` doesn't identify itself as `node_id` on the network
+    /// while `node_id` is among the `members`, as it would then share its ID with one of them.
+    pub fn with_members(
+        mut node: impl Network<V> + 'static,
+        node_id: NodeId,
+        members: Vec<NodeId>,
+        state_machine: impl ReplicatedStateMachine<Command = V> + Send + 'static,
+    ) -> Result<Self, PaxosError> {
+        node.discover(&members);
+        Self::check_node_id(&node, node_id)?;
+        Ok(Self::create(Box::new(node), node_id, members, Box::new(state_machine)))
+    }
+
+    fn create(
+        node: Box<dyn Network<V>>,
+        node_id: NodeId,
+        mut members: Vec<NodeId>,
+        state_machine: Box<dyn ReplicatedStateMachine<Command = V> + Send>,
+    ) -> Self {
+        members.push(node_id);
+        members.sort_unstable();
+        members.dedup();
+        let mut rng = StdRng::from_entropy();
         Self {
             node_id,
+            role: Role::Voter,
             node,
-            client_cmd_queue: Vec::new(),
+            state_machine,
+            client_cmd_queue: BTreeMap::new(),
+            queued_total: 0,
+            proposal_window: PROPOSAL_WINDOW,
+            fan_out: FanOut::Direct,
+            max_index_gap: MAX_INDEX_GAP,
+            proposals: BTreeMap::new(),
+            proposal_timeout: PROPOSAL_TIMEOUT,
+            max_proposal_retries: MAX_PROPOSAL_RETRIES,
+            learn_acks: None,
+            unacked_learns: BTreeMap::new(),
             log: Vec::new(),
-            quorum: node_count / 2 + 1,
-            current_leader: 0,
+            apply_index: 0,
+            snapshot_index: 0,
+            snapshot: None,
+            applied_requests: AppliedRequests::new(),
+            max_tracked_clients: MAX_TRACKED_CLIENTS,
+            superseded_requests: BTreeMap::new(),
+            request_origins: HashMap::new(),
+            pending_snapshot: None,
+            state_file: None,
+            storage_codec: Arc::new(BincodeCodec::default()),
+            initial_membership: Membership::new(members),
+            configs: BTreeMap::new(),
+            current_leader: None,
+            clock: Arc::new(SystemClock),
+            epoch: Instant::now(),
+            track_latency: false,
+            chosen_at: BTreeMap::new(),
+            metrics: Metrics::default(),
             leader_lease_start: Instant::now(),
-            random_timeout_offset: Duration::from_millis(thread_rng().gen_range(100..=200)),
+            random_timeout_offset: Duration::from_millis(rng.gen_range(100..=200)),
+            rng: Box::new(rng),
+            election_rank: None,
+            leader_policy: Box::new(DefaultPolicy),
+            last_election: Instant::now(),
+            prepare_nacks: HashSet::new(),
+            prepare_round_timeout: PREPARE_ROUND_TIMEOUT,
+            candidate_seen: false,
+            election_deferred: None,
+            poll_interval: POLL_INTERVAL,
+            apply_deadline: None,
+            apply_lag_threshold: None,
+            apply_lag_warned: false,
+            backpressure: false,
             highest_promised: Ballot::default(),
-            promises: HashMap::new(),
+            promises: BTreeMap::new(),
+            heartbeat_seq: 0,
+            heartbeat_sent: Instant::now(),
+            heartbeat_acks: HashSet::new(),
+            suppress_heartbeats: false,
+            lease_refreshed: HashMap::new(),
+            leader_applied: None,
+            caught_up_at: None,
+            ready_index: 0,
+            quorum_contact: None,
+            compact_storage: false,
+            log_archive: None,
+            archive: None,
+            archive_truncated: false,
+            spilled_index: 0,
+            track_applied: false,
+            applied: HashMap::new(),
+            learners: HashMap::new(),
+            catch_ups_served: HashMap::new(),
+            catch_up_window: CATCH_UP_WINDOW,
+            catching_up: None,
+            suspects: HashSet::new(),
+            drained: None,
+            forced: None,
+            transfer: None,
+            handover: None,
+            on_leader_change: None,
+            watchdog: None,
+            recent_messages: VecDeque::new(),
+            duplicate_window: DUPLICATE_WINDOW,
+            applied_results: BTreeMap::new(),
+            subscribers: Vec::new(),
+            apply_watch: ApplyWatch::default(),
+            subscriber_retention: None,
+            retained_index: 0,
+            health_probes: Vec::new(),
+            group_commit: None,
+            pending_accepts: Vec::new(),
+            pending_accepts_since: Instant::now(),
         }
     }
 
-    /// Runs a single iteration of this Paxos replica's main loop.
-    pub fn tick(&mut self) {
-        // event loop for incoming messages
-        while let Ok(msg) = self.node.recv(Duration::from_millis(10)) {
-            let (src, cmd) = msg;
-            self.handle_paxos_message(src, cmd);
-        }
+    /// Makes this replica persist its state in the given file, recovering from it first.
+    /// If the file doesn't exist yet or holds corrupted state, the replica starts out empty.
+    pub fn with_state_file(mut self, filename: &str) -> Self {
+        self.state_file = Some(filename.to_owned());
+        self.recover_from_disk();
+        self
+    }
 
-        // detect leader timeout or try to extend our own lease
-        if self.leader_lease_start.elapsed().as_millis()
-            >= LEASE_DURATION + self.random_timeout_offset.as_millis()
-        {
-            warn!("Leader's lease timed out: Starting election.");
-            self.start_election();
-        } else if self.leader_lease_start.elapsed().as_millis() >= LEASE_DURATION / 2
-            && self.node_id == self.current_leader
-        {
-            info!("Extending my lease: Starting election.");
-            self.start_election();
-        }
+    /// Makes this replica persist its state in `{base}/{node_id}/state.bin`, like
+    /// `with_state_file`, so that replicas sharing a host or process can share the base directory.
+    /// Missing directories are created, failing with `PaxosError::Io` if that isn't possible.
+    pub fn with_data_dir(self, base: impl AsRef<Path>) -> Result<Self, PaxosError> {
+        let dir = base.as_ref().join(self.node_id.to_string());
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            PaxosError::Io(format!("failed to create data directory {}: {}", dir.display(), e))
+        })?;
+        let filename = dir.join("state.bin");
+        Ok(self.with_state_file(&filename.to_string_lossy()))
     }
 
-    /// The value is treated as a `ClientRequest` and handled accordingly.
-    pub fn submit_value(&mut self, value: V) {
-        self.handle_paxos_message(0, PaxosMsg::ClientRequest(value));
+    /// Makes this replica keep its state in memory only, which is also the default if neither
+    /// `with_state_file` nor `with_data_dir` is used. Such an ephemeral replica never touches the
+    /// disk or its storage codec, and drops any state file set before.
+    ///
+    /// This sacrifices crash-recovery safety: after a restart the replica has forgotten its
+    /// promises and accepted values, so it might go back on them. It must only rejoin its group
+    /// as a new member, or the whole group must be ephemeral and restarted from scratch, e.g. in
+    /// tests or for caches which can be rebuilt.
+    pub fn with_ephemeral_storage(mut self) -> Self {
+        self.state_file = None;
+        self
     }
 
-    /// Parses the message and calls the method corresponding to the message type.
-    fn handle_paxos_message(&mut self, src: usize, cmd: PaxosMsg<V>) {
-        trace!("Received a message from {}: {:?}", src, cmd);
-        match cmd {
-            PaxosMsg::Prepare { ballot, holes } => self.handle_prepare(src, ballot, holes),
-            PaxosMsg::Promise { ballot, accepted } => self.handle_promise(src, ballot, accepted),
-            PaxosMsg::Propose { index, ballot, value } => self.handle_propose(src, index, ballot, value),
-            PaxosMsg::Accept { index, ballot } => self.handle_accept(src, index, ballot),
-            PaxosMsg::Learn { index, ballot, value } => self.handle_learn(index, ballot, value),
-            PaxosMsg::Nack { ballot } => self.handle_nack(ballot),
-            PaxosMsg::ClientRequest(value) => self.handle_client_request(value),
-        }
+    /// Makes this replica encode its persistent state with the given codec, instead of bincode.
+    /// Needs to be called before `with_state_file`, so that recovery uses the same codec.
+    pub fn with_storage_codec(mut self, codec: impl Codec<PersistentState<V>> + 'static) -> Self {
+        self.storage_codec = Arc::new(codec);
+        self
     }
 
-    /// Responds to a Paxos Prepare (1a) message.
-    /// Sends a Promise back to the sender iff this node has not yet made a Promise for a higher
-    /// ballot number.
-    fn handle_prepare(&mut self, src: usize, ballot: Ballot, holes: Vec<usize>) {
-        if ballot < self.highest_promised {
-            warn!("Prepare rejected: {:?}<{:?}", ballot, self.highest_promised);
-            self.node.send(src, &PaxosMsg::Nack { ballot });
-            return;
-        } else if self.leader_lease_start.elapsed().as_millis() < LEASE_DURATION
-            && src != self.current_leader
-        {
-            warn!("Prepare rejected: {:?} holds lease", self.current_leader);
-            self.node.send(src, &PaxosMsg::Nack { ballot });
-            return;
-        }
+    /// Makes this replica leave out of its state file which replicas accepted each entry.
+    /// These votes only matter to a leader counting them for its current ballot, and a replica
+    /// is never leader right after restarting, so it starts over with a new ballot anyway.
+    ///
+    /// Values which are accepted but not chosen yet are persisted nevertheless, with their
+    /// ballot: they might be chosen by a quorum this replica is part of, and a new leader only
+    /// learns about them from the Promises. Persisting just the promised ballot and the chosen
+    /// values would let a later leader replace such a value, breaking safety.
+    pub fn with_compact_storage(mut self) -> Self {
+        self.compact_storage = true;
+        self
+    }
 
-        debug!("Promise vote: {:?}", ballot);
-        self.highest_promised = ballot;
-        self.promises.clear();
-        self.current_leader = src;
-        self.leader_lease_start = Instant::now();
-        self.flush_to_disk();
+    /// Bounds the memory taken by the log: once entries are applied, all but the last `window`
+    /// of them are spilled to the indexed log file `filename`, keeping only their ballots in
+    /// memory. Proposing, accepting and applying only involve recent entries, which stay in
+    /// memory, while catching up a lagging replica reads older values back from disk.
+    /// Spilled commands are left out of `history` and can't be subscribed to anymore.
+    ///
+    /// The file only holds values which are chosen, so it is safe to share its lifetime with the
+    /// state file: needs to be called before `with_state_file`, so that recovery replays the
+    /// spilled entries. Without a state file, what is left of an earlier run is discarded.
+    /// Fails with `PaxosError::Io` if the file can't be opened.
+    pub fn with_log_archive(mut self, filename: &str, window: usize) -> Result<Self, PaxosError> {
+        let archive = LogFile::open(filename, BincodeCodec::default()).map_err(|e| {
+            PaxosError::Io(format!("failed to open log archive {}: {}", filename, e))
+        })?;
+        self.log_archive = Some((filename.to_owned(), window));
+        self.archive = Some(archive);
+        Ok(self)
+    }
 
-        // Fill `accepted` with all values this node has accepted and the sender
-        // of the Prepare has marked as not yet known to be chosen (in `holes`).
-        let mut accepted = Vec::new();
-        for (index, ballot, value) in self.get_accepted_values_iter() {
-            for hole in holes.iter().copied().chain(holes.last().unwrap() + 1..) {
-                if index < hole {
-                    break;
-                } else if hole < index {
-                    continue;
-                }
-                accepted.push((index, ballot, value.clone()));
-            }
+    /// Makes this replica use the given clock for all its leases and timeouts.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.leader_lease_start = clock.now();
+        self.last_election = clock.now();
+        self.epoch = clock.now();
+        self.heartbeat_sent = clock.now();
+        self.pending_accepts_since = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Makes this replica take the given role in its group.
+    /// A learner isn't part of the membership itself, only the voters given to the constructor.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        if role == Role::Learner {
+            let node_id = self.node_id;
+            self.initial_membership.current.retain(|&id| id != node_id);
         }
+        self
+    }
 
-        let promise = PaxosMsg::Promise { ballot, accepted };
-        self.node.send(src, &promise);
+    /// Makes the learners with the given IDs receive all chosen values from this replica,
+    /// once it becomes leader. They don't count toward any quorum.
+    pub fn with_learners(mut self, learners: &[NodeId]) -> Self {
+        self.node.discover(learners);
+        self
     }
 
-    /// Responds to a Paxos Promise (1b) message.
-    fn handle_promise(&mut self, src: usize, ballot: Ballot, accepted: Promise<V>) {
-        if ballot != self.highest_promised {
-            warn!("Promise ignored: {:?}!={:?}", ballot, self.highest_promised);
-            return;
+    /// Makes this replica send timestamps with its Prepare and Propose messages, which are echoed
+    /// back in the responses, to measure the round-trip times to its peers, see `metrics`.
+    /// It also measures how long entries take to be chosen and applied.
+    /// Timestamps are only ever compared by their sender, so clock skew doesn't matter,
+    /// and they don't influence the protocol in any way.
+    pub fn with_latency_tracking(mut self) -> Self {
+        self.track_latency = true;
+        self
+    }
+
+    /// Makes this replica report to the leader how far it applied the log, and lets it track
+    /// the reports of its followers as leader. This way clients can wait until a quorum has
+    /// applied their commands, not just until they are chosen, see `ReplicaStatus`.
+    /// All replicas of the group need to enable this for the leader's view to be complete.
+    pub fn with_apply_acks(mut self) -> Self {
+        self.track_applied = true;
+        self
+    }
+
+    /// Sets the upper bound for the time `tick` asks to wait until it is called again.
+    /// Shorter intervals reduce the delay for handling messages at the cost of more wakeups.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Makes this replica log a warning once the number of chosen entries which are not applied
+    /// yet reaches `threshold`, see `Metrics::apply_lag`. Entries pile up like this when the
+    /// state machine is slower than the group, as `tick` applies them for at most a poll
+    /// interval, to keep handling messages meanwhile.
+    pub fn with_apply_lag_threshold(mut self, threshold: usize) -> Self {
+        self.apply_lag_threshold = Some(threshold);
+        self
+    }
+
+    /// Makes this replica reject client requests with `PaxosError::Overloaded` while the apply
+    /// lag is at the threshold set by `with_apply_lag_threshold`, until its state machine
+    /// catches up. Without a threshold this has no effect.
+    pub fn with_backpressure(mut self) -> Self {
+        self.backpressure = true;
+        self
+    }
+
+    /// Limits the number of entries this replica proposes as leader, which are not chosen yet.
+    /// Further client requests are queued until entries are chosen, to not overrun followers.
+    pub fn with_proposal_window(mut self, size: usize) -> Self {
+        self.proposal_window = size.max(1);
+        self
+    }
+
+    /// Makes this replica spread chosen values with the given strategy, see `FanOut`.
+    /// All replicas of a group should use the same one.
+    pub fn with_fan_out(mut self, fan_out: FanOut) -> Self {
+        self.fan_out = fan_out;
+        self
+    }
+
+    /// Limits how far beyond the end of its log this replica accepts entries and snapshots.
+    /// Messages referring to indices further ahead are dropped, as they would make the log grow
+    /// by that many entries at once, e.g. if they are malformed. A replica which fell behind
+    /// its group by more than this, or joins a group whose log is longer, can't install the
+    /// group's snapshot until the limit is raised accordingly.
+    pub fn with_max_index_gap(mut self, gap: usize) -> Self {
+        self.max_index_gap = gap.max(1);
+        self
+    }
+
+    /// Calls `callback` with the old and new `LeaderState` and the new leader's ballot,
+    /// whenever this replica becomes leader, steps down, or follows another leader.
+    /// It is called once per change, not for every message confirming the current leader.
+    pub fn with_leader_change_callback(
+        mut self,
+        callback: impl FnMut(LeaderState, LeaderState, Ballot) + Send + 'static,
+    ) -> Self {
+        self.on_leader_change = Some(Box::new(callback));
+        self
+    }
+
+    /// Starts a thread watching this replica's main loop, which calls `on_stall` with the time
+    /// since the last tick once `tick` hasn't been called for longer than `deadline`, e.g.
+    /// because the state machine hangs on a command. Stalls are only reported, the replica is
+    /// left alone. As an idle replica ticks once per poll interval, the deadline needs to be
+    /// well above it.
+    pub fn with_watchdog(
+        mut self,
+        deadline: Duration,
+        on_stall: impl FnMut(Duration) + Send + 'static,
+    ) -> Self {
+        self.watchdog = Some(Watchdog::spawn(self.node_id, deadline, Box::new(on_stall)));
+        self
+    }
+
+    /// Makes this replica, as leader, send Proposes again for entries which are not chosen
+    /// `timeout` after they were proposed, up to `max_retries` times.
+    pub fn with_proposal_retry(mut self, timeout: Duration, max_retries: u32) -> Self {
+        self.proposal_timeout = timeout;
+        self.max_proposal_retries = max_retries;
+        self
+    }
+
+    /// Makes followers acknowledge each Learn of their leader, and this replica, as leader,
+    /// send Learns again to peers which haven't acknowledged them `timeout` after they were
+    /// sent, up to `max_retries` times. Thus a follower which missed a Learn gets it again
+    /// without waiting for the next election to catch up. Once the retries are used up, or the
+    /// value is no longer in memory, the follower is left to catch up as before.
+    /// All replicas of the group need to enable this, and it only applies to `FanOut::Direct`,
+    /// as gossiped Learns don't come from the leader.
+    pub fn with_learn_acks(mut self, timeout: Duration, max_retries: u32) -> Self {
+        self.learn_acks = Some((timeout, max_retries));
+        self
+    }
+
+    /// Makes the timing of this replica's elections deterministic, e.g. to know in tests which
+    /// replica becomes leader. Instead of waiting a random 100 to 200ms more than the lease,
+    /// replicas of rank `r` wait `(r + 1) * 10ms`. Thus the replica with the lowest rank starts
+    /// the first election, and wins it unless messages are delayed.
+    /// This only affects timing, so safety doesn't depend on the ranks being unique.
+    pub fn with_election_rank(mut self, rank: u32) -> Self {
+        self.election_rank = Some(rank);
+        self.random_timeout_offset = self.election_offset();
+        self
+    }
+
+    /// Makes this replica draw its random election timeouts and gossip targets from the given
+    /// generator, instead of one seeded by the operating system, e.g. from a seeded `StdRng` to
+    /// reproduce a test run. Replicas of a group should be seeded differently, as elections
+    /// take longer if they time out at the same moment.
+    pub fn with_rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = Box::new(rng);
+        self.random_timeout_offset = self.election_offset();
+        self
+    }
+
+    /// Makes this replica consult the policy on how eagerly it stands for election,
+    /// e.g. to have the group prefer a certain leader. This only affects timing, like ranks.
+    /// All replicas of a group should use the same policy.
+    pub fn with_leader_policy(mut self, policy: impl LeaderPolicy + 'static) -> Self {
+        self.leader_policy = Box::new(policy);
+        self.random_timeout_offset = self.election_offset();
+        self
+    }
+
+    /// Sets how long this replica waits at most for a quorum to answer its Prepares, when it runs
+    /// for leader. Until then, it doesn't start another round with a higher ballot while the
+    /// current one is still gathering Promises, unless a quorum rejects it. This keeps slow
+    /// replicas from driving up the ballots. Defaults to `PREPARE_ROUND_TIMEOUT`, while zero
+    /// makes the replica retry elections on its timer alone.
+    pub fn with_prepare_round_timeout(mut self, timeout: Duration) -> Self {
+        self.prepare_round_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of recently processed Prepare and Propose messages this replica remembers,
+    /// to ignore duplicates of them, e.g. delivered twice by the network. Zero disables this.
+    pub fn with_duplicate_window(mut self, size: usize) -> Self {
+        self.duplicate_window = size;
+        self
+    }
+
+    /// Makes this replica persist the values it accepts in groups: instead of writing its state
+    /// for every Propose before replying, it holds back the Accepts for up to `window`, then
+    /// persists its state once for all of them and only afterwards sends them. A burst of
+    /// Proposes thus costs a single write, at the expense of up to `window` of commit latency.
+    pub fn with_group_commit(mut self, window: Duration) -> Self {
+        self.group_commit = Some(window);
+        self
+    }
+
+    /// Keeps applied log entries around until all subscribers have acknowledged them, see
+    /// `Subscription::ack`, even once they are covered by a snapshot. Subscribers can thus
+    /// resume from their last acknowledged entry, e.g. after a restart of the consumer.
+    /// To bound the space this takes, a subscriber which leaves more than `max_entries` entries
+    /// unacknowledged is dropped, with its next `ack` failing with `PaxosError::SubscriberLagging`.
+    pub fn with_subscriber_retention(mut self, max_entries: usize) -> Self {
+        self.subscriber_retention = Some(max_entries);
+        self
+    }
+
+    /// Makes the leader skip the Heartbeat it periodically sends to a follower, if it sent that
+    /// follower a Propose or Learn within the last half lease duration, which refresh the lease
+    /// just as well. This saves traffic under load, while idle followers still get Heartbeats.
+    ///
+    /// Followers learn how far the leader has applied the log only from Heartbeats, however, so
+    /// that under load, `read_bounded` on followers may fail with `TooStale` more often.
+    pub fn with_heartbeat_suppression(mut self) -> Self {
+        self.suppress_heartbeats = true;
+        self
+    }
+
+    /// Makes this replica send at most `window` chosen values per CatchUp request, with which a
+    /// replica which fell behind asks for the values it missed. It asks for the next window only
+    /// once it has applied the previous one, so that catching up doesn't flood the network or
+    /// hold up the helping replica, no matter how far behind it is. All replicas of a group
+    /// should use the same window. Defaults to `CATCH_UP_WINDOW`.
+    pub fn with_catch_up_window(mut self, window: usize) -> Self {
+        self.catch_up_window = window.max(1);
+        self
+    }
+
+    /// Sets the number of clients whose applied requests this replica remembers, to skip
+    /// resubmissions of them. Beyond that, the least recently active clients are forgotten,
+    /// see `AppliedRequests`. All replicas of a group need to use the same capacity, as it
+    /// decides which resubmissions are applied. Defaults to `MAX_TRACKED_CLIENTS`.
+    pub fn with_tracked_clients(mut self, capacity: usize) -> Self {
+        self.max_tracked_clients = capacity;
+        self
+    }
+
+    /// Seeds the log with values which count as chosen already, e.g. a genesis state or data
+    /// migrated from another system. They fill the log entries `0..values.len()` and are applied
+    /// right away, so that the first value proposed afterwards follows them.
+    ///
+    /// Seeding isn't replicated: every replica treats the values as chosen without asking the
+    /// others, so all replicas of the group must be seeded with the same values, in the same
+    /// order. A replica which recovered a log from its state file was seeded before, and ignores
+    /// the values, so this must be called after `with_state_file`.
+    pub fn with_initial_log(mut self, values: Vec<V>) -> Self {
+        if !self.log.is_empty() {
+            info!("Log recovered, ignoring {} initial values.", values.len());
+            return self;
         }
+        self.log = values
+            .into_iter()
+            .map(|value| LogEntry {
+                value: Some(Command::App(value)),
+                chosen: true,
+                ..LogEntry::default()
+            })
+            .collect();
+        self.apply_chosen();
+        self.flush_to_disk();
+        self
+    }
 
-        debug!("Got a promise: {:?}, {:?}", ballot, accepted);
-        // TODO: do not overwrite newer promises (out of order messages)
-        self.promises.insert(src, (ballot, accepted));
+    /// Runs a single iteration of this Paxos replica's main loop, without blocking.
+    /// Handles all messages which are available right now, then any due timer-driven work.
+    ///
+    /// Returns how long the caller may wait before calling `tick` again.
+    /// This is the time until the next timer fires, but at most the poll interval.
+    pub fn tick(&mut self) -> Duration {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.beat();
+        }
 
-        // check that we don't count old promises
-        for (i, _) in self.promises.values() {
-            assert_eq!(*i, self.highest_promised);
+        // a group of one is permanently leader, without the need for a lease
+        if self.is_single_node() && !self.is_leader() {
+            info!("Single replica in the group: Starting election.");
+            self.start_election();
         }
 
-        if self.promises.len() == self.quorum {
-            info!("Got elected.");
-            self.current_leader = self.node_id;
-            self.leader_lease_start = Instant::now();
-
-            // adapt values in log based on accepted values in received Promise messages
-            for (_, accepted_values) in self.promises.values() {
-                for (index, ballot, value) in accepted_values {
-                    if self.log[*index].accepted_ballot < *ballot {
-                        trace!(
-                            "Using value from Promise: [{}] {:?}, {:?}",
-                            *index,
-                            *ballot,
-                            value
-                        );
-                        self.log[*index].value = Some(value.clone());
-                    }
+        // apply entries left over from the last tick, sharing the time budget with new ones
+        self.apply_deadline = Some(self.clock.now() + self.poll_interval);
+        self.apply_chosen();
+
+        // event loop for incoming messages
+        loop {
+            match self.node.try_recv() {
+                Ok((src, cmd)) => self.handle_paxos_message(src, cmd),
+                Err(RecvError::Timeout) => break,
+                // a single malformed message shouldn't hold up the ones queued behind it
+                Err(RecvError::Io(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    warn!("Dropping undecodable message: {}", e);
+                }
+                Err(RecvError::Io(e)) => {
+                    error!("Failed to receive messages: {}", e);
+                    break;
                 }
             }
+        }
+        self.finish_snapshot();
+        self.release_acknowledged();
+        self.run_timers();
+        self.apply_deadline = None;
+        if self.log.get(self.apply_index).is_some_and(|entry| entry.chosen) {
+            return Duration::from_secs(0);
+        }
+        self.next_timeout()
+    }
 
-            // send Propose messages for not yet chosen log entries
-            for (i, entry) in self
-                .log
-                .iter()
-                .enumerate()
-                .filter(|(_, entry)| !entry.chosen)
-            {
-                self.node.broadcast(&PaxosMsg::Propose {
-                    index: i,
-                    ballot,
-                    value: entry.value.clone().unwrap(),
-                });
-            }
+    /// The value is treated as a `ClientRequest` and handled accordingly.
+    /// Values which are too large to ever be proposed are rejected right away,
+    /// as are those the state machine fails to validate, see `ReplicatedStateMachine::validate`.
+    pub fn submit_value(&mut self, value: V) -> Result<(), PaxosError> {
+        self.submit_value_with_priority(value, DEFAULT_PRIORITY)
+    }
+
+    /// Submits the value like `submit_value`, but lets it overtake queued commands of lower
+    /// priority, e.g. to get a configuration change in ahead of bulk writes.
+    pub fn submit_value_with_priority(&mut self, value: V, priority: u8) -> Result<(), PaxosError> {
+        if self.drained.is_some() {
+            return Err(PaxosError::Draining);
         }
+        self.node.check_command_size(&value)?;
+        self.handle_client_request(Command::App(value), 0, priority, None)
     }
 
-    /// Responds to a Paxos Propose (2a) message.
-    fn handle_propose(&mut self, src: usize, index: usize, ballot: Ballot, value: V) {
-        if ballot < self.highest_promised {
-            warn!("Propose rejected: {:?}<{:?}", ballot, self.highest_promised);
-            self.node.send(src, &PaxosMsg::Nack { ballot });
-            return;
+    /// Submits the commands as a single log entry, so that the state machine applies them all
+    /// or none of them, see `ReplicatedStateMachine::execute_batch`.
+    /// The whole batch is rejected right away if any command fails to validate.
+    pub fn submit_batch(&mut self, cmds: Vec<V>) -> Result<(), PaxosError> {
+        if self.drained.is_some() {
+            return Err(PaxosError::Draining);
         }
+        self.node.check_batch_size(&cmds)?;
+        self.handle_client_request(Command::Batch(cmds), 0, DEFAULT_PRIORITY, None)
+    }
 
-        debug!("Propose accepted: {:?}", value);
-        self.current_leader = src;
-        while index >= self.log.len() {
-            self.log.push(LogEntry::default());
+    /// Stops accepting new commands, e.g. before taking this replica down for an upgrade.
+    /// Client requests are rejected from now on, so that clients retry with another replica,
+    /// but entries already in the log are still driven to being chosen and applied.
+    /// Unlike stepping down, this keeps leadership until all in-flight work is finished.
+    ///
+    /// Returns a flag, which is set once there are no more in-flight entries.
+    pub fn start_drain(&mut self) -> Arc<AtomicBool> {
+        info!("Draining: No longer accepting new commands.");
+        let drained = self
+            .drained
+            .get_or_insert_with(|| Arc::new(AtomicBool::new(false)))
+            .clone();
+        if !self.client_cmd_queue.is_empty() {
+            warn!("Dropping {} queued client requests.", self.client_cmd_queue.len());
+            self.client_cmd_queue.clear();
         }
-        self.node.send(src, &PaxosMsg::Accept { index, ballot });
-        self.log[index].value = Some(value);
-        self.log[index].accepted_ballot = ballot;
+        self.check_drained();
+        drained
     }
 
-    /// Responds to a Paxos Accept (2b) message.
-    fn handle_accept(&mut self, src: usize, index: usize, ballot: Ballot) {
-        if ballot != self.highest_promised {
-            warn!("Accept rejected: {:?}!={:?}", ballot, self.highest_promised);
-            return;
-        } else if self.log[index].acceptances.contains(&src) {
-            warn!("Duplicate Accept ignored: [{}] {}", index, src);
-            return;
+    /// Hands leadership over to `target`, e.g. before taking this replica down for maintenance,
+    /// which is quicker than letting the other replicas wait for the lease to run out.
+    /// This replica stops proposing right away and sends the target the chosen values it is
+    /// missing, until the target reports that it has applied all of them. Only then it tells
+    /// the target to start an election, dropping the queued client requests, so that clients
+    /// retry with the new leader. The values still in flight reach the target with the Promises
+    /// of its election.
+    ///
+    /// This replica stays leader until the target's Prepare arrives. If the target doesn't catch
+    /// up within a lease duration, e.g. because it crashed, the transfer is abandoned and this
+    /// replica resumes proposing.
+    pub fn transfer_leadership_to(&mut self, target: NodeId) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        } else if target == self.node_id || !self.latest_membership().nodes().any(|&n| n == target)
+        {
+            return Err(PaxosError::InvalidConfig(format!(
+                "can't transfer leadership to {}, which is no other member",
+                target
+            )));
         }
+        info!("Transferring leadership to {}, once it has caught up.", target);
+        self.transfer = Some((target, self.clock.now()));
+        self.continue_transfer();
+        Ok(())
+    }
 
-        self.log[index].acceptances.push(src);
-        if self.log[index].acceptances.len() == self.quorum {
-            debug!(
-                "Sending Learn with {}/{} acceptances.",
-                self.log[index].acceptances.len(),
-                self.quorum
-            );
-            let value = self.log[index].value.clone().unwrap();
-            info!("Value was chosen: [{}] {:?}, {:?}", index, ballot, value);
-            self.node.broadcast(&PaxosMsg::Learn { index, ballot, value });
-            self.log[index].chosen = true;
+    /// Overwrites the value of the log entry at `index` with `value`, if this replica is the
+    /// leader. This is meant for disaster recovery only, e.g. for an entry an operator found
+    /// to be stuck on a value which can't ever be chosen.
+    /// The leader runs another election with a ballot `FORCE_ROUNDS` higher, and then proposes
+    /// `value` for the entry, instead of the value accepted at the highest ballot.
+    ///
+    /// This is dangerous, as it breaks the rule that keeps Paxos safe: if the entry was chosen
+    /// without this replica knowing, replicas might end up applying different values for it.
+    /// The override is refused with `AlreadyChosen` if this replica knows the entry to be
+    /// chosen, and skipped if the Promises show that a quorum accepted the same value for it.
+    /// But a value chosen by a quorum other than the promising one goes unnoticed, so check
+    /// the entry on every replica before forcing it.
+    pub fn force_commit(&mut self, index: usize, value: V) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        } else if index < self.snapshot_index || self.log.get(index).is_some_and(|e| e.chosen) {
+            return Err(PaxosError::AlreadyChosen { index });
+        } else if !self.in_reach(index) {
+            return Err(PaxosError::InvalidConfig(format!(
+                "index {} is beyond the maximum index gap",
+                index
+            )));
         }
+        self.highest_promised.skip_rounds(FORCE_ROUNDS)?;
+        warn!("Forcing value into [{}]: {:?}", index, value);
+        self.forced = Some((index, Command::App(value)));
+        self.start_election();
+        Ok(())
     }
 
-    /// Handles a Learn message.
-    fn handle_learn(&mut self, index: usize, ballot: Ballot, value: V) {
-        info!("Learned: [{}] {:?}, {:?}", index, ballot, value);
-        while index >= self.log.len() {
-            self.log.push(LogEntry::default());
+    /// Blocks until a message arrives or the timeout has passed, whichever happens first.
+    /// Calling it with the timeout returned by `tick` lets a replica sleep while it is idle,
+    /// but still react to incoming messages right away.
+    pub fn wait(&self, timeout: Duration) {
+        self.node.wait(timeout);
+    }
+
+    /// Starts changing the group's membership to `members`, if this replica is the leader.
+    /// The change is performed by joint consensus, i.e. the group first moves to a joint
+    /// configuration of old and new members and then to the new members alone.
+    pub fn reconfigure(&mut self, mut members: Vec<NodeId>) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        } else if self.drained.is_some() {
+            return Err(PaxosError::Draining);
         }
-        self.log[index].value = Some(value);
-        self.log[index].accepted_ballot = ballot;
-        self.log[index].chosen = true;
-        self.flush_to_disk();
+        let in_progress = match self.configs.iter().next_back() {
+            Some((&index, membership)) => membership.next.is_some() || !self.log[index].chosen,
+            None => false,
+        };
+        if in_progress {
+            return Err(PaxosError::ReconfigurationInProgress);
+        }
+        members.sort_unstable();
+        members.dedup();
+        let joint = Membership {
+            current: self.latest_membership().current.clone(),
+            next: Some(members),
+        };
+        info!("Starting reconfiguration: {:?}", joint);
+        self.propose(Command::Reconfigure(joint));
+        Ok(())
     }
 
-    /// Handles a negative acknowledgement message.
-    fn handle_nack(&mut self, ballot: Ballot) {
-        warn!("Received a NACK.");
-        self.random_timeout_offset = 2 * Duration::from_millis(thread_rng().gen_range(100..=200));
-        // TODO: clean state for request
+    /// Adds the replica `node_id` to the group as a learner, if this replica is the leader.
+    /// The learner is sent the chosen values it missed, and all further ones, but doesn't vote,
+    /// so that adding it doesn't change any quorum. Once it has caught up, `promote` makes it a
+    /// voter. The replica itself has to be started with `Role::Learner`.
+    /// Only this leader knows about the learner, after a leader change it has to be added again.
+    pub fn add_learner(&mut self, node_id: NodeId) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        } else if self.latest_membership().nodes().any(|&id| id == node_id) {
+            let reason = format!("{} is a member already", node_id);
+            return Err(PaxosError::InvalidConfig(reason));
+        }
+        info!("Adding learner {}.", node_id);
+        self.node.discover(&[node_id]);
+        self.learners.insert(node_id, 0);
+        self.handle_catch_up(node_id, 0);
+        Ok(())
     }
 
-    /// Handles a client request directly if this replica believes itself to be the leader.
-    /// Relays the request to the (replica we believe to be the) current leader otherwise.
-    fn handle_client_request(&mut self, cmd: V) {
-        if self.node_id == self.current_leader {
-            debug!("Handling client request: {:?}", cmd);
-            let value = cmd;
-            self.log.push(LogEntry::new(value.clone()));
-            self.node.broadcast(&PaxosMsg::Propose {
-                index: self.log.len() - 1,
-                ballot: self.highest_promised,
-                value,
-            });
-        } else {
-            // TODO: is relaying to leader safe? (esp. if our current_leader value is wrong)
-            trace!("Received a client request, relaying to leader: {:?}", cmd);
-            if !self
-                .node
-                .send(self.current_leader, &PaxosMsg::ClientRequest(cmd.clone()))
-            {
-                error!("Relaying command to leader failed.");
-                self.client_cmd_queue.push(cmd);
+    /// Starts reconfiguring the group to include the learner `node_id` as a voter, once it has
+    /// applied all but at most `MAX_LEARNER_LAG` of the entries this leader has applied.
+    /// Learners report their progress in response to Heartbeats, so this fails with
+    /// `LearnerBehind` until one confirms that the learner has caught up.
+    /// The learner becomes a voter as soon as it learns of the new membership.
+    pub fn promote(&mut self, node_id: NodeId) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        }
+        let applied = match self.learners.get(&node_id) {
+            Some(&applied) => applied,
+            None => {
+                let reason = format!("{} wasn't added as learner", node_id);
+                return Err(PaxosError::InvalidConfig(reason));
             }
+        };
+        if applied + MAX_LEARNER_LAG < self.apply_index {
+            let leader = self.apply_index;
+            return Err(PaxosError::LearnerBehind { applied, leader });
         }
+        let mut members = self.latest_membership().current.clone();
+        members.push(node_id);
+        self.reconfigure(members)?;
+        self.learners.remove(&node_id);
+        Ok(())
     }
 
-    /// Initiates a new election, i.e. a single Prepare/Promise sequence for the whole log.
-    fn start_election(&mut self) {
-        self.highest_promised.increment_for(self.node_id);
-        let accepted_values = self
-            .get_accepted_values_iter()
-            .map(|(index, ballot, value)| (index, ballot, value.clone()))
-            .collect();
-        self.promises.clear();
-        self.promises
-            .insert(self.node_id, (self.highest_promised, accepted_values));
+    /// Starts reconfiguring the group to turn the voter `node_id` into a learner, if this replica
+    /// is the leader, e.g. to shrink the quorums without losing that replica's copy of the log.
+    /// The demoted replica votes until the new membership is chosen, and stops voting and
+    /// standing for election once it has applied it, but keeps applying chosen values.
+    /// Like other learners, it can be promoted again. To demote the leader itself, transfer
+    /// leadership to another voter first.
+    pub fn demote(&mut self, node_id: NodeId) -> Result<(), PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        } else if node_id == self.node_id {
+            let reason = format!("can't demote {}, which is the leader", node_id);
+            return Err(PaxosError::InvalidConfig(reason));
+        }
+        let mut members = self.latest_membership().current.clone();
+        if !members.contains(&node_id) {
+            let reason = format!("{} isn't a voter", node_id);
+            return Err(PaxosError::InvalidConfig(reason));
+        }
+        members.retain(|&id| id != node_id);
+        self.reconfigure(members)?;
+        let applied = self.applied.get(&node_id).copied().unwrap_or(0);
+        self.learners.insert(node_id, applied);
+        Ok(())
+    }
 
-        // create a list of all values we are still missing in our log
-        let mut holes: Vec<usize> = (&self.log)
+    /// Executes a read-only command against the state machine, guaranteeing that it observes
+    /// all values chosen before this call, without relying on the leader's lease.
+    ///
+    /// This uses the ReadIndex technique: the leader notes the end of its log, confirms that it
+    /// is still leader by exchanging heartbeats with a quorum, and waits until its state machine
+    /// has caught up to the noted index before executing the command. A newly elected leader
+    /// also waits until the NoOp it proposed upon its election is applied.
+    /// Meanwhile, messages are handled as usual. Only the leader can serve these reads.
+    pub fn linearizable_read(&mut self, cmd: V) -> Result<String, PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        }
+        let read_index = self
+            .log
             .iter()
-            .enumerate()
-            .filter(|(_, entry)| !entry.chosen)
-            .map(|(index, _)| index)
-            .collect();
-        holes.push(self.log.len());
-        debug!("Missing values: {:?}", holes);
+            .rposition(|entry| entry.chosen)
+            .map_or(0, |index| index + 1)
+            .max(self.ready_index + 1);
+        let deadline = self.clock.now() + LEASE_DURATION;
+        self.send_heartbeats();
+        // entries left over by `tick` are applied right away, as the read waits for them anyway
+        self.apply_chosen();
 
-        self.node.broadcast(&PaxosMsg::Prepare {
-            ballot: self.highest_promised,
-            holes,
-        });
+        while !self.latest_membership().is_quorum(self.heartbeat_acks.iter())
+            || self.apply_index < read_index
+        {
+            let now = self.clock.now();
+            if now >= deadline {
+                warn!("Linearizable read timed out.");
+                return Err(PaxosError::Timeout);
+            }
+            if let Ok((src, msg)) = self.node.recv((deadline - now).min(self.next_timeout())) {
+                self.handle_paxos_message(src, msg);
+            }
+            self.run_timers();
+            if !self.is_leader() {
+                return Err(PaxosError::NotLeader);
+            }
+        }
+
+        debug!("Serving linearizable read at index {}: {:?}", read_index, cmd);
+        self.state_machine
+            .query(&cmd)
+            .map_err(|()| PaxosError::CommandFailed)
     }
 
-    /// Save all persistent state for this replica to disk, or die if it doesn't work.
-    fn flush_to_disk(&self) {
-        store_in_disk_file("log.bin", &self.log).unwrap();
-        // TODO: flush other relevant information (e.g. highest Ballot)
+    /// Executes the read-only command on the local state machine, like `linearizable_read`,
+    /// but relies on the leader's lease instead of exchanging messages with the other replicas.
+    /// The lease is only valid while a quorum has confirmed this replica's leadership within the
+    /// last lease duration, since only then no other replica can have been elected meanwhile.
+    /// A leader cut off from the majority thus refuses to serve possibly stale reads with
+    /// `LeaseExpired`, even though it still believes itself to be leader.
+    /// A newly elected leader refuses reads with `NotReady`, until the NoOp it proposed upon its
+    /// election is applied, as only then it knows how far the log is chosen.
+    pub fn read_local(&mut self, cmd: V) -> Result<String, PaxosError> {
+        if !self.is_leader() {
+            return Err(PaxosError::NotLeader);
+        }
+        if !self.has_quorum_contact() {
+            warn!("Local read refused, no recent contact with a quorum.");
+            return Err(PaxosError::LeaseExpired);
+        }
+        self.apply_chosen();
+        if self.apply_index <= self.ready_index {
+            debug!("Local read deferred, NoOp [{}] not applied yet.", self.ready_index);
+            return Err(PaxosError::NotReady);
+        }
+        self.state_machine
+            .query(&cmd)
+            .map_err(|()| PaxosError::CommandFailed)
     }
 
-    /// Recover this replica's state from what it previously saved to disk.
-    fn recover_from_disk(&mut self) {
-        self.log = load_from_disk_file("log.bin").unwrap();
-        // TODO: load other relevant information (e.g. highest Ballot)
+    /// Executes the read-only command on the local state machine of any replica, as long as its
+    /// state is at most `max_staleness` older than the leader's, e.g. to scale out reads.
+    ///
+    /// Followers are as fresh as the leader at the last Heartbeat they received, once they have
+    /// applied as many entries as the leader reported in it. The delay of the Heartbeat itself
+    /// is not accounted for. The leader is as fresh as its last contact with a quorum, once it
+    /// applied the NoOp proposed upon its election.
+    /// Otherwise `TooStale` is returned, and the caller may retry on a fresher replica.
+    pub fn read_bounded(&mut self, cmd: V, max_staleness: Duration) -> Result<String, PaxosError> {
+        self.apply_chosen();
+        let fresh_at = if self.is_single_node() {
+            Some(self.clock.now())
+        } else if self.is_leader() {
+            self.quorum_contact.filter(|_| self.apply_index > self.ready_index)
+        } else {
+            self.caught_up_at
+        };
+        if fresh_at.is_none_or(|at| self.clock.elapsed(at) > max_staleness) {
+            debug!("Bounded read refused, last caught up at {:?}.", fresh_at);
+            return Err(PaxosError::TooStale);
+        }
+        self.state_machine
+            .query(&cmd)
+            .map_err(|()| PaxosError::CommandFailed)
+    }
+
+    /// The ID identifying this replica.
+    pub fn id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// The number of entries at the start of the log which have been chosen and applied.
+    pub fn committed_len(&self) -> usize {
+        self.apply_index
     }
 
-    fn get_accepted_values_iter(&self) -> impl Iterator<Item = (usize, Ballot, &V)> {
-        (&self.log)
+    /// Blocks until this replica has applied the log entry at `index`, or fails with
+    /// `PaxosError::Timeout` if that takes longer than `timeout`.
+    /// The replica only applies entries while it is driven, e.g. by `tick`, so that this can only
+    /// succeed for entries applied already. Wait through `apply_watch` from other threads instead.
+    pub fn wait_for_apply(&self, index: usize, timeout: Duration) -> Result<(), PaxosError> {
+        self.apply_watch.wait_for_apply(index, timeout)
+    }
+
+    /// A handle for other threads to wait until this replica has applied specific log entries,
+    /// e.g. while the replica runs on a `ReplicaHandle`.
+    pub fn apply_watch(&self) -> ApplyWatch {
+        self.apply_watch.clone()
+    }
+
+    /// Iterates over all commands applied to the state machine, together with their log index,
+    /// in the order they were applied. Entries covered by a snapshot or spilled to the log
+    /// archive are not included.
+    /// The commands of a batch share its index, and are included even if it was rolled back.
+    /// Resubmissions of a tracked command are skipped, as they were when applying them.
+    pub fn history(&self) -> impl Iterator<Item = (usize, &V)> {
+        let mut requests = self
+            .snapshot
+            .as_ref()
+            .map_or_else(AppliedRequests::new, |snapshot| snapshot.requests.clone());
+        let capacity = self.max_tracked_clients;
+        self.log[..self.apply_index]
             .iter()
             .enumerate()
-            .filter(|(_, i)| i.value.is_some())
-            .map(|(index, entry)| (index, entry.accepted_ballot, entry.value.as_ref().unwrap()))
+            .skip(self.snapshot_index)
+            .filter(move |(_, entry)| {
+                let value = entry.value.as_ref();
+                value.is_none_or(|value| record_request(&mut requests, capacity, value))
+            })
+            .flat_map(|(index, entry)| {
+                let cmds = match &entry.value {
+                    Some(Command::App(cmd)) => std::slice::from_ref(cmd),
+                    Some(Command::Batch(cmds)) => cmds.as_slice(),
+                    Some(Command::Tracked(_, cmd)) => std::slice::from_ref(cmd),
+                    Some(Command::Expiring { value: cmd, .. }) => std::slice::from_ref(cmd),
+                    _ => &[],
+                };
+                cmds.iter().map(move |cmd| (index, cmd))
+            })
+    }
+
+    /// Streams every command applied to the state machine from log index `from` onwards,
+    /// together with its index and result, e.g. to copy changes into an external system.
+    /// Commands which are applied already are replayed first, followed by the others as they are
+    /// applied. The commands of a batch share its index, while NoOps, membership changes and
+    /// resubmissions of tracked commands are left out, as they don't change the state machine.
+    ///
+    /// Fails with `PaxosError::Truncated` if `from` is covered by the snapshot, as those
+    /// commands are no longer retained, unless kept for other subscribers, see
+    /// `with_subscriber_retention`. Subscribing from the snapshot's index yields the rest.
+    /// Likewise, if this replica falls behind and installs another replica's snapshot, the
+    /// commands it covers are skipped.
+    pub fn subscribe(&mut self, from: usize) -> Result<Subscription<V>, PaxosError> {
+        let retained_index = self.retained_index.max(self.spilled_index);
+        if from < retained_index {
+            return Err(PaxosError::Truncated {
+                snapshot_index: retained_index,
+            });
+        }
+        let (sender, receiver) = mpsc::channel();
+        let state = Arc::new(SubscriberState::default());
+        state.unacked.store(from, Ordering::Relaxed);
+        let mut subscribers = vec![(sender, state.clone())];
+        for (&index, results) in self.applied_results.range(from..) {
+            if let Some(value) = &self.log[index].value {
+                publish(&mut subscribers, self.subscriber_retention, index, value, results);
+            }
+        }
+        self.subscribers.append(&mut subscribers);
+        Ok(Subscription { receiver, state })
+    }
+
+    /// Passes every command applied to the state machine to `sink` on a separate thread, so that
+    /// the sink doesn't hold up the replica. The sink receives the commands in order, starting at
+    /// its `resume_index`, and acknowledges them once processed, see `subscribe`.
+    /// More sinks can be added, each getting its own thread.
+    ///
+    /// The thread ends once the replica is dropped or drops the sink's subscription, see
+    /// `with_subscriber_retention`, and its handle returns the sink then. Fails like `subscribe`
+    /// if the commands from the sink's `resume_index` onwards are no longer retained.
+    pub fn add_sink<S>(&mut self, mut sink: S) -> Result<JoinHandle<S>, PaxosError>
+    where
+        S: CommitSink<V> + Send + 'static,
+    {
+        let subscription = self.subscribe(sink.resume_index())?;
+        Ok(thread::spawn(move || {
+            let mut processing = None;
+            for (index, cmd, result) in subscription.iter() {
+                // acknowledge an entry once all commands of the batch it may be are processed
+                if let Some(done) = processing.filter(|&done| done < index) {
+                    if subscription.ack(done).is_err() {
+                        break;
+                    }
+                }
+                sink.on_commit(index, &cmd, &result);
+                processing = Some(index);
+            }
+            sink
+        }))
+    }
+
+    /// Replaces all applied log entries with a snapshot of the state machine, to save space.
+    /// Replicas which missed some of these entries receive the snapshot instead.
+    /// Returns the new `snapshot_index`.
+    ///
+    /// The snapshot is sent in a single message, so the log is kept and `SnapshotTooLarge`
+    /// returned if it doesn't fit, as replicas behind it couldn't catch up otherwise.
+    pub fn take_snapshot(&mut self) -> Result<usize, PaxosError> {
+        let data = self
+            .state_machine
+            .snapshot()
+            .ok_or(PaxosError::SnapshotUnsupported)?;
+        info!("Taking snapshot at index {}.", self.apply_index);
+        let snapshot = Snapshot {
+            membership: self.membership_for(self.apply_index).clone(),
+            data,
+            requests: self.applied_requests.clone(),
+        };
+        self.node.check_snapshot_size(&snapshot)?;
+        self.install_snapshot(self.apply_index, snapshot);
+        Ok(self.snapshot_index)
+    }
+
+    /// Like `take_snapshot`, but writes the snapshot on a separate thread, so that commands
+    /// keep being applied meanwhile. The snapshot covers all entries applied so far, and
+    /// replaces them during the first `tick` after it has been written, unless it is too large
+    /// to be sent, in which case it is discarded and the failure logged.
+    /// Returns the `snapshot_index` the snapshot will have.
+    pub fn start_snapshot(&mut self) -> Result<usize, PaxosError> {
+        if self.pending_snapshot.is_some() {
+            return Err(PaxosError::SnapshotInProgress);
+        }
+        let writer = self
+            .state_machine
+            .snapshot_writer()
+            .ok_or(PaxosError::SnapshotUnsupported)?;
+        info!("Starting snapshot at index {}.", self.apply_index);
+        let membership = self.membership_for(self.apply_index).clone();
+        let requests = self.applied_requests.clone();
+        let writer = thread::spawn(writer);
+        self.pending_snapshot = Some((self.apply_index, membership, requests, writer));
+        Ok(self.apply_index)
+    }
+
+    /// Writes the complete log to the file at `path` for offline analysis, including entries which
+    /// aren't chosen and the acceptances collected for them, see `LogExport`.
+    /// Unlike the persisted state, the export is meant for people and tools, not for recovery.
+    pub fn export_log(&self, path: &str) -> Result<(), PaxosError> {
+        let export = LogExport {
+            node_id: self.node_id,
+            promised_ballot: self.highest_promised,
+            snapshot_index: self.snapshot_index,
+            apply_index: self.apply_index,
+            log: self.log.clone(),
+        };
+        export
+            .store(path)
+            .map_err(|e| PaxosError::Io(format!("failed to export log: {}", e)))
+    }
+
+    /// Shuts this replica down for good: waits for a snapshot still being written, persists the
+    /// final state and releases the network node, so that e.g. a restarted replica can bind the
+    /// same UDP port right away.
+    /// Fails with `PaxosError::Io` if the state couldn't be persisted, instead of panicking like
+    /// the replica does when that happens while it is running.
+    pub fn close(mut self) -> Result<(), PaxosError> {
+        while self.pending_snapshot.is_some() {
+            thread::sleep(Duration::from_millis(1));
+            self.finish_snapshot();
+        }
+        info!("Closing replica {}.", self.node_id);
+        self.try_flush_to_disk()
+            .map_err(|e| PaxosError::Io(format!("failed to persist state: {}", e)))
+    }
+
+    /// The measurements this replica collected so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// What this replica believes about the leadership of its group.
+    pub fn leader_state(&self) -> LeaderState {
+        match self.current_leader {
+            None => LeaderState::Unknown,
+            Some(leader) if leader == self.node_id => LeaderState::Leader,
+            Some(leader) => LeaderState::Follower(leader),
+        }
+    }
+
+    /// Whether the peer `node_id` is suspected to be gone, as the last broadcast couldn't be sent
+    /// to it, e.g. because its node was shut down, and it hasn't sent any message since.
+    pub fn is_suspect(&self, node_id: NodeId) -> bool {
+        self.suspects.contains(&node_id)
+    }
+
+    /// Whether this replica believes itself to be the current leader.
+    pub fn is_leader(&self) -> bool {
+        self.current_leader == Some(self.node_id)
+    }
+
+    /// Processes a single message as if it was received from `from`, without involving the
+    /// network. This lets tests drive precise scenarios, e.g. deliver a crafted Promise.
+    #[cfg(any(test, feature = "test-hooks"))]
+    pub fn handle_message(&mut self, from: NodeId, msg: PaxosMsg<V>) {
+        self.handle_paxos_message(from, msg);
+    }
+
+    /// Parses the message and calls the method corresponding to the message type.
+    fn handle_paxos_message(&mut self, src: NodeId, cmd: PaxosMsg<V>) {
+        trace!("Received a message from {}: {:?}", src, cmd);
+        self.detect_id_conflict(src, &cmd);
+        if self.suspects.remove(&src) {
+            info!("Heard from suspected peer {} again.", src);
+        }
+        match cmd {
+            PaxosMsg::Prepare { ballot, holes, sent_at } => {
+                self.handle_prepare(src, ballot, holes, sent_at)
+            }
+            PaxosMsg::Promise { ballot, accepted, sent_at } => {
+                self.record_round_trip(src, sent_at);
+                self.handle_promise(src, ballot, accepted)
+            }
+            PaxosMsg::Propose { index, ballot, value, sent_at } => {
+                self.handle_propose(src, index, ballot, value, sent_at)
+            }
+            PaxosMsg::Accept { index, ballot, sent_at } => {
+                self.record_round_trip(src, sent_at);
+                self.handle_accept(src, index, ballot)
+            }
+            PaxosMsg::Learn { index, ballot, value } => {
+                self.handle_learn(src, index, ballot, value)
+            }
+            PaxosMsg::LearnAck { index } => self.handle_learn_ack(src, index),
+            PaxosMsg::CatchUp { from } => self.handle_catch_up_request(src, from),
+            PaxosMsg::InstallSnapshot {
+                snapshot_index,
+                membership,
+                data,
+                requests,
+            } => {
+                let snapshot = Snapshot {
+                    membership,
+                    data,
+                    requests,
+                };
+                self.handle_install_snapshot(snapshot_index, snapshot)
+            }
+            PaxosMsg::Nack { ballot, promised } => self.handle_nack(src, ballot, promised),
+            PaxosMsg::Heartbeat {
+                ballot,
+                seq,
+                applied,
+            } => self.handle_heartbeat(src, ballot, seq, applied),
+            PaxosMsg::HeartbeatAck { ballot, seq } => self.handle_heartbeat_ack(src, ballot, seq),
+            PaxosMsg::TimeoutNow { ballot, target } => self.handle_timeout_now(src, ballot, target),
+            PaxosMsg::Applied { index } => self.handle_applied(src, index),
+            PaxosMsg::ClientRequest {
+                value,
+                hops,
+                priority,
+                deadline,
+            } => {
+                let cmd = match deadline {
+                    Some(deadline) => Command::Expiring { deadline, value },
+                    None => Command::App(value),
+                };
+                self.handle_client_msg(src, cmd, hops, priority, None)
+            }
+            PaxosMsg::ClientBatch {
+                values,
+                hops,
+                priority,
+            } => self.handle_client_msg(src, Command::Batch(values), hops, priority, None),
+            PaxosMsg::TrackedRequest {
+                id,
+                value,
+                hops,
+                priority,
+                origin,
+            } => {
+                // only relayed requests carry their origin, clients can't name another one
+                let origin = origin.filter(|_| hops > 0).unwrap_or(src);
+                let cmd = Command::Tracked(id, value);
+                self.handle_client_msg(src, cmd, hops, priority, Some(origin))
+            }
+            PaxosMsg::NotLeader { .. } => warn!("Unexpected NotLeader from {}", src),
+            PaxosMsg::StatusRequest => self.handle_status_request(src),
+            PaxosMsg::StatusReply(status) => self.handle_transfer_status(src, status),
+            PaxosMsg::StateDumpRequest => self.handle_state_dump_request(src),
+            PaxosMsg::HealthProbe { prober, hops } => self.handle_health_probe(prober, hops),
+            PaxosMsg::HealthOk { .. } => warn!("Unexpected HealthOk from {}", src),
+            PaxosMsg::StateDumpReply(_) => warn!("Unexpected StateDumpReply from {}", src),
+            PaxosMsg::RequestStatus(id) => self.handle_request_status(src, id),
+            PaxosMsg::RequestStatusReply { .. } => {
+                warn!("Unexpected RequestStatusReply from {}", src)
+            }
+            PaxosMsg::RequestApplied { .. } => warn!("Unexpected RequestApplied from {}", src),
+            PaxosMsg::Rejected { .. } => warn!("Unexpected Rejected from {}", src),
+            PaxosMsg::CancelRequest(id) => self.handle_cancel_request(src, id),
+        }
+    }
+
+    /// Counts the message in `Metrics::id_conflicts` if it shows that another replica uses this
+    /// replica's ID: either it was sent from this ID, or it is a Prepare or Propose for a ballot
+    /// of this ID, which only this replica itself could have generated.
+    fn detect_id_conflict(&mut self, src: NodeId, msg: &PaxosMsg<V>) {
+        let ballot = match msg {
+            PaxosMsg::Prepare { ballot, .. } | PaxosMsg::Propose { ballot, .. } => Some(ballot),
+            _ => None,
+        };
+        if src != self.node_id && ballot.is_none_or(|ballot| ballot.node() != self.node_id) {
+            return;
+        }
+        if self.metrics.id_conflicts == 0 {
+            error!(
+                "Message from {} implies that another replica uses ID {} as well: {:?}",
+                src, self.node_id, msg
+            );
+        }
+        self.metrics.id_conflicts += 1;
+    }
+
+    /// Responds to a Paxos Prepare (1a) message.
+    /// Sends a Promise back to the sender iff this node has not yet made a Promise for a higher
+    /// ballot number.
+    fn handle_prepare(
+        &mut self,
+        src: NodeId,
+        ballot: Ballot,
+        mut holes: Vec<usize>,
+        sent_at: Option<Timestamp>,
+    ) {
+        // the sender has chosen all values before its first hole
+        let chosen_until = holes.iter().copied().min().unwrap_or(0);
+        if self.role == Role::Learner {
+            // learners don't vote, but follow the candidate and catch up with it
+            self.set_leader(src, ballot);
+            self.request_catch_up(src, chosen_until);
+            return;
+        } else if self.recent_messages.contains(&(None, ballot)) {
+            trace!("Duplicate Prepare ignored: {:?}", ballot);
+            return;
+        } else if ballot < self.highest_promised {
+            warn!("Prepare rejected: {:?}<{:?}", ballot, self.highest_promised);
+            self.node.send(src, &self.nack(ballot));
+            return;
+        } else if self.clock.elapsed(self.leader_lease_start) < LEASE_DURATION
+            && self.current_leader.is_some_and(|leader| leader != src)
+            && self.handover != Some(src)
+        {
+            warn!("Prepare rejected: {:?} holds lease", self.current_leader);
+            self.node.send(src, &self.nack(ballot));
+            if ballot > self.highest_promised {
+                self.candidate_seen = true;
+            }
+            return;
+        }
+
+        debug!("Promise vote: {:?}", ballot);
+        self.remember_message((None, ballot));
+        self.highest_promised = ballot;
+        self.promises.clear();
+        self.set_leader(src, ballot);
+        self.leader_lease_start = self.clock.now();
+        self.flush_to_disk();
+
+        // Fill `accepted` with all values this node has accepted and the sender
+        // of the Prepare has marked as not yet known to be chosen (in `holes`).
+        // Values spilled to the log archive are read back from disk.
+        holes.sort_unstable();
+        let tail = holes.last().copied().unwrap_or(0);
+        let mut accepted: Vec<_> = holes
+            .iter()
+            .filter(|&&index| index < self.spilled_index)
+            .filter_map(|&index| {
+                let entry = self.read_spilled(index..index + 1).pop()?;
+                Some((index, entry.accepted_ballot, entry.value?))
+            })
+            .collect();
+        accepted.extend(
+            self.get_accepted_values_iter()
+                .filter(|(index, _, _)| *index >= tail || holes.binary_search(index).is_ok())
+                .map(|(index, ballot, value)| (index, ballot, value.clone())),
+        );
+
+        let promise = PaxosMsg::Promise {
+            ballot,
+            accepted,
+            sent_at,
+        };
+        self.node.send(src, &promise);
+
+        // ask the sender for the chosen values this replica is missing
+        self.request_catch_up(src, chosen_until);
+    }
+
+    /// Asks `src` for the values this replica is missing, if `src` has chosen all values
+    /// before index `chosen_until`.
+    fn request_catch_up(&mut self, src: NodeId, chosen_until: usize) {
+        if self.apply_index < chosen_until {
+            debug!("Catching up from {} to {}.", self.apply_index, chosen_until);
+            self.catching_up = Some((src, chosen_until, self.apply_index, self.clock.now()));
+            self.request_catch_up_batch();
+        }
+    }
+
+    /// Asks the replica this one is catching up from for the next window of values.
+    fn request_catch_up_batch(&mut self) {
+        if let Some((helper, _, from, requested)) = &mut self.catching_up {
+            *from = self.apply_index;
+            *requested = self.clock.now();
+            self.node.send(*helper, &PaxosMsg::CatchUp { from: *from });
+        }
+    }
+
+    /// Asks for the next window of values once the last one has been applied, until all values
+    /// chosen by the helping replica have been.
+    fn continue_catch_up(&mut self) {
+        match self.catching_up {
+            Some((_, chosen_until, ..)) if self.apply_index >= chosen_until => {
+                debug!("Caught up to {}.", chosen_until);
+                self.catching_up = None;
+            }
+            Some((_, _, from, _)) if self.apply_index >= from + self.catch_up_window => {
+                self.request_catch_up_batch();
+            }
+            _ => {}
+        }
+    }
+
+    /// Asks again for the values still missing, if the last window hasn't fully arrived in time.
+    /// Gives up if none of it arrived, until the next Prepare or Heartbeat shows what's missing.
+    fn retry_catch_up(&mut self) {
+        match self.catching_up {
+            Some((.., requested)) if self.clock.elapsed(requested) < CATCH_UP_TIMEOUT => {}
+            Some((helper, _, from, _)) if self.apply_index == from => {
+                debug!("No values received from {}, no longer catching up.", helper);
+                self.catching_up = None;
+            }
+            Some(_) => self.request_catch_up_batch(),
+            None => {}
+        }
+    }
+
+    /// Responds to a Paxos Promise (1b) message.
+    fn handle_promise(&mut self, src: NodeId, ballot: Ballot, accepted: Promise<V>) {
+        if ballot != self.highest_promised {
+            warn!("Promise ignored: {:?}!={:?}", ballot, self.highest_promised);
+            return;
+        } else if let Some((index, _, _)) = accepted.iter().find(|(i, _, _)| !self.in_reach(*i)) {
+            warn!("Promise from {} ignored, index out of reach: [{}]", src, index);
+            return;
+        }
+
+        debug!("Got a promise: {:?}, {:?}", ballot, accepted);
+        // TODO: do not overwrite newer promises (out of order messages)
+        self.promises.insert(src, (ballot, accepted));
+
+        // check that we don't count old promises
+        for (i, _) in self.promises.values() {
+            assert_eq!(*i, self.highest_promised);
+        }
+
+        self.check_elected();
+    }
+
+    /// Responds to a Paxos Propose (2a) message.
+    fn handle_propose(
+        &mut self,
+        src: NodeId,
+        index: usize,
+        ballot: Ballot,
+        value: Command<V>,
+        sent_at: Option<Timestamp>,
+    ) {
+        if self.role == Role::Learner {
+            trace!("Learner ignored Propose: [{}] {:?}", index, ballot);
+            return;
+        } else if index < self.snapshot_index {
+            trace!("Propose for entry covered by snapshot ignored: [{}]", index);
+            return;
+        } else if self.conflicts_with_accepted(index, ballot, &value) {
+            // a ballot proposes one value per entry, so this can only be a faulty leader
+            error!("Propose ignored, another value accepted with {:?}: [{}]", ballot, index);
+            return;
+        } else if self.is_accept_pending(index, ballot) {
+            trace!("Duplicate Propose, Accept pending: [{}] {:?}", index, ballot);
+            return;
+        } else if self.recent_messages.contains(&(Some(index), ballot)) {
+            // the Accept might have been lost, so acknowledge again without redoing the work
+            trace!("Duplicate Propose: [{}] {:?}", index, ballot);
+            self.node.send(src, &PaxosMsg::Accept { index, ballot, sent_at });
+            return;
+        } else if ballot < self.highest_promised {
+            warn!("Propose rejected: {:?}<{:?}", ballot, self.highest_promised);
+            self.node.send(src, &self.nack(ballot));
+            return;
+        } else if !self.in_reach(index) {
+            warn!("Propose ignored, index out of reach: [{}]", index);
+            return;
+        }
+
+        debug!("Propose accepted: {:?}", value);
+        self.remember_message((Some(index), ballot));
+        self.set_leader(src, ballot);
+        self.refresh_lease(src, ballot);
+        while index >= self.log.len() {
+            self.log.push(LogEntry::default());
+        }
+        self.set_value(index, value);
+        self.log[index].accepted_ballot = ballot;
+        if self.group_commit.is_some() {
+            if self.pending_accepts.is_empty() {
+                self.pending_accepts_since = self.clock.now();
+            }
+            self.pending_accepts.push((src, index, ballot, sent_at));
+            return;
+        }
+        self.flush_to_disk();
+        self.node.send(
+            src,
+            &PaxosMsg::Accept {
+                index,
+                ballot,
+                sent_at,
+            },
+        );
+    }
+
+    /// Whether this replica accepted a different value for the entry with the same ballot,
+    /// which it must never replace, as another quorum might have chosen it.
+    /// Values are compared by their encoding, as commands needn't implement `PartialEq`.
+    fn conflicts_with_accepted(&self, index: usize, ballot: Ballot, value: &Command<V>) -> bool {
+        match self.log.get(index) {
+            Some(LogEntry {
+                accepted_ballot,
+                value: Some(accepted),
+                ..
+            }) if *accepted_ballot == ballot => {
+                bincode::serialize(accepted).ok() != bincode::serialize(value).ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the Accept for the Propose is held back until the next group commit.
+    fn is_accept_pending(&self, index: usize, ballot: Ballot) -> bool {
+        self.pending_accepts
+            .iter()
+            .any(|&(_, i, b, _)| i == index && b == ballot)
+    }
+
+    /// Persists the values accepted since the last group commit, then sends their Accepts,
+    /// once the first of them has been held back for the group commit window.
+    fn commit_accepts(&mut self) {
+        match self.group_commit {
+            Some(window) if !self.pending_accepts.is_empty() => {
+                if self.clock.elapsed(self.pending_accepts_since) < window {
+                    return;
+                }
+            }
+            _ => return,
+        }
+        debug!("Group commit of {} accepted values.", self.pending_accepts.len());
+        self.flush_to_disk();
+        for (dst, index, ballot, sent_at) in std::mem::take(&mut self.pending_accepts) {
+            self.node.send(dst, &PaxosMsg::Accept { index, ballot, sent_at });
+        }
+    }
+
+    /// Responds to a Paxos Accept (2b) message.
+    fn handle_accept(&mut self, src: NodeId, index: usize, ballot: Ballot) {
+        if ballot != self.highest_promised {
+            warn!("Accept rejected: {:?}!={:?}", ballot, self.highest_promised);
+            return;
+        } else if self.log.get(index).is_none_or(|entry| entry.value.is_none()) {
+            warn!("Accept for unknown entry ignored: [{}] {}", index, src);
+            return;
+        } else if self.log[index].acceptances.contains(&src) {
+            warn!("Duplicate Accept ignored: [{}] {}", index, src);
+            return;
+        } else if self.log[index].chosen {
+            trace!("Accept for chosen value ignored: [{}] {}", index, src);
+            return;
+        }
+
+        if let Some(&(sent, 0, _)) = self.proposals.get(&index) {
+            // answers a Propose sent after the last Heartbeat, which refreshed the lease just as
+            // well, see `send_scheduled_heartbeats`; retried ones might answer an earlier Propose
+            if sent >= self.heartbeat_sent {
+                self.confirm_contact(src);
+            }
+        }
+        self.log[index].acceptances.insert(src);
+        self.check_chosen(index);
+        self.propose_queued();
+    }
+
+    /// Marks the entry at `index` as chosen once a quorum has accepted it, and lets all other
+    /// replicas learn about it.
+    fn check_chosen(&mut self, index: usize) {
+        let membership = self.membership_for(index);
+        if !membership.is_quorum(self.log[index].acceptances.iter()) {
+            return;
+        }
+        debug!(
+            "Sending Learn with {} acceptances from {:?}.",
+            self.log[index].acceptances.len(),
+            membership
+        );
+        let ballot = self.log[index].accepted_ballot;
+        let value = self.log[index].value.clone().unwrap();
+        info!("Value was chosen: [{}] {:?}, {:?}", index, ballot, value);
+        if let Some(now) = self.timestamp() {
+            if let Some(&(_, _, Some(proposed_at))) = self.proposals.get(&index) {
+                let latency = Duration::from_micros(now.saturating_sub(proposed_at));
+                self.metrics.commit_latency.record(latency);
+            }
+            self.chosen_at.insert(index, now);
+        }
+        self.spread_learn(
+            &PaxosMsg::Learn {
+                index,
+                ballot,
+                value: value.clone(),
+            },
+            None,
+        );
+        if self.learn_acks.is_some() && self.fan_out == FanOut::Direct && self.is_leader() {
+            let peers: HashSet<NodeId> = self.node.peers().into_iter().collect();
+            if !peers.is_empty() {
+                self.unacked_learns.insert(index, (self.clock.now(), 0, peers));
+            }
+        }
+        self.log[index].chosen = true;
+        self.apply_chosen();
+        self.flush_to_disk();
+
+        // once the joint configuration is chosen, move on to the new configuration alone
+        if let Command::Reconfigure(Membership {
+            next: Some(members),
+            ..
+        }) = value
+        {
+            info!("Joint configuration chosen, finishing reconfiguration.");
+            self.propose(Command::Reconfigure(Membership::new(members)));
+        }
+    }
+
+    /// Handles a Learn message.
+    fn handle_learn(&mut self, src: NodeId, index: usize, ballot: Ballot, value: Command<V>) {
+        if index < self.snapshot_index {
+            trace!("Learn for entry covered by snapshot ignored: [{}]", index);
+            self.ack_learn(src, index);
+            return;
+        } else if !self.in_reach(index) {
+            warn!("Learn ignored, index out of reach: [{}]", index);
+            return;
+        }
+        info!("Learned: [{}] {:?}, {:?}", index, ballot, value);
+        self.refresh_lease(src, ballot);
+        self.ack_learn(src, index);
+        while index >= self.log.len() {
+            self.log.push(LogEntry::default());
+        }
+        if self.proposals.contains_key(&index) && self.log[index].accepted_ballot != ballot {
+            // another leader got a value chosen, which is the proposed one only if it adopted it
+            let adopted = match (&self.log[index].value, &value) {
+                (Some(Command::Tracked(proposed, _)), Command::Tracked(chosen, _)) => {
+                    proposed == chosen
+                }
+                _ => false,
+            };
+            if !adopted {
+                self.abandon_proposal(index);
+            }
+        }
+        if !self.log[index].chosen && matches!(self.fan_out, FanOut::Gossip { .. }) {
+            let learn = PaxosMsg::Learn { index, ballot, value: value.clone() };
+            self.spread_learn(&learn, Some(src));
+        }
+        if let Some(now) = self.timestamp().filter(|_| !self.log[index].chosen) {
+            self.chosen_at.insert(index, now);
+        }
+        self.set_value(index, value);
+        self.log[index].accepted_ballot = ballot;
+        self.log[index].chosen = true;
+        self.apply_chosen();
+        self.flush_to_disk();
+    }
+
+    /// Acknowledges a Learn, if it comes from the leader and learns are acknowledged, see
+    /// `with_learn_acks`. Learns from other replicas, e.g. while catching up, are not tracked.
+    fn ack_learn(&mut self, src: NodeId, index: usize) {
+        if self.learn_acks.is_some() && self.current_leader == Some(src) && src != self.node_id {
+            self.node.send(src, &PaxosMsg::LearnAck { index });
+        }
+    }
+
+    /// Handles a LearnAck message, the entry is no longer sent again to its sender.
+    fn handle_learn_ack(&mut self, src: NodeId, index: usize) {
+        if let Some((_, _, peers)) = self.unacked_learns.get_mut(&index) {
+            peers.remove(&src);
+            if peers.is_empty() {
+                trace!("All peers acknowledged the Learn: [{}]", index);
+                self.unacked_learns.remove(&index);
+            }
+        }
+    }
+
+    /// Sends the message to all peers, suspecting those it couldn't be sent to to be gone.
+    fn broadcast(&mut self, msg: &PaxosMsg<V>) {
+        let BroadcastResult { sent, failed } = self.node.broadcast(msg);
+        if self.suppress_heartbeats
+            && self.is_leader()
+            && msg.lease_ballot() == Some(self.highest_promised)
+        {
+            let now = self.clock.now();
+            self.lease_refreshed.extend(sent.iter().map(|&peer| (peer, now)));
+        }
+        for peer in failed {
+            if self.suspects.insert(peer) {
+                warn!("Failed to send to {}, suspecting it to be gone.", peer);
+            }
+        }
+        for peer in sent {
+            if self.suspects.remove(&peer) {
+                info!("Sending to suspected peer {} succeeded again.", peer);
+            }
+        }
+    }
+
+    /// Sends the Learn to other replicas as the fan-out strategy says, except for `src`,
+    /// which it was received from.
+    fn spread_learn(&mut self, learn: &PaxosMsg<V>, src: Option<NodeId>) {
+        match self.fan_out {
+            FanOut::Direct => self.broadcast(learn),
+            FanOut::Gossip { fan_out } => {
+                let peers: Vec<NodeId> =
+                    self.node.peers().into_iter().filter(|&p| Some(p) != src).collect();
+                for &peer in peers.choose_multiple(&mut self.rng, fan_out) {
+                    self.node.send(peer, learn);
+                }
+            }
+        }
+    }
+
+    /// Responds to a CatchUp request, unless it comes from a node which isn't a known peer, or
+    /// the last request of that replica was answered only recently. As the response can be much
+    /// larger than the request, this keeps requests with a forged source from making this replica
+    /// flood their victim.
+    fn handle_catch_up_request(&mut self, src: NodeId, from: usize) {
+        if !self.node.peers().contains(&src) {
+            warn!("CatchUp from unknown node {} ignored.", src);
+            return;
+        }
+        if let Some(&(served, next)) = self.catch_ups_served.get(&src) {
+            // a request for the values after the last window acknowledges it
+            if from < next && self.clock.elapsed(served) < CATCH_UP_INTERVAL {
+                debug!("CatchUp from {} ignored, answered one recently.", src);
+                return;
+            }
+        }
+        let next = self.handle_catch_up(src, from);
+        self.catch_ups_served.insert(src, (self.clock.now(), next));
+    }
+
+    /// Sends a window of chosen values from index `from` onwards to a replica which has missed
+    /// them, see `with_catch_up_window`, and returns the index after the window.
+    /// Values which were replaced by the snapshot are sent as the snapshot as a whole.
+    fn handle_catch_up(&mut self, src: NodeId, from: usize) -> usize {
+        debug!("Sending chosen values from {} to {}.", from, src);
+        if let Some(snapshot) = self.snapshot.as_ref().filter(|_| from < self.snapshot_index) {
+            debug!("Sending snapshot at {} to {}.", self.snapshot_index, src);
+            let sent = self.node.send(
+                src,
+                &PaxosMsg::InstallSnapshot {
+                    snapshot_index: self.snapshot_index,
+                    membership: snapshot.membership.clone(),
+                    data: snapshot.data.clone(),
+                    requests: snapshot.requests.clone(),
+                },
+            );
+            if !sent {
+                error!("Failed to send snapshot at {} to {}.", self.snapshot_index, src);
+            }
+        }
+        let from = from.max(self.snapshot_index);
+        let until = self.log.len().min(from + self.catch_up_window);
+        let spilled = self.read_spilled(from..until);
+        for index in from..until {
+            let entry = spilled.get(index - from).unwrap_or(&self.log[index]);
+            if let (true, Some(value)) = (entry.chosen, &entry.value) {
+                self.node.send(
+                    src,
+                    &PaxosMsg::Learn {
+                        index,
+                        ballot: entry.accepted_ballot,
+                        value: value.clone(),
+                    },
+                );
+            }
+        }
+        until
+    }
+
+    /// Installs the snapshot started by `start_snapshot`, once it has been written.
+    fn finish_snapshot(&mut self) {
+        match &self.pending_snapshot {
+            Some((.., writer)) if writer.is_finished() => {}
+            _ => return,
+        }
+        let (index, membership, requests, writer) = self.pending_snapshot.take().unwrap();
+        match writer.join() {
+            Ok(Some(data)) => {
+                let snapshot = Snapshot {
+                    membership,
+                    data,
+                    requests,
+                };
+                match self.node.check_snapshot_size(&snapshot) {
+                    Ok(()) => self.install_snapshot(index, snapshot),
+                    Err(e) => error!("Discarding snapshot at index {}: {}", index, e),
+                }
+            }
+            Ok(None) | Err(_) => error!("Failed to write snapshot at index {}.", index),
+        }
+    }
+
+    /// Replaces the applied log entries up to `index` with the snapshot of our own state machine,
+    /// unless a newer snapshot has been installed in the meantime.
+    fn install_snapshot(&mut self, index: usize, snapshot: Snapshot) {
+        if index <= self.snapshot_index {
+            debug!("Snapshot at {} discarded, already at {}.", index, self.snapshot_index);
+            return;
+        }
+        self.snapshot_index = index;
+        self.snapshot = Some(snapshot);
+        self.truncate_log();
+        self.flush_to_disk();
+    }
+
+    /// Replaces the state machine's state with the snapshot, if it is ahead of this replica.
+    /// Entries after the snapshot are caught up with the usual Learn messages.
+    fn handle_install_snapshot(&mut self, snapshot_index: usize, snapshot: Snapshot) {
+        if snapshot_index <= self.apply_index {
+            debug!("Snapshot at {} ignored, already applied.", snapshot_index);
+            return;
+        } else if !self.in_reach(snapshot_index) {
+            warn!("Snapshot ignored, index out of reach: [{}]", snapshot_index);
+            return;
+        } else if self.state_machine.restore(&snapshot.data).is_err() {
+            error!("Failed to restore snapshot at {}.", snapshot_index);
+            return;
+        }
+        info!("Installed snapshot at index {}.", snapshot_index);
+        while snapshot_index > self.log.len() {
+            self.log.push(LogEntry::default());
+        }
+        let nodes: Vec<NodeId> = snapshot.membership.nodes().copied().collect();
+        self.node.discover(&nodes);
+        self.snapshot_index = snapshot_index;
+        self.applied_requests = snapshot.requests.clone();
+        self.snapshot = Some(snapshot);
+        self.apply_index = snapshot_index;
+        self.chosen_at = self.chosen_at.split_off(&snapshot_index);
+        self.truncate_log();
+        self.apply_chosen();
+        self.flush_to_disk();
+    }
+
+    /// Handles a negative acknowledgement message.
+    fn handle_nack(&mut self, src: NodeId, ballot: Ballot, promised: Ballot) {
+        warn!("Received a NACK: {:?}<{:?}", ballot, promised);
+        if !self.is_leader() && ballot == self.highest_promised {
+            self.prepare_nacks.insert(src);
+        }
+        self.random_timeout_offset = 2 * self.election_offset();
+        if promised > self.highest_promised {
+            // another candidate is trying to get elected, competing would only prolong that
+            self.candidate_seen = true;
+        }
+        if promised > ballot {
+            // proposals with the rejected ballot won't be chosen, a higher one took over
+            let superseded: Vec<usize> = self
+                .proposals
+                .keys()
+                .copied()
+                .filter(|&index| self.log[index].accepted_ballot <= ballot)
+                .collect();
+            for index in superseded {
+                self.abandon_proposal(index);
+            }
+        }
+        // TODO: clean state for request
+    }
+
+    /// Stops proposing the entry, as a leader with a higher ballot took over, so that its value
+    /// can only be chosen if that leader adopted it. Remembers a tracked request as superseded,
+    /// so that its client submits it again right away instead of waiting for it to be applied.
+    fn abandon_proposal(&mut self, index: usize) {
+        if self.proposals.remove(&index).is_none() {
+            return;
+        }
+        warn!("Proposal superseded, no longer retrying it: [{}]", index);
+        if let Some(Command::Tracked(id, _)) = &self.log[index].value {
+            let now = self.clock.now();
+            let entry = self.superseded_requests.entry(id.client).or_insert((id.seq, now));
+            *entry = (id.seq.max(entry.0), now);
+        }
+    }
+
+    /// Rejects a message carrying the ballot, telling the sender which ballot it lost against.
+    fn nack(&self, ballot: Ballot) -> PaxosMsg<V> {
+        PaxosMsg::Nack {
+            ballot,
+            promised: self.highest_promised,
+        }
+    }
+
+    /// Responds to a Heartbeat from the leader, which also refreshes its lease.
+    fn handle_heartbeat(&mut self, src: NodeId, ballot: Ballot, seq: u64, applied: usize) {
+        if self.role == Role::Learner {
+            // learners don't vote, but tell the leader how far they got, see `promote`
+            self.request_catch_up(src, applied);
+            self.node.send(src, &PaxosMsg::Applied { index: self.apply_index });
+            return;
+        } else if ballot < self.highest_promised {
+            warn!("Heartbeat rejected: {:?}<{:?}", ballot, self.highest_promised);
+            self.node.send(src, &self.nack(ballot));
+            return;
+        } else if ballot > self.highest_promised {
+            debug!("Heartbeat ignored, no promise made: {:?}", ballot);
+            return;
+        }
+
+        self.set_leader(src, ballot);
+        if let (FanOut::Gossip { .. }, Some((applied, _))) = (self.fan_out, self.leader_applied) {
+            // gossip might have missed this replica, if it is still behind the last Heartbeat
+            self.request_catch_up(src, applied);
+        }
+        self.leader_lease_start = self.clock.now();
+        self.leader_applied = Some((applied, self.leader_lease_start));
+        self.update_caught_up();
+        self.node.send(src, &PaxosMsg::HeartbeatAck { ballot, seq });
+    }
+
+    /// Refreshes the lease of the leader, as any Propose or Learn of the ballot this replica
+    /// promised shows that it is still in office, just like a Heartbeat.
+    fn refresh_lease(&mut self, src: NodeId, ballot: Ballot) {
+        let from_leader = src != self.node_id && self.current_leader == Some(src);
+        if from_leader && ballot == self.highest_promised {
+            self.leader_lease_start = self.clock.now();
+        }
+    }
+
+    /// Starts a new round of Heartbeats, to confirm that a quorum still follows this leader.
+    fn send_heartbeats(&mut self) {
+        let heartbeat = self.next_heartbeat();
+        self.broadcast(&heartbeat);
+    }
+
+    /// Sends the periodic Heartbeats, but if suppressed, only to the followers which haven't
+    /// been sent another message refreshing their lease within the last half lease duration.
+    /// Learners always get them, as they report their progress for `promote` in response.
+    /// Busy followers confirm the leadership by accepting Proposes sent after the round started,
+    /// instead of acknowledging the Heartbeat.
+    fn send_scheduled_heartbeats(&mut self) {
+        if !self.suppress_heartbeats {
+            return self.send_heartbeats();
+        }
+        let peers = self.node.peers();
+        let idle: Vec<NodeId> = peers
+            .iter()
+            .copied()
+            .filter(|peer| {
+                self.learners.contains_key(peer)
+                    || self
+                        .lease_refreshed
+                        .get(peer)
+                        .is_none_or(|&sent| self.clock.elapsed(sent) >= LEASE_DURATION / 2)
+            })
+            .collect();
+        if idle.len() == peers.len() {
+            return self.send_heartbeats();
+        }
+        trace!("Skipping Heartbeats to busy followers, sending them to {:?}.", idle);
+        let heartbeat = self.next_heartbeat();
+        for peer in idle {
+            if self.node.send(peer, &heartbeat) {
+                self.lease_refreshed.insert(peer, self.heartbeat_sent);
+            }
+        }
+    }
+
+    /// Starts a new round of Heartbeats, returning the Heartbeat to send.
+    fn next_heartbeat(&mut self) -> PaxosMsg<V> {
+        self.heartbeat_seq += 1;
+        self.heartbeat_sent = self.clock.now();
+        self.heartbeat_acks.clear();
+        self.heartbeat_acks.insert(self.node_id);
+        PaxosMsg::Heartbeat {
+            ballot: self.highest_promised,
+            seq: self.heartbeat_seq,
+            applied: self.apply_index,
+        }
+    }
+
+    /// Follows the leader's handover to `target`, unless `src` is no longer the leader.
+    /// The target only starts its election here, it isn't leader until a quorum promised.
+    /// The other replicas keep following the leader until then, but answer the target's Prepare.
+    fn handle_timeout_now(&mut self, src: NodeId, ballot: Ballot, target: NodeId) {
+        if self.current_leader != Some(src) || ballot != self.highest_promised {
+            debug!("Ignoring stale TimeoutNow from {} for {:?}", src, ballot);
+        } else if target == self.node_id {
+            info!("Leadership handed over by {}: Starting election.", src);
+            self.start_election();
+        } else {
+            self.handover = Some(target);
+        }
+    }
+
+    /// Asks the target of the leadership transfer how far it got, or abandons the transfer if
+    /// it hasn't caught up within a lease duration.
+    fn continue_transfer(&mut self) {
+        let (target, started) = match self.transfer {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        if !self.is_leader() {
+            self.transfer = None;
+        } else if self.clock.elapsed(started) >= LEASE_DURATION {
+            warn!("{} didn't catch up in time, keeping leadership.", target);
+            self.transfer = None;
+            self.propose_queued();
+        } else {
+            self.node.send(target, &PaxosMsg::StatusRequest);
+        }
+    }
+
+    /// Sends the target of the leadership transfer the chosen values it is missing, or tells it
+    /// to start its election, once it has applied all values this leader has.
+    fn handle_transfer_status(&mut self, src: NodeId, status: ReplicaStatus) {
+        match self.transfer {
+            Some((target, _)) if target == src => {}
+            _ => {
+                debug!("StatusReply from {} ignored, no transfer to it in progress.", src);
+                return;
+            }
+        }
+        if status.commit_index < self.apply_index {
+            self.handle_catch_up(src, status.commit_index);
+            return;
+        }
+        info!("{} has caught up: Handing leadership over.", src);
+        self.transfer = None;
+        if !self.client_cmd_queue.is_empty() {
+            warn!("Dropping {} queued client requests.", self.client_cmd_queue.len());
+            self.client_cmd_queue.clear();
+        }
+        let ballot = self.highest_promised;
+        self.handover = Some(src);
+        self.broadcast(&PaxosMsg::TimeoutNow { ballot, target: src });
+    }
+
+    /// Records the replica as leader, and reports it if this changes the leadership.
+    fn set_leader(&mut self, leader: NodeId, ballot: Ballot) {
+        let old = self.leader_state();
+        self.current_leader = Some(leader);
+        let new = self.leader_state();
+        if leader != self.node_id {
+            // only the election started by `force_commit` may override the entry
+            self.forced = None;
+        }
+        if old != new {
+            debug!("Leadership changed: {:?} -> {:?}, {:?}", old, new, ballot);
+            self.handover = None;
+            self.applied.clear();
+            // the NoOps might never be chosen, the probers time out instead
+            self.health_probes.clear();
+            // the new leader hasn't heard how far this replica got yet
+            self.report_applied();
+            if let Some(callback) = &mut self.on_leader_change {
+                callback(old, new, ballot);
+            }
+        }
+    }
+
+    /// Records how far a follower has applied the log, if this replica is leader.
+    /// The latest report wins, as a restarted follower might have to apply entries again.
+    fn handle_applied(&mut self, src: NodeId, index: usize) {
+        if let Some(applied) = self.learners.get_mut(&src) {
+            *applied = index;
+            return;
+        } else if !self.track_applied || !self.is_leader() {
+            trace!("Applied from {} ignored: {}", src, index);
+            return;
+        }
+        self.applied.insert(src, index);
+    }
+
+    /// Tells the leader how far this replica has applied the log, if it tracks application.
+    fn report_applied(&mut self) {
+        match self.current_leader {
+            Some(leader) if self.track_applied && leader != self.node_id => {
+                self.node.send(leader, &PaxosMsg::Applied { index: self.apply_index });
+            }
+            _ => {}
+        }
+    }
+
+    /// The number of log entries a quorum of replicas has applied, as far as this leader knows.
+    fn quorum_applied(&self) -> usize {
+        let mut applied = self.applied.clone();
+        applied.insert(self.node_id, self.apply_index);
+        let membership = self.latest_membership();
+        let mut candidates: Vec<usize> = applied.values().copied().collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+        candidates
+            .into_iter()
+            .find(|&n| {
+                let reached = applied.iter().filter(|(_, &i)| i >= n).map(|(id, _)| id);
+                membership.is_quorum(reached)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Handles the acknowledgement of a Heartbeat this replica sent as leader.
+    fn handle_heartbeat_ack(&mut self, src: NodeId, ballot: Ballot, seq: u64) {
+        if ballot != self.highest_promised || seq != self.heartbeat_seq {
+            trace!("Outdated HeartbeatAck ignored: {:?}, {}", ballot, seq);
+            return;
+        }
+        self.confirm_contact(src);
+    }
+
+    /// Counts the replica as following this leader since the last Heartbeat was sent.
+    /// The election may have confirmed a more recent contact already.
+    fn confirm_contact(&mut self, src: NodeId) {
+        self.heartbeat_acks.insert(src);
+        if self.latest_membership().is_quorum(self.heartbeat_acks.iter()) {
+            self.quorum_contact = self.quorum_contact.max(Some(self.heartbeat_sent));
+        }
+    }
+
+    /// Handles a client request or batch received from `src`, which is either a client or
+    /// another replica relaying it. The `origin` of tracked requests is the submitting client.
+    fn handle_client_msg(
+        &mut self,
+        src: NodeId,
+        cmd: Command<V>,
+        hops: u32,
+        priority: u8,
+        origin: Option<NodeId>,
+    ) {
+        // point clients which don't know the leader to it, for their next requests
+        if hops == 0 && !self.is_leader() {
+            let leader_hint = self.current_leader;
+            self.node.send(src, &PaxosMsg::NotLeader { leader_hint });
+        }
+        let id = match &cmd {
+            Command::Tracked(id, _) => Some(*id),
+            _ => None,
+        };
+        if let Err(reason) = self.handle_client_request(cmd, hops, priority, origin) {
+            warn!("Rejected client request from {}: {}", src, reason);
+            // relayed requests can only be answered if they name their origin
+            if let Some(client) = origin.or(Some(src).filter(|_| hops == 0)) {
+                self.node.send(client, &PaxosMsg::Rejected { id, reason });
+            }
+        }
+    }
+
+    /// Handles a client request directly if this replica believes itself to be the leader.
+    /// Relays the request to the (replica we believe to be the) current leader otherwise.
+    /// Requests which were already relayed as often as there are members are dropped,
+    /// as they are most likely caught in a loop.
+    /// Fails if the command is rejected, in which case it doesn't enter the log. A leader which
+    /// lost contact with a quorum rejects all requests with `NoQuorum`, until it regains it,
+    /// so that clients fail fast instead of waiting for commands which can't be committed.
+    /// The `origin` of a tracked request is told once it is applied, see `remember_origin`.
+    fn handle_client_request(
+        &mut self,
+        cmd: Command<V>,
+        hops: u32,
+        priority: u8,
+        origin: Option<NodeId>,
+    ) -> Result<(), PaxosError> {
+        if self.drained.is_some() {
+            return Err(PaxosError::Draining);
+        } else if self.is_overloaded() {
+            return Err(PaxosError::Overloaded);
+        } else if self.is_leader() && !self.has_quorum_contact() {
+            debug!("Rejecting client request, no recent contact with a quorum: {:?}", cmd);
+            return Err(PaxosError::NoQuorum);
+        } else if self.is_leader() {
+            debug!("Handling client request: {:?}", cmd);
+            self.remember_origin(&cmd, origin);
+            self.enqueue(cmd, priority)?;
+            self.propose_queued();
+        } else if hops as usize >= self.latest_membership().current.len() {
+            warn!("Client request was relayed {} times, dropping it: {:?}", hops, cmd);
+        } else if let Some(leader) = self.current_leader {
+            // TODO: is relaying to leader safe? (esp. if our current_leader value is wrong)
+            trace!("Received a client request, relaying to leader: {:?}", cmd);
+            let hops = hops + 1;
+            let msg = match cmd.clone() {
+                Command::Batch(values) => PaxosMsg::ClientBatch {
+                    values,
+                    hops,
+                    priority,
+                },
+                Command::App(value) => PaxosMsg::ClientRequest {
+                    value,
+                    hops,
+                    priority,
+                    deadline: None,
+                },
+                Command::Expiring { deadline, value } => PaxosMsg::ClientRequest {
+                    value,
+                    hops,
+                    priority,
+                    deadline: Some(deadline),
+                },
+                Command::Tracked(id, value) => PaxosMsg::TrackedRequest {
+                    id,
+                    value,
+                    hops,
+                    priority,
+                    origin,
+                },
+                _ => unreachable!("only client commands are relayed"),
+            };
+            if !self.node.send(leader, &msg) {
+                error!("Relaying command to leader failed.");
+                self.remember_origin(&cmd, origin);
+                self.enqueue(cmd, priority)?;
+            }
+        } else {
+            warn!("Received a client request, but no leader is known: {:?}", cmd);
+            self.remember_origin(&cmd, origin);
+            self.enqueue(cmd, priority)?;
+        }
+        Ok(())
+    }
+
+    /// Remembers which client submitted the tracked request this replica queues, to send it a
+    /// RequestApplied once the request is applied, no matter which replicas relayed it.
+    fn remember_origin(&mut self, cmd: &Command<V>, origin: Option<NodeId>) {
+        if let (Command::Tracked(id, _), Some(origin)) = (cmd, origin) {
+            self.request_origins.insert(*id, (origin, self.clock.now()));
+        }
+    }
+
+    /// Queues the command to be proposed, once this replica is leader and the proposal window
+    /// allows it, unless the state machine rejects it. Batches are rejected as a whole,
+    /// if any of their commands is.
+    ///
+    /// Commands are keyed by a virtual deadline, which is earlier the higher their priority.
+    /// Since the deadline also grows with every queued command, a command is overtaken by fewer
+    /// than `PRIORITY_AGING` later ones per level of priority they are higher, so low-priority
+    /// commands age into being proposed instead of starving.
+    fn enqueue(&mut self, cmd: Command<V>, priority: u8) -> Result<(), PaxosError> {
+        let values = match &cmd {
+            Command::App(value) => std::slice::from_ref(value),
+            Command::Batch(values) => values.as_slice(),
+            Command::Tracked(_, value) | Command::Expiring { value, .. } => {
+                std::slice::from_ref(value)
+            }
+            _ => &[],
+        };
+        for value in values {
+            self.state_machine
+                .validate(value)
+                .map_err(PaxosError::CommandRejected)?;
+        }
+        let seq = self.queued_total;
+        self.queued_total += 1;
+        let deadline = seq + u64::from(u8::MAX - priority) * PRIORITY_AGING;
+        self.client_cmd_queue.insert((deadline, seq), cmd);
+        Ok(())
+    }
+
+    /// Whether the value is an `Expiring` command whose deadline has passed by this replica's
+    /// wall clock.
+    fn is_expired(&self, value: &Command<V>) -> bool {
+        let deadline = match value {
+            Command::Expiring { deadline, .. } => *deadline,
+            _ => return false,
+        };
+        let now = self.clock.system_time().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now.as_millis() > u128::from(deadline)
+    }
+
+    /// Reports this replica's leader and progress back to the sender.
+    fn handle_status_request(&mut self, src: NodeId) {
+        let status = ReplicaStatus {
+            leader: self.current_leader,
+            commit_index: self.committed_len(),
+            quorum_applied: (self.track_applied && self.is_leader()).then(|| self.quorum_applied()),
+        };
+        self.node.send(src, &PaxosMsg::StatusReply(status));
+    }
+
+    /// Tells the sender whether the command with the request ID has been applied, and the latest
+    /// log entry holding it, unless it is covered by the snapshot.
+    fn handle_request_status(&mut self, src: NodeId, id: RequestId) {
+        let applied = self.applied_requests.get(id.client).is_some_and(|seq| seq >= id.seq);
+        let index = (self.snapshot_index..self.log.len()).rev().find(|&i| {
+            matches!(&self.log[i].value, Some(Command::Tracked(r, _)) if *r == id)
+        });
+        let superseded = !applied && self.is_superseded(id);
+        let reply = PaxosMsg::RequestStatusReply {
+            id,
+            applied,
+            index,
+            superseded,
+        };
+        self.node.send(src, &reply);
+    }
+
+    /// Whether this replica abandoned the proposal of the request, see `abandon_proposal`.
+    fn is_superseded(&self, id: RequestId) -> bool {
+        self.superseded_requests.get(&id.client).is_some_and(|&(seq, _)| seq == id.seq)
+    }
+
+    /// Forgets the request, as its client stopped waiting for it: drops it from the queue if it
+    /// hasn't been proposed yet, and forgets that its proposal was abandoned. Proposed commands
+    /// can't be withdrawn, so that they might still be chosen.
+    fn handle_cancel_request(&mut self, src: NodeId, id: RequestId) {
+        let queued = self.client_cmd_queue.len();
+        self.client_cmd_queue
+            .retain(|_, cmd| !matches!(cmd, Command::Tracked(r, _) if *r == id));
+        if self.client_cmd_queue.len() < queued {
+            debug!("Dropped request {:?} cancelled by {} from the queue.", id, src);
+        }
+        if self.is_superseded(id) {
+            self.superseded_requests.remove(&id.client);
+        }
+        self.request_origins.remove(&id);
+    }
+
+    /// Forgets the superseded requests whose clients haven't asked about them for `REQUEST_TTL`,
+    /// so that clients which give up without cancelling don't leak state, as well as the origins
+    /// of requests which weren't applied within that time.
+    fn expire_requests(&mut self) {
+        let clock = &self.clock;
+        self.superseded_requests.retain(|_, &mut (_, since)| clock.elapsed(since) < REQUEST_TTL);
+        self.request_origins.retain(|_, &mut (_, since)| clock.elapsed(since) < REQUEST_TTL);
+    }
+
+    /// Proposes a NoOp for the prober, if this replica is the leader and in contact with a quorum,
+    /// which is answered once the NoOp has been applied, see `answer_health_probes`.
+    /// Other replicas relay the probe to the leader. Probes which can't be handled are dropped,
+    /// so that the prober times out.
+    fn handle_health_probe(&mut self, prober: NodeId, hops: u32) {
+        if self.is_leader() && self.has_quorum_contact() {
+            debug!("Proposing NoOp for health probe from {}.", prober);
+            self.health_probes.push((self.log.len(), prober, self.clock.now()));
+            self.propose(Command::NoOp);
+        } else if self.is_leader() {
+            debug!("Health probe from {} dropped, no recent contact with a quorum.", prober);
+        } else if hops as usize >= self.latest_membership().current.len() {
+            warn!("Health probe was relayed {} times, dropping it.", hops);
+        } else if let Some(leader) = self.current_leader {
+            let hops = hops + 1;
+            self.node.send(leader, &PaxosMsg::HealthProbe { prober, hops });
+        } else {
+            debug!("Health probe from {} dropped, no leader known.", prober);
+        }
+    }
+
+    /// Tells the probers whose NoOps have been applied how long that took.
+    fn answer_health_probes(&mut self) {
+        let apply_index = self.apply_index;
+        let (done, pending) = std::mem::take(&mut self.health_probes)
+            .into_iter()
+            .partition(|&(index, _, _)| index < apply_index);
+        self.health_probes = pending;
+        for (_, prober, received) in done {
+            let latency_ms = self.clock.elapsed(received).as_millis() as u64;
+            self.node.send(prober, &PaxosMsg::HealthOk { latency_ms });
+        }
+    }
+
+    /// Reports a summary of this replica's internal state back to the sender.
+    fn handle_state_dump_request(&mut self, src: NodeId) {
+        let dump = StateDump {
+            ballot: self.highest_promised,
+            role: self.role,
+            leader: self.current_leader,
+            commit_index: self.log.iter().filter(|entry| entry.chosen).count(),
+            apply_index: self.apply_index,
+            log_len: self.log.len(),
+        };
+        self.node.send(src, &PaxosMsg::StateDumpReply(dump));
+    }
+
+    /// Proposes queued client commands by priority, as long as the proposal window isn't full.
+    /// This keeps a leader from flooding the network with Proposes faster than a quorum of
+    /// followers can accept them.
+    fn propose_queued(&mut self) {
+        // a leader handing over its leadership doesn't propose while the target catches up
+        while self.is_leader()
+            && self.transfer.is_none()
+            && self.log.len() - self.apply_index < self.proposal_window
+        {
+            match self.client_cmd_queue.pop_first() {
+                Some((_, cmd)) if self.is_expired(&cmd) => {
+                    debug!("Dropping client request past its deadline: {:?}", cmd)
+                }
+                Some((_, cmd)) => self.propose(cmd),
+                None => return,
+            }
+        }
+        if !self.client_cmd_queue.is_empty() {
+            trace!("Proposal window full, {} commands queued.", self.client_cmd_queue.len());
+        }
+    }
+
+    /// Appends the value to the log and sends Propose messages for it to all other replicas.
+    fn propose(&mut self, value: Command<V>) {
+        let index = self.log.len();
+        self.log.push(LogEntry::new(value.clone(), self.node_id, self.highest_promised));
+        self.set_value(index, value.clone());
+        self.flush_to_disk();
+        self.broadcast(&PaxosMsg::Propose {
+            index,
+            ballot: self.highest_promised,
+            value,
+            sent_at: self.timestamp(),
+        });
+        self.proposals.insert(index, (self.clock.now(), 0, self.timestamp()));
+        self.check_chosen(index);
+    }
+
+    /// Sends the Proposes for entries which haven't been chosen in time again.
+    /// Acceptors which already accepted them answer duplicates with another Accept.
+    fn retry_proposals(&mut self) {
+        let log = &self.log;
+        self.proposals.retain(|&index, _| !log[index].chosen);
+        if !self.is_leader() {
+            return;
+        }
+        let (timeout, max_retries) = (self.proposal_timeout, self.max_proposal_retries);
+        let due: Vec<usize> = self
+            .proposals
+            .iter()
+            .filter(|(_, &(sent, retries, _))| {
+                retries < max_retries && self.clock.elapsed(sent) >= timeout
+            })
+            .map(|(&index, _)| index)
+            .collect();
+        for index in due {
+            let entry = &self.log[index];
+            let (ballot, value) = (entry.accepted_ballot, entry.value.clone().unwrap());
+            let (sent, retries, _) = self.proposals.get_mut(&index).unwrap();
+            *sent = self.clock.now();
+            *retries += 1;
+            if *retries == max_retries {
+                warn!("Proposing [{}] for the last time, leaving it to the next election.", index);
+            } else {
+                debug!("Entry not chosen in time, proposing again: [{}]", index);
+            }
+            self.broadcast(&PaxosMsg::Propose {
+                index,
+                ballot,
+                value,
+                sent_at: self.timestamp(),
+            });
+        }
+    }
+
+    /// Sends the Learns which some peers haven't acknowledged in time again to those peers,
+    /// see `with_learn_acks`. Entries whose value is no longer in memory, because it was
+    /// replaced by a snapshot or spilled to the archive, are left to catch-up instead.
+    fn retry_learns(&mut self) {
+        let (timeout, max_retries) = match self.learn_acks {
+            Some(learn_acks) => learn_acks,
+            None => return,
+        };
+        if !self.is_leader() {
+            self.unacked_learns.clear();
+            return;
+        }
+        let log = &self.log;
+        let snapshot_index = self.snapshot_index;
+        self.unacked_learns.retain(|&index, (_, retries, _)| {
+            *retries < max_retries && index >= snapshot_index && log[index].value.is_some()
+        });
+        let due: Vec<usize> = self
+            .unacked_learns
+            .iter()
+            .filter(|(_, (sent, _, _))| self.clock.elapsed(*sent) >= timeout)
+            .map(|(&index, _)| index)
+            .collect();
+        for index in due {
+            let entry = &self.log[index];
+            let learn = PaxosMsg::Learn {
+                index,
+                ballot: entry.accepted_ballot,
+                value: entry.value.clone().unwrap(),
+            };
+            let (sent, retries, peers) = self.unacked_learns.get_mut(&index).unwrap();
+            *sent = self.clock.now();
+            *retries += 1;
+            debug!("Learn not acknowledged in time, sending again to {:?}: [{}]", peers, index);
+            for &peer in peers.iter() {
+                self.node.send(peer, &learn);
+            }
+        }
+    }
+
+    /// Takes over leadership once a quorum has promised to follow this replica's ballot.
+    /// Adopts the values accepted by the quorum and proposes all entries not yet chosen again.
+    fn check_elected(&mut self) {
+        if !self.latest_membership().is_quorum(self.promises.keys()) {
+            return;
+        }
+        self.quorum_contact = Some(self.last_election);
+        if self.is_leader() && self.forced.is_none() {
+            return;
+        }
+        info!("Got elected.");
+        let ballot = self.highest_promised;
+        self.set_leader(self.node_id, ballot);
+        self.leader_lease_start = self.clock.now();
+
+        // adapt values in log based on accepted values in received Promise messages, picking
+        // the one to adopt for each entry deterministically, see `Ballot`
+        let promises = std::mem::take(&mut self.promises);
+        let mut adopted: BTreeMap<usize, (Ballot, &Command<V>)> = BTreeMap::new();
+        for (_, accepted_values) in promises.values() {
+            for (index, ballot, value) in accepted_values {
+                // replicas are visited in order of their IDs, so the lowest one wins ties
+                if adopted.get(index).is_none_or(|(best, _)| best < ballot) {
+                    adopted.insert(*index, (*ballot, value));
+                }
+            }
+        }
+        for (index, (ballot, value)) in adopted {
+            if index < self.snapshot_index {
+                continue;
+            }
+            while index >= self.log.len() {
+                self.log.push(LogEntry::default());
+            }
+            let entry = &self.log[index];
+            if entry.chosen {
+                continue;
+            } else if entry.value.is_none() || entry.accepted_ballot <= ballot {
+                trace!("Using value from Promise: [{}] {:?}, {:?}", index, ballot, value);
+                self.set_value(index, value.clone());
+                self.log[index].accepted_ballot = ballot;
+            }
+        }
+        self.promises = promises;
+        self.apply_forced();
+
+        // send Propose messages for not yet chosen log entries
+        let unchosen: Vec<usize> = (0..self.log.len())
+            .filter(|&i| !self.log[i].chosen)
+            .collect();
+        for index in unchosen {
+            if self.log[index].value.is_none() {
+                self.set_value(index, Command::NoOp);
+            }
+            let entry = &mut self.log[index];
+            entry.accepted_ballot = ballot;
+            entry.acceptances = std::iter::once(self.node_id).collect();
+            let value = entry.value.clone().unwrap();
+            self.broadcast(&PaxosMsg::Propose {
+                index,
+                ballot,
+                value,
+                sent_at: self.timestamp(),
+            });
+            self.proposals.insert(index, (self.clock.now(), 0, self.timestamp()));
+            self.check_chosen(index);
+        }
+        self.flush_to_disk();
+
+        // commit an entry of our own, to learn how far the log is chosen before serving reads
+        self.ready_index = self.log.len();
+        self.propose(Command::NoOp);
+
+        // commands received while no leader was known can be proposed now
+        self.propose_queued();
+    }
+
+    /// Replaces the adopted value of the entry `force_commit` overrides, unless the Promises
+    /// show that a quorum accepted the value at the same ballot, i.e. that it is chosen.
+    fn apply_forced(&mut self) {
+        let (index, value) = match self.forced.take() {
+            Some(forced) => forced,
+            None => return,
+        };
+        let reported = |accepted: &Promise<V>| {
+            let entry = accepted.iter().find(|(i, _, _)| *i == index);
+            entry.map(|(_, ballot, _)| *ballot)
+        };
+        let highest = self.promises.values().filter_map(|(_, a)| reported(a)).max();
+        let voters: Vec<NodeId> = self
+            .promises
+            .iter()
+            .filter(|(_, (_, accepted))| highest.is_some() && reported(accepted) == highest)
+            .map(|(&id, _)| id)
+            .collect();
+        if index < self.snapshot_index
+            || self.log.get(index).is_some_and(|e| e.chosen)
+            || self.membership_for(index).is_quorum(voters.iter())
+        {
+            error!("Value not forced into [{}], it is chosen already.", index);
+            return;
+        }
+        while index >= self.log.len() {
+            self.log.push(LogEntry::default());
+        }
+        warn!("Forcing value into [{}], replacing {:?}", index, self.log[index].value);
+        self.set_value(index, value);
+    }
+
+    /// Initiates a new election, i.e. a single Prepare/Promise sequence for the whole log.
+    fn start_election(&mut self) {
+        if let Err(e) = self.highest_promised.increment_for(self.node_id) {
+            error!("Can't start election: {}", e);
+            return;
+        }
+        self.last_election = self.clock.now();
+        self.prepare_nacks.clear();
+        let accepted_values = self
+            .get_accepted_values_iter()
+            .map(|(index, ballot, value)| (index, ballot, value.clone()))
+            .collect();
+        self.promises.clear();
+        self.promises
+            .insert(self.node_id, (self.highest_promised, accepted_values));
+
+        // create a list of all values we are still missing in our log
+        let mut holes: Vec<usize> = self
+            .log
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.chosen)
+            .map(|(index, _)| index)
+            .collect();
+        holes.push(self.log.len());
+        debug!("Missing values: {:?}", holes);
+        self.flush_to_disk();
+
+        self.broadcast(&PaxosMsg::Prepare {
+            ballot: self.highest_promised,
+            holes,
+            sent_at: self.timestamp(),
+        });
+        self.check_elected();
+    }
+
+    /// Remembers the message as processed, forgetting the oldest one if the window is full.
+    fn remember_message(&mut self, key: MessageKey) {
+        if self.duplicate_window == 0 {
+            return;
+        }
+        if self.recent_messages.len() >= self.duplicate_window {
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages.push_back(key);
+    }
+
+    /// The time to wait beyond the lease before starting an election, random unless ranked,
+    /// as adjusted by the leader policy.
+    fn election_offset(&mut self) -> Duration {
+        let base = match self.election_rank {
+            Some(rank) => Duration::from_millis(10) * rank.saturating_add(1),
+            None => Duration::from_millis(self.rng.gen_range(100..=200)),
+        };
+        self.leader_policy.election_delay(self.node_id, base)
+    }
+
+    /// The time after which the leader's lease is considered expired, or for the leader itself
+    /// the time after which it extends its lease.
+    fn lease_timer(&self) -> Duration {
+        if self.is_leader() {
+            LEASE_DURATION / 2
+        } else {
+            LEASE_DURATION + self.random_timeout_offset
+        }
+    }
+
+    /// Runs the timer-driven work which is due, i.e. retries proposals and Learns, forgets stale
+    /// requests, detects the leader's timeout, extends our own lease, or repeats an election that
+    /// has not succeeded.
+    fn run_timers(&mut self) {
+        self.commit_accepts();
+        self.retry_proposals();
+        self.retry_learns();
+        self.expire_requests();
+        self.retry_catch_up();
+        self.continue_transfer();
+        let idle = self.is_single_node() || self.role == Role::Learner;
+        if idle || self.clock.elapsed(self.leader_lease_start) < self.lease_timer() {
+            return;
+        }
+        // repeat elections that have not succeeded after a random delay
+        if self.clock.elapsed(self.last_election) >= self.random_timeout_offset {
+            if self.defers_election() {
+                return;
+            } else if self.is_leader() {
+                info!("Extending my lease: Sending heartbeats and starting election.");
+                self.send_scheduled_heartbeats();
+            } else if self.is_preparing() {
+                debug!("Prepares are still being answered: Not starting another round.");
+                return;
+            } else {
+                warn!("Leader's lease timed out: Starting election.");
+            }
+            self.start_election();
+        }
+    }
+
+    /// Whether this replica's round of Prepares is still in progress: it hasn't been answered by a
+    /// quorum yet, in either direction, nor has it taken longer than the round timeout, nor has
+    /// this replica promised another candidate since starting it.
+    fn is_preparing(&self) -> bool {
+        let own = self.promises.get(&self.node_id).map(|(ballot, _)| *ballot);
+        !self.is_leader()
+            && own == Some(self.highest_promised)
+            && self.clock.elapsed(self.last_election) < self.prepare_round_timeout
+            && !self.latest_membership().is_quorum(self.prepare_nacks.iter())
+    }
+
+    /// Whether this replica puts off the election its timer is due for, because it recently
+    /// learned of another candidate with a higher ballot. Instead of competing with it, this
+    /// replica gives it one more timeout to get elected, which together with the random
+    /// timeouts dampens storms of competing elections.
+    fn defers_election(&mut self) -> bool {
+        if self.is_leader() {
+            return false;
+        }
+        if std::mem::take(&mut self.candidate_seen) {
+            debug!("Another candidate is active: Deferring election.");
+            self.election_deferred = Some(self.clock.now());
+        }
+        self.election_deferred
+            .is_some_and(|deferred| self.clock.elapsed(deferred) < self.random_timeout_offset)
+    }
+
+    /// The time until `run_timers` has work to do, but at most the poll interval.
+    fn next_timeout(&self) -> Duration {
+        if self.is_single_node() || self.role == Role::Learner {
+            return self.poll_interval;
+        }
+        let timer = self.lease_timer();
+        let lease_elapsed = self.clock.elapsed(self.leader_lease_start);
+        let next_wake = if lease_elapsed < timer {
+            timer - lease_elapsed
+        } else {
+            let since = |time| self.random_timeout_offset.saturating_sub(self.clock.elapsed(time));
+            let deferred = self.election_deferred.filter(|_| !self.is_leader()).map(since);
+            since(self.last_election).max(deferred.unwrap_or_default())
+        };
+        let next_retry = self
+            .proposals
+            .values()
+            .filter(|&&(_, retries, _)| self.is_leader() && retries < self.max_proposal_retries)
+            .map(|&(sent, _, _)| self.proposal_timeout.saturating_sub(self.clock.elapsed(sent)))
+            .min();
+        let next_learn = self
+            .learn_acks
+            .filter(|_| self.is_leader())
+            .and_then(|(timeout, _)| {
+                let sent = self.unacked_learns.values().map(|&(sent, _, _)| sent).min()?;
+                Some(timeout.saturating_sub(self.clock.elapsed(sent)))
+            });
+        let next_commit = self
+            .group_commit
+            .filter(|_| !self.pending_accepts.is_empty())
+            .map(|window| window.saturating_sub(self.clock.elapsed(self.pending_accepts_since)));
+        next_wake
+            .min(next_retry.unwrap_or(next_wake))
+            .min(next_learn.unwrap_or(next_wake))
+            .min(next_commit.unwrap_or(next_wake))
+            .min(self.poll_interval)
+    }
+
+    /// The current time as a timestamp for outgoing messages, if latencies are tracked.
+    fn timestamp(&self) -> Option<Timestamp> {
+        if !self.track_latency {
+            return None;
+        }
+        Some(self.clock.elapsed(self.epoch).as_micros() as Timestamp)
+    }
+
+    /// Records the round-trip time to `src`, if the response echoed one of our timestamps.
+    fn record_round_trip(&mut self, src: NodeId, sent_at: Option<Timestamp>) {
+        if let (Some(sent_at), Some(now)) = (sent_at, self.timestamp()) {
+            let rtt = Duration::from_micros(now.saturating_sub(sent_at));
+            trace!("Round-trip time to {}: {:?}", src, rtt);
+            self.metrics
+                .round_trip_times
+                .entry(src)
+                .or_default()
+                .record(rtt);
+        }
+    }
+
+    /// Save all persistent state for this replica to disk, or die if it doesn't work.
+    fn flush_to_disk(&self) {
+        if let Err(e) = self.try_flush_to_disk() {
+            panic!("Failed to persist state: {}", e);
+        }
+    }
+
+    /// Writes the state which has to survive a crash to the state file, if there is one.
+    fn try_flush_to_disk(&self) -> Result<(), StorageError> {
+        if let Some(filename) = &self.state_file {
+            let mut log = self.log.clone();
+            if self.compact_storage {
+                for entry in &mut log {
+                    entry.acceptances.clear();
+                }
+            }
+            let state = PersistentState {
+                promised_ballot: self.highest_promised,
+                log,
+                snapshot_index: self.snapshot_index,
+                snapshot: self.snapshot.clone(),
+                apply_index: self.apply_index,
+            };
+            store_in_disk_file(&*self.storage_codec, filename, &state)?;
+        }
+        Ok(())
+    }
+
+    /// Recover this replica's state from what it previously saved to disk.
+    /// The state machine is rebuilt from the snapshot and by applying all chosen entries again.
+    fn recover_from_disk(&mut self) {
+        let filename = match &self.state_file {
+            Some(filename) if std::path::Path::new(filename).exists() => filename,
+            _ => return,
+        };
+        let mut state = match load_from_disk_file(&*self.storage_codec, filename) {
+            Ok(state) if state.is_consistent() => state,
+            Ok(_) => {
+                error!("Persistent state is inconsistent, starting with empty state.");
+                return;
+            }
+            Err(e) => {
+                error!("Failed to load persistent state ({}), starting with empty state.", e);
+                return;
+            }
+        };
+        info!(
+            "Recovered {} log entries, {} applied.",
+            state.log.len(),
+            state.apply_index
+        );
+        if let Err(e) = self.restore_spilled(&mut state.log, state.snapshot_index) {
+            error!("Failed to read log archive ({}), starting with empty state.", e);
+            // the archive's entries don't belong to the empty log, so they are discarded later on
+            self.archive_truncated = false;
+            return;
+        }
+        if let Some(snapshot) = &state.snapshot {
+            if self.state_machine.restore(&snapshot.data).is_err() {
+                error!("Failed to restore snapshot, starting with empty state.");
+                return;
+            }
+        }
+        self.applied_requests = state
+            .snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.requests.clone())
+            .unwrap_or_default();
+        self.highest_promised = state.promised_ballot;
+        self.log = state.log;
+        self.snapshot_index = state.snapshot_index;
+        self.snapshot = state.snapshot;
+        self.apply_index = state.snapshot_index;
+        self.configs.clear();
+        for index in 0..self.log.len() {
+            if let Some(value) = self.log[index].value.clone() {
+                self.set_value(index, value);
+            }
+        }
+        self.truncate_log();
+        self.apply_chosen();
+    }
+
+    /// Applies all chosen log entries to the state machine, up to the first one not yet chosen.
+    fn apply_chosen(&mut self) {
+        let start = self.apply_index;
+        while let Some(entry) = self.log.get(self.apply_index).filter(|entry| entry.chosen) {
+            if self.apply_deadline.is_some_and(|deadline| self.clock.now() >= deadline) {
+                break;
+            }
+            let chosen_at = self.chosen_at.remove(&self.apply_index);
+            if let Some(value) = &entry.value {
+                let capacity = self.max_tracked_clients;
+                if !record_request(&mut self.applied_requests, capacity, value) {
+                    debug!("Skipped resubmitted command [{}] {:?}", self.apply_index, value);
+                    self.apply_index += 1;
+                    continue;
+                }
+                let sizes = &mut self.metrics.command_sizes;
+                match value {
+                    Command::App(cmd)
+                    | Command::Tracked(_, cmd)
+                    | Command::Expiring { value: cmd, .. } => sizes.record(command_wire_size(cmd)),
+                    Command::Batch(cmds) => {
+                        for cmd in cmds {
+                            sizes.record(command_wire_size(cmd));
+                        }
+                    }
+                    Command::Reconfigure(_) | Command::NoOp => {}
+                }
+                let results = execute(&mut *self.state_machine, value);
+                trace!("Applied [{}] {:?}: {:?}", self.apply_index, value, results);
+                let retention = self.subscriber_retention;
+                publish(&mut self.subscribers, retention, self.apply_index, value, &results);
+                if let Command::Tracked(id, _) = value {
+                    if let Some((origin, _)) = self.request_origins.remove(id) {
+                        let result = results.first().cloned().unwrap_or(Err(()));
+                        let index = self.apply_index;
+                        let applied = PaxosMsg::RequestApplied { id: *id, index, result };
+                        self.node.send(origin, &applied);
+                    }
+                }
+                if !results.is_empty() {
+                    self.applied_results.insert(self.apply_index, results);
+                }
+            }
+            if let (Some(chosen_at), Some(now)) = (chosen_at, self.timestamp()) {
+                let latency = Duration::from_micros(now.saturating_sub(chosen_at));
+                self.metrics.apply_latency.record(latency);
+            }
+            self.apply_index += 1;
+        }
+        if self.apply_index > start {
+            self.report_applied();
+            self.update_caught_up();
+            self.answer_health_probes();
+            self.check_demoted();
+            self.continue_catch_up();
+            self.spill_log();
+        }
+        self.apply_watch.update(self.apply_index);
+        self.update_apply_lag();
+        self.check_drained();
+    }
+
+    /// Turns this voter into a learner once a membership which doesn't include it anymore has
+    /// been applied, see `demote`. It then keeps applying chosen values, but no longer votes.
+    fn check_demoted(&mut self) {
+        let node_id = self.node_id;
+        let membership = self.membership_for(self.apply_index);
+        if self.role == Role::Voter && !membership.nodes().any(|&id| id == node_id) {
+            info!("Demoted from voter to learner.");
+            self.role = Role::Learner;
+            if self.is_leader() {
+                self.current_leader = None;
+            }
+        }
+    }
+
+    /// Updates the apply lag metric, and warns once it reaches the threshold.
+    fn update_apply_lag(&mut self) {
+        let chosen = self.log.iter().rposition(|entry| entry.chosen).map_or(0, |i| i + 1);
+        self.metrics.apply_lag = chosen.saturating_sub(self.apply_index);
+        match self.apply_lag_threshold {
+            Some(threshold) if self.metrics.apply_lag >= threshold => {
+                if !self.apply_lag_warned {
+                    warn!("State machine lags {} entries behind.", self.metrics.apply_lag);
+                    self.apply_lag_warned = true;
+                }
+            }
+            _ => self.apply_lag_warned = false,
+        }
+    }
+
+    /// Whether a quorum has confirmed this replica's leadership within the last lease duration.
+    fn has_quorum_contact(&self) -> bool {
+        self.is_single_node()
+            || self
+                .quorum_contact
+                .is_some_and(|contact| self.clock.elapsed(contact) < LEASE_DURATION)
+    }
+
+    /// Whether client requests are rejected, as the state machine lags too far behind.
+    fn is_overloaded(&self) -> bool {
+        self.backpressure
+            && self
+                .apply_lag_threshold
+                .is_some_and(|threshold| self.metrics.apply_lag >= threshold)
+    }
+
+    /// Notes that this replica's state is as fresh as the leader's at the last Heartbeat,
+    /// once it has applied as many entries as the leader had then.
+    fn update_caught_up(&mut self) {
+        if let Some((applied, received)) = self.leader_applied {
+            if self.apply_index >= applied {
+                self.caught_up_at = Some(received);
+            }
+        }
+    }
+
+    /// Discards the values of all entries covered by the snapshot, which are all chosen,
+    /// except for those still retained for subscribers.
+    /// The memberships stored in these entries are replaced by the snapshot's membership.
+    fn truncate_log(&mut self) {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) if self.snapshot_index > 0 => snapshot,
+            _ => return,
+        };
+        let retained_index = self.retain_from();
+        for (index, entry) in self.log[..self.snapshot_index].iter_mut().enumerate() {
+            if index < retained_index {
+                entry.value = None;
+            }
+            entry.acceptances.clear();
+            entry.chosen = true;
+        }
+        self.configs = self.configs.split_off(&self.snapshot_index);
+        self.configs.insert(self.snapshot_index - 1, snapshot.membership.clone());
+        self.applied_results = self.applied_results.split_off(&retained_index);
+        self.retained_index = retained_index;
+    }
+
+    /// Discards all entries of the log archive from index `len` onwards, as they are left over
+    /// from an earlier run, unless this was done already.
+    fn truncate_archive(&mut self, len: usize) -> Result<(), StorageError> {
+        match &mut self.archive {
+            Some(archive) if !self.archive_truncated => {
+                archive.truncate(len)?;
+                self.archive_truncated = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Puts the values spilled to the log archive back into the `log` recovered from the state
+    /// file, from index `from` onwards, so that they are applied again.
+    /// Entries beyond the recovered log are left over from before a crash and discarded.
+    fn restore_spilled(
+        &mut self,
+        log: &mut [LogEntry<V>],
+        from: usize,
+    ) -> Result<(), StorageError> {
+        self.truncate_archive(log.len())?;
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return Ok(()),
+        };
+        let spilled = archive.range(from..archive.len())?;
+        for (entry, spilled) in log[from..].iter_mut().zip(spilled) {
+            if entry.chosen && entry.value.is_none() {
+                entry.value = spilled.value;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the values of all applied entries but the last window to the log archive, if there
+    /// is one, see `with_log_archive`. Entries retained for subscribers stay in memory.
+    /// The values are only dropped once they are synced to disk. If that fails, spilling stops and
+    /// all further entries are kept in memory.
+    fn spill_log(&mut self) {
+        let window = match &self.log_archive {
+            Some((_, window)) => *window,
+            None => return,
+        };
+        let mut until = self.apply_index.saturating_sub(window);
+        if self.subscriber_retention.is_some() {
+            until = self
+                .subscribers
+                .iter()
+                .map(|(_, state)| state.unacked.load(Ordering::Relaxed))
+                .fold(until, usize::min);
+        }
+        if until <= self.spilled_index {
+            return;
+        }
+        let result = self.truncate_archive(0).and_then(|()| {
+            let archive = self.archive.as_mut().unwrap();
+            self.log[archive.len().min(until)..until]
+                .iter()
+                .try_for_each(|entry| archive.append(entry))
+                .and_then(|()| archive.sync())
+        });
+        if let Err(e) = result {
+            error!("Failed to spill log entries ({}), keeping them in memory.", e);
+            self.log_archive = None;
+            return;
+        }
+        trace!("Spilled log entries {} to {}.", self.spilled_index, until);
+        for entry in &mut self.log[self.spilled_index..until] {
+            entry.value = None;
+            entry.acceptances.clear();
+        }
+        self.applied_results = self.applied_results.split_off(&until);
+        self.spilled_index = until;
+    }
+
+    /// Reads the entries in the range which were spilled to the log archive back from disk.
+    /// Entries which are still in memory are left out.
+    fn read_spilled(&self, range: Range<usize>) -> Vec<LogEntry<V>> {
+        let end = range.end.min(self.spilled_index);
+        match &self.archive {
+            Some(archive) if range.start < end => {
+                archive.range(range.start..end).unwrap_or_else(|e| {
+                    error!("Failed to read spilled log entries: {}", e);
+                    Vec::new()
+                })
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The first log entry which has to be kept for subscribers, or the snapshot's index if
+    /// none has to be kept.
+    fn retain_from(&self) -> usize {
+        if self.subscriber_retention.is_none() {
+            return self.snapshot_index;
+        }
+        self.subscribers
+            .iter()
+            .map(|(_, state)| state.unacked.load(Ordering::Relaxed))
+            .fold(self.snapshot_index, usize::min)
+    }
+
+    /// Discards the entries retained for subscribers beyond the snapshot once all of them have
+    /// acknowledged these entries, or have been dropped.
+    fn release_acknowledged(&mut self) {
+        if self.retained_index < self.snapshot_index && self.retain_from() > self.retained_index {
+            debug!("Releasing entries retained for subscribers before {}.", self.retain_from());
+            self.truncate_log();
+        }
+    }
+
+    /// Signals the end of draining, once all entries in the log have been applied.
+    fn check_drained(&self) {
+        if let Some(drained) = &self.drained {
+            if self.apply_index == self.log.len() && !drained.load(Ordering::Relaxed) {
+                info!("Draining finished at index {}.", self.apply_index);
+                drained.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Stores the value in the log at `index`, keeping track of the memberships in the log.
+    /// Replicas joining the group with a new membership become known as peers right away.
+    fn set_value(&mut self, index: usize, value: Command<V>) {
+        self.configs.remove(&index);
+        if let Command::Reconfigure(membership) = &value {
+            let nodes: Vec<NodeId> = membership.nodes().copied().collect();
+            if self.role == Role::Learner && nodes.contains(&self.node_id) {
+                info!("Promoted from learner to voter.");
+                self.role = Role::Voter;
+            }
+            self.node.discover(&nodes);
+            self.configs.insert(index, membership.clone());
+        }
+        self.log[index].value = Some(value);
+    }
+
+    /// The membership which governs the log entry at `index`.
+    fn membership_for(&self, index: usize) -> &Membership {
+        self.configs
+            .range(..index)
+            .next_back()
+            .map_or(&self.initial_membership, |(_, membership)| membership)
+    }
+
+    /// Whether this replica is the only member of its group, i.e. it forms a quorum on its own.
+    fn is_single_node(&self) -> bool {
+        let membership = self.latest_membership();
+        membership.next.is_none() && membership.current == [self.node_id]
+    }
+
+    /// The most recent membership this replica knows about, used when running for leader.
+    fn latest_membership(&self) -> &Membership {
+        self.membership_for(usize::MAX)
+    }
+
+    /// Whether another replica may refer to the entry at `index`, see `with_max_index_gap`.
+    fn in_reach(&self, index: usize) -> bool {
+        index.saturating_sub(self.log.len()) < self.max_index_gap
+    }
+
+    fn get_accepted_values_iter(&self) -> impl Iterator<Item = (usize, Ballot, &Command<V>)> {
+        self
+            .log
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| i.value.is_some())
+            .map(|(index, entry)| (index, entry.accepted_ballot, entry.value.as_ref().unwrap()))
+    }
+}
+
+/// Ticks all replicas in turn until none of them has messages to handle or work which is due,
+/// or `max_steps` rounds have passed. Returns whether the replicas became idle in time.
+///
+/// Meant for tests over a `MemoryNetwork` with a `ManualClock`, where this makes progress
+/// deterministic: all messages are delivered right away, and timers only fire once the clock
+/// is advanced. Messages delayed beyond the clock's current time don't count as pending.
+pub fn run_until_idle<V: crate::AppCommand>(
+    replicas: &mut [PaxosReplica<V>],
+    max_steps: usize,
+) -> bool {
+    for _ in 0..max_steps {
+        let mut busy = false;
+        for replica in replicas.iter_mut() {
+            busy |= replica.tick().is_zero();
+        }
+        if !busy && !replicas.iter().any(|replica| replica.node.wait(Duration::ZERO)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Applies the chosen entries at the start of a log to the state machine, in order and without
+/// any networking, e.g. to reconstruct a replica's state offline from its persisted log.
+/// Returns the results of all commands together with their log index.
+///
+/// Replay ends at the first entry which isn't chosen. NoOps and membership changes are skipped,
+/// as they don't affect the state machine, while batches are applied all or nothing, as usual.
+/// Resubmissions of a tracked command within the entries are skipped as well, remembering as
+/// many clients as replicas do by default, see `AppliedRequests`.
+/// Entries covered by a snapshot no longer hold their value, so a replica's snapshot has to be
+/// restored first and replay started from its `snapshot_index`.
+pub fn replay_log<S: ReplicatedStateMachine>(
+    entries: &[LogEntry<S::Command>],
+    sm: &mut S,
+) -> Vec<(usize, Result<String, ()>)> {
+    let mut requests = AppliedRequests::new();
+    let chosen = entries.iter().take_while(|entry| entry.chosen);
+    let values = chosen.enumerate().filter_map(|(i, entry)| Some((i, entry.value.as_ref()?)));
+    values
+        .filter(|(_, value)| record_request(&mut requests, MAX_TRACKED_CLIENTS, value))
+        .flat_map(|(index, value)| execute(sm, value).into_iter().map(move |r| (index, r)))
+        .collect()
+}
+
+/// Records a tracked command's request as applied, remembering up to `capacity` clients.
+/// Returns false if it has been applied before, in which case the command is a resubmission and
+/// must be skipped. Other values are never skipped.
+fn record_request<V>(requests: &mut AppliedRequests, capacity: usize, value: &Command<V>) -> bool {
+    match value {
+        Command::Tracked(id, _) => requests.record(*id, capacity),
+        _ => true,
+    }
+}
+
+/// Sends the commands of an applied entry with their results to all subscribers, dropping those
+/// which have hung up, or which would leave more than `retention` entries unacknowledged.
+fn publish<V: Clone>(
+    subscribers: &mut Vec<Subscriber<V>>,
+    retention: Option<usize>,
+    index: usize,
+    value: &Command<V>,
+    results: &[Result<String, ()>],
+) {
+    subscribers.retain(|(sender, state)| {
+        let unacked = state.unacked.load(Ordering::Relaxed);
+        if retention.is_some_and(|max| index.saturating_sub(unacked) >= max) {
+            error!("Dropping subscriber lagging behind at index {}.", unacked);
+            state.dropped.store(true, Ordering::Relaxed);
+            return false;
+        }
+        value
+            .commands()
+            .iter()
+            .zip(results)
+            .all(|(cmd, result)| sender.send((index, cmd.clone(), result.clone())).is_ok())
+    });
+}
+
+/// Applies the value of a chosen entry to the state machine and returns the results of its
+/// commands. If a batch fails, all of its commands are reported as failed.
+fn execute<S>(sm: &mut S, value: &Command<S::Command>) -> Vec<Result<String, ()>>
+where
+    S: ReplicatedStateMachine + ?Sized,
+{
+    match value {
+        Command::App(cmd) | Command::Tracked(_, cmd) | Command::Expiring { value: cmd, .. } => {
+            vec![sm.execute(cmd.clone())]
+        }
+        Command::Batch(cmds) => match sm.execute_batch(cmds.clone()) {
+            Ok(results) => results.into_iter().map(Ok).collect(),
+            Err(()) => vec![Err(()); cmds.len()],
+        },
+        Command::Reconfigure(_) | Command::NoOp => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::codec::CodecError;
+    use crate::leader_policy::PreferNode;
+    use crate::network::{MemoryNetwork, MessageCounts};
+    use crate::udp_network::UdpNetworkNode;
+    use crate::SnapshotWriter;
+    use std::convert::TryInto;
+    use std::sync::Mutex;
+
+    /// The IDs of in-memory nodes with the given numbers, e.g. to list a group's members.
+    fn nodes(ids: &[usize]) -> Vec<NodeId> {
+        ids.iter().copied().map(NodeId).collect()
+    }
+
+    /// Creates replicas for a group of `size` members, all connected to each other.
+    /// Additionally creates `spare` replicas, which know the group but aren't members yet.
+    fn create_group(size: usize, spare: usize) -> Vec<PaxosReplica<u32>> {
+        let nodes: Vec<_> = (0..size + spare)
+            .map(|_| UdpNetworkNode::<u32>::new())
+            .collect();
+        let node_ids: Vec<NodeId> = nodes.iter().map(|n| n.id()).collect();
+        nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let members = if i < size { &node_ids[..size] } else { &node_ids };
+                let node_id = node.id();
+                PaxosReplica::with_members(node, node_id, members.to_vec(), Counter::default())
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Creates in-memory replicas with the given IDs, which form a group and share a manually
+    /// advanced clock. Each replica is passed through `configure`, e.g. to enable a feature.
+    fn clocked_group(
+        ids: &[usize],
+        configure: impl Fn(PaxosReplica<u32>) -> PaxosReplica<u32>,
+    ) -> (Arc<ManualClock>, MemoryNetwork<u32>, Vec<PaxosReplica<u32>>) {
+        let clock = Arc::new(ManualClock::new());
+        let network = MemoryNetwork::with_clock(clock.clone());
+        let replicas = ids
+            .iter()
+            .map(|&id| {
+                let node = network.connect(NodeId(id));
+                let replica =
+                    PaxosReplica::with_members(node, NodeId(id), nodes(ids), Counter::default())
+                        .unwrap();
+                configure(replica.with_clock(clock.clone()))
+            })
+            .collect();
+        (clock, network, replicas)
+    }
+
+    /// State machine summing up all commands, whose sum any query reads.
+    #[derive(Default)]
+    struct Counter(u32);
+
+    impl ReplicatedStateMachine for Counter {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            self.0 += v;
+            Ok(self.0.to_string())
+        }
+
+        fn query(&self, _: &u32) -> Result<String, ()> {
+            Ok(self.0.to_string())
+        }
+
+        fn snapshot(&self) -> Option<Vec<u8>> {
+            Some(self.0.to_le_bytes().to_vec())
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), ()> {
+            self.0 = u32::from_le_bytes(snapshot.try_into().map_err(|_| ())?);
+            Ok(())
+        }
+    }
+
+    /// State machine recording all executed commands in a log shared with the test.
+    struct Recorder(Arc<Mutex<Vec<u32>>>);
+
+    impl ReplicatedStateMachine for Recorder {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            let mut executed = self.0.lock().unwrap();
+            executed.push(v);
+            Ok(executed.len().to_string())
+        }
+    }
+
+    /// Account balance, which only accepts deposits.
+    #[derive(Default)]
+    struct Bank(i64);
+
+    impl ReplicatedStateMachine for Bank {
+        type Command = i64;
+
+        fn execute(&mut self, amount: i64) -> Result<String, ()> {
+            self.0 += amount;
+            Ok(self.0.to_string())
+        }
+
+        fn query(&self, _: &i64) -> Result<String, ()> {
+            Ok(self.0.to_string())
+        }
+
+        fn validate(&self, amount: &i64) -> Result<(), String> {
+            if *amount < 0 {
+                return Err(format!("negative deposit: {}", amount));
+            }
+            Ok(())
+        }
+    }
+
+    /// Change of an account's balance by the given amount.
+    #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Posting(u8, i64);
+
+    impl crate::AppCommand for Posting {}
+
+    /// Balances of several accounts, none of which may be overdrawn.
+    #[derive(Default)]
+    struct Accounts(BTreeMap<u8, i64>);
+
+    impl ReplicatedStateMachine for Accounts {
+        type Command = Posting;
+
+        fn execute(&mut self, Posting(account, amount): Posting) -> Result<String, ()> {
+            let balance = self.0.entry(account).or_default();
+            if *balance + amount < 0 {
+                return Err(());
+            }
+            *balance += amount;
+            Ok(balance.to_string())
+        }
+
+        fn snapshot(&self) -> Option<Vec<u8>> {
+            bincode::serialize(&self.0).ok()
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> Result<(), ()> {
+            self.0 = bincode::deserialize(snapshot).map_err(|_| ())?;
+            Ok(())
+        }
+    }
+
+    /// Key-value store mapping commands to their squares, whose snapshots are written
+    /// only while the `gate` isn't locked.
+    #[derive(Default)]
+    struct GatedStore {
+        entries: HashMap<u32, u64>,
+        gate: Arc<Mutex<()>>,
+    }
+
+    impl ReplicatedStateMachine for GatedStore {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            self.entries.insert(v, u64::from(v) * u64::from(v));
+            Ok(self.entries.len().to_string())
+        }
+
+        fn snapshot_writer(&self) -> Option<SnapshotWriter> {
+            let entries = self.entries.clone();
+            let gate = self.gate.clone();
+            Some(Box::new(move || {
+                let _open = gate.lock().unwrap();
+                bincode::serialize(&entries).ok()
+            }))
+        }
+    }
+
+    /// Lets the replica handle all incoming messages on a separate thread, until `stop` is set.
+    fn spawn_follower(
+        mut replica: PaxosReplica<u32>,
+        stop: Arc<AtomicBool>,
+    ) -> thread::JoinHandle<PaxosReplica<u32>> {
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                if let Ok((src, msg)) = replica.node.recv(Duration::from_millis(10)) {
+                    replica.handle_paxos_message(src, msg);
+                }
+            }
+            replica
+        })
+    }
+
+    /// Delivers messages between the replicas until none are left in flight.
+    fn deliver_all(replicas: &mut [PaxosReplica<u32>]) {
+        loop {
+            let mut delivered = false;
+            for replica in replicas.iter_mut() {
+                while let Ok((src, msg)) = replica.node.recv(Duration::from_millis(1)) {
+                    replica.handle_paxos_message(src, msg);
+                    delivered = true;
+                }
+            }
+            if !delivered {
+                return;
+            }
+        }
+    }
+
+    /// Delivers messages among the replicas like `deliver_all`, but drops those from any other
+    /// replica, as if the network were partitioned.
+    fn deliver_partitioned(replicas: &mut [PaxosReplica<u32>]) {
+        let ids: Vec<_> = replicas.iter().map(|r| r.id()).collect();
+        loop {
+            let mut delivered = false;
+            for replica in replicas.iter_mut() {
+                while let Ok((src, msg)) = replica.node.recv(Duration::from_millis(1)) {
+                    if ids.contains(&src) {
+                        replica.handle_paxos_message(src, msg);
+                        delivered = true;
+                    }
+                }
+            }
+            if !delivered {
+                return;
+            }
+        }
+    }
+
+    /// Collects the values chosen in the replica's log, indexed by their position in the log.
+    fn chosen_values(replica: &PaxosReplica<u32>) -> Vec<(usize, Command<u32>)> {
+        replica
+            .log
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.chosen)
+            .map(|(i, entry)| (i, entry.value.clone().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn joint_consensus_reconfiguration() {
+        let mut replicas = create_group(3, 2);
+        let all_ids: Vec<NodeId> = replicas.iter().map(|r| r.node_id).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_leader());
+
+        // submit values while the reconfiguration is in flight
+        for v in 0..10 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        replicas[0].reconfigure(all_ids.clone()).unwrap();
+        assert_eq!(
+            replicas[0].reconfigure(all_ids.clone()),
+            Err(PaxosError::ReconfigurationInProgress)
+        );
+        for v in 10..20 {
+            replicas[0].submit_value(v).unwrap();
+            if v % 3 == 0 {
+                deliver_all(&mut replicas);
+            }
+        }
+        deliver_all(&mut replicas);
+        for v in 20..30 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        let mut expected = all_ids;
+        expected.sort_unstable();
+        assert_eq!(replicas[0].latest_membership(), &Membership::new(expected));
+
+        // all values are chosen exactly once and in the order they were submitted
+        let leader_log = chosen_values(&replicas[0]);
+        assert_eq!(leader_log.len(), replicas[0].log.len());
+        let app_values: Vec<u32> = leader_log
+            .iter()
+            .filter_map(|(_, value)| match value {
+                Command::App(v) => Some(*v),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(app_values, (0..30).collect::<Vec<_>>());
+
+        // no replica, old or new, disagrees with the leader about any chosen value
+        for replica in &replicas[1..] {
+            for (index, value) in chosen_values(replica) {
+                assert_eq!(leader_log[index].1, value);
+            }
+        }
+        // the new members took part in reaching consensus on the latest values
+        for replica in &replicas[3..] {
+            assert!(chosen_values(replica).iter().any(|(i, _)| *i == leader_log.len() - 1));
+        }
+    }
+
+    #[test]
+    fn create_with_members() {
+        let nodes: Vec<_> = (0..5).map(|_| UdpNetworkNode::<u32>::new()).collect();
+        let mut ids: Vec<NodeId> = nodes.iter().map(|n| n.id()).collect();
+        let node = UdpNetworkNode::<u32>::new();
+        let node_id = node.id();
+        let replica =
+            PaxosReplica::with_members(node, node_id, ids.clone(), Counter::default()).unwrap();
+
+        ids.sort_unstable();
+        assert_eq!(replica.node.peers(), ids);
+        ids.push(node_id);
+        ids.sort_unstable();
+        assert_eq!(replica.initial_membership, Membership::new(ids.clone()));
+        assert!(!replica.latest_membership().is_quorum(ids[..3].iter()));
+        assert!(replica.latest_membership().is_quorum(ids[..4].iter()));
+    }
+
+    #[test]
+    fn seed_initial_log() {
+        let network = MemoryNetwork::<u32>::new();
+        let genesis = vec![1, 2, 3];
+        let mut replicas: Vec<_> = (0..3)
+            .map(|id| {
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[0, 1, 2]),
+                    Counter::default(),
+                )
+                .unwrap()
+                .with_initial_log(genesis.clone())
+            })
+            .collect();
+        for replica in &replicas {
+            assert_eq!(replica.committed_len(), 3);
+            assert_eq!(replica.apply_index, 3);
+            assert_eq!(replica.history().map(|(_, &v)| v).collect::<Vec<_>>(), genesis);
+        }
+
+        // the group continues where the initial log ends
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(4).unwrap();
+        deliver_all(&mut replicas);
+        let expected = chosen_values(&replicas[0]);
+        for replica in &mut replicas {
+            assert_eq!(chosen_values(replica), expected);
+            assert_eq!(replica.history().last(), Some((expected.len() - 1, &4)));
+            assert_eq!(replica.state_machine.query(&0), Ok("10".to_owned()));
+        }
+    }
+
+    #[test]
+    fn single_node_group() {
+        let mut replica = create_group(1, 0).remove(0);
+        assert!(replica.node.peers().is_empty());
+
+        // values submitted before the first tick wait until the replica has elected itself
+        replica.submit_value(1).unwrap();
+        assert!(!replica.is_leader());
+        assert_eq!(replica.tick(), POLL_INTERVAL);
+        assert!(replica.is_leader());
+        assert_eq!(replica.committed_len(), 2);
+
+        // afterwards, values are chosen and applied right away
+        for v in 2..=10 {
+            replica.submit_value(v).unwrap();
+            assert_eq!(replica.committed_len(), v as usize + 1);
+        }
+        let values = (1..=10).map(|v| (v as usize, Command::App(v)));
+        let expected: Vec<_> = std::iter::once((0, Command::NoOp)).chain(values).collect();
+        assert_eq!(chosen_values(&replica), expected);
+        assert_eq!(replica.state_machine.query(&0), Ok("55".to_owned()));
+
+        // the replica stays leader without ever extending its lease
+        let ballot = replica.highest_promised;
+        let clock = Arc::new(ManualClock::new());
+        let mut replica = replica.with_clock(clock.clone());
+        clock.advance(10 * LEASE_DURATION);
+        assert_eq!(replica.tick(), POLL_INTERVAL);
+        assert!(replica.is_leader());
+        assert_eq!(replica.highest_promised, ballot);
+        assert_eq!(replica.linearizable_read(0), Ok("55".to_owned()));
+
+        // nothing was ever sent, not even to the replica itself
+        assert!(replica.node.try_recv().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn group_size_mismatch() {
+        let peers: Vec<_> = (0..2).map(|_| UdpNetworkNode::<u32>::new()).collect();
+        let peer_ids: Vec<NodeId> = peers.iter().map(|n| n.id()).collect();
+        let create_node = || {
+            let mut node = UdpNetworkNode::<u32>::new();
+            node.discover(&peer_ids);
+            node
+        };
+
+        let node = create_node();
+        let node_id = node.id();
+        let result = PaxosReplica::new(node, node_id, 5, Counter::default());
+        assert_eq!(
+            result.unwrap_err(),
+            PaxosError::GroupSizeMismatch {
+                expected: 5,
+                known: 3
+            }
+        );
+        let node = create_node();
+        let node_id = node.id();
+        assert!(PaxosReplica::new(node, node_id, 3, Counter::default()).is_ok());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn reject_empty_group() {
+        let expected = PaxosError::InvalidConfig("group size must be at least 1".to_owned());
+        let node = UdpNetworkNode::<u32>::new();
+        let node_id = node.id();
+        // this is reported instead of the mismatch with the known replicas
+        let result = PaxosReplica::new(node, node_id, 0, Counter::default());
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn reject_own_id_among_peers() {
+        let network = MemoryNetwork::<u32>::new();
+        let expected = PaxosError::InvalidConfig("node id 2 appears among its own peers".into());
+        let mut node = network.connect(NodeId(1));
+        node.discover(&nodes(&[2, 3]));
+        let result = PaxosReplica::new(node, NodeId(2), 3, Counter::default());
+        assert_eq!(result.unwrap_err(), expected);
+        let node = network.connect(NodeId(1));
+        let result =
+            PaxosReplica::with_members(node, NodeId(2), nodes(&[1, 2, 3]), Counter::default());
+        assert_eq!(result.unwrap_err(), expected);
+    }
+
+    #[test]
+    fn flag_replicas_sharing_an_id() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=2)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                    .unwrap()
+            })
+            .collect();
+
+        // the third replica is misconfigured to use the ID of the first one, which is rejected,
+        // unless its node only learns about the first replica after it was created
+        let node = network.connect(NodeId(3));
+        let result =
+            PaxosReplica::with_members(node, NodeId(1), nodes(&[1, 2, 3]), Counter::default());
+        assert!(matches!(result, Err(PaxosError::InvalidConfig(_))));
+        let node = network.connect(NodeId(3));
+        let mut impostor =
+            PaxosReplica::with_members(node, NodeId(1), nodes(&[2, 3]), Counter::default())
+                .unwrap();
+        impostor.node.discover(&nodes(&[1]));
+        replicas.push(impostor);
+
+        // its Prepare carries a ballot only the first replica should be able to generate
+        replicas[2].start_election();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].metrics().id_conflicts > 0);
+        assert_eq!(replicas[1].metrics().id_conflicts, 0);
+
+        // a correctly configured group never reports a conflict
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        assert!(replicas.iter().all(|r| r.metrics().id_conflicts == 0));
+    }
+
+    #[test]
+    fn drain_finishes_in_flight_proposals() {
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        replicas[0].submit_value(2).unwrap();
+
+        let drained = replicas[0].start_drain();
+        assert!(!drained.load(Ordering::Relaxed));
+        assert_eq!(replicas[0].submit_value(3), Err(PaxosError::Draining));
+        replicas[1].submit_value(4).unwrap();
+        deliver_all(&mut replicas);
+
+        assert!(drained.load(Ordering::Relaxed));
+        assert!(replicas[0].is_leader());
+        let expected = vec![(0, Command::NoOp), (1, Command::App(1)), (2, Command::App(2))];
+        for replica in &replicas {
+            assert_eq!(chosen_values(replica), expected);
+        }
+        assert_eq!(replicas[0].log.len(), 3);
+    }
+
+    #[test]
+    fn promote_learner_once_caught_up() {
+        let network = MemoryNetwork::<u32>::new();
+        let voters = nodes(&[1, 2, 3]);
+        let create = |id| {
+            let node = network.connect(NodeId(id));
+            PaxosReplica::with_members(node, NodeId(id), voters.clone(), Counter::default())
+                .unwrap()
+        };
+        // ordered so that the leader and learner can be cut off from the others
+        let learner = create(4).with_role(Role::Learner);
+        let mut replicas = vec![create(1), learner, create(2), create(3)];
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=20 {
+            replicas[0].submit_value(v).unwrap();
+            deliver_all(&mut replicas);
+        }
+        assert_eq!(replicas[1].committed_len(), 0);
+
+        // the learner catches up, but can't be promoted before the leader hears about it
+        replicas[0].add_learner(NodeId(4)).unwrap();
+        assert_eq!(
+            replicas[0].promote(NodeId(4)),
+            Err(PaxosError::LearnerBehind { applied: 0, leader: 21 })
+        );
+        deliver_all(&mut replicas);
+        assert_eq!(chosen_values(&replicas[1]), chosen_values(&replicas[0]));
+
+        // the learner doesn't vote, so the leader can't reach a quorum with it alone
+        replicas[0].submit_value(21).unwrap();
+        deliver_all(&mut replicas[..2]);
+        assert!(!replicas[0].log[21].chosen);
+        deliver_all(&mut replicas);
+        assert!(replicas[0].log[21].chosen);
+
+        // once it reported its progress, it becomes a voter counting toward quorums
+        replicas[0].send_heartbeats();
+        deliver_all(&mut replicas);
+        replicas[0].promote(NodeId(4)).unwrap();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[1].role, Role::Voter);
+        assert_eq!(replicas[0].latest_membership(), &Membership::new(nodes(&[1, 2, 3, 4])));
+        replicas[0].submit_value(22).unwrap();
+        deliver_all(&mut replicas[..3]);
+        let index = replicas[0].log.len() - 1;
+        assert!(replicas[0].log[index].chosen);
+        assert!(replicas[0].log[index].acceptances.contains(&NodeId(4)));
+    }
+
+    #[test]
+    fn demote_voter_to_learner() {
+        let network = MemoryNetwork::<u32>::new();
+        let voters = nodes(&[1, 2, 3, 4, 5]);
+        let mut replicas: Vec<_> = (1..=5)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), voters.clone(), Counter::default())
+                    .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].demote(NodeId(1)).is_err());
+        replicas[0].demote(NodeId(3)).unwrap();
+        deliver_all(&mut replicas);
+        let not_voter = PaxosError::InvalidConfig("3 isn't a voter".to_owned());
+        assert_eq!(replicas[0].demote(NodeId(3)), Err(not_voter));
+        for replica in &replicas {
+            assert_eq!(replica.latest_membership(), &Membership::new(nodes(&[1, 2, 4, 5])));
+        }
+        assert_eq!(replicas[2].role, Role::Learner);
+        assert!(replicas.iter().filter(|r| r.node_id != NodeId(3)).all(|r| r.role == Role::Voter));
+
+        // three of five replicas no longer form a quorum, as only two of four voters accept
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas[..3]);
+        let index = replicas[0].log.len() - 1;
+        assert!(!replicas[0].log[index].chosen);
+        deliver_all(&mut replicas);
+        assert!(replicas[0].log[index].chosen);
+        assert!(!replicas[0].log[index].acceptances.contains(&NodeId(3)));
+
+        // the learner still applies all chosen values
+        assert_eq!(replicas[2].apply_index, replicas[0].apply_index);
+        assert_eq!(replicas[2].state_machine.query(&0), Ok("1".to_owned()));
+    }
+
+    #[test]
+    fn learners_follow_without_voting() {
+        let clock = Arc::new(ManualClock::new());
+        let mut replicas = create_group(3, 0);
+        let mut voters: Vec<NodeId> = replicas.iter().map(|r| r.node_id).collect();
+        voters.sort_unstable();
+        let learners: Vec<_> = (0..2).map(|_| UdpNetworkNode::<u32>::new()).collect();
+        let learner_ids: Vec<NodeId> = learners.iter().map(|n| n.id()).collect();
+        replicas = replicas
+            .into_iter()
+            .map(|r| r.with_learners(&learner_ids).with_clock(clock.clone()))
+            .collect();
+        for node in learners {
+            let node_id = node.id();
+            let learner =
+                PaxosReplica::with_members(node, node_id, voters.clone(), Counter::default())
+                    .unwrap()
+                    .with_role(Role::Learner)
+                    .with_clock(clock.clone());
+            assert_eq!(learner.latest_membership(), &Membership::new(voters.clone()));
+            replicas.push(learner);
+        }
+
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=5 {
+            replicas[3].submit_value(v).unwrap();
+            deliver_all(&mut replicas);
+        }
+        let expected = chosen_values(&replicas[0]);
+        assert_eq!(expected.len(), 6);
+        for replica in &replicas {
+            assert_eq!(chosen_values(replica), expected);
+            assert_eq!(replica.committed_len(), 6);
+        }
+
+        // learners never run for leader
+        clock.advance(10 * LEASE_DURATION);
+        let ballot = replicas[3].highest_promised;
+        replicas[3].tick();
+        replicas[4].tick();
+        assert_eq!(replicas[3].highest_promised, ballot);
+        assert!(!replicas[3].is_leader() && !replicas[4].is_leader());
+
+        // learners don't help the leader reach a quorum once two voters are gone
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        replicas.drain(1..3);
+        replicas[0].submit_value(6).unwrap();
+        deliver_all(&mut replicas);
+        assert!(!replicas[0].log[6].chosen);
+        for replica in &replicas {
+            assert_eq!(replica.committed_len(), 6);
+        }
+    }
+
+    #[test]
+    fn history_of_applied_commands() {
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in [3, 1, 4, 1, 5] {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        // chosen, but not applied yet, as the entry before is still missing
+        let ballot = replicas[1].highest_promised;
+        let leader = replicas[0].node_id;
+        replicas[1].handle_learn(leader, 7, ballot, Command::App(9));
+        assert!(replicas[1].log[7].chosen);
+
+        let expected = vec![(1, &3), (2, &1), (3, &4), (4, &1), (5, &5)];
+        for replica in &replicas {
+            assert_eq!(replica.history().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn stream_applied_commands() {
+        let mut replicas = create_group(3, 0);
+        let live = replicas[1].subscribe(0).unwrap();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // later subscribers get the commands applied so far replayed first
+        let replayed = replicas[1].subscribe(0).unwrap();
+        let tail = replicas[1].subscribe(3).unwrap();
+        replicas[0].submit_batch(vec![4, 5]).unwrap();
+        deliver_all(&mut replicas);
+        let expected: Vec<_> = [(1, 1, 1), (2, 2, 3), (3, 3, 6), (4, 4, 10), (4, 5, 15)]
+            .iter()
+            .map(|&(index, v, sum)| (index, v, Ok(sum.to_string())))
+            .collect();
+        assert_eq!(live.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(replayed.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(tail.try_iter().collect::<Vec<_>>(), expected[2..]);
+
+        // commands replaced by the snapshot can't be streamed anymore
+        assert_eq!(replicas[1].take_snapshot(), Ok(5));
+        let truncated = replicas[1].subscribe(4).err();
+        assert_eq!(truncated, Some(PaxosError::Truncated { snapshot_index: 5 }));
+        assert_eq!(replicas[1].subscribe(5).unwrap().try_iter().count(), 0);
+    }
+
+    #[test]
+    fn retain_entries_for_slow_subscriber() {
+        let mut replicas = create_group(3, 0);
+        replicas[1] = replicas.remove(1).with_subscriber_retention(3);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let slow = replicas[1].subscribe(1).unwrap();
+        replicas[0].submit_value(1).unwrap();
+        replicas[0].submit_value(2).unwrap();
+        deliver_all(&mut replicas);
+
+        // the snapshot doesn't discard the entries the subscriber hasn't acknowledged yet
+        assert_eq!(replicas[1].take_snapshot(), Ok(3));
+        assert_eq!(slow.try_iter().count(), 2);
+        let replayed = replicas[1].subscribe(1).unwrap();
+        assert_eq!(replayed.try_iter().map(|(index, ..)| index).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(replayed.ack(2), Ok(()));
+        assert_eq!(slow.ack(1), Ok(()));
+        replicas[1].tick();
+        assert_eq!(replicas[1].retained_index, 2);
+        let truncated = replicas[1].subscribe(1).err();
+        assert_eq!(truncated, Some(PaxosError::Truncated { snapshot_index: 2 }));
+
+        // entries 2 to 4 are retained, but the subscriber is dropped before entry 5
+        for v in 3..=5 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[1].apply_index, 6);
+        assert_eq!(slow.try_iter().map(|(index, ..)| index).collect::<Vec<_>>(), [3, 4]);
+        assert!(slow.try_recv().is_err());
+        assert_eq!(slow.ack(4), Err(PaxosError::SubscriberLagging { acked: 2 }));
+        assert_eq!(replicas[1].subscribers.len(), 1);
+
+        // the entries only the dropped subscriber hadn't acknowledged are released
+        replicas[1].tick();
+        assert_eq!(replicas[1].retained_index, 3);
+        assert!(replicas[1].log[2].value.is_none());
+    }
+
+    #[test]
+    fn drop_commands_past_deadline() {
+        let (clock, network, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let client = network.connect(NodeId(9));
+        let request = |value, timeout| {
+            let deadline = clock.system_time() + Duration::from_millis(timeout);
+            let deadline = deadline.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            PaxosMsg::ClientRequest { value, hops: 0, priority: 0, deadline: Some(deadline) }
+        };
+
+        // the leader proposes both commands, which are applied although it stalls until the
+        // first one's deadline passed, as the deadline only matters until a command is proposed
+        client.send(NodeId(1), &request(1, 100));
+        client.send(NodeId(1), &request(2, 10_000));
+        while let Ok((src, msg)) = replicas[0].node.try_recv() {
+            replicas[0].handle_message(src, msg);
+        }
+        clock.advance(Duration::from_millis(200));
+        deliver_all(&mut replicas);
+        for replica in &mut replicas {
+            assert!(matches!(
+                chosen_values(replica)[1..],
+                [(1, Command::Expiring { value: 1, .. }), (2, Command::Expiring { value: 2, .. })]
+            ));
+            assert_eq!(replica.state_machine.query(&0), Ok("3".to_owned()));
+        }
+
+        // commands arriving after their deadline aren't proposed at all
+        client.send(NodeId(1), &request(3, 0));
+        clock.advance(Duration::from_millis(1));
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].log.len(), 3);
+    }
+
+    #[test]
+    fn agree_on_expiring_command_despite_clock_skew() {
+        let (clock, network, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        // the second replica's wall clock is an hour ahead of the others
+        let skewed = Arc::new(ManualClock::new());
+        skewed.set_system_time(clock.system_time() + Duration::from_secs(3600));
+        let second = replicas.remove(1).with_clock(skewed.clone());
+        replicas.insert(1, second);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let client = network.connect(NodeId(9));
+        let deadline = clock.system_time() + Duration::from_millis(100);
+        let deadline = deadline.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let request = PaxosMsg::ClientRequest {
+            value: 5,
+            hops: 0,
+            priority: 0,
+            deadline: Some(deadline),
+        };
+
+        // the leader proposes in time, but fails once the second replica accepted,
+        // while the Propose to the third one is lost
+        client.send(NodeId(1), &request);
+        while let Ok((src, msg)) = replicas[0].node.try_recv() {
+            replicas[0].handle_message(src, msg);
+        }
+        while let Ok((src, msg)) = replicas[1].node.try_recv() {
+            replicas[1].handle_message(src, msg);
+        }
+        while replicas[2].node.try_recv().is_ok() {}
+        let failed = replicas.remove(0);
+        while failed.node.try_recv().is_ok() {}
+
+        // the new leader is past the deadline by its clock, but still applies the command
+        clock.advance(LEASE_DURATION * 2);
+        skewed.advance(LEASE_DURATION * 2);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for replica in &replicas {
+            assert_eq!(replica.state_machine.query(&0), Ok("5".to_owned()));
+        }
+
+        // and so does the old leader once it is back, whose clock says the deadline hasn't passed
+        replicas.insert(0, failed);
+        replicas[1].start_election();
+        deliver_all(&mut replicas);
+        for replica in &mut replicas {
+            let expected = (1, Command::Expiring { deadline, value: 5 });
+            assert_eq!(chosen_values(replica)[1..2], [expected]);
+            assert_eq!(replica.state_machine.query(&0), Ok("5".to_owned()));
+        }
+    }
+
+    #[test]
+    fn take_all_timestamps_from_injected_clock() {
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_secs(3600));
+        let replica = create_group(1, 0).remove(0).with_clock(clock.clone());
+        let now = clock.now();
+        assert_eq!(replica.epoch, now);
+        assert_eq!(replica.leader_lease_start, now);
+        assert_eq!(replica.last_election, now);
+        assert_eq!(replica.heartbeat_sent, now);
+        assert_eq!(replica.pending_accepts_since, now);
+    }
+
+    #[test]
+    fn measure_round_trip_times() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[1, 2, 3], |r| r.with_latency_tracking());
+        network.set_delay(NodeId(3), Duration::from_millis(50));
+
+        replicas[0].start_election();
+        for v in 1..=20 {
+            replicas[0].submit_value(v).unwrap();
+            for _ in 0..10 {
+                clock.advance(Duration::from_millis(10));
+                for replica in &mut replicas {
+                    while let Ok((src, msg)) = replica.node.try_recv() {
+                        replica.handle_paxos_message(src, msg);
+                    }
+                }
+            }
+        }
+        assert_eq!(replicas[2].committed_len(), 21);
+
+        let rtts = &replicas[0].metrics().round_trip_times;
+        assert!(rtts[&NodeId(3)].mean() >= Duration::from_millis(50));
+        assert!(rtts[&NodeId(3)].mean() > rtts[&NodeId(2)].mean());
+        // followers never send messages with timestamps
+        assert!(replicas[1].metrics().round_trip_times.is_empty());
+    }
+
+    #[test]
+    fn measure_commit_and_apply_latency() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[1, 2, 3], |r| r.with_latency_tracking());
+        network.set_delay(NodeId(2), Duration::from_millis(30));
+        network.set_delay(NodeId(3), Duration::from_millis(30));
+
+        replicas[0].start_election();
+        for v in 1..=20 {
+            replicas[0].submit_value(v).unwrap();
+            for _ in 0..10 {
+                clock.advance(Duration::from_millis(10));
+                for replica in &mut replicas {
+                    while let Ok((src, msg)) = replica.node.try_recv() {
+                        replica.handle_paxos_message(src, msg);
+                    }
+                }
+            }
+        }
+        assert_eq!(replicas[2].committed_len(), 21);
+
+        // the Proposes are delayed, while the state machine applies chosen entries right away
+        let metrics = replicas[0].metrics();
+        assert_eq!(metrics.commit_latency.len(), 21);
+        assert!(metrics.commit_latency.percentile(50.0).unwrap() >= Duration::from_millis(30));
+        assert!(metrics.commit_latency.percentile(99.0).unwrap() <= Duration::from_millis(40));
+        assert_eq!(metrics.apply_latency.percentile(99.0), Some(Duration::from_secs(0)));
+        // followers never propose, but apply what they learn
+        assert!(replicas[1].metrics().commit_latency.is_empty());
+        assert_eq!(replicas[1].metrics().apply_latency.len(), 21);
+    }
+
+    #[test]
+    fn count_messages_in_steady_state() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                    .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        network.reset_message_counts();
+
+        // each value is proposed to both followers, which accept it, and then learned by them
+        for v in 1..=10 {
+            replicas[0].submit_value(v).unwrap();
+            deliver_all(&mut replicas);
+        }
+        assert!(replicas.iter().all(|r| r.committed_len() == 11));
+        let expected = MessageCounts {
+            proposes: 20,
+            accepts: 20,
+            learns: 20,
+            ..MessageCounts::default()
+        };
+        assert_eq!(network.message_counts(), expected);
+    }
+
+    #[test]
+    fn limit_catch_up_responses() {
+        let (clock, network, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        let victim = network.connect(NodeId(9));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        network.reset_message_counts();
+
+        // a request with the forged address of a node outside the group is ignored
+        replicas[0].handle_message(NodeId(9), PaxosMsg::CatchUp { from: 0 });
+        assert_eq!(network.message_counts(), MessageCounts::default());
+        assert!(victim.try_recv().is_err());
+
+        // members are answered, but only once per interval
+        let catch_up = || PaxosMsg::CatchUp { from: 0 };
+        replicas[0].handle_message(NodeId(2), catch_up());
+        assert_eq!(network.message_counts().learns, 4);
+        replicas[0].handle_message(NodeId(2), catch_up());
+        assert_eq!(network.message_counts().learns, 4);
+        clock.advance(CATCH_UP_INTERVAL);
+        replicas[0].handle_message(NodeId(2), catch_up());
+        assert_eq!(network.message_counts().learns, 8);
+    }
+
+    #[test]
+    fn catch_up_in_windows() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                    .unwrap()
+                    .with_catch_up_window(100)
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        // the third replica misses 1000 values
+        for v in 0..1000 {
+            replicas[0].submit_value(v).unwrap();
+            if v % 50 == 49 {
+                deliver_all(&mut replicas[..2]);
+            }
+        }
+        while replicas[2].node.recv(Duration::ZERO).is_ok() {}
+        let chosen_until = replicas[0].apply_index;
+        assert!(chosen_until >= 1000);
+        assert!(replicas[2].apply_index < 10);
+
+        replicas[2].request_catch_up(NodeId(1), chosen_until);
+        let mut batches = 0;
+        while replicas[2].apply_index < chosen_until {
+            network.reset_message_counts();
+            while let Ok((src, msg)) = replicas[0].node.recv(Duration::ZERO) {
+                replicas[0].handle_paxos_message(src, msg);
+            }
+            let learns = network.message_counts().learns;
+            assert!(learns > 0 && learns <= 100, "sent {} values at once", learns);
+            batches += 1;
+            while let Ok((src, msg)) = replicas[2].node.recv(Duration::ZERO) {
+                replicas[2].handle_paxos_message(src, msg);
+            }
+        }
+        assert!(batches >= 10);
+        assert_eq!(replicas[2].state_machine.query(&0), replicas[0].state_machine.query(&0));
+        assert_eq!(replicas[2].catching_up, None);
+    }
+
+    #[test]
+    fn catch_up_from_spilled_log() {
+        let network = MemoryNetwork::<u32>::new();
+        let path = |name: &str| {
+            let path = std::env::temp_dir().join(format!("paxos-spill-{}", name));
+            path.to_str().unwrap().to_owned()
+        };
+        let (archive, file) = (path("archive.bin"), path("state.bin"));
+        for name in [&archive, &format!("{}.idx", archive), &file] {
+            let _ = std::fs::remove_file(name);
+        }
+        let create = |id| {
+            let node = network.connect(NodeId(id));
+            PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                .unwrap()
+        };
+        let mut replicas = vec![
+            create(1).with_log_archive(&archive, 10).unwrap().with_state_file(&file),
+            create(2),
+            create(3),
+        ];
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        // the third replica misses 200 values
+        for v in 0..200 {
+            replicas[0].submit_value(v).unwrap();
+            deliver_all(&mut replicas[..2]);
+        }
+        while replicas[2].node.recv(Duration::ZERO).is_ok() {}
+        let chosen_until = replicas[0].apply_index;
+        assert_eq!(chosen_until, 201);
+        assert_eq!(replicas[0].spilled_index, 191);
+        assert!(replicas[0].log[..191].iter().all(|entry| entry.value.is_none()));
+        assert!(replicas[0].log[191..].iter().all(|entry| entry.value.is_some()));
+
+        replicas[2].request_catch_up(NodeId(1), chosen_until);
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[2].apply_index, chosen_until);
+        assert_eq!(replicas[2].log[150].value, Some(Command::App(149)));
+        let sum = replicas[0].state_machine.query(&0);
+        assert_eq!(replicas[2].state_machine.query(&0), sum);
+
+        // spilled values are applied again after a restart
+        drop(replicas.remove(0));
+        let recovered = create(1).with_log_archive(&archive, 10).unwrap().with_state_file(&file);
+        assert_eq!(recovered.apply_index, chosen_until);
+        assert_eq!(recovered.spilled_index, 191);
+        assert_eq!(recovered.state_machine.query(&0), sum);
+        for name in [&archive, &format!("{}.idx", archive), &file] {
+            std::fs::remove_file(name).unwrap();
+        }
+    }
+
+    #[test]
+    fn fail_to_open_log_archive() {
+        let network = MemoryNetwork::<u32>::new();
+        let dir = std::env::temp_dir().join(format!("paxos-archive-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1]),
+            Counter::default(),
+        )
+        .unwrap()
+        .with_log_archive(dir.to_str().unwrap(), 10);
+        assert!(matches!(replica, Err(PaxosError::Io(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn suppress_heartbeats_to_busy_followers() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[0, 1, 2], |r| r.with_heartbeat_suppression());
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        network.reset_message_counts();
+
+        // a steady stream of Proposes refreshes the followers' leases beyond LEASE_DURATION
+        for v in 0..30 {
+            clock.advance(Duration::from_millis(100));
+            replicas[0].submit_value(v).unwrap();
+            for replica in &mut replicas {
+                replica.tick();
+            }
+            deliver_all(&mut replicas);
+        }
+        let counts = network.message_counts();
+        assert_eq!(counts.proposes, 2 * 30);
+        assert_eq!(counts.heartbeats, 0);
+        assert!(replicas[0].is_leader() && replicas[0].has_quorum_contact());
+        assert!(replicas.iter().all(|replica| replica.current_leader == Some(NodeId(0))));
+
+        // once idle, the leader sends Heartbeats on schedule again
+        network.reset_message_counts();
+        clock.advance(LEASE_DURATION / 2 + Duration::from_millis(200));
+        replicas[0].tick();
+        assert_eq!(network.message_counts().heartbeats, 2);
+    }
+
+    #[test]
+    fn suspect_unreachable_peer() {
+        let network = MemoryNetwork::<u32>::new();
+        let connect = |id| {
+            let node = network.connect(NodeId(id));
+            PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                .unwrap()
+        };
+        let mut replicas: Vec<_> = (1..=3).map(connect).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert!(!replicas[0].is_suspect(NodeId(3)));
+
+        // replica 3 is gone, so the leader can't send it the Propose
+        replicas.pop();
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_suspect(NodeId(3)));
+        assert!(!replicas[0].is_suspect(NodeId(2)));
+        assert_eq!(replicas[0].committed_len(), 2);
+
+        // once it is back, the next broadcast reaches it again
+        replicas.push(connect(3));
+        replicas[0].submit_value(2).unwrap();
+        assert!(!replicas[0].is_suspect(NodeId(3)));
+    }
+
+    #[test]
+    fn converge_with_gossip() {
+        let network = MemoryNetwork::<u32>::new();
+        let members = nodes(&[1, 2, 3, 4, 5]);
+        let mut replicas: Vec<_> = (1..=5)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), members.clone(), Counter::default())
+                    .unwrap()
+                    .with_fan_out(FanOut::Gossip { fan_out: 1 })
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=10 {
+            replicas[0].submit_value(v).unwrap();
+            deliver_all(&mut replicas);
+        }
+
+        // replicas which gossip missed catch up once Heartbeats show them behind
+        for _ in 0..2 {
+            replicas[0].send_heartbeats();
+            deliver_all(&mut replicas);
+        }
+        let expected = chosen_values(&replicas[0]);
+        assert_eq!(expected.len(), 11);
+        for replica in &replicas {
+            assert_eq!(chosen_values(replica), expected);
+            assert_eq!(replica.apply_index, 11);
+        }
+    }
+
+    #[test]
+    fn drop_client_requests_relayed_in_a_loop() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), nodes(&[1, 2, 3]), Counter::default())
+                    .unwrap()
+            })
+            .collect();
+        // everyone believes the next replica to be the leader
+        for (i, replica) in replicas.iter_mut().enumerate() {
+            replica.current_leader = Some(NodeId((i + 1) % 3 + 1));
+        }
+
+        replicas[0].submit_value(42).unwrap();
+        let mut relayed = 0;
+        for _ in 0..100 {
+            for replica in &mut replicas {
+                while let Ok((src, msg)) = replica.node.try_recv() {
+                    assert!(matches!(msg, PaxosMsg::ClientRequest { value: 42, .. }));
+                    replica.handle_paxos_message(src, msg);
+                    relayed += 1;
+                }
+            }
+        }
+        assert_eq!(relayed, 3);
+        for replica in &replicas {
+            assert!(replica.log.is_empty() && replica.client_cmd_queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn install_snapshot_on_far_behind_replica() {
+        let network = MemoryNetwork::<u32>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2, 3]),
+                Counter::default(),
+            )
+            .unwrap()
+        };
+        let mut replicas: Vec<_> = (1..=3).map(create).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // the third replica crashes and misses the values which end up in the snapshot
+        replicas.pop();
+        for v in 1..=10 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].take_snapshot(), Ok(11));
+        assert!(replicas[0].log[..11].iter().all(|e| e.value.is_none()));
+        for v in 11..=12 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // once it is back, it receives the snapshot and the entries after it
+        replicas.push(create(3));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let recovered = &mut replicas[2];
+        assert_eq!(recovered.snapshot_index, 11);
+        assert_eq!(recovered.committed_len(), 13);
+        assert_eq!(recovered.history().collect::<Vec<_>>(), vec![(11, &11), (12, &12)]);
+        assert_eq!(recovered.state_machine.query(&0), Ok("78".to_owned()));
+    }
+
+    #[test]
+    fn skip_resubmitted_commands() {
+        let network = MemoryNetwork::<u32>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2, 3]),
+                Counter::default(),
+            )
+            .unwrap()
+        };
+        let mut replicas: Vec<_> = (1..=3).map(create).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let client = NodeId(4);
+        let request = |seq, value| PaxosMsg::TrackedRequest {
+            id: RequestId { client: 7, seq },
+            value,
+            hops: 0,
+            priority: 0,
+            origin: None,
+        };
+
+        // the client submits its command again, e.g. because it missed the confirmation
+        replicas.pop();
+        replicas[0].handle_paxos_message(client, request(1, 5));
+        replicas[0].handle_paxos_message(client, request(1, 5));
+        deliver_all(&mut replicas);
+        for replica in &mut replicas {
+            assert_eq!(chosen_values(replica).len(), 3);
+            assert_eq!(replica.history().collect::<Vec<_>>(), vec![(1, &5)]);
+            assert_eq!(replica.state_machine.query(&0), Ok("5".to_owned()));
+        }
+
+        // a replica catching up from the snapshot skips later resubmissions as well
+        assert_eq!(replicas[0].take_snapshot(), Ok(3));
+        replicas[0].handle_paxos_message(client, request(1, 5));
+        replicas[0].handle_paxos_message(client, request(2, 6));
+        deliver_all(&mut replicas);
+        replicas.push(create(3));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let recovered = &mut replicas[2];
+        assert_eq!(recovered.snapshot_index, 3);
+        assert_eq!(recovered.committed_len(), 5);
+        assert_eq!(recovered.history().collect::<Vec<_>>(), vec![(4, &6)]);
+        assert_eq!(recovered.state_machine.query(&0), Ok("11".to_owned()));
+    }
+
+    #[test]
+    fn keep_log_while_snapshot_too_large() {
+        let node = UdpNetworkNode::<u32>::new().with_max_msg_size(200);
+        let node_id = node.id();
+        let mut replica =
+            PaxosReplica::with_members(node, node_id, vec![node_id], Vec::new()).unwrap();
+        replica.tick();
+        for v in 1..=50 {
+            replica.submit_value(v).unwrap();
+        }
+        replica.tick();
+        assert_eq!(replica.committed_len(), 51);
+
+        // a snapshot which can't be sent in a single message doesn't replace the log
+        let result = replica.take_snapshot();
+        assert!(matches!(result, Err(PaxosError::SnapshotTooLarge { max: 200, .. })));
+        assert_eq!(replica.snapshot_index, 0);
+        assert!(replica.log[1..].iter().all(|e| e.value.is_some()));
+    }
+
+    #[test]
+    fn execute_each_committed_command() {
+        let network = MemoryNetwork::<u32>::new();
+        let executed: Vec<_> = (0..3).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                let recorder = Recorder(executed[id - 1].clone());
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[1, 2, 3]),
+                    recorder,
+                )
+                .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=5 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // each command is executed once on every replica, and re-election doesn't repeat any
+        for (replica, executed) in replicas.iter().zip(&executed) {
+            assert_eq!(replica.committed_len(), 6);
+            assert_eq!(*executed.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        }
+    }
+
+    #[test]
+    fn apply_commands_during_snapshot() {
+        let network = MemoryNetwork::<u32>::new();
+        let gate = Arc::new(Mutex::new(()));
+        let store = GatedStore {
+            entries: (1000..101_000).map(|k| (k, 0)).collect(),
+            gate: gate.clone(),
+        };
+        let mut replicas = vec![
+            PaxosReplica::with_members(
+                network.connect(NodeId(1)),
+                NodeId(1),
+                nodes(&[1, 2, 3]),
+                store,
+            )
+            .unwrap(),
+            PaxosReplica::with_members(
+                network.connect(NodeId(2)),
+                NodeId(2),
+                nodes(&[1, 2, 3]),
+                Counter::default(),
+            )
+            .unwrap(),
+            PaxosReplica::with_members(
+                network.connect(NodeId(3)),
+                NodeId(3),
+                nodes(&[1, 2, 3]),
+                Counter::default(),
+            )
+            .unwrap(),
+        ];
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=10 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // the snapshot can't be written yet, but consensus goes on
+        let closed = gate.lock().unwrap();
+        assert_eq!(replicas[0].start_snapshot(), Ok(11));
+        assert_eq!(replicas[0].start_snapshot(), Err(PaxosError::SnapshotInProgress));
+        for v in 11..=20 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        replicas[0].tick();
+        assert_eq!(replicas[0].apply_index, 21);
+        assert_eq!(replicas[0].snapshot_index, 0);
+
+        // once written, the snapshot covers only the entries applied when it was started
+        drop(closed);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while replicas[0].snapshot_index == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            replicas[0].tick();
+        }
+        assert_eq!(replicas[0].snapshot_index, 11);
+        assert!(replicas[0].log[..11].iter().all(|e| e.value.is_none()));
+        assert!(replicas[0].log[11..].iter().all(|e| e.value.is_some()));
+        let data = &replicas[0].snapshot.as_ref().unwrap().data;
+        let entries: HashMap<u32, u64> = bincode::deserialize(data).unwrap();
+        assert_eq!(entries.len(), 100_010);
+        assert_eq!(entries.get(&10), Some(&100));
+        assert_eq!(entries.get(&11), None);
+    }
+
+    #[test]
+    fn throttle_proposals_to_slow_follower() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[1, 2, 3], |r| r.with_proposal_window(4));
+        // the third replica is down, so the leader depends on the slow second one
+        replicas.pop();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        network.set_delay(NodeId(2), Duration::from_secs(1));
+
+        for v in 1..=10 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].log.len(), 5);
+        assert_eq!(replicas[0].client_cmd_queue.len(), 6);
+
+        // each batch of accepted entries makes room for the next proposals
+        for (proposed, committed) in [(9, 5), (11, 9), (11, 11)] {
+            clock.advance(Duration::from_secs(1));
+            deliver_all(&mut replicas);
+            assert_eq!(replicas[0].log.len(), proposed);
+            assert_eq!(replicas[0].committed_len(), committed);
+        }
+        assert!(replicas[0].client_cmd_queue.is_empty());
+        assert_eq!(replicas[0].state_machine.query(&0), Ok("55".to_owned()));
+    }
+
+    #[test]
+    fn propose_high_priority_commands_first() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[1, 2, 3], |r| r.with_proposal_window(4));
+        replicas.pop();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        network.set_delay(NodeId(2), Duration::from_secs(1));
+
+        // a flood of bulk writes fills the proposal window and the queue behind it
+        for v in 1..=20 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        replicas[0].submit_value_with_priority(100, u8::MAX).unwrap();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].client_cmd_queue.len(), 17);
+
+        clock.advance(Duration::from_secs(1));
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].log[5].value, Some(Command::App(100)));
+        assert!(replicas[0].client_cmd_queue.len() > 10);
+
+        // the bulk writes still follow in the order they were submitted
+        while !replicas[0].client_cmd_queue.is_empty() || replicas[0].committed_len() < 22 {
+            clock.advance(Duration::from_secs(1));
+            deliver_all(&mut replicas);
+        }
+        let bulk: Vec<_> = chosen_values(&replicas[0])
+            .into_iter()
+            .filter_map(|(_, value)| match value {
+                Command::App(v) if v != 100 => Some(v),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bulk, (1..=20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn low_priority_commands_age_into_proposals() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1, 2, 3]),
+            Counter(0),
+        )
+        .unwrap();
+        // without a leader, everything stays queued
+        replica.submit_value(1).unwrap();
+        for v in 2..=1000 {
+            replica.submit_value_with_priority(v, 1).unwrap();
+        }
+        let queue = &replica.client_cmd_queue;
+        let position = queue.values().position(|v| *v == Command::App(1)).unwrap();
+        assert_eq!(position as u64, PRIORITY_AGING - 1);
+    }
+
+    #[test]
+    fn track_quorum_application() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[1, 2, 3]),
+                    Counter(0),
+                )
+                .unwrap()
+                .with_apply_acks()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let step = |replica: &mut PaxosReplica<u32>| {
+            while let Ok((src, msg)) = replica.node.try_recv() {
+                replica.handle_paxos_message(src, msg);
+            }
+        };
+
+        // the value is chosen once the followers accept it, but they haven't applied it yet
+        replicas[0].submit_value(42).unwrap();
+        step(&mut replicas[1]);
+        step(&mut replicas[2]);
+        step(&mut replicas[0]);
+        assert_eq!(replicas[0].committed_len(), 2);
+        assert_eq!(replicas[0].quorum_applied(), 1);
+
+        // the leader and a single follower form a quorum
+        step(&mut replicas[1]);
+        step(&mut replicas[0]);
+        assert_eq!(replicas[1].committed_len(), 2);
+        assert_eq!(replicas[2].committed_len(), 1);
+        assert_eq!(replicas[0].quorum_applied(), 2);
+    }
+
+    #[test]
+    fn refuse_local_reads_without_quorum() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |mut r| {
+            r.state_machine = Box::new(Counter(7));
+            r
+        });
+        assert_eq!(replicas[0].read_local(0), Err(PaxosError::NotLeader));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].read_local(0), Ok("7".to_owned()));
+
+        // heartbeats extend the lease while a quorum answers them
+        let extend = LEASE_DURATION / 2 + Duration::from_millis(200);
+        clock.advance(extend);
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        clock.advance(extend);
+        assert_eq!(replicas[0].read_local(0), Ok("7".to_owned()));
+
+        // once partitioned, the leader still believes in its leadership but refuses to read
+        replicas.truncate(1);
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        clock.advance(LEASE_DURATION / 2);
+        assert!(replicas[0].is_leader());
+        assert_eq!(replicas[0].read_local(0), Err(PaxosError::LeaseExpired));
+    }
+
+    #[test]
+    fn ignore_wall_clock_jumps_for_leases() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |mut r| {
+            r.state_machine = Box::new(Counter(7));
+            r
+        });
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // the wall clock jumps back and forth, but the lease holds for its full duration
+        let hour = Duration::from_secs(3600);
+        for jump in [hour, hour * 2] {
+            clock.set_system_time(clock.system_time() - jump);
+            clock.set_system_time(clock.system_time() + hour);
+            clock.advance(LEASE_DURATION / 4);
+            replicas[1].start_election();
+            deliver_all(&mut replicas);
+            assert!(replicas[0].is_leader() && !replicas[1].is_leader());
+            assert_eq!(replicas[0].read_local(0), Ok("7".to_owned()));
+        }
+
+        // it still expires after the lease duration, no matter the wall-clock time
+        replicas.truncate(1);
+        clock.set_system_time(clock.system_time() - hour);
+        clock.advance(LEASE_DURATION);
+        assert_eq!(replicas[0].read_local(0), Err(PaxosError::LeaseExpired));
+    }
+
+    #[test]
+    fn reject_requests_without_quorum() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].committed_len(), 2);
+
+        // partitioned from the others, the leader stops accepting requests once its lease ends
+        let mut others = replicas.split_off(1);
+        clock.advance(LEASE_DURATION / 2);
+        replicas[0].submit_value(2).unwrap();
+        clock.advance(LEASE_DURATION / 2);
+        replicas[0].tick();
+        assert!(replicas[0].is_leader());
+        assert_eq!(replicas[0].submit_value(3), Err(PaxosError::NoQuorum));
+
+        // once healed, heartbeats are answered again and requests are accepted,
+        // while the messages sent across the partition were lost
+        for replica in &mut others {
+            while replica.node.try_recv().is_ok() {}
+        }
+        replicas.append(&mut others);
+        for _ in 0..20 {
+            clock.advance(Duration::from_millis(100));
+            replicas[0].tick();
+            deliver_all(&mut replicas);
+        }
+        assert!(replicas[0].is_leader());
+        replicas[0].submit_value(4).unwrap();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].committed_len(), 4);
+        assert_eq!(replicas[0].state_machine.query(&0), Ok("7".to_owned()));
+    }
+
+    #[test]
+    fn no_progress_in_even_split() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3, 4], |r| r);
+        // half of the group is one replica short of a quorum
+        assert_eq!(crate::protocol::quorum(4), 3);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        assert!(replicas.iter().all(|r| r.committed_len() == 2));
+
+        // split into two halves, each of which keeps trying to elect a leader and commit
+        for round in 0..50 {
+            clock.advance(Duration::from_millis(100));
+            for replica in &mut replicas {
+                replica.tick();
+            }
+            if round % 10 == 0 {
+                let _ = replicas[0].submit_value(2);
+                replicas[2].start_election();
+                let _ = replicas[2].submit_value(3);
+            }
+            let (left, right) = replicas.split_at_mut(2);
+            deliver_partitioned(left);
+            deliver_partitioned(right);
+        }
+        assert!(replicas.iter().all(|r| r.committed_len() == 2));
+        assert!(replicas[2..].iter().all(|r| !r.is_leader()));
+
+        // once healed, the group elects a leader and commits again
+        for _ in 0..50 {
+            clock.advance(Duration::from_millis(100));
+            for replica in &mut replicas {
+                replica.tick();
+            }
+            deliver_all(&mut replicas);
+        }
+        let leader = replicas.iter().position(|r| r.is_leader()).unwrap();
+        replicas[leader].submit_value(4).unwrap();
+        deliver_all(&mut replicas);
+        let (len, sum) = (replicas[0].committed_len(), replicas[0].state_machine.query(&0));
+        assert!(len > 2);
+        for replica in &mut replicas {
+            assert_eq!(replica.committed_len(), len);
+            assert_eq!(replica.state_machine.query(&0), sum);
+        }
+    }
+
+    /// State machine which takes a while to execute each command.
+    struct SlowCounter(u32);
+
+    impl ReplicatedStateMachine for SlowCounter {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            thread::sleep(Duration::from_millis(10));
+            self.0 += v;
+            Ok(self.0.to_string())
+        }
+    }
+
+    #[test]
+    fn report_apply_lag_and_push_back() {
+        let network = MemoryNetwork::<u32>::new();
+        let client = network.connect(NodeId(0));
+        let slow = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[id]),
+                SlowCounter(0),
+            )
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(30))
+            .with_apply_lag_threshold(5)
+        };
+        let mut replicas = vec![slow(1), slow(2).with_backpressure()];
+        for replica in &mut replicas {
+            replica.tick();
+            assert!(replica.is_leader());
+        }
+
+        // a tick only applies the entries it has time for, the others pile up
+        for v in 0..20 {
+            let request = PaxosMsg::ClientRequest {
+                value: v,
+                hops: 0,
+                priority: 0,
+                deadline: None,
+            };
+            client.send(NodeId(1), &request);
+            client.send(NodeId(2), &request);
+        }
+        for replica in &mut replicas {
+            assert_eq!(replica.tick(), Duration::from_secs(0));
+        }
+        assert!(replicas[0].metrics().apply_lag > 5);
+        assert_eq!(replicas[0].committed_len() + replicas[0].metrics().apply_lag, 21);
+        assert_eq!(replicas[0].submit_value(20), Ok(()));
+
+        // with backpressure, requests are rejected once the lag reaches the threshold
+        assert_eq!(replicas[1].metrics().apply_lag, 5);
+        assert!(replicas[1].committed_len() + 5 < 21);
+        assert_eq!(replicas[1].submit_value(20), Err(PaxosError::Overloaded));
+        while replicas[1].metrics().apply_lag > 0 {
+            replicas[1].tick();
+        }
+        assert_eq!(replicas[1].submit_value(20), Ok(()));
+    }
+
+    /// State machine which hangs on the command 13, until the test releases it.
+    struct Hanging(Arc<AtomicBool>);
+
+    impl ReplicatedStateMachine for Hanging {
+        type Command = u32;
+
+        fn execute(&mut self, v: u32) -> Result<String, ()> {
+            while v == 13 && !self.0.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Ok(v.to_string())
+        }
+    }
+
+    #[test]
+    fn detect_stalled_tick_loop() {
+        let network = MemoryNetwork::<u32>::new();
+        let client = network.connect(NodeId(0));
+        let released = Arc::new(AtomicBool::new(false));
+        let (stalls, stalled) = std::sync::mpsc::channel();
+        let replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1]),
+            Hanging(released.clone()),
+        )
+        .unwrap()
+        .with_poll_interval(Duration::from_millis(10))
+        .with_watchdog(Duration::from_millis(200), move |elapsed| {
+            stalls.send(elapsed).unwrap();
+        });
+        let handle = crate::ReplicaHandle::spawn(replica);
+        let submit = |value| {
+            client.send(NodeId(1), &PaxosMsg::ClientRequest {
+                value,
+                hops: 0,
+                priority: 0,
+                deadline: None,
+            });
+        };
+
+        // a replica which keeps ticking isn't reported
+        submit(1);
+        assert!(stalled.recv_timeout(Duration::from_millis(500)).is_err());
+
+        // once a command hangs, the stall is reported a single time
+        submit(13);
+        let elapsed = stalled.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(stalled.recv_timeout(Duration::from_millis(300)).is_err());
+
+        released.store(true, Ordering::Relaxed);
+        let replica = handle.stop();
+        assert_eq!(replica.committed_len(), 3);
+    }
+
+    #[test]
+    fn wait_for_applied_entry() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (1..=3)
+            .map(|id| {
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[1, 2, 3]),
+                    Counter::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let applied = replicas[2].committed_len();
+        assert_eq!(replicas[2].wait_for_apply(applied - 1, Duration::ZERO), Ok(()));
+        let short = Duration::from_millis(50);
+        assert_eq!(replicas[2].wait_for_apply(applied, short), Err(PaxosError::Timeout));
+
+        // the leader's notification tells the index, which the follower applies soon after
+        let commits = replicas[0].subscribe(applied).unwrap();
+        let watch = replicas[2].apply_watch();
+        let _handles: Vec<_> = replicas.into_iter().map(crate::ReplicaHandle::spawn).collect();
+        let client = network.connect(NodeId(0));
+        client.send(NodeId(1), &PaxosMsg::ClientRequest {
+            value: 5,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+            deadline: None,
+        });
+        let (index, value, _) = commits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(value, 5);
+        let start = Instant::now();
+        assert_eq!(watch.wait_for_apply(index, Duration::from_secs(5)), Ok(()));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(watch.applied(), index + 1);
+        assert_eq!(watch.wait_for_apply(index + 1, short), Err(PaxosError::Timeout));
+    }
+
+    #[test]
+    fn bound_staleness_of_follower_reads() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let step = |replica: &mut PaxosReplica<u32>, learn: bool| {
+            while let Ok((src, msg)) = replica.node.try_recv() {
+                if learn || !matches!(msg, PaxosMsg::Learn { .. }) {
+                    replica.handle_paxos_message(src, msg);
+                }
+            }
+        };
+
+        // the third replica misses that the value was chosen, but receives the next Heartbeat
+        replicas[0].submit_value(42).unwrap();
+        step(&mut replicas[1], true);
+        step(&mut replicas[2], true);
+        step(&mut replicas[0], true);
+        replicas[0].send_heartbeats();
+        step(&mut replicas[1], true);
+        step(&mut replicas[2], false);
+        assert_eq!(replicas[1].committed_len(), 2);
+        assert_eq!(replicas[2].committed_len(), 1);
+
+        clock.advance(Duration::from_millis(100));
+        let bound = Duration::from_millis(200);
+        assert_eq!(replicas[0].read_bounded(0, bound), Ok("42".to_owned()));
+        assert_eq!(replicas[1].read_bounded(0, bound), Ok("42".to_owned()));
+        assert_eq!(replicas[2].read_bounded(0, bound), Err(PaxosError::TooStale));
+        let tight = Duration::from_millis(50);
+        assert_eq!(replicas[1].read_bounded(0, tight), Err(PaxosError::TooStale));
+    }
+
+    #[test]
+    fn report_leader_changes() {
+        type Events = Arc<Mutex<Vec<(LeaderState, LeaderState, Ballot)>>>;
+        let events: Vec<Events> = (0..3).map(|_| Events::default()).collect();
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| {
+            let events = events[r.id().0 - 1].clone();
+            r.with_leader_change_callback(move |old, new, ballot| {
+                events.lock().unwrap().push((old, new, ballot));
+            })
+        });
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let first = replicas[0].highest_promised;
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // once the lease has expired, another replica takes over
+        clock.advance(LEASE_DURATION + Duration::from_millis(200));
+        replicas[1].start_election();
+        deliver_all(&mut replicas);
+        let second = replicas[1].highest_promised;
+        assert_eq!(replicas[1].leader_state(), LeaderState::Leader);
+
+        use LeaderState::*;
+        let (one, two) = (Follower(NodeId(1)), Follower(NodeId(2)));
+        let expected = [
+            vec![(Unknown, Leader, first), (Leader, two, second)],
+            vec![(Unknown, one, first), (one, Leader, second)],
+            vec![(Unknown, one, first), (one, two, second)],
+        ];
+        for (events, expected) in events.iter().zip(&expected) {
+            assert_eq!(&*events.lock().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_commands_before_proposing() {
+        let network = MemoryNetwork::<i64>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2]),
+                Bank::default(),
+            )
+            .unwrap()
+        };
+        let mut leader = create(1);
+        let mut follower = create(2);
+        leader.start_election();
+        follower.tick();
+        leader.tick();
+        assert!(leader.is_leader());
+
+        leader.submit_value(10).unwrap();
+        assert_eq!(
+            leader.submit_value(-5),
+            Err(PaxosError::CommandRejected("negative deposit: -5".to_owned()))
+        );
+        // requests relayed by followers are checked by the leader as well
+        follower.submit_value(-7).unwrap();
+        follower.submit_value(3).unwrap();
+        leader.tick();
+        follower.tick();
+        leader.tick();
+        assert_eq!(leader.log.len(), 3);
+        assert_eq!(leader.committed_len(), 3);
+        assert_eq!(leader.state_machine.query(&0), Ok("13".to_owned()));
+    }
+
+    #[test]
+    fn apply_batches_atomically() {
+        let network = MemoryNetwork::<Posting>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2]),
+                Accounts::default(),
+            )
+            .unwrap()
+        };
+        let balances = |replica: &PaxosReplica<Posting>| {
+            let snapshot = replica.state_machine.snapshot().unwrap();
+            bincode::deserialize::<BTreeMap<u8, i64>>(&snapshot).unwrap()
+        };
+        let mut leader = create(1);
+        let mut follower = create(2);
+        leader.start_election();
+        follower.tick();
+        leader.tick();
+        assert!(leader.is_leader());
+
+        leader.submit_batch(vec![Posting(1, 100), Posting(2, 50)]).unwrap();
+        // the last withdrawal overdraws the account, so neither transfer takes place
+        let overdraft = vec![Posting(1, -30), Posting(2, 30), Posting(2, -200)];
+        follower.submit_batch(overdraft).unwrap();
+        leader.tick();
+        follower.tick();
+        leader.tick();
+        follower.tick();
+        for replica in [&leader, &follower] {
+            assert_eq!(replica.committed_len(), 3);
+            assert_eq!(balances(replica), BTreeMap::from([(1, 100), (2, 50)]));
+        }
+
+        leader.submit_batch(vec![Posting(1, -30), Posting(2, 30)]).unwrap();
+        follower.tick();
+        leader.tick();
+        follower.tick();
+        for replica in [&leader, &follower] {
+            assert_eq!(replica.committed_len(), 4);
+            assert_eq!(balances(replica), BTreeMap::from([(1, 70), (2, 80)]));
+        }
+        assert_eq!(leader.history().filter(|&(index, _)| index == 2).count(), 3);
+    }
+
+    #[test]
+    fn track_command_sizes() {
+        let network = MemoryNetwork::<String>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2]),
+                Vec::<String>::new(),
+            )
+            .unwrap()
+        };
+        let mut leader = create(1);
+        let mut follower = create(2);
+        leader.start_election();
+        follower.tick();
+        leader.tick();
+
+        let lengths = [10, 10, 10, 500, 500, 3000];
+        for &len in &lengths[..5] {
+            leader.submit_value("x".repeat(len)).unwrap();
+        }
+        leader.submit_batch(vec!["y".repeat(lengths[5])]).unwrap();
+        follower.tick();
+        leader.tick();
+        follower.tick();
+        assert_eq!(follower.committed_len(), 7);
+
+        // sizes are counted once the commands are applied, by every replica
+        let bucket = |len| command_wire_size(&"x".repeat(len)).next_power_of_two();
+        let expected = vec![(bucket(10), 3), (bucket(500), 2), (bucket(3000), 1)];
+        for replica in [&leader, &follower] {
+            let sizes = &replica.metrics().command_sizes;
+            assert_eq!(sizes.buckets().collect::<Vec<_>>(), expected);
+            assert_eq!(sizes.max(), command_wire_size(&"x".repeat(3000)));
+        }
+    }
+
+    #[test]
+    fn replay_persisted_log() {
+        let network = MemoryNetwork::<Posting>::new();
+        let file = std::env::temp_dir().join("paxos-replay.bin");
+        let file = file.to_str().unwrap();
+        let _ = std::fs::remove_file(file);
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2]),
+                Accounts::default(),
+            )
+            .unwrap()
+        };
+        let mut leader = create(1).with_state_file(file);
+        let mut follower = create(2);
+        leader.start_election();
+        follower.tick();
+        leader.tick();
+
+        leader.submit_value(Posting(1, 100)).unwrap();
+        leader.submit_batch(vec![Posting(2, 50), Posting(1, -200)]).unwrap();
+        leader.reconfigure(nodes(&[1, 2])).unwrap();
+        leader.submit_batch(vec![Posting(1, -30), Posting(2, 30)]).unwrap();
+        for _ in 0..3 {
+            follower.tick();
+            leader.tick();
+        }
+        assert_eq!(leader.committed_len(), 6);
+
+        let state: PersistentState<Posting> =
+            load_from_disk_file(&BincodeCodec::default(), file).unwrap();
+        let mut store = Accounts::default();
+        let results = replay_log(&state.log, &mut store);
+        assert_eq!(store.snapshot(), leader.state_machine.snapshot());
+        assert_eq!(store.0, BTreeMap::from([(1, 70), (2, 30)]));
+        let expected = vec![
+            (1, Ok("100".to_owned())),
+            (2, Err(())),
+            (2, Err(())),
+            (4, Ok("70".to_owned())),
+            (4, Ok("30".to_owned())),
+        ];
+        assert_eq!(results, expected);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn retry_lost_proposals() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| {
+            r.with_proposal_retry(Duration::from_millis(100), 2)
+        });
+        // the third replica is down, so every entry needs the second one to accept it
+        replicas.pop();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        let drop_messages = |replica: &PaxosReplica<u32>| {
+            let mut dropped = 0;
+            while replica.node.try_recv().is_ok() {
+                dropped += 1;
+            }
+            dropped
+        };
+
+        replicas[0].submit_value(42).unwrap();
+        assert_eq!(drop_messages(&replicas[1]), 1);
+        assert_eq!(replicas[0].tick(), Duration::from_millis(100));
+        clock.advance(Duration::from_millis(100));
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].committed_len(), 2);
+        assert_eq!(replicas[1].committed_len(), 2);
+
+        // entries are proposed again only a limited number of times
+        replicas[0].submit_value(7).unwrap();
+        for _ in 0..3 {
+            assert_eq!(drop_messages(&replicas[1]), 1);
+            clock.advance(Duration::from_millis(100));
+            replicas[0].tick();
+        }
+        assert_eq!(drop_messages(&replicas[1]), 0);
+        assert_eq!(replicas[0].committed_len(), 2);
+    }
+
+    #[test]
+    fn resend_unacknowledged_learns() {
+        let (clock, network, mut replicas) = clocked_group(&[1, 2, 3], |r| {
+            r.with_learn_acks(Duration::from_millis(100), 2)
+        });
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].unacked_learns.is_empty());
+
+        // the Learn to the third replica is lost
+        replicas[0].submit_value(42).unwrap();
+        loop {
+            let mut delivered = false;
+            for replica in replicas.iter_mut() {
+                while let Ok((src, msg)) = replica.node.try_recv() {
+                    delivered = true;
+                    if replica.id() != NodeId(3) || !matches!(msg, PaxosMsg::Learn { .. }) {
+                        replica.handle_paxos_message(src, msg);
+                    }
+                }
+            }
+            if !delivered {
+                break;
+            }
+        }
+        assert_eq!(replicas[1].committed_len(), 2);
+        assert_eq!(replicas[2].committed_len(), 1);
+        assert_eq!(replicas[0].tick(), Duration::from_millis(100));
+
+        // the leader sends it again to the third replica only, which converges without catching up
+        network.reset_message_counts();
+        clock.advance(Duration::from_millis(100));
+        replicas[0].tick();
+        assert_eq!(network.message_counts().learns, 1);
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[2].committed_len(), 2);
+        assert!(replicas[0].unacked_learns.is_empty());
+        assert!(replicas[0].catch_ups_served.is_empty());
+    }
+
+    #[test]
+    fn preferred_replica_wins_first_election() {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| {
+                if r.id() == NodeId(2) {
+                    r.with_election_rank(0)
+                } else {
+                    r
+                }
+            });
+
+            let leader = loop {
+                clock.advance(Duration::from_millis(10));
+                replicas.shuffle(&mut rng);
+                for replica in &mut replicas {
+                    replica.tick();
+                }
+                if let Some(leader) = replicas.iter().find(|r| r.is_leader()) {
+                    break leader.id();
+                }
+            };
+            assert_eq!(leader, NodeId(2), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn elect_preferred_leader() {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| {
+                r.with_leader_policy(PreferNode(NodeId(3)))
+            });
+            let mut run_until_elected = |replicas: &mut Vec<PaxosReplica<u32>>| {
+                for _ in 0..1000 {
+                    clock.advance(Duration::from_millis(10));
+                    replicas.shuffle(&mut rng);
+                    for replica in replicas.iter_mut() {
+                        replica.tick();
+                    }
+                    if let Some(leader) = replicas.iter().find(|r| r.is_leader()) {
+                        return leader.id();
+                    }
+                }
+                panic!("no leader elected, seed {}", seed);
+            };
+            assert_eq!(run_until_elected(&mut replicas), NodeId(3), "seed {}", seed);
+
+            // once the preferred replica is partitioned, another one takes over
+            replicas.retain(|r| r.id() != NodeId(3));
+            assert_ne!(run_until_elected(&mut replicas), NodeId(3), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn defer_election_to_higher_candidate() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r.with_election_rank(9));
+        // only the first replica runs, the others just receive its messages
+        let mut replica = replicas.remove(0);
+        clock.advance(LEASE_DURATION * 2);
+        replica.tick();
+        let ballot = replica.highest_promised;
+        let started = replica.last_election;
+
+        // the Nacks reveal that the third replica is trying to get elected with a higher ballot
+        let mut promised = ballot;
+        promised.increment_for(NodeId(3)).unwrap();
+        for id in 2..=3 {
+            replica.handle_message(NodeId(id), PaxosMsg::Nack { ballot, promised });
+        }
+        let timeout = replica.random_timeout_offset;
+        clock.advance(timeout);
+        replica.tick();
+        clock.advance(timeout - Duration::from_millis(1));
+        replica.tick();
+        assert_eq!(replica.last_election, started);
+
+        // it didn't get elected within one more timeout, so this replica competes again
+        clock.advance(Duration::from_millis(1));
+        replica.tick();
+        assert_ne!(replica.last_election, started);
+    }
+
+    #[test]
+    fn wait_for_prepare_round() {
+        let (clock, network, mut replicas) =
+            clocked_group(&[1, 2, 3], |r| r.with_election_rank(0));
+        let down = replicas.pop().unwrap();
+        clock.advance(LEASE_DURATION * 2);
+        replicas[0].tick();
+        let started = replicas[0].last_election;
+        assert_eq!(network.message_counts().prepares, 2);
+
+        // while nobody answers, the candidate waits for the round timeout to try again
+        let step = replicas[0].random_timeout_offset;
+        while clock.elapsed(started) + step < PREPARE_ROUND_TIMEOUT {
+            clock.advance(step);
+            replicas[0].tick();
+            assert_eq!(replicas[0].last_election, started);
+            assert_eq!(network.message_counts().prepares, 2);
+        }
+        clock.advance(step);
+        replicas[0].tick();
+        assert_ne!(replicas[0].last_election, started);
+        assert_eq!(network.message_counts().prepares, 4);
+        let ballot = replicas[0].highest_promised;
+
+        // a slow acceptor answers the second round before it times out, which wins the election
+        clock.advance(PREPARE_ROUND_TIMEOUT - Duration::from_millis(1));
+        replicas[0].tick();
+        assert_eq!(network.message_counts().prepares, 4);
+        while let Ok((src, msg)) = replicas[1].node.recv(Duration::ZERO) {
+            replicas[1].handle_paxos_message(src, msg);
+        }
+        replicas[0].tick();
+        assert!(replicas[0].is_leader());
+        assert_eq!(replicas[0].highest_promised, ballot);
+        assert_eq!(network.message_counts().prepares, 4);
+        while down.node.try_recv().is_ok() {}
+    }
+
+    #[test]
+    fn elect_same_leader_with_same_seed() {
+        fn elect(seed: u64) -> (NodeId, Vec<Duration>) {
+            let (clock, _, mut replicas) = clocked_group(&[0, 1, 2, 3, 4], |r| {
+                let id = r.id().0 as u64;
+                r.with_rng(StdRng::seed_from_u64(seed.wrapping_add(id)))
+            });
+            let offsets = replicas.iter().map(|r| r.random_timeout_offset).collect();
+            for _ in 0..10_000 {
+                clock.advance(Duration::from_millis(1));
+                for replica in replicas.iter_mut() {
+                    replica.tick();
+                }
+                if let Some(leader) = replicas.iter().find(|r| r.is_leader()) {
+                    return (leader.id(), offsets);
+                }
+            }
+            panic!("no leader elected");
+        }
+
+        assert_eq!(elect(1), elect(1));
+        assert_eq!(elect(42), elect(42));
+        assert_ne!(elect(1).1, elect(42).1);
+    }
+
+    #[test]
+    fn abandon_superseded_proposal() {
+        let (clock, network, mut replicas) = clocked_group(&[0, 1, 2], |r| r);
+        let client = network.connect(NodeId(7));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // the other replicas miss the proposal and elect a new leader, which doesn't know it
+        let id = RequestId { client: 7, seq: 1 };
+        let request = PaxosMsg::TrackedRequest {
+            id,
+            value: 5,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+            origin: None,
+        };
+        replicas[0].handle_message(NodeId(7), request);
+        let index = replicas[0].log.len() - 1;
+        for replica in &mut replicas[1..] {
+            while replica.node.recv(Duration::ZERO).is_ok() {}
+        }
+        clock.advance(LEASE_DURATION);
+        replicas[1].start_election();
+        deliver_all(&mut replicas[1..]);
+        while replicas[0].node.recv(Duration::ZERO).is_ok() {}
+        assert!(replicas[0].is_leader() && replicas[1].is_leader());
+
+        // the retried Propose is rejected, after which the old leader stops retrying
+        clock.advance(PROPOSAL_TIMEOUT);
+        replicas[0].retry_proposals();
+        deliver_all(&mut replicas);
+        assert!(!replicas[0].proposals.contains_key(&index));
+        clock.advance(PROPOSAL_TIMEOUT);
+        replicas[0].retry_proposals();
+        assert!(replicas[1].node.recv(Duration::ZERO).is_err());
+
+        // the client learns that it has to submit the request again
+        assert!(client.send(NodeId(0), &PaxosMsg::RequestStatus(id)));
+        deliver_all(&mut replicas);
+        match client.recv(Duration::ZERO) {
+            Ok((_, PaxosMsg::RequestStatusReply {
+                applied,
+                superseded,
+                ..
+            })) => assert!(!applied && superseded),
+            other => panic!("expected RequestStatusReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn notify_origin_of_relayed_request() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (0..3)
+            .map(|id| {
+                let node = network.connect(NodeId(id));
+                PaxosReplica::with_members(node, NodeId(id), nodes(&[0, 1, 2]), Counter::default())
+                    .unwrap()
+            })
+            .collect();
+        let client = network.connect(NodeId(7));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // the follower relays the request to the leader, which tells the client once applied
+        let id = RequestId { client: 7, seq: 1 };
+        let request = PaxosMsg::TrackedRequest {
+            id,
+            value: 5,
+            hops: 0,
+            priority: DEFAULT_PRIORITY,
+            origin: Some(NodeId(8)),
+        };
+        assert!(client.send(NodeId(2), &request));
+        deliver_all(&mut replicas);
+        match client.recv(Duration::ZERO) {
+            Ok((NodeId(2), PaxosMsg::NotLeader { leader_hint })) => {
+                assert_eq!(leader_hint, Some(NodeId(0)))
+            }
+            other => panic!("expected NotLeader, got {:?}", other),
+        }
+        match client.recv(Duration::ZERO) {
+            Ok((NodeId(0), PaxosMsg::RequestApplied { id: r, index, result })) => {
+                assert_eq!(r, id);
+                let value = &replicas[2].log[index].value;
+                assert!(matches!(value, Some(Command::Tracked(r, 5)) if *r == id));
+                assert_eq!(result, Ok("5".to_owned()));
+            }
+            other => panic!("expected RequestApplied, got {:?}", other),
+        }
+        assert!(client.recv(Duration::ZERO).is_err());
+        assert!(replicas.iter().all(|replica| replica.request_origins.is_empty()));
+    }
+
+    #[test]
+    fn forget_cancelled_and_stale_requests() {
+        let (clock, _, mut replicas) = clocked_group(&[0, 1, 2], |r| r.with_proposal_window(2));
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // two requests are proposed, the third one is queued behind them
+        let ids: Vec<_> = (7..10).map(|client| RequestId { client, seq: 1 }).collect();
+        for &id in &ids {
+            let request = PaxosMsg::TrackedRequest {
+                id,
+                value: 5,
+                hops: 0,
+                priority: DEFAULT_PRIORITY,
+                origin: None,
+            };
+            replicas[0].handle_message(NodeId(7), request);
+        }
+        assert_eq!(replicas[0].client_cmd_queue.len(), 1);
+        replicas[0].handle_message(NodeId(9), PaxosMsg::CancelRequest(ids[2]));
+        assert!(replicas[0].client_cmd_queue.is_empty());
+
+        // cancelling a proposed request only forgets about it, the proposal goes on
+        let proposed: Vec<usize> = replicas[0].proposals.keys().copied().collect();
+        for index in proposed {
+            replicas[0].abandon_proposal(index);
+        }
+        replicas[0].handle_message(NodeId(7), PaxosMsg::CancelRequest(ids[0]));
+        assert!(!replicas[0].is_superseded(ids[0]) && replicas[0].is_superseded(ids[1]));
+
+        // the other client never asks again, so its request is forgotten eventually
+        clock.advance(REQUEST_TTL);
+        replicas[0].run_timers();
+        assert!(replicas[0].superseded_requests.is_empty());
+    }
+
+    #[test]
+    fn force_commit_stuck_entry() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (0..3)
+            .map(|id| {
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[0, 1, 2]),
+                    Counter::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        // the Proposes get lost, so that only the leader accepted the value
+        replicas[0].submit_value(5).unwrap();
+        for replica in &mut replicas[1..] {
+            while replica.node.recv(Duration::ZERO).is_ok() {}
+        }
+        let stuck = replicas[0].log.len() - 1;
+        assert!(!replicas[0].log[stuck].chosen);
+
+        assert!(matches!(
+            replicas[1].force_commit(stuck, 7),
+            Err(PaxosError::NotLeader)
+        ));
+        replicas[0].force_commit(stuck, 7).unwrap();
+        deliver_all(&mut replicas);
+        for replica in replicas.iter_mut() {
+            assert_eq!(replica.log[stuck].value, Some(Command::App(7)));
+            assert!(replica.log[stuck].chosen);
+            assert_eq!(replica.state_machine.query(&0), Ok("7".to_string()));
+        }
+        assert!(replicas[0].is_leader());
+
+        // chosen entries are never overwritten
+        assert!(matches!(
+            replicas[0].force_commit(stuck, 9),
+            Err(PaxosError::AlreadyChosen { index }) if index == stuck
+        ));
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[2].log[stuck].value, Some(Command::App(7)));
+    }
+
+    #[test]
+    fn transfer_leadership() {
+        let network = MemoryNetwork::<u32>::new();
+        let mut replicas: Vec<_> = (0..5)
+            .map(|id| {
+                PaxosReplica::with_members(
+                    network.connect(NodeId(id)),
+                    NodeId(id),
+                    nodes(&[0, 1, 2, 3, 4]),
+                    Counter::default(),
+                )
+                .unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for value in 1..=3 {
+            replicas[0].submit_value(value).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        assert!(matches!(
+            replicas[1].transfer_leadership_to(NodeId(4)),
+            Err(PaxosError::NotLeader)
+        ));
+        assert!(matches!(
+            replicas[0].transfer_leadership_to(NodeId(7)),
+            Err(PaxosError::InvalidConfig(_))
+        ));
+        replicas[0].transfer_leadership_to(NodeId(4)).unwrap();
+        // the leader stays in office until the target's Prepare arrives
+        assert!(replicas[0].is_leader());
+        // a replica standing for election at the same time loses the race
+        replicas[1].start_election();
+        deliver_all(&mut replicas);
+
+        assert!(replicas[4].is_leader());
+        assert!(replicas.iter().all(|r| r.current_leader == Some(NodeId(4))));
+        replicas[4].submit_value(4).unwrap();
+        deliver_all(&mut replicas);
+        for replica in replicas.iter_mut() {
+            assert_eq!(replica.state_machine.query(&0), Ok("10".to_string()));
+        }
+    }
+
+    #[test]
+    fn transfer_leadership_once_target_caught_up() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        // the third replica is down while values are chosen
+        let third = replicas.pop().unwrap();
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        while third.node.try_recv().is_ok() {}
+
+        // the leader waits for the target to answer, without proposing meanwhile
+        replicas[0].transfer_leadership_to(NodeId(3)).unwrap();
+        replicas[0].submit_value(4).unwrap();
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_leader());
+        assert_eq!(replicas[1].current_leader, Some(NodeId(1)));
+        assert_eq!(replicas[1].handover, None);
+        assert_eq!(replicas[0].state_machine.query(&0), Ok("6".to_owned()));
+
+        // without an answer within a lease duration, it gives up and resumes proposing
+        clock.advance(LEASE_DURATION);
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_leader());
+        assert_eq!(replicas[0].state_machine.query(&0), Ok("10".to_owned()));
+
+        // once back, the target catches up before it is told to start its election
+        while third.node.try_recv().is_ok() {}
+        replicas.push(third);
+        replicas[0].transfer_leadership_to(NodeId(3)).unwrap();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_leader());
+        replicas[0].tick();
+        deliver_all(&mut replicas);
+        assert!(replicas[2].is_leader());
+        for replica in &replicas {
+            assert_eq!(replica.current_leader, Some(NodeId(3)));
+            assert_eq!(replica.state_machine.query(&0), Ok("10".to_owned()));
+        }
+    }
+
+    /// Runs a group of seven replicas, whose initial leases all expire around the same time,
+    /// until they agree on a leader. Returns the number of elections started until then.
+    fn elections_until_stable(seed: u64) -> usize {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let (clock, network, mut replicas) = clocked_group(&[1, 2, 3, 4, 5, 6, 7], |r| r);
+        for replica in &replicas {
+            // slow links let Prepares cross each other, which provokes competing elections
+            network.set_delay(replica.id(), Duration::from_millis(rng.gen_range(20..=80)));
+        }
+
+        let mut elections = 0;
+        for _ in 0..2000 {
+            clock.advance(Duration::from_millis(10));
+            replicas.shuffle(&mut rng);
+            for replica in &mut replicas {
+                let last_election = replica.last_election;
+                replica.tick();
+                if replica.last_election != last_election {
+                    elections += 1;
+                }
+            }
+            let leaders: Vec<_> = replicas.iter().filter(|r| r.is_leader()).collect();
+            if let [leader] = leaders[..] {
+                let id = leader.id();
+                if replicas.iter().all(|r| r.current_leader == Some(id)) {
+                    return elections;
+                }
+            }
+        }
+        panic!("no stable leader for seed {}", seed);
+    }
+
+    #[test]
+    fn dampen_competing_elections() {
+        let seeds = 50;
+        let elections: usize = (0..seeds).map(elections_until_stable).sum();
+        let average = elections as f64 / seeds as f64;
+        assert!(average < 18.0, "{} elections on average", average);
+    }
+
+    #[test]
+    fn ignore_duplicate_messages() {
+        let network = MemoryNetwork::<u32>::new();
+        let members = nodes(&[1, 2, 3, 4, 5]);
+        let mut replicas: Vec<_> = members
+            .iter()
+            .map(|&id| {
+                let node = network.connect(id);
+                PaxosReplica::with_members(node, id, members.clone(), Counter::default()).unwrap()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+
+        // the second replica receives the Propose twice, but accepts it only once
+        replicas[0].submit_value(7).unwrap();
+        let (src, propose) = replicas[1].node.try_recv().unwrap();
+        assert!(matches!(propose, PaxosMsg::Propose { index: 1, .. }));
+        replicas[1].handle_paxos_message(src, propose.clone());
+        replicas[1].handle_paxos_message(src, propose);
+        let recent = &replicas[1].recent_messages;
+        assert_eq!(recent.iter().filter(|(index, _)| *index == Some(1)).count(), 1);
+
+        // both Accepts are answered, but the duplicate doesn't count toward the quorum
+        for _ in 0..2 {
+            let (src, accept) = replicas[0].node.try_recv().unwrap();
+            assert!(matches!(accept, PaxosMsg::Accept { index: 1, .. }));
+            replicas[0].handle_paxos_message(src, accept);
+        }
+        assert_eq!(replicas[0].log[1].acceptances, nodes(&[1, 2]).into_iter().collect());
+        assert!(!replicas[0].log[1].chosen);
+
+        deliver_all(&mut replicas);
+        assert!(replicas.iter().all(|r| r.committed_len() == 2));
+    }
+
+    #[test]
+    fn reject_conflicting_value_at_same_ballot() {
+        let network = MemoryNetwork::<u32>::new();
+        let leader = network.connect(NodeId(2));
+        let _down = network.connect(NodeId(3));
+        let mut replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1, 2, 3]),
+            Counter::default(),
+        )
+        .unwrap();
+        let mut ballot = Ballot::default();
+        ballot.increment_for(NodeId(2)).unwrap();
+        replica.handle_message(NodeId(2), PaxosMsg::Prepare {
+            ballot,
+            holes: vec![0],
+            sent_at: None,
+        });
+        assert!(matches!(leader.try_recv(), Ok((_, PaxosMsg::Promise { .. }))));
+        let propose = |value| PaxosMsg::Propose {
+            index: 0,
+            ballot,
+            value: Command::App(value),
+            sent_at: None,
+        };
+
+        // the first value is accepted, a different one with the same ballot never replaces it
+        replica.handle_message(NodeId(2), propose(5));
+        assert!(matches!(leader.try_recv(), Ok((_, PaxosMsg::Accept { index: 0, .. }))));
+        replica.handle_message(NodeId(2), propose(6));
+        assert!(leader.try_recv().is_err());
+        assert!(matches!(replica.log[0].value, Some(Command::App(5))));
+        assert_eq!(replica.log[0].accepted_ballot, ballot);
+
+        // while the same value is acknowledged again, e.g. after a lost Accept
+        replica.handle_message(NodeId(2), propose(5));
+        assert!(matches!(leader.try_recv(), Ok((_, PaxosMsg::Accept { index: 0, .. }))));
+        assert!(matches!(replica.log[0].value, Some(Command::App(5))));
+    }
+
+    /// Creates the first of three replicas, which has accepted `5` for the first entry at a
+    /// ballot of the second replica, and has started an election afterwards.
+    fn candidate_with_accepted_value(network: &MemoryNetwork<u32>) -> (PaxosReplica<u32>, Ballot) {
+        let mut replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1, 2, 3]),
+            Counter::default(),
+        )
+        .unwrap();
+        let mut accepted = Ballot::default();
+        accepted.increment_for(NodeId(2)).unwrap();
+        replica.handle_message(NodeId(2), PaxosMsg::Prepare {
+            ballot: accepted,
+            holes: vec![0],
+            sent_at: None,
+        });
+        replica.handle_message(NodeId(2), PaxosMsg::Propose {
+            index: 0,
+            ballot: accepted,
+            value: Command::App(5),
+            sent_at: None,
+        });
+        replica.start_election();
+        assert!(replica.highest_promised > accepted);
+        (replica, accepted)
+    }
+
+    #[test]
+    fn adopt_value_from_promise_with_higher_ballot() {
+        let network = MemoryNetwork::<u32>::new();
+        let follower = network.connect(NodeId(2));
+        let _down = network.connect(NodeId(3));
+        let (mut replica, accepted) = candidate_with_accepted_value(&network);
+        while follower.try_recv().is_ok() {}
+
+        // the third replica accepted another value at a later ballot, which might be chosen
+        let mut later = accepted;
+        later.increment_for(NodeId(3)).unwrap();
+        replica.handle_message(NodeId(3), PaxosMsg::Promise {
+            ballot: replica.highest_promised,
+            accepted: vec![(0, later, Command::App(7))],
+            sent_at: None,
+        });
+        assert!(replica.is_leader());
+        assert_eq!(replica.log[0].value, Some(Command::App(7)));
+        match follower.try_recv() {
+            Ok((NodeId(1), PaxosMsg::Propose { index: 0, ballot, value, .. })) => {
+                assert_eq!(ballot, replica.highest_promised);
+                assert_eq!(value, Command::App(7));
+            }
+            other => panic!("expected Propose, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn adopt_value_with_highest_ballot_across_promises() {
+        for reversed in [false, true] {
+            let network = MemoryNetwork::<u32>::new();
+            let follower = network.connect(NodeId(2));
+            let _down: Vec<_> = (3..=5).map(|id| network.connect(NodeId(id))).collect();
+            let node = network.connect(NodeId(1));
+            let members = nodes(&[1, 2, 3, 4, 5]);
+            let mut replica =
+                PaxosReplica::with_members(node, NodeId(1), members, Counter::default()).unwrap();
+            replica.start_election();
+            while follower.try_recv().is_ok() {}
+
+            // the Promises disagree about entry 1, only the value of the later ballot might have
+            // been chosen, no matter which replica reported it or in which order they arrive
+            let mut earlier = Ballot::default();
+            earlier.increment_for(NodeId(2)).unwrap();
+            let mut later = earlier;
+            later.increment_for(NodeId(3)).unwrap();
+            let mut promises = vec![
+                (NodeId(4), vec![(1, earlier, Command::App(7)), (3, earlier, Command::App(8))]),
+                (NodeId(5), vec![(1, later, Command::App(9))]),
+            ];
+            if reversed {
+                promises.reverse();
+            }
+            for (src, accepted) in promises {
+                replica.handle_message(src, PaxosMsg::Promise {
+                    ballot: replica.highest_promised,
+                    accepted,
+                    sent_at: None,
+                });
+            }
+            assert!(replica.is_leader());
+
+            // entries no Promise reported a value for are filled with NoOps, followed by the
+            // NoOp the new leader appends
+            let values: Vec<_> = replica.log.iter().map(|entry| entry.value.clone()).collect();
+            assert_eq!(
+                values,
+                vec![
+                    Some(Command::NoOp),
+                    Some(Command::App(9)),
+                    Some(Command::NoOp),
+                    Some(Command::App(8)),
+                    Some(Command::NoOp),
+                ]
+            );
+            let proposed: Vec<_> = std::iter::from_fn(|| follower.try_recv().ok())
+                .filter_map(|(_, msg)| match msg {
+                    PaxosMsg::Propose { index, ballot, value, .. } => Some((index, ballot, value)),
+                    _ => None,
+                })
+                .collect();
+            let ballot = replica.highest_promised;
+            assert_eq!(proposed[1], (1, ballot, Command::App(9)));
+            assert_eq!(proposed.len(), 5);
+        }
+    }
+
+    #[test]
+    fn keep_value_over_promise_with_lower_ballot() {
+        let network = MemoryNetwork::<u32>::new();
+        let follower = network.connect(NodeId(2));
+        let _down = network.connect(NodeId(3));
+        let (mut replica, accepted) = candidate_with_accepted_value(&network);
+        while follower.try_recv().is_ok() {}
+
+        // values accepted at earlier ballots can't have been chosen, unlike the own one
+        let mut earlier = Ballot::default();
+        earlier.increment_for(NodeId(1)).unwrap();
+        assert!(earlier < accepted);
+        replica.handle_message(NodeId(3), PaxosMsg::Promise {
+            ballot: replica.highest_promised,
+            accepted: vec![(0, earlier, Command::App(7)), (2, earlier, Command::App(9))],
+            sent_at: None,
+        });
+        assert!(replica.is_leader());
+        let values: Vec<_> = replica.log.iter().map(|entry| entry.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Some(Command::App(5)),
+                Some(Command::NoOp),
+                Some(Command::App(9)),
+                Some(Command::NoOp),
+            ]
+        );
+    }
+
+    #[test]
+    fn recover_values_for_holes() {
+        let network = MemoryNetwork::<u32>::new();
+        let _down = network.connect(NodeId(3));
+        let clock = Arc::new(ManualClock::new());
+        let members = nodes(&[1, 2, 3]);
+        let node = network.connect(NodeId(1));
+        let mut candidate =
+            PaxosReplica::with_members(node, NodeId(1), members.clone(), Counter::default())
+                .unwrap();
+        let node = network.connect(NodeId(2));
+        let mut acceptor = PaxosReplica::with_members(node, NodeId(2), members, Counter::default())
+            .unwrap()
+            .with_clock(clock.clone());
+
+        // the third replica led and had the acceptor accept six values, of which the candidate
+        // only learned the ones chosen at even indices
+        let mut old = Ballot::default();
+        old.increment_for(NodeId(3)).unwrap();
+        for replica in [&mut candidate, &mut acceptor] {
+            replica.handle_message(NodeId(3), PaxosMsg::Prepare {
+                ballot: old,
+                holes: vec![0],
+                sent_at: None,
+            });
+        }
+        for index in 0..6 {
+            let value = Command::App(10 + index as u32);
+            acceptor.handle_message(NodeId(3), PaxosMsg::Propose {
+                index,
+                ballot: old,
+                value: value.clone(),
+                sent_at: None,
+            });
+            if index % 2 == 0 && index < 5 {
+                candidate.handle_message(NodeId(3), PaxosMsg::Learn { index, ballot: old, value });
+            }
+        }
+        while candidate.node.try_recv().is_ok() {}
+        clock.advance(LEASE_DURATION);
+
+        candidate.start_election();
+        let prepare = loop {
+            match acceptor.node.try_recv().unwrap() {
+                (NodeId(1), msg @ PaxosMsg::Prepare { .. }) => break msg,
+                _ => continue,
+            }
+        };
+        assert!(matches!(&prepare, PaxosMsg::Prepare { holes, .. } if *holes == vec![1, 3, 5]));
+        acceptor.handle_message(NodeId(1), prepare);
+
+        // the Promise only carries the values for the candidate's holes, which it adopts
+        let promise = loop {
+            match candidate.node.try_recv().unwrap() {
+                (NodeId(2), msg @ PaxosMsg::Promise { .. }) => break msg,
+                _ => continue,
+            }
+        };
+        match &promise {
+            PaxosMsg::Promise { accepted, .. } => {
+                let indices: Vec<_> = accepted.iter().map(|(index, _, _)| *index).collect();
+                assert_eq!(indices, vec![1, 3, 5]);
+            }
+            _ => unreachable!(),
+        }
+        candidate.handle_message(NodeId(2), promise);
+        assert!(candidate.is_leader());
+        let values: Vec<_> = candidate.log.iter().map(|entry| entry.value.clone()).collect();
+        let expected: Vec<_> = (10..16).map(|v| Some(Command::App(v))).collect();
+        assert_eq!(values[..6], expected[..]);
+    }
+
+    #[test]
+    fn ignore_malformed_messages() {
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        let ballot = replicas[0].highest_promised;
+        let data = replicas[1].state_machine.snapshot().unwrap();
+
+        // messages for entries which don't exist, or are too far ahead to create them
+        let far = MAX_INDEX_GAP + 2;
+        let malformed = vec![
+            PaxosMsg::Accept { index: 7, ballot, sent_at: None },
+            PaxosMsg::Accept { index: usize::MAX, ballot, sent_at: None },
+            PaxosMsg::Propose { index: far, ballot, value: Command::App(2), sent_at: None },
+            PaxosMsg::Learn { index: usize::MAX, ballot, value: Command::App(2) },
+            PaxosMsg::Promise {
+                ballot,
+                accepted: vec![(far, ballot, Command::NoOp)],
+                sent_at: None,
+            },
+            PaxosMsg::InstallSnapshot {
+                snapshot_index: usize::MAX,
+                membership: Membership::new(nodes(&[1, 2, 3])),
+                data,
+                requests: AppliedRequests::new(),
+            },
+        ];
+        for msg in malformed {
+            for replica in &mut replicas {
+                replica.handle_message(NodeId(2), msg.clone());
+            }
+        }
+        assert!(replicas.iter().all(|r| r.log.len() == 2 && r.snapshot_index == 0));
+
+        replicas[0].submit_value(2).unwrap();
+        deliver_all(&mut replicas);
+        assert!(replicas.iter().all(|r| r.committed_len() == 3));
+
+        // the limit is configurable, e.g. to be stricter
+        let mut strict = create_group(3, 0).remove(2).with_max_index_gap(4);
+        let learn = |index| PaxosMsg::Learn { index, ballot, value: Command::App(3) };
+        strict.handle_message(NodeId(1), learn(4));
+        assert!(strict.log.is_empty());
+        strict.handle_message(NodeId(1), learn(3));
+        assert_eq!(strict.log.len(), 4);
+    }
+
+    #[test]
+    fn break_ties_between_promises_deterministically() {
+        for reversed in [false, true] {
+            let (clock, _, mut replicas) = clocked_group(&[1, 2, 3, 4, 5], |r| r);
+
+            // the second and third replica accepted different values at the exact same ballot,
+            // which should never happen, but has to be resolved the same way everywhere
+            let mut ballot = Ballot::default();
+            ballot.increment_for(NodeId(4)).unwrap();
+            for replica in &mut replicas {
+                replica.handle_message(NodeId(4), PaxosMsg::Prepare {
+                    ballot,
+                    holes: vec![0],
+                    sent_at: None,
+                });
+            }
+            for (i, value) in [(1, 5), (2, 7)] {
+                replicas[i].handle_message(NodeId(4), PaxosMsg::Propose {
+                    index: 0,
+                    ballot,
+                    value: Command::App(value),
+                    sent_at: None,
+                });
+            }
+            for replica in &mut replicas {
+                while replica.node.try_recv().is_ok() {}
+            }
+            clock.advance(LEASE_DURATION);
+
+            // the candidate needs both Promises for a quorum, whichever order they arrive in
+            replicas[0].start_election();
+            let mut promises = Vec::new();
+            for i in 1..3 {
+                let (src, prepare) = replicas[i].node.try_recv().unwrap();
+                replicas[i].handle_paxos_message(src, prepare);
+                promises.push(replicas[0].node.try_recv().unwrap());
+            }
+            if reversed {
+                promises.reverse();
+            }
+            for (src, promise) in promises {
+                replicas[0].handle_paxos_message(src, promise);
+            }
+            assert!(replicas[0].is_leader());
+            deliver_all(&mut replicas);
+            for replica in &replicas {
+                assert_eq!(chosen_values(replica)[0], (0, Command::App(5)));
+            }
+        }
+    }
+
+    #[test]
+    fn tick_reports_next_wake() {
+        let mut replica = create_group(3, 0).remove(0);
+        assert!(replica.tick() <= POLL_INTERVAL);
+
+        // a follower wakes up once the leader's lease times out
+        let clock = Arc::new(ManualClock::new());
+        let mut replica = replica
+            .with_clock(clock.clone())
+            .with_poll_interval(Duration::from_secs(10));
+        let offset = replica.random_timeout_offset;
+        assert_eq!(replica.tick(), LEASE_DURATION + offset);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(replica.tick(), LEASE_DURATION + offset - Duration::from_millis(500));
+
+        // the leader wakes up in time to extend its lease
+        replica.current_leader = Some(replica.node_id);
+        replica.leader_lease_start = clock.now();
+        assert_eq!(replica.tick(), LEASE_DURATION / 2);
+
+        // an election that has not succeeded is repeated after a short random delay
+        replica.current_leader = None;
+        clock.advance(2 * LEASE_DURATION);
+        let ballot = replica.highest_promised;
+        assert_eq!(replica.tick(), offset);
+        assert!(replica.highest_promised > ballot);
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(replica.tick(), offset - Duration::from_millis(50));
+    }
+
+    #[test]
+    fn wait_only_until_next_timer() {
+        let clock = Arc::new(ManualClock::new());
+        let mut replicas: Vec<_> = create_group(3, 0)
+            .into_iter()
+            .map(|r| r.with_clock(clock.clone()))
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        assert!(replicas[0].is_leader());
+
+        // the leader has to extend its lease in 50ms, well before the poll interval is over
+        clock.advance(LEASE_DURATION / 2 - Duration::from_millis(50));
+        let timeout = replicas[0].tick();
+        assert_eq!(timeout, Duration::from_millis(50));
+        let start = Instant::now();
+        replicas[0].wait(timeout);
+        assert!(start.elapsed() < Duration::from_millis(90), "waited {:?}", start.elapsed());
+
+        clock.advance(timeout);
+        replicas[0].tick();
+        assert_eq!(replicas[0].last_election, clock.now());
+    }
+
+    #[test]
+    fn commit_noop_before_serving_reads() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // the new leader doesn't know yet whether its log is complete
+        clock.advance(LEASE_DURATION);
+        replicas[1].start_election();
+        for i in [0, 2, 1] {
+            while let Ok((src, msg)) = replicas[i].node.try_recv() {
+                replicas[i].handle_paxos_message(src, msg);
+            }
+        }
+        assert!(replicas[1].is_leader());
+        assert_eq!(replicas[1].ready_index, 4);
+        assert_eq!(replicas[1].read_local(0), Err(PaxosError::NotReady));
+
+        // once its NoOp is chosen, everything before it is known to be chosen as well
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[1].log[4].value, Some(Command::NoOp));
+        assert!(replicas[1].log[4].chosen);
+        assert_eq!(replicas[1].read_local(0), Ok("6".to_owned()));
+    }
+
+    #[test]
+    fn linearizable_read_sees_chosen_values() {
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=5 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[1].linearizable_read(0), Err(PaxosError::NotLeader));
+
+        let mut leader = replicas.remove(0);
+        let stop = Arc::new(AtomicBool::new(false));
+        let followers: Vec<_> = replicas
+            .into_iter()
+            .map(|r| spawn_follower(r, stop.clone()))
+            .collect();
+        assert_eq!(leader.linearizable_read(0), Ok("15".to_owned()));
+        stop.store(true, Ordering::Relaxed);
+        for follower in followers {
+            assert_eq!(follower.join().unwrap().apply_index, 6);
+        }
+    }
+
+    /// Path of a file in the temporary directory, which is unique to this test and replica.
+    /// Any leftovers from previous runs are removed.
+    fn state_file(test: &str, replica: &PaxosReplica<u32>) -> String {
+        let name = format!("paxos-{}-{}.bin", test, replica.node_id);
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn persist_and_recover_state() {
+        let mut replicas = create_group(3, 0);
+        let files: Vec<String> = replicas.iter().map(|r| state_file("recover", r)).collect();
+        replicas = replicas
+            .into_iter()
+            .zip(&files)
+            .map(|(r, file)| r.with_state_file(file))
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=5 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        replicas[0].take_snapshot().unwrap();
+
+        for (replica, file) in replicas.iter().zip(&files) {
+            let recovered = create_group(1, 0).remove(0).with_state_file(file);
+            assert_eq!(recovered.log, replica.log);
+            assert_eq!(recovered.highest_promised, replica.highest_promised);
+            assert_eq!(recovered.apply_index, 6);
+            assert_eq!(recovered.snapshot_index, replica.snapshot_index);
+            assert_eq!(recovered.state_machine.query(&0), Ok("15".to_owned()));
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn export_full_log() {
+        let mut replicas = create_group(3, 0);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=2 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        // proposed, but not chosen yet
+        replicas[0].submit_value(3).unwrap();
+        assert!(!replicas[0].log[3].chosen);
+
+        let file = state_file("export", &replicas[0]);
+        replicas[0].export_log(&file).unwrap();
+        let export = LogExport::<u32>::load(&file).unwrap();
+        assert_eq!(export.node_id, replicas[0].node_id);
+        assert_eq!(export.promised_ballot, replicas[0].highest_promised);
+        assert_eq!(export.apply_index, 3);
+        assert_eq!(export.log, replicas[0].log);
+        let printed = export.to_string();
+        assert!(printed.contains("apply index: 3"));
+        assert!(printed.contains("[2] chosen"));
+        assert!(printed.contains("[3] accepted"));
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn close_and_restart() {
+        let mut replicas = create_group(3, 0);
+        let members = replicas.iter().map(|r| r.id()).collect::<Vec<_>>();
+        let file = state_file("close", &replicas[0]);
+        let first = replicas.remove(0).with_state_file(&file);
+        replicas.insert(0, first);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // the port can be bound again right away, and the restarted replica has the final state
+        let closed = replicas.remove(0);
+        let addr = closed.id().to_addr().unwrap();
+        let log = closed.log.clone();
+        closed.close().unwrap();
+        let node = UdpNetworkNode::<u32>::new_on(addr.ip(), addr.port()).unwrap();
+        let node_id = node.id();
+        let restarted = PaxosReplica::with_members(node, node_id, members, Counter::default())
+            .unwrap()
+            .with_state_file(&file);
+        assert_eq!(restarted.log, log);
+        assert_eq!(restarted.state_machine.query(&0), Ok("6".to_owned()));
+        std::fs::remove_file(&file).unwrap();
+
+        // failing to persist the final state is reported
+        let dir = std::env::temp_dir().join("paxos-close-missing-dir");
+        let file = dir.join("state.bin");
+        let replica = create_group(1, 0).remove(0).with_state_file(file.to_str().unwrap());
+        assert!(matches!(replica.close(), Err(PaxosError::Io(_))));
+    }
+
+    #[test]
+    fn keep_logical_id_across_address_change() {
+        let clock = Arc::new(ManualClock::new());
+        let mut udp_nodes: Vec<_> = (1..=3)
+            .map(|id| UdpNetworkNode::<u32>::new().with_id(NodeId(id)))
+            .collect();
+        let addrs: Vec<_> = udp_nodes
+            .iter()
+            .map(|node| node.socket.local_addr().unwrap())
+            .collect();
+        for node in &mut udp_nodes {
+            for (id, &addr) in (1..).map(NodeId).zip(&addrs) {
+                if id != node.id() {
+                    node.discover_at(id, addr);
+                }
+            }
+        }
+        let create = |node: UdpNetworkNode<u32>| {
+            let node_id = node.id();
+            PaxosReplica::with_members(node, node_id, nodes(&[1, 2, 3]), Counter::default())
+                .unwrap()
+                .with_clock(clock.clone())
+        };
+        let mut replicas: Vec<_> = udp_nodes.into_iter().map(create).collect();
+        let file = state_file("logical-id", &replicas[2]);
+        let third = replicas.pop().unwrap().with_state_file(&file);
+        replicas.push(third);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        replicas[0].submit_value(1).unwrap();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[2].committed_len(), 2);
+
+        // the third replica comes back on another port, while the second one stays down
+        replicas.pop().unwrap().close().unwrap();
+        replicas.pop().unwrap().close().unwrap();
+        let mut node = UdpNetworkNode::<u32>::new().with_id(NodeId(3));
+        node.discover_at(NodeId(1), addrs[0]);
+        assert_ne!(node.socket.local_addr().unwrap(), addrs[2]);
+        let restarted = create(node).with_state_file(&file);
+        assert_eq!(restarted.committed_len(), 2);
+        replicas.push(restarted);
+        replicas[0].submit_value(2).unwrap();
+
+        // the first replica reaches it at its new address, and counts its votes towards quorums
+        for _ in 0..1000 {
+            clock.advance(Duration::from_millis(10));
+            for replica in replicas.iter_mut() {
+                replica.tick();
+            }
+            let sums: Vec<_> = replicas
+                .iter_mut()
+                .map(|replica| replica.state_machine.query(&0))
+                .collect();
+            if sums.iter().all(|sum| *sum == Ok("3".to_owned())) {
+                std::fs::remove_file(&file).unwrap();
+                return;
+            }
+        }
+        panic!("value wasn't chosen by the first and the restarted replica");
+    }
+
+    /// Encodes with bincode, counting how often the codec is used.
+    #[derive(Debug, Default)]
+    struct CountingCodec {
+        calls: Arc<Mutex<usize>>,
+    }
+
+    impl Codec<PersistentState<u32>> for CountingCodec {
+        fn encode(&self, value: &PersistentState<u32>) -> Result<Vec<u8>, CodecError> {
+            *self.calls.lock().unwrap() += 1;
+            BincodeCodec::default().encode(value)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<PersistentState<u32>, CodecError> {
+            *self.calls.lock().unwrap() += 1;
+            BincodeCodec::default().decode(bytes)
+        }
+    }
+
+    #[test]
+    fn group_commit_accepts() {
+        let (clock, _, mut replicas) = clocked_group(&[1, 2, 3], |r| r);
+        let calls = Arc::new(Mutex::new(0));
+        // the third replica is down, so that the leader depends on the second one's Accepts
+        replicas.pop();
+        let follower = replicas.pop().unwrap();
+        let follower = follower.with_storage_codec(CountingCodec { calls: calls.clone() });
+        let file = state_file("group-commit", &follower);
+        let follower = follower
+            .with_state_file(&file)
+            .with_group_commit(Duration::from_millis(10));
+        replicas.push(follower);
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        clock.advance(Duration::from_millis(10));
+        replicas[1].tick();
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].committed_len(), 1);
+
+        // the Accepts for a burst of Proposes are held back, without persisting anything
+        let persisted = *calls.lock().unwrap();
+        for v in 1..=20 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        assert_eq!(*calls.lock().unwrap(), persisted);
+        assert_eq!(replicas[0].committed_len(), 1);
+
+        // once the window has passed, they are all persisted at once before being sent
+        clock.advance(Duration::from_millis(10));
+        replicas[1].tick();
+        assert_eq!(*calls.lock().unwrap(), persisted + 1);
+        let state: PersistentState<u32> =
+            load_from_disk_file(&BincodeCodec::default(), &file).unwrap();
+        assert_eq!(state.log.len(), 21);
+        assert!(state.log.iter().all(|entry| entry.value.is_some()));
+        deliver_all(&mut replicas);
+        assert_eq!(replicas[0].committed_len(), 21);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn run_ephemeral_replicas() {
+        let calls = Arc::new(Mutex::new(0));
+        let file = state_file("ephemeral", &create_group(1, 0)[0]);
+        let mut replicas: Vec<_> = create_group(3, 0)
+            .into_iter()
+            .map(|r| {
+                r.with_storage_codec(CountingCodec { calls: calls.clone() })
+                    .with_state_file(&file)
+                    .with_ephemeral_storage()
+            })
+            .collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=5 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        replicas[0].take_snapshot().unwrap();
+        deliver_all(&mut replicas);
+
+        for replica in &mut replicas {
+            assert_eq!(replica.state_machine.query(&0), Ok("15".to_owned()));
+        }
+        assert_eq!(*calls.lock().unwrap(), 0);
+        assert!(!Path::new(&file).exists());
+    }
+
+    #[test]
+    fn recover_from_compact_storage() {
+        let network = MemoryNetwork::<u32>::new();
+        let file = |id| {
+            let path = std::env::temp_dir().join(format!("paxos-compact-{}.bin", id));
+            path.to_str().unwrap().to_owned()
+        };
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2, 3]),
+                Counter::default(),
+            )
+            .unwrap()
+            .with_compact_storage()
+            .with_state_file(&file(id))
+        };
+        for id in 1..=3 {
+            let _ = std::fs::remove_file(file(id));
+        }
+        let mut replicas: Vec<_> = (1..=3).map(create).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+
+        // the second replica accepts another value, which isn't chosen before it crashes
+        replicas[0].submit_value(4).unwrap();
+        let (src, propose) = replicas[1].node.try_recv().unwrap();
+        replicas[1].handle_message(src, propose);
+        let ballot = replicas[1].highest_promised;
+        let log = replicas[1].log.clone();
+        drop(replicas.remove(1));
+
+        let mut recovered = create(2);
+        assert_eq!(recovered.highest_promised, ballot);
+        assert_eq!(recovered.committed_len(), 4);
+        assert_eq!(recovered.state_machine.query(&0), Ok("6".to_owned()));
+        assert!(recovered.log.iter().all(|entry| entry.acceptances.is_empty()));
+        let values = |log: &[LogEntry<u32>]| -> Vec<_> {
+            log.iter().map(|e| (e.value.clone(), e.accepted_ballot, e.chosen)).collect()
+        };
+        assert_eq!(values(&recovered.log), values(&log));
+        assert_eq!(recovered.log[4].value, Some(Command::App(4)));
+
+        // messages of earlier ballots are still rejected
+        while replicas[1].node.try_recv().is_ok() {}
+        recovered.handle_message(NodeId(3), PaxosMsg::Prepare {
+            ballot: Ballot::default(),
+            holes: vec![4],
+            sent_at: None,
+        });
+        assert!(matches!(replicas[1].node.try_recv(), Ok((NodeId(2), PaxosMsg::Nack { .. }))));
+        for id in 1..=3 {
+            std::fs::remove_file(file(id)).unwrap();
+        }
+    }
+
+    #[test]
+    fn separate_data_dirs() {
+        let base = std::env::temp_dir().join(format!("paxos-data-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let network = MemoryNetwork::<u32>::new();
+        let create = |id| {
+            PaxosReplica::with_members(
+                network.connect(NodeId(id)),
+                NodeId(id),
+                nodes(&[1, 2]),
+                Counter::default(),
+            )
+            .unwrap()
+            .with_data_dir(&base)
+            .unwrap()
+        };
+        let mut replicas: Vec<_> = (1..=2).map(create).collect();
+        replicas[0].start_election();
+        deliver_all(&mut replicas);
+        for v in 1..=3 {
+            replicas[0].submit_value(v).unwrap();
+        }
+        deliver_all(&mut replicas);
+        replicas[0].take_snapshot().unwrap();
+
+        assert!(base.join("1").join("state.bin").exists());
+        assert!(base.join("2").join("state.bin").exists());
+        drop(replicas);
+        let recovered: Vec<_> = (1..=2).map(create).collect();
+        assert_eq!(recovered[0].snapshot_index, 4);
+        assert_eq!(recovered[1].snapshot_index, 0);
+        assert_eq!(recovered[1].committed_len(), 4);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn fail_to_create_data_dir() {
+        let base = std::env::temp_dir().join(format!("paxos-data-file-{}", std::process::id()));
+        std::fs::write(&base, b"not a directory").unwrap();
+        let network = MemoryNetwork::<u32>::new();
+        let replica = PaxosReplica::with_members(
+            network.connect(NodeId(1)),
+            NodeId(1),
+            nodes(&[1]),
+            Counter::default(),
+        )
+        .unwrap()
+        .with_data_dir(&base);
+        assert!(matches!(replica, Err(PaxosError::Io(_))));
+        std::fs::remove_file(&base).unwrap();
+    }
+
+    #[test]
+    fn recover_from_corrupted_state() {
+        let replica = create_group(1, 0).remove(0);
+        let file = state_file("corrupted", &replica);
+
+        std::fs::write(&file, b"definitely not a valid state").unwrap();
+        let recovered = create_group(1, 0).remove(0).with_state_file(&file);
+        assert!(recovered.log.is_empty());
+
+        let state = PersistentState::<u32> {
+            promised_ballot: Ballot::default(),
+            log: vec![LogEntry::default()],
+            snapshot_index: 0,
+            snapshot: None,
+            apply_index: 1,
+        };
+        store_in_disk_file(&BincodeCodec::default(), &file, &state).unwrap();
+        let recovered = create_group(1, 0).remove(0).with_state_file(&file);
+        assert!(recovered.log.is_empty());
+        assert_eq!(recovered.apply_index, 0);
+        std::fs::remove_file(file).unwrap();
     }
 }
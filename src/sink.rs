@@ -0,0 +1,187 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Sinks which copy the commands applied by a replica into external systems.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::error;
+
+use crate::replica::AppliedCommand;
+
+/// Receives every command a replica applies, in log order, e.g. to replicate the changes into
+/// a database, a file or a message queue, see `PaxosReplica::add_sink`.
+///
+/// Within a run, each command is passed to the sink exactly once. Across restarts, delivery is
+/// at-least-once: the replica replays all commands from `resume_index` onwards, so a sink which
+/// needs exactly-once output has to skip the commands it has processed already.
+pub trait CommitSink<V> {
+    /// Processes the command applied at log index `index`, with its result.
+    /// The commands of a batch share its index and are passed on in order.
+    fn on_commit(&mut self, index: usize, cmd: &V, result: &Result<String, ()>);
+
+    /// The log index from which on the replica passes commands to the sink when it is added,
+    /// i.e. the index of the first entry the sink hasn't processed completely. Starts at the
+    /// beginning of the log by default.
+    fn resume_index(&self) -> usize {
+        0
+    }
+}
+
+/// Appends the applied commands to a file, one JSON line of index, command and result each.
+///
+/// The file itself records the sink's progress: when reopened, the sink resumes after the last
+/// command in the file, so each command is written exactly once across restarts.
+/// Once writing fails, the sink stops and logs the error, leaving the rest to the next run.
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+    /// The index of the last command written, and how many commands of its entry were written.
+    last: Option<(usize, usize)>,
+    /// The number of commands passed on for the index of the last command written, in this run.
+    seen: usize,
+}
+
+impl FileSink {
+    /// Opens the file at `path`, creating it if needed, and reads how far it got.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let mut last: Option<(usize, usize)> = None;
+        for line in BufReader::new(&file).lines() {
+            let (index, _, _): (usize, serde_json::Value, serde_json::Value) =
+                serde_json::from_str(&line?)?;
+            last = match last {
+                Some((last_index, count)) if last_index == index => Some((index, count + 1)),
+                _ => Some((index, 1)),
+            };
+        }
+        Ok(Self {
+            path,
+            file: Some(file),
+            last,
+            seen: 0,
+        })
+    }
+
+    /// Reads back the commands written to the file at `path`, with their indices and results.
+    pub fn read<V, P>(path: P) -> io::Result<Vec<AppliedCommand<V>>>
+    where
+        V: DeserializeOwned,
+        P: AsRef<Path>,
+    {
+        let file = File::open(path)?;
+        let mut commands = Vec::new();
+        for line in BufReader::new(file).lines() {
+            commands.push(serde_json::from_str(&line?)?);
+        }
+        Ok(commands)
+    }
+
+    /// Whether the command is one of those written in an earlier run.
+    fn is_written(&mut self, index: usize) -> bool {
+        match self.last {
+            Some((last_index, _)) if index < last_index => true,
+            Some((last_index, count)) if index == last_index => {
+                self.seen += 1;
+                self.seen <= count
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<V: Serialize> CommitSink<V> for FileSink {
+    fn on_commit(&mut self, index: usize, cmd: &V, result: &Result<String, ()>) {
+        if self.is_written(index) {
+            return;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let mut line = serde_json::to_vec(&(index, cmd, result)).expect("serializable command");
+        line.push(b'\n');
+        if let Err(e) = file.write_all(&line) {
+            error!("Failed to write to sink {}, stopping: {}", self.path.display(), e);
+            self.file = None;
+            return;
+        }
+        self.last = match self.last {
+            Some((last_index, count)) if last_index == index => Some((index, count + 1)),
+            _ => Some((index, 1)),
+        };
+        self.seen = self.last.map_or(0, |(_, count)| count);
+    }
+
+    fn resume_index(&self) -> usize {
+        // the entry of the last command may be a batch which was only written in part
+        self.last.map_or(0, |(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{MemoryNetwork, NodeId};
+    use crate::replica::PaxosReplica;
+
+    #[test]
+    fn file_sink_survives_restart() {
+        let dir = std::env::temp_dir();
+        let state_file = dir.join("paxos-sink-state.bin");
+        let state_file = state_file.to_str().unwrap();
+        let sink_file = dir.join("paxos-sink.jsonl");
+        let _ = std::fs::remove_file(state_file);
+        let _ = std::fs::remove_file(&sink_file);
+        let network = MemoryNetwork::<u32>::new();
+        let create = || {
+            let node = network.connect(NodeId(1));
+            PaxosReplica::with_members(node, NodeId(1), vec![NodeId(1)], Vec::new())
+                .unwrap()
+                .with_state_file(state_file)
+        };
+
+        // commands applied before the sink is added are replayed to it
+        let mut replica = create();
+        replica.tick();
+        replica.submit_value(1).unwrap();
+        replica.submit_batch(vec![2, 3]).unwrap();
+        replica.tick();
+        let sink = replica.add_sink(FileSink::open(&sink_file).unwrap()).unwrap();
+        replica.submit_value(4).unwrap();
+        replica.submit_batch(vec![5, 6]).unwrap();
+        replica.tick();
+        drop(replica);
+        sink.join().unwrap();
+
+        // crash in the middle of writing the batch
+        let written = std::fs::read_to_string(&sink_file).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 6);
+        std::fs::write(&sink_file, lines[..5].join("\n") + "\n").unwrap();
+
+        // the restarted replica replays the batch, of which only the missing command is written
+        let mut replica = create();
+        let sink = replica.add_sink(FileSink::open(&sink_file).unwrap()).unwrap();
+        replica.tick();
+        replica.submit_value(7).unwrap();
+        replica.tick();
+        drop(replica);
+        sink.join().unwrap();
+
+        let commands: Vec<AppliedCommand<u32>> = FileSink::read(&sink_file).unwrap();
+        let values: Vec<_> = commands.iter().map(|(_, v, _)| *v).collect();
+        assert_eq!(values, (1..=7).collect::<Vec<_>>());
+        let indices: Vec<_> = commands.iter().map(|(i, _, _)| *i).collect();
+        assert!(indices.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(indices[1], indices[2]);
+        assert_eq!(indices[4], indices[5]);
+        assert!(commands.iter().all(|(_, _, result)| result.is_ok()));
+    }
+}
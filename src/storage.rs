@@ -4,46 +4,415 @@
 //! Defines ways of persisting data to disk and retrieving it back.
 //! PaxosReplica uses this module's methods to keep its persistent state.
 
-use serde::{de::DeserializeOwned, Serialize};
-use tracing::error;
-
-/// Serializes the `value` into a file called `filename`.
-pub fn store_in_disk_file<T: ?Sized + Serialize>(filename: &str, value: &T) -> Result<(), ()> {
-    let storage = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(filename)
-        .map_err(|e| {
-            error!("Failed to create or open file: {:?}", e);
-        })?;
-    bincode::serialize_into(storage, value).map_err(|e| {
-        error!("Failed to serialize state: {:?}", e);
-    })
-}
-
-/// Deserializes the previously stored value from the file called `filename`.
-pub fn load_from_disk_file<T: DeserializeOwned>(filename: &str) -> Result<T, ()> {
-    let storage = std::fs::OpenOptions::new()
-        .read(true)
-        .open(filename)
-        .map_err(|e| {
-            error!("Failed to create or open file: {:?}", e);
-        })?;
-    bincode::deserialize_from(storage).map_err(|e| {
-        error!("Failed to serialize state: {:?}", e);
-    })
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use crate::codec::{Codec, CodecError};
+use crate::network::NodeId;
+use crate::protocol::{Ballot, LogEntry, Snapshot};
+
+/// All state a replica needs to persist in order to safely recover after a crash.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PersistentState<V> {
+    /// The highest Ballot this replica has promised not to undercut.
+    pub promised_ballot: Ballot,
+    pub log: Vec<LogEntry<V>>,
+    /// Index up to which the log is covered by a snapshot of the state machine.
+    pub snapshot_index: usize,
+    /// The snapshot covering the log up to `snapshot_index`, if there is one.
+    pub snapshot: Option<Snapshot>,
+    /// Index of the next log entry to be applied to the state machine.
+    pub apply_index: usize,
+}
+
+impl<V> PersistentState<V> {
+    /// Checks the indices against each other and the log, to detect corrupted state.
+    /// Entries can only be applied once they are chosen, which in turn requires them to be in
+    /// the log, i.e. `snapshot_index <= apply_index <= committed <= log.len()`.
+    /// Entries covered by the snapshot can only be discarded if the snapshot exists.
+    pub fn is_consistent(&self) -> bool {
+        let committed = self.log.iter().take_while(|entry| entry.chosen).count();
+        self.snapshot_index <= self.apply_index
+            && self.apply_index <= committed
+            && (self.snapshot_index == 0 || self.snapshot.is_some())
+    }
+}
+
+/// A replica's complete log, as written by `PaxosReplica::export_log` for offline analysis.
+/// Unlike `PersistentState`, it holds all the replica knows about each entry, e.g. the
+/// acceptances it collected as leader, also for entries which aren't chosen yet.
+///
+/// Exports are stored as pretty-printed JSON, so that other tools can process them as well.
+/// `Display` prints them as a table with one entry per line, like `paxos_replica inspect` does.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogExport<V> {
+    pub node_id: NodeId,
+    /// The highest Ballot the replica has promised not to undercut.
+    pub promised_ballot: Ballot,
+    /// Index up to which the log is covered by a snapshot, so that these entries lack values.
+    pub snapshot_index: usize,
+    /// Index of the next log entry to be applied to the state machine.
+    pub apply_index: usize,
+    pub log: Vec<LogEntry<V>>,
+}
+
+impl<V: Serialize> LogExport<V> {
+    /// Writes the export to the file called `filename`, replacing any previous one.
+    pub fn store(&self, filename: &str) -> Result<(), StorageError> {
+        let file = File::create(filename).map_err(StorageError::Io)?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| StorageError::Codec(Box::new(e)))
+    }
+}
+
+impl<V: DeserializeOwned> LogExport<V> {
+    /// Reads an export back from the file called `filename`.
+    pub fn load(filename: &str) -> Result<Self, StorageError> {
+        let file = File::open(filename).map_err(StorageError::Io)?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| StorageError::Codec(Box::new(e)))
+    }
+}
+
+impl<V: fmt::Debug> fmt::Display for LogExport<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "replica: {}", self.node_id)?;
+        writeln!(f, "promised ballot: {:?}", self.promised_ballot)?;
+        writeln!(f, "snapshot index: {}", self.snapshot_index)?;
+        writeln!(f, "apply index: {}", self.apply_index)?;
+        for (index, entry) in self.log.iter().enumerate() {
+            let state = if index < self.snapshot_index {
+                "snapshot"
+            } else if entry.chosen {
+                "chosen"
+            } else if entry.value.is_some() {
+                "accepted"
+            } else {
+                "empty"
+            };
+            let mut acceptances: Vec<_> = entry.acceptances.iter().map(|id| id.0).collect();
+            acceptances.sort_unstable();
+            write!(f, "[{}] {} {:?} {:?}", index, state, entry.accepted_ballot, acceptances)?;
+            match &entry.value {
+                Some(value) => writeln!(f, " {:?}", value)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Version of the file format written by `store_in_disk_file`, stored in its first byte.
+const FORMAT_VERSION: u8 = 5;
+
+/// Length of the header preceding the payload: the version byte and the payload's CRC-32.
+const HEADER_LEN: usize = 5;
+
+/// Errors which can occur when persisting state or reading it back.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The file could not be created, written or read.
+    Io(std::io::Error),
+    /// The value could not be encoded, or the payload could not be decoded with the codec.
+    Codec(CodecError),
+    /// The file was written in a format this version doesn't understand.
+    UnsupportedVersion(u8),
+    /// The file is truncated or its payload doesn't match the stored checksum,
+    /// e.g. because writing it was interrupted or the disk corrupted it.
+    Corrupt,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Codec(e) => write!(f, "codec error: {}", e),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported storage format version {}", version)
+            }
+            Self::Corrupt => write!(f, "checksum mismatch, file is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Computes the CRC-32 (IEEE) checksum of the bytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// The file new state is written to, before it replaces the file called `filename`.
+fn temp_file(filename: &str) -> String {
+    format!("{}.tmp", filename)
+}
+
+/// Serializes the `value` with the `codec` into a file called `filename`.
+/// The payload is preceded by the format version and its checksum.
+///
+/// The value is first written to a temporary file, which then atomically replaces the old one.
+/// A crash at any point therefore leaves either the old or the new value behind, never a mix.
+pub fn store_in_disk_file<T>(
+    codec: &dyn Codec<T>,
+    filename: &str,
+    value: &T,
+) -> Result<(), StorageError> {
+    let payload = codec.encode(value).map_err(StorageError::Codec)?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.push(FORMAT_VERSION);
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+
+    let temp = temp_file(filename);
+    let mut file = File::create(&temp).map_err(StorageError::Io)?;
+    file.write_all(&bytes).map_err(StorageError::Io)?;
+    file.sync_all().map_err(StorageError::Io)?;
+    std::fs::rename(&temp, filename).map_err(StorageError::Io)
+}
+
+/// Deserializes the value previously stored with the `codec` from the file called `filename`.
+/// The payload is only decoded once its checksum has been verified.
+pub fn load_from_disk_file<T>(codec: &dyn Codec<T>, filename: &str) -> Result<T, StorageError> {
+    let bytes = std::fs::read(filename).map_err(StorageError::Io)?;
+    if bytes.len() < HEADER_LEN {
+        return Err(StorageError::Corrupt);
+    }
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    if header[0] != FORMAT_VERSION {
+        return Err(StorageError::UnsupportedVersion(header[0]));
+    }
+    let checksum = u32::from_le_bytes(header[1..].try_into().unwrap());
+    if crc32(payload) != checksum {
+        return Err(StorageError::Corrupt);
+    }
+    codec.decode(payload).map_err(StorageError::Codec)
+}
+
+/// Suffix of the sidecar file holding the byte offsets of the entries in a `LogFile`.
+const INDEX_SUFFIX: &str = ".idx";
+
+/// Length of the header preceding each entry in a `LogFile`: its length and CRC-32.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Log entries stored one after another in a file, each with its own length and checksum.
+/// A sidecar index file holds the byte offset of every entry, so that single entries or ranges
+/// can be read without decoding all entries before them, e.g. to help a lagging replica catch
+/// up. Only the index is kept in memory, at 8 bytes per entry.
+///
+/// The data file is authoritative: the index is rebuilt from it whenever the two don't match,
+/// e.g. after a crash between appending to one and the other. An entry only partially written
+/// to the end of the data file is discarded.
+#[derive(Debug)]
+pub struct LogFile<V> {
+    data: File,
+    index: File,
+    /// Offsets of all entries in the data file, followed by the end of the last entry.
+    offsets: Vec<u64>,
+    codec: Arc<dyn Codec<LogEntry<V>>>,
+}
+
+impl<V> LogFile<V> {
+    /// Opens the log stored in the file called `filename` and its index, creating both if they
+    /// don't exist yet. The entries are encoded with the `codec`.
+    pub fn open(
+        filename: &str,
+        codec: impl Codec<LogEntry<V>> + 'static,
+    ) -> Result<Self, StorageError> {
+        let open = |name: &str| {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create(true);
+            options.open(name).map_err(StorageError::Io)
+        };
+        let mut log = Self {
+            data: open(filename)?,
+            index: open(&format!("{}{}", filename, INDEX_SUFFIX))?,
+            offsets: vec![0],
+            codec: Arc::new(codec),
+        };
+        log.offsets = match log.read_index()? {
+            Some(offsets) => offsets,
+            None => log.rebuild_index()?,
+        };
+        Ok(log)
+    }
+
+    /// The number of entries in the log.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends the entry to the end of the log.
+    /// Like all entries appended since, it is only guaranteed to survive a crash after `sync`.
+    pub fn append(&mut self, entry: &LogEntry<V>) -> Result<(), StorageError> {
+        let payload = self.codec.encode(entry).map_err(StorageError::Codec)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let end = *self.offsets.last().unwrap();
+        self.data.seek(SeekFrom::Start(end)).map_err(StorageError::Io)?;
+        self.data.write_all(&record).map_err(StorageError::Io)?;
+        let new_end = end + record.len() as u64;
+        let position = SeekFrom::Start(self.len() as u64 * 8);
+        self.index.seek(position).map_err(StorageError::Io)?;
+        self.index.write_all(&end.to_le_bytes()).map_err(StorageError::Io)?;
+        self.offsets.push(new_end);
+        Ok(())
+    }
+
+    /// Discards all entries from index `len` onwards, e.g. ones left over from an earlier run.
+    /// Like appending, this is only guaranteed to survive a crash after `sync`.
+    pub fn truncate(&mut self, len: usize) -> Result<(), StorageError> {
+        if len >= self.len() {
+            return Ok(());
+        }
+        self.offsets.truncate(len + 1);
+        self.data.set_len(self.offsets[len]).map_err(StorageError::Io)?;
+        self.index.set_len(len as u64 * 8).map_err(StorageError::Io)
+    }
+
+    /// Flushes all appended entries to disk.
+    pub fn sync(&self) -> Result<(), StorageError> {
+        self.data.sync_data().map_err(StorageError::Io)?;
+        self.index.sync_data().map_err(StorageError::Io)
+    }
+
+    /// Reads the entry at `index`, or `None` if the log isn't that long.
+    pub fn get(&self, index: usize) -> Result<Option<LogEntry<V>>, StorageError> {
+        if index >= self.len() {
+            return Ok(None);
+        }
+        Ok(self.range(index..index + 1)?.pop())
+    }
+
+    /// Reads the entries in the range of indices, which is cut off at the end of the log.
+    pub fn range(&self, range: Range<usize>) -> Result<Vec<LogEntry<V>>, StorageError> {
+        let end = range.end.min(self.len());
+        if range.start >= end {
+            return Ok(Vec::new());
+        }
+        let (from, to) = (self.offsets[range.start], self.offsets[end]);
+        let mut bytes = vec![0; (to - from) as usize];
+        let mut data = &self.data;
+        data.seek(SeekFrom::Start(from)).map_err(StorageError::Io)?;
+        data.read_exact(&mut bytes).map_err(StorageError::Io)?;
+
+        let mut entries = Vec::with_capacity(end - range.start);
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let (payload, len) = parse_record(rest).ok_or(StorageError::Corrupt)?;
+            entries.push(self.codec.decode(payload).map_err(StorageError::Codec)?);
+            rest = &rest[len..];
+        }
+        Ok(entries)
+    }
+
+    /// Reads the offsets stored in the index file, if they match the data file.
+    /// The last entry needs to end exactly where the data file does.
+    fn read_index(&mut self) -> Result<Option<Vec<u64>>, StorageError> {
+        let mut bytes = Vec::new();
+        self.index.read_to_end(&mut bytes).map_err(StorageError::Io)?;
+        let data_len = self.data.metadata().map_err(StorageError::Io)?.len();
+        if bytes.len() % 8 != 0 {
+            return Ok(None);
+        }
+        let mut offsets: Vec<u64> = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        let end = match offsets.last() {
+            Some(&last) => {
+                let mut header = [0; RECORD_HEADER_LEN];
+                let mut data = &self.data;
+                data.seek(SeekFrom::Start(last)).map_err(StorageError::Io)?;
+                if data.read_exact(&mut header).is_err() {
+                    return Ok(None);
+                }
+                let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+                last + (RECORD_HEADER_LEN as u64) + u64::from(len)
+            }
+            None => 0,
+        };
+        let increasing = offsets.windows(2).all(|pair| pair[0] < pair[1]);
+        if end != data_len || offsets.first().is_some_and(|&first| first != 0) || !increasing {
+            return Ok(None);
+        }
+        offsets.push(end);
+        Ok(Some(offsets))
+    }
+
+    /// Scans the data file for all complete entries, discarding any partially written one at
+    /// its end, and writes the index anew.
+    fn rebuild_index(&mut self) -> Result<Vec<u64>, StorageError> {
+        let mut bytes = Vec::new();
+        self.data.seek(SeekFrom::Start(0)).map_err(StorageError::Io)?;
+        self.data.read_to_end(&mut bytes).map_err(StorageError::Io)?;
+        let mut offsets = vec![0];
+        let mut end = 0;
+        while let Some((_, len)) = parse_record(&bytes[end..]) {
+            end += len;
+            offsets.push(end as u64);
+        }
+        if end < bytes.len() {
+            warn!("Discarding {} bytes at the end of the log.", bytes.len() - end);
+            self.data.set_len(end as u64).map_err(StorageError::Io)?;
+        }
+
+        let index: Vec<u8> = offsets[..offsets.len() - 1]
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect();
+        self.index.set_len(0).map_err(StorageError::Io)?;
+        self.index.seek(SeekFrom::Start(0)).map_err(StorageError::Io)?;
+        self.index.write_all(&index).map_err(StorageError::Io)?;
+        self.index.sync_data().map_err(StorageError::Io)?;
+        Ok(offsets)
+    }
+}
+
+/// Splits off the payload of the record at the start of the bytes, together with the length
+/// of the whole record. Fails if the record is incomplete or its checksum doesn't match.
+fn parse_record(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[4..RECORD_HEADER_LEN].try_into().unwrap());
+    let payload = bytes[RECORD_HEADER_LEN..].get(..len)?;
+    (crc32(payload) == checksum).then(|| (payload, RECORD_HEADER_LEN + len))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::codec::{BincodeCodec, JsonCodec};
+    use crate::network::NodeId;
+    use crate::protocol::Command;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn store_and_load() {
         static FILENAME: &str = "store_and_load.XlWG2sQCFyXNjIyq.bin";
-        store_in_disk_file(FILENAME, &999).unwrap();
-        let num: i32 = load_from_disk_file(FILENAME).unwrap();
+        store_in_disk_file(&BincodeCodec::default(), FILENAME, &999).unwrap();
+        let num: i32 = load_from_disk_file(&BincodeCodec::default(), FILENAME).unwrap();
         assert_eq!(num, 999);
         std::fs::remove_file(FILENAME).unwrap();
     }
@@ -52,10 +421,214 @@ mod tests {
     fn store_and_load_vec() {
         static FILENAME: &str = "store_and_load_vec.TWvJtuzqqbwOVGu5.bin";
         let squares = vec![0, 1, 4, 9, 16, 25, 36, 49, 64, 81];
-        store_in_disk_file(FILENAME, &squares).unwrap();
-        let squares_loaded: Vec<i32> = load_from_disk_file(FILENAME).unwrap();
+        store_in_disk_file(&BincodeCodec::default(), FILENAME, &squares).unwrap();
+        let squares_loaded: Vec<i32> =
+            load_from_disk_file(&BincodeCodec::default(), FILENAME).unwrap();
         println!("{:?}", squares_loaded);
         assert_eq!(squares_loaded, squares);
         std::fs::remove_file(FILENAME).unwrap();
     }
+
+    #[test]
+    fn store_and_load_json() {
+        static FILENAME: &str = "store_and_load_json.k2Qd8VxLmR0aPz1e.json";
+        let state = PersistentState::<String> {
+            promised_ballot: Ballot::default(),
+            log: vec![LogEntry::default()],
+            snapshot_index: 0,
+            snapshot: None,
+            apply_index: 0,
+        };
+        store_in_disk_file(&JsonCodec, FILENAME, &state).unwrap();
+        let bytes = std::fs::read(FILENAME).unwrap();
+        let payload = std::str::from_utf8(&bytes[HEADER_LEN..]).unwrap();
+        assert!(payload.contains("\"promised_ballot\""));
+        let loaded = load_from_disk_file(&JsonCodec, FILENAME).unwrap();
+        assert_eq!(state, loaded);
+        let bincode = BincodeCodec::default();
+        assert!(load_from_disk_file::<PersistentState<String>>(&bincode, FILENAME).is_err());
+        std::fs::remove_file(FILENAME).unwrap();
+    }
+
+    #[test]
+    fn checksum() {
+        // check value of the CRC-32 variant used by zlib, PNG, etc.
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn detect_corruption() {
+        static FILENAME: &str = "detect_corruption.Hq3vNn8cYw5ZtEo2.bin";
+        let squares = vec![0, 1, 4, 9, 16, 25, 36, 49, 64, 81];
+        store_in_disk_file(&BincodeCodec::default(), FILENAME, &squares).unwrap();
+        let stored = std::fs::read(FILENAME).unwrap();
+
+        // a flipped bit would still decode, but into a different value
+        let mut bytes = stored.clone();
+        bytes[HEADER_LEN + 8] ^= 0x04;
+        std::fs::write(FILENAME, &bytes).unwrap();
+        assert!(matches!(
+            load_from_disk_file::<Vec<i32>>(&BincodeCodec::default(), FILENAME),
+            Err(StorageError::Corrupt)
+        ));
+
+        // a partially written file
+        std::fs::write(FILENAME, &stored[..stored.len() - 4]).unwrap();
+        assert!(matches!(
+            load_from_disk_file::<Vec<i32>>(&BincodeCodec::default(), FILENAME),
+            Err(StorageError::Corrupt)
+        ));
+        std::fs::write(FILENAME, &stored[..3]).unwrap();
+        assert!(matches!(
+            load_from_disk_file::<Vec<i32>>(&BincodeCodec::default(), FILENAME),
+            Err(StorageError::Corrupt)
+        ));
+
+        let mut bytes = stored;
+        bytes[0] = FORMAT_VERSION + 1;
+        std::fs::write(FILENAME, &bytes).unwrap();
+        assert!(matches!(
+            load_from_disk_file::<Vec<i32>>(&BincodeCodec::default(), FILENAME),
+            Err(StorageError::UnsupportedVersion(6))
+        ));
+        std::fs::remove_file(FILENAME).unwrap();
+    }
+
+    #[test]
+    fn reject_huge_length_prefix() {
+        static FILENAME: &str = "reject_huge_length_prefix.Zc4rLw8tHn1yQe6v.bin";
+        // a correctly checksummed file claiming a string of 2^40 bytes, after the codec's tag
+        let mut payload = vec![0];
+        payload.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        let mut bytes = vec![FORMAT_VERSION];
+        bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        std::fs::write(FILENAME, &bytes).unwrap();
+        assert!(matches!(
+            load_from_disk_file::<String>(&BincodeCodec::default(), FILENAME),
+            Err(StorageError::Codec(_))
+        ));
+        std::fs::remove_file(FILENAME).unwrap();
+    }
+
+    #[test]
+    fn crash_while_storing() {
+        static FILENAME: &str = "crash_while_storing.U7kPq2xRbZ9mWd4s.bin";
+        store_in_disk_file(&BincodeCodec::default(), FILENAME, &vec![1, 2, 3]).unwrap();
+
+        // a crash before the swap leaves a partially written temporary file behind
+        let mut bytes = std::fs::read(FILENAME).unwrap();
+        bytes.truncate(bytes.len() - 2);
+        std::fs::write(temp_file(FILENAME), &bytes).unwrap();
+        let loaded: Vec<i32> = load_from_disk_file(&BincodeCodec::default(), FILENAME).unwrap();
+        assert_eq!(loaded, vec![1, 2, 3]);
+
+        // the next store replaces both, so a complete file is never mixed with a stale one
+        store_in_disk_file(&BincodeCodec::default(), FILENAME, &vec![4, 5]).unwrap();
+        assert!(!std::path::Path::new(&temp_file(FILENAME)).exists());
+        let loaded: Vec<i32> = load_from_disk_file(&BincodeCodec::default(), FILENAME).unwrap();
+        assert_eq!(loaded, vec![4, 5]);
+        std::fs::remove_file(FILENAME).unwrap();
+    }
+
+    /// Creates a log file called `filename` holding the entries with values 0 to `n - 1`.
+    /// Any leftovers from previous runs are removed.
+    fn create_log_file(filename: &str, n: u32) -> LogFile<u32> {
+        let _ = std::fs::remove_file(filename);
+        let _ = std::fs::remove_file(format!("{}{}", filename, INDEX_SUFFIX));
+        let mut log = LogFile::open(filename, BincodeCodec::default()).unwrap();
+        for v in 0..n {
+            log.append(&entry(v)).unwrap();
+        }
+        log.sync().unwrap();
+        log
+    }
+
+    fn entry(v: u32) -> LogEntry<u32> {
+        LogEntry::new(Command::App(v), NodeId(1), Ballot::default())
+    }
+
+    fn remove_log_file(filename: &str) {
+        std::fs::remove_file(filename).unwrap();
+        std::fs::remove_file(format!("{}{}", filename, INDEX_SUFFIX)).unwrap();
+    }
+
+    #[test]
+    fn random_access_to_log_file() {
+        static FILENAME: &str = "random_access_to_log_file.Rk7wQz2mPx4sLd9c.bin";
+        let log = create_log_file(FILENAME, 10_000);
+        assert_eq!(log.len(), 10_000);
+        assert_eq!(log.get(10_000).unwrap(), None);
+        assert_eq!(log.range(9_998..10_005).unwrap(), vec![entry(9_998), entry(9_999)]);
+
+        // reading entries at the end of the log takes no longer than at its start
+        let reopened = LogFile::open(FILENAME, BincodeCodec::default()).unwrap();
+        let time_reads = |indices: Range<usize>| {
+            let start = Instant::now();
+            for i in indices {
+                assert_eq!(reopened.get(i).unwrap(), Some(entry(i as u32)));
+            }
+            start.elapsed()
+        };
+        let first = time_reads(0..100);
+        let last = time_reads(9_900..10_000);
+        assert!(last < 10 * first + Duration::from_millis(5), "{:?} vs {:?}", last, first);
+        for i in [4_711, 17, 9_001, 2_500] {
+            assert_eq!(reopened.get(i).unwrap(), Some(entry(i as u32)));
+        }
+        remove_log_file(FILENAME);
+    }
+
+    #[test]
+    fn rebuild_log_file_index() {
+        static FILENAME: &str = "rebuild_log_file_index.Vn3bTq8hWz1kMc6y.bin";
+        let index = format!("{}{}", FILENAME, INDEX_SUFFIX);
+        drop(create_log_file(FILENAME, 100));
+
+        // a crash while appending leaves a partial entry behind, which isn't indexed yet
+        let data = std::fs::read(FILENAME).unwrap();
+        let mut file = OpenOptions::new().append(true).open(FILENAME).unwrap();
+        file.write_all(&data[..RECORD_HEADER_LEN + 2]).unwrap();
+        let mut log = LogFile::<u32>::open(FILENAME, BincodeCodec::default()).unwrap();
+        assert_eq!(log.len(), 100);
+        assert_eq!(std::fs::read(FILENAME).unwrap(), data);
+        log.append(&entry(100)).unwrap();
+        assert_eq!(log.get(100).unwrap(), Some(entry(100)));
+        drop(log);
+
+        // a crash between appending to the data file and the index
+        let offsets = std::fs::read(&index).unwrap();
+        std::fs::write(&index, &offsets[..offsets.len() - 8]).unwrap();
+        let log = LogFile::<u32>::open(FILENAME, BincodeCodec::default()).unwrap();
+        assert_eq!(log.len(), 101);
+        assert_eq!(std::fs::read(&index).unwrap(), offsets);
+
+        // a garbled index
+        std::fs::write(&index, [0xFF; 20]).unwrap();
+        let log = LogFile::<u32>::open(FILENAME, BincodeCodec::default()).unwrap();
+        assert_eq!(log.range(0..101).unwrap(), (0..=100).map(entry).collect::<Vec<_>>());
+        remove_log_file(FILENAME);
+    }
+
+    #[test]
+    fn truncate_log_file() {
+        static FILENAME: &str = "truncate_log_file.Jc5xRm2vQs8nHb4t.bin";
+        let mut log = create_log_file(FILENAME, 20);
+        log.truncate(25).unwrap();
+        assert_eq!(log.len(), 20);
+        log.truncate(10).unwrap();
+        assert_eq!(log.len(), 10);
+        assert_eq!(log.get(10).unwrap(), None);
+        log.append(&entry(42)).unwrap();
+        log.sync().unwrap();
+        drop(log);
+
+        // the index still matches the data file, so it is used as is
+        let log = LogFile::<u32>::open(FILENAME, BincodeCodec::default()).unwrap();
+        assert_eq!(log.len(), 11);
+        assert_eq!(log.get(9).unwrap(), Some(entry(9)));
+        assert_eq!(log.get(10).unwrap(), Some(entry(42)));
+        remove_log_file(FILENAME);
+    }
 }
@@ -1,45 +1,300 @@
 // Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
 // Distributed under terms of the MIT license.
 
-//! A network implementation that uses UDP and bincode for sending messages.
+//! A network implementation that uses UDP and bincode (or another codec) for sending messages.
 
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap};
+use std::convert::{TryFrom, TryInto};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{fmt::Debug, io};
 
-use bincode::{deserialize, serialize};
 use rand::prelude::*;
+use socket2::SockRef;
+use tracing::{debug, error, warn};
 
-use crate::protocol::PaxosMsg;
+use crate::codec::{BincodeCodec, Codec, CodecError};
+use crate::network::{install_snapshot, BroadcastResult, Network, NodeId, RecvError};
+use crate::protocol::{Ballot, Command, PaxosMsg, Snapshot, PROTOCOL_VERSION};
+use crate::rate_limit::RateLimiter;
+use crate::PaxosError;
 
-const MAX_MSG_SIZE: usize = 64 * 1024; // TODO: we can't usually send 64 KB via UDP, right?
+/// Largest payload which fits into a single UDP datagram sent over IPv4.
+const MAX_MSG_SIZE: usize = 65_507;
+
+/// Length of the header preceding each encoded message, which holds the protocol version
+/// followed by the sender's group and node ID.
+const HEADER_LEN: usize = 18;
+
+/// The number of random ports `try_new` tries to bind to, before it gives up.
+const BIND_ATTEMPTS: usize = 100;
+
+/// Options set on the socket of a `UdpNetworkNode`, see `with_socket_options`.
+///
+/// By default the operating system's buffer sizes are kept, which are often only a few hundred
+/// KiB. Under bursts of messages, e.g. a leader broadcasting a batch of Proposes, datagrams
+/// arriving while the receive buffer is full are dropped and have to be proposed again.
+/// Buffers of a few MiB, e.g. 4 MiB, usually avoid that for high-throughput deployments.
+///
+/// The operating system treats the sizes as hints: Linux doubles them to account for its own
+/// bookkeeping, and clamps them to `net.core.rmem_max` and `net.core.wmem_max` unless these
+/// are raised, while other platforms apply limits of their own. Read the actual sizes back from
+/// the socket to be sure.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Size of the socket's send buffer in bytes, if it shouldn't be left to the OS.
+    pub send_buffer_size: Option<usize>,
+    /// Size of the socket's receive buffer in bytes, if it shouldn't be left to the OS.
+    pub recv_buffer_size: Option<usize>,
+}
 
 #[derive(Debug)]
-pub struct UdpNetworkNode<V> {
+pub struct UdpNetworkNode<V: Debug> {
     pub socket: UdpSocket,
-    pub peers: HashSet<usize>,
-    _marker: std::marker::PhantomData<V>,
+    /// Derived from the socket's address, unless set by `with_id`.
+    id: NodeId,
+    /// Kept sorted, so that peers are always iterated over in the same order.
+    pub peers: BTreeSet<NodeId>,
+    /// Addresses of the peers which aren't reachable at the address their ID encodes, because
+    /// they use a logical ID or were restarted on another port. Updated from the messages they
+    /// send, so that a peer keeps its identity when its address changes.
+    addresses: Mutex<HashMap<NodeId, SocketAddr>>,
+    /// Upper bound for the size of a single serialized message, e.g. derived from the path MTU.
+    max_msg_size: usize,
+    /// Encoding of messages on the wire, which all nodes of a group need to agree on.
+    codec: Arc<dyn Codec<PaxosMsg<V>>>,
+    /// Limits the rate of outgoing datagrams, if set.
+    rate_limiter: Option<RateLimiter>,
+    /// Only messages sent by nodes of the same group are received, see `with_group`.
+    group: u64,
+}
+
+impl<V: crate::AppCommand> Default for UdpNetworkNode<V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<V: crate::AppCommand> UdpNetworkNode<V> {
     /// Creates a new network node on localhost with random port.
+    /// Panics if no unused port is found, see `try_new`.
     pub fn new() -> Self {
-        // loop until we find an unused port
-        loop {
-            let port = rand::thread_rng().gen_range(1024..=65535);
-            if let Ok(socket) = UdpSocket::bind(("127.0.0.1", port)) {
-                return Self {
-                    socket,
-                    peers: HashSet::new(),
-                    _marker: Default::default(),
-                };
+        Self::try_new().expect("failed to bind UDP socket")
+    }
+
+    /// Creates a new network node on localhost with random port.
+    /// Fails with the last error, if none of several ports tried can be bound.
+    pub fn try_new() -> io::Result<Self> {
+        Self::try_new_with_rng(&mut thread_rng())
+    }
+
+    /// Creates a new network node on localhost like `try_new`, but draws the ports to try from
+    /// the given generator, e.g. from a seeded one to bind the same ports in every test run.
+    pub fn try_new_with_rng(rng: &mut impl Rng) -> io::Result<Self> {
+        let mut result = Err(io::Error::new(io::ErrorKind::AddrInUse, "no port tried"));
+        for _ in 0..BIND_ATTEMPTS {
+            let port = rng.gen_range(1024..=65535);
+            result = Self::new_on(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+
+    /// Creates a new network node bound to the given interface address and port, e.g. to
+    /// form groups spanning multiple hosts. Port 0 lets the operating system pick a free one.
+    /// Only IPv4 addresses are supported, as node IDs are derived from them.
+    pub fn new_on(addr: IpAddr, port: u16) -> io::Result<Self> {
+        if addr.is_ipv6() {
+            let e = "node IDs can only be derived from IPv4 addresses";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        let socket = UdpSocket::bind((addr, port))?;
+        let id = NodeId::from_addr(socket.local_addr()?).unwrap();
+        Ok(Self {
+            socket,
+            id,
+            peers: BTreeSet::new(),
+            addresses: Mutex::new(HashMap::new()),
+            max_msg_size: MAX_MSG_SIZE,
+            codec: Arc::new(BincodeCodec::default()),
+            rate_limiter: None,
+            group: 0,
+        })
+    }
+
+    /// Makes this node use the given logical ID, instead of the one derived from its address.
+    /// Peers reach it at the address its messages come from, so that it keeps its identity
+    /// when it is restarted on another port, and with it the promises and votes of its replica.
+    /// Peers have to be told its address with `discover_at`, and only follow it to addresses
+    /// on the same host, see `decode_from`.
+    /// Logical IDs must not collide with any other node's ID, including derived ones.
+    pub fn with_id(mut self, id: NodeId) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Makes this node part of the given group, so that it drops all messages sent by nodes of
+    /// other groups, e.g. when several groups run on the same hosts and one of them reaches a
+    /// node of another one at an address it reuses. Node IDs only need to be unique within a
+    /// group then. Nodes are part of group 0 by default.
+    pub fn with_group(mut self, group: u64) -> Self {
+        self.group = group;
+        self
+    }
+
+    /// Adds the node with the given ID as a peer, which is reachable at `addr` instead of the
+    /// address its ID encodes, e.g. because it uses a logical ID, see `with_id`.
+    pub fn discover_at(&mut self, id: NodeId, addr: SocketAddr) {
+        self.addresses.lock().unwrap().insert(id, addr);
+        self.discover(&[id]);
+    }
+
+    /// The address the node with the given ID was last seen at, or the one its ID encodes.
+    fn addr_of(&self, id: NodeId) -> Option<SocketAddr> {
+        let addresses = self.addresses.lock().unwrap();
+        addresses.get(&id).copied().or_else(|| id.to_addr())
+    }
+
+    /// Limits the size of messages sent by this node, e.g. to avoid IP fragmentation.
+    /// The limit can not be raised above the maximum UDP payload size.
+    pub fn with_max_msg_size(mut self, max_msg_size: usize) -> Self {
+        self.max_msg_size = max_msg_size.min(MAX_MSG_SIZE);
+        self
+    }
+
+    /// Makes this node encode messages with the given codec, instead of bincode.
+    pub fn with_codec(mut self, codec: impl Codec<PaxosMsg<V>> + 'static) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Limits the bytes this node sends to `rate` per second, after an initial burst of up to
+    /// `burst` bytes, e.g. so that a leader broadcasting to many peers doesn't saturate its
+    /// own uplink and cause messages to be lost. Messages exceeding the budget are queued and
+    /// sent once it allows, with heartbeats overtaking queued Proposes and Learns.
+    pub fn with_rate_limit(mut self, rate: u64, burst: usize) -> Self {
+        let socket = self.socket.try_clone().expect("failed to clone UDP socket");
+        self.rate_limiter = Some(RateLimiter::spawn(socket, rate, burst));
+        self
+    }
+
+    /// Sets the options on this node's socket, e.g. to enlarge its buffers so that bursts of
+    /// messages aren't dropped. Fails if the operating system rejects one of them.
+    pub fn with_socket_options(self, options: &SocketOptions) -> io::Result<Self> {
+        let socket = SockRef::from(&self.socket);
+        if let Some(size) = options.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(self)
+    }
+
+    /// Encodes the message into a datagram, prefixed with the protocol version, this node's group
+    /// and its ID.
+    fn encode(&self, msg: &PaxosMsg<V>) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = PROTOCOL_VERSION.to_le_bytes().to_vec();
+        bytes.extend(self.group.to_le_bytes());
+        bytes.extend((self.id.0 as u64).to_le_bytes());
+        bytes.extend(self.codec.encode(msg)?);
+        Ok(bytes)
+    }
+
+    /// Decodes a received datagram into its sender's ID and the message, reporting malformed
+    /// ones as invalid data. Datagrams sent with another protocol version are rejected without
+    /// decoding them, as their layout might differ in ways the codec can't detect. So are
+    /// datagrams sent by nodes of other groups.
+    fn decode(&self, bytes: &[u8]) -> io::Result<(NodeId, PaxosMsg<V>)> {
+        if bytes.len() < HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "missing header"));
+        }
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        let version = u16::from_le_bytes([header[0], header[1]]);
+        if version != PROTOCOL_VERSION {
+            warn!("Dropping message with incompatible protocol version {}.", version);
+            let e = format!("incompatible protocol version {}", version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let group = u64::from_le_bytes(header[2..10].try_into().unwrap());
+        if group != self.group {
+            warn!("Dropping message of group {}.", group);
+            let e = format!("message of group {}", group);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        let src = u64::from_le_bytes(header[10..].try_into().unwrap());
+        let src = usize::try_from(src).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("invalid node ID {}", src))
+        })?;
+        let msg = self
+            .codec
+            .decode(payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((NodeId(src), msg))
+    }
+
+    /// Decodes a datagram received from `from`, and remembers that address for its sender,
+    /// unless it is the one the sender's ID encodes anyway.
+    ///
+    /// Rebinding is unauthenticated: the sender's ID comes from the datagram, which anyone can
+    /// forge. So peers are only followed to addresses on the same host, i.e. at the IP their ID
+    /// encodes or they were last seen at, which keeps other hosts from redirecting the traffic
+    /// meant for them. Other nodes, e.g. clients, are followed to any address.
+    fn decode_from(
+        &self,
+        bytes: &[u8],
+        from: SocketAddr,
+    ) -> Result<(NodeId, PaxosMsg<V>), RecvError> {
+        let (src, msg) = self.decode(bytes).map_err(RecvError::Io)?;
+        let mut addresses = self.addresses.lock().unwrap();
+        let last_seen = addresses.get(&src).copied();
+        if src.to_addr() == Some(from) {
+            addresses.remove(&src);
+        } else if last_seen != Some(from) {
+            let same_host = |addr: Option<SocketAddr>| addr.is_some_and(|a| a.ip() == from.ip());
+            if !self.peers.contains(&src) || same_host(last_seen) || same_host(src.to_addr()) {
+                debug!("Node {} is now at {}.", src, from);
+                addresses.insert(src, from);
+            } else {
+                warn!("Ignoring address {} of peer {} on another host.", from, src);
             }
         }
+        Ok((src, msg))
     }
 
-    /// Adds another peer's ID to this node's list of known peers.
-    pub fn discover(&mut self, other_nodes: &Vec<usize>) {
+    /// Measures the size of the value wrapped into a Propose with this node's framing.
+    /// The index is chosen as large as possible, in case the codec uses variable length integers.
+    fn check_proposed_size(&self, value: Command<V>) -> Result<(), PaxosError> {
+        let envelope = PaxosMsg::Propose {
+            index: usize::MAX,
+            ballot: Ballot::default(),
+            value,
+            sent_at: Some(u64::MAX),
+        };
+        let size = self.encode(&envelope).map_or(usize::MAX, |bytes| bytes.len());
+        if size > self.max_msg_size {
+            return Err(PaxosError::CommandTooLarge {
+                size,
+                max: self.max_msg_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<V: crate::AppCommand> Network<V> for UdpNetworkNode<V> {
+    fn id(&self) -> NodeId {
+        self.id
+    }
+
+    fn peers(&self) -> Vec<NodeId> {
+        self.peers.iter().copied().collect()
+    }
+
+    fn discover(&mut self, other_nodes: &[NodeId]) {
         for node in other_nodes {
             if *node == self.id() {
                 continue;
@@ -48,10 +303,40 @@ impl<V: crate::AppCommand> UdpNetworkNode<V> {
         }
     }
 
-    /// Try to receive a new Paxos message from this node's UDP socket.
-    /// Blocks until the next message is received.
-    /// If this takes longer than timeout an `io::Error` is returned instead.
-    pub fn recv(&self, timeout: Duration) -> io::Result<(usize, PaxosMsg<V>)> {
+    fn send(&self, dst: NodeId, msg: &PaxosMsg<V>) -> bool {
+        let serialized = match self.encode(msg) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                error!("Failed to serialize message: {}", e);
+                return false;
+            }
+        };
+        if serialized.len() > self.max_msg_size {
+            error!("Message too large: {} bytes", serialized.len());
+            return false;
+        }
+        let addr = match self.addr_of(dst) {
+            Some(addr) => addr,
+            None => {
+                error!("Invalid node ID: {}", dst);
+                return false;
+            }
+        };
+        match &self.rate_limiter {
+            Some(limiter) => limiter.send(&self.socket, (addr, serialized), msg.is_urgent()),
+            None => self.socket.send_to(&serialized, addr).is_ok(),
+        }
+    }
+
+    fn broadcast(&self, msg: &PaxosMsg<V>) -> BroadcastResult {
+        let mut result = BroadcastResult::default();
+        for &peer in &self.peers {
+            result.record(peer, self.send(peer, msg));
+        }
+        result
+    }
+
+    fn recv(&self, timeout: Duration) -> Result<(NodeId, PaxosMsg<V>), RecvError> {
         self.socket
             .set_read_timeout(Some(timeout))
             .expect("set_read_timeout call failed");
@@ -59,62 +344,110 @@ impl<V: crate::AppCommand> UdpNetworkNode<V> {
         let mut buf = [0; MAX_MSG_SIZE];
         let (n, from) = self.socket.recv_from(&mut buf)?;
 
-        let cmd: PaxosMsg<V> = deserialize(&buf[..n]).unwrap();
-        Ok((Self::addr_to_node_id(from).unwrap(), cmd))
+        self.decode_from(&buf[..n], from)
     }
 
-    /// Sends the Paxos message to all other replicas.
-    pub fn broadcast(&self, cmd: &PaxosMsg<V>) {
-        for addr in self.peers.clone() {
-            self.send(addr, cmd);
-        }
+    fn try_recv(&self) -> Result<(NodeId, PaxosMsg<V>), RecvError> {
+        self.socket
+            .set_nonblocking(true)
+            .expect("set_nonblocking call failed");
+
+        let mut buf = [0; MAX_MSG_SIZE];
+        let result = self.socket.recv_from(&mut buf);
+        self.socket
+            .set_nonblocking(false)
+            .expect("set_nonblocking call failed");
+        let (n, from) = result?;
+
+        self.decode_from(&buf[..n], from)
     }
 
-    /// Sends the Paxos message to another replica.
-    pub fn send(&self, dst: usize, cmd: &PaxosMsg<V>) -> bool {
-        let serialized = serialize(cmd).unwrap();
-        assert!(serialized.len() <= MAX_MSG_SIZE);
+    fn wait(&self, timeout: Duration) -> bool {
+        if timeout == Duration::from_secs(0) {
+            return false;
+        }
         self.socket
-            .send_to(&serialized, Self::node_id_to_addr(dst))
-            .is_ok()
+            .set_read_timeout(Some(timeout))
+            .expect("set_read_timeout call failed");
+        let mut buf = [0; 1];
+        self.socket.peek_from(&mut buf).is_ok()
     }
 
-    pub fn id(&self) -> usize {
-        Self::addr_to_node_id(self.socket.local_addr().unwrap()).unwrap()
+    fn max_msg_size(&self) -> usize {
+        self.max_msg_size
     }
 
-    /// Convert a socket address (IP + port) into a usize node ID.
-    /// This transformation can be reversed.
-    fn addr_to_node_id(addr: SocketAddr) -> Option<usize> {
-        let port = addr.port();
-        if let IpAddr::V4(ip) = addr.ip() {
-            let ipv4: u32 = ip.into();
-            Some(ipv4 as usize * 65536 + port as usize)
-        } else {
-            None
-        }
+    fn check_command_size(&self, cmd: &V) -> Result<(), PaxosError> {
+        self.check_proposed_size(Command::App(cmd.clone()))
+    }
+
+    fn check_batch_size(&self, cmds: &[V]) -> Result<(), PaxosError> {
+        self.check_proposed_size(Command::Batch(cmds.to_vec()))
     }
 
-    /// Convert a usize node ID into a socket address (IP + port).
-    /// This transformation can be reversed.
-    fn node_id_to_addr(node_id: usize) -> SocketAddr {
-        let port = (node_id % 65536) as u16;
-        let ip = (node_id / 65536) as u32;
-        SocketAddr::from((Ipv4Addr::from(ip), port))
+    fn check_snapshot_size(&self, snapshot: &Snapshot) -> Result<(), PaxosError> {
+        let envelope = install_snapshot(snapshot);
+        let size = self.encode(&envelope).map_or(usize::MAX, |bytes| bytes.len());
+        if size > self.max_msg_size {
+            let max = self.max_msg_size;
+            return Err(PaxosError::SnapshotTooLarge { size, max });
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use proptest::prelude::*;
+    use crate::codec::JsonCodec;
+    use std::time::Instant;
 
-    proptest! {
-        #[test]
-        fn node_id_addr_conversion(ip: u32, port: u16) {
-            let addr = SocketAddr::from((Ipv4Addr::from(ip), port));
-            assert_eq!(UdpNetworkNode::<u32>::node_id_to_addr(UdpNetworkNode::<u32>::addr_to_node_id(addr).unwrap()), addr);
-        }
+    #[test]
+    fn pick_ports_from_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let first = UdpNetworkNode::<u32>::try_new_with_rng(&mut StdRng::seed_from_u64(7));
+        let first = first.unwrap();
+        let id = first.id();
+        drop(first);
+        let second = UdpNetworkNode::<u32>::try_new_with_rng(&mut StdRng::seed_from_u64(7));
+        let second = second.unwrap();
+        assert_eq!(second.id(), id);
+    }
+
+    #[test]
+    fn bind_to_interface() {
+        let node = UdpNetworkNode::<u32>::new_on(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).unwrap();
+        let addr = node.id().to_addr().unwrap();
+        assert_eq!(addr, node.socket.local_addr().unwrap());
+        assert!(addr.ip().is_unspecified());
+        assert_ne!(addr.port(), 0);
+
+        let ipv6 = UdpNetworkNode::<u32>::new_on(IpAddr::V6(std::net::Ipv6Addr::LOCALHOST), 0);
+        assert_eq!(ipv6.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn enlarge_socket_buffers() {
+        let node = UdpNetworkNode::<u32>::new();
+        let default = SockRef::from(&node.socket).recv_buffer_size().unwrap();
+        let options = SocketOptions {
+            recv_buffer_size: Some(default * 4),
+            ..SocketOptions::default()
+        };
+        let node = node.with_socket_options(&options).unwrap();
+        // the kernel may clamp the size, but never below what it was
+        let enlarged = SockRef::from(&node.socket).recv_buffer_size().unwrap();
+        assert!(enlarged > default, "{} not above {}", enlarged, default);
+
+        let options = SocketOptions {
+            send_buffer_size: Some(4096),
+            ..SocketOptions::default()
+        };
+        let node = node.with_socket_options(&options).unwrap();
+        let socket = SockRef::from(&node.socket);
+        assert!(socket.send_buffer_size().unwrap() >= 4096);
+        assert_eq!(socket.recv_buffer_size().unwrap(), enlarged);
     }
 
     #[test]
@@ -126,32 +459,198 @@ mod tests {
     fn send_and_receive() {
         let node1 = UdpNetworkNode::<u32>::new();
         let node2 = UdpNetworkNode::<u32>::new();
-        node1.send(node2.id(), &PaxosMsg::ClientRequest(42));
+        node1.send(node2.id(), &PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
         let (recv_id, recv_msg) = node2.recv(Duration::from_secs(1)).unwrap();
         assert_eq!(recv_id, node1.id());
         match recv_msg {
-            PaxosMsg::ClientRequest(v) => assert_eq!(v, 42),
+            PaxosMsg::ClientRequest { value: v, .. } => assert_eq!(v, 42),
             _ => unreachable!(),
         }
     }
 
+    #[test]
+    fn wait_for_message() {
+        let node1 = UdpNetworkNode::<u32>::new();
+        let node2 = UdpNetworkNode::<u32>::new();
+        assert!(!node2.wait(Duration::from_millis(10)));
+        node1.send(node2.id(), &PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
+        assert!(node2.wait(Duration::from_secs(1)));
+        assert!(node2.try_recv().is_ok());
+        assert!(matches!(node2.try_recv(), Err(RecvError::Timeout)));
+    }
+
+    #[test]
+    fn distinguish_timeout_from_invalid_data() {
+        let node1 = UdpNetworkNode::<u32>::new();
+        let node2 = UdpNetworkNode::<u32>::new();
+        assert!(matches!(node2.recv(Duration::from_millis(10)), Err(RecvError::Timeout)));
+
+        node1.socket.send_to(b"garbage", node2.socket.local_addr().unwrap()).unwrap();
+        let err = node2.recv(Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(err, RecvError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+        assert!(matches!(node2.recv(Duration::from_millis(10)), Err(RecvError::Timeout)));
+    }
+
+    #[test]
+    fn send_and_receive_json() {
+        let node1 = UdpNetworkNode::<u32>::new().with_codec(JsonCodec);
+        let node2 = UdpNetworkNode::<u32>::new().with_codec(JsonCodec);
+        let node3 = UdpNetworkNode::<u32>::new();
+        node1.send(node2.id(), &PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
+        assert!(matches!(
+            node2.recv(Duration::from_secs(1)),
+            Ok((_, PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None }))
+        ));
+
+        // nodes using different codecs can't understand each other, but don't crash either
+        node1.send(node3.id(), &PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
+        let err = node3.recv(Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(err, RecvError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+
     #[test]
     fn discover_and_broadcast() {
         let mut node1 = UdpNetworkNode::<u32>::new();
         let node2 = UdpNetworkNode::<u32>::new();
         let node3 = UdpNetworkNode::<u32>::new();
-        node1.discover(&vec![node2.id()]);
-        node1.discover(&vec![node3.id()]);
-        node1.broadcast(&PaxosMsg::ClientRequest(42));
+        node1.discover(&[node2.id()]);
+        node1.discover(&[node3.id()]);
+        node1.broadcast(&PaxosMsg::ClientRequest {
+            value: 42,
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        });
         let mut received = Vec::new();
         received.push(node2.recv(Duration::from_secs(1)).unwrap());
         received.push(node3.recv(Duration::from_secs(1)).unwrap());
         for (id, msg) in received {
             assert_eq!(id, node1.id());
             match msg {
-                PaxosMsg::ClientRequest(v) => assert_eq!(v, 42),
+                PaxosMsg::ClientRequest { value: v, .. } => assert_eq!(v, 42),
                 _ => unreachable!(),
             }
         }
     }
+
+    #[test]
+    fn reject_incompatible_version() {
+        let node1 = UdpNetworkNode::<u32>::new();
+        let node2 = UdpNetworkNode::<u32>::new();
+        let msg = PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None };
+        let mut datagram = (PROTOCOL_VERSION + 1).to_le_bytes().to_vec();
+        datagram.extend(0u64.to_le_bytes());
+        datagram.extend((node1.id().0 as u64).to_le_bytes());
+        datagram.extend(BincodeCodec::default().encode(&msg).unwrap());
+        node1.socket.send_to(&datagram, node2.socket.local_addr().unwrap()).unwrap();
+        let err = node2.recv(Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(err, RecvError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+
+        datagram[..2].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        node1.socket.send_to(&datagram, node2.socket.local_addr().unwrap()).unwrap();
+        assert!(matches!(
+            node2.recv(Duration::from_secs(1)),
+            Ok((_, PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None }))
+        ));
+    }
+
+    #[test]
+    fn drop_messages_of_other_groups() {
+        let node1 = UdpNetworkNode::<u32>::new().with_group(1);
+        let node2 = UdpNetworkNode::<u32>::new().with_group(2);
+        let node3 = UdpNetworkNode::<u32>::new().with_group(2);
+        let msg = PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None };
+        node1.send(node2.id(), &msg);
+        let err = node2.recv(Duration::from_secs(1)).unwrap_err();
+        assert!(matches!(err, RecvError::Io(e) if e.kind() == io::ErrorKind::InvalidData));
+
+        node3.send(node2.id(), &msg);
+        assert_eq!(node2.recv(Duration::from_secs(1)).unwrap().0, node3.id());
+    }
+
+    #[test]
+    fn follow_peers_only_on_the_same_host() {
+        let mut node = UdpNetworkNode::<u32>::new();
+        let peer = UdpNetworkNode::<u32>::new().with_id(NodeId(5));
+        let peer_addr = peer.socket.local_addr().unwrap();
+        node.discover_at(NodeId(5), peer_addr);
+        let msg = PaxosMsg::ClientRequest { value: 42, hops: 0, priority: 0, deadline: None };
+
+        // a datagram claiming the peer's ID from another host doesn't redirect its traffic
+        let other_host = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let forged = UdpNetworkNode::<u32>::new_on(other_host, 0).unwrap().with_id(NodeId(5));
+        forged.send(node.id(), &msg);
+        assert_eq!(node.recv(Duration::from_secs(1)).unwrap().0, NodeId(5));
+        assert_eq!(node.addr_of(NodeId(5)), Some(peer_addr));
+
+        // restarted on another port of the same host, the peer is reached at its new address
+        let restarted = UdpNetworkNode::<u32>::new().with_id(NodeId(5));
+        restarted.send(node.id(), &msg);
+        assert_eq!(node.recv(Duration::from_secs(1)).unwrap().0, NodeId(5));
+        assert_eq!(node.addr_of(NodeId(5)), restarted.socket.local_addr().ok());
+
+        // nodes which aren't peers are followed anywhere, e.g. to answer clients
+        let client = UdpNetworkNode::<u32>::new_on(other_host, 0).unwrap().with_id(NodeId(6));
+        client.send(node.id(), &msg);
+        assert_eq!(node.recv(Duration::from_secs(1)).unwrap().0, NodeId(6));
+        assert_eq!(node.addr_of(NodeId(6)), client.socket.local_addr().ok());
+    }
+
+    #[test]
+    fn limit_outbound_rate() {
+        let (rate, burst) = (100_000, 5_000);
+        let node1 = UdpNetworkNode::<String>::new().with_rate_limit(rate, burst);
+        let node2 = UdpNetworkNode::<String>::new();
+        let request = PaxosMsg::ClientRequest {
+            value: "x".repeat(1000),
+            hops: 0,
+            priority: 0,
+            deadline: None,
+        };
+        let heartbeat = PaxosMsg::Heartbeat {
+            ballot: Ballot::default(),
+            seq: 1,
+            applied: 0,
+        };
+        let start = Instant::now();
+        for _ in 0..50 {
+            assert!(node1.send(node2.id(), &request));
+        }
+        assert!(node1.send(node2.id(), &heartbeat));
+
+        let mut received = 0;
+        let mut heartbeat_position = None;
+        for position in 0..51 {
+            let (_, msg) = node2.recv(Duration::from_secs(1)).unwrap();
+            received += node1.encode(&msg).unwrap().len();
+            let budget = burst as f64 + start.elapsed().as_secs_f64() * rate as f64;
+            assert!(received as f64 <= budget, "{} bytes sent, {} allowed", received, budget);
+            if matches!(msg, PaxosMsg::Heartbeat { .. }) {
+                heartbeat_position = Some(position);
+            }
+        }
+        // the heartbeat overtakes the queued requests, instead of waiting behind all of them
+        assert!(heartbeat_position.unwrap() < 10);
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
 }
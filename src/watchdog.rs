@@ -0,0 +1,61 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Detects replicas whose main loop stopped making progress, e.g. because a command hangs.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::network::NodeId;
+
+/// Called with the time since the last tick, once a replica has been stuck for too long.
+pub(crate) type StallCallback = Box<dyn FnMut(Duration) + Send>;
+
+/// Watches a replica from a separate thread, which reports when `beat` isn't called in time.
+/// The thread only observes the replica and never interferes with it.
+/// It ends once the watchdog is dropped, together with the replica owning it.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    last_beat: Arc<Mutex<Instant>>,
+}
+
+impl Watchdog {
+    /// Starts watching the replica, calling `on_stall` whenever it goes without a beat for
+    /// longer than `deadline`. A stall is reported once, until the replica makes progress again.
+    pub(crate) fn spawn(node_id: NodeId, deadline: Duration, mut on_stall: StallCallback) -> Self {
+        let last_beat = Arc::new(Mutex::new(Instant::now()));
+        let watched = Arc::downgrade(&last_beat);
+        thread::spawn(move || {
+            let mut stalled = false;
+            while let Some(elapsed) = since_last_beat(&watched) {
+                if elapsed < deadline {
+                    if stalled {
+                        info!("Replica {} makes progress again.", node_id);
+                    }
+                    stalled = false;
+                } else if !stalled {
+                    warn!("Replica {} made no progress for {:?}.", node_id, elapsed);
+                    on_stall(elapsed);
+                    stalled = true;
+                }
+                thread::sleep(deadline / 4);
+            }
+        });
+        Self { last_beat }
+    }
+
+    /// Records that the replica is making progress.
+    pub(crate) fn beat(&self) {
+        *self.last_beat.lock().unwrap() = Instant::now();
+    }
+}
+
+/// The time since the last beat, or `None` once the watchdog is gone.
+fn since_last_beat(last_beat: &Weak<Mutex<Instant>>) -> Option<Duration> {
+    let last_beat = last_beat.upgrade()?;
+    let elapsed = last_beat.lock().unwrap().elapsed();
+    Some(elapsed)
+}
@@ -0,0 +1,161 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Crashes and restarts replicas of an in-memory group while it is under load,
+//! to exercise persistence, recovery and catch-up together.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use paxos::{quorum, ManualClock, MemoryNetwork, NodeId, PaxosReplica, ReplicatedStateMachine};
+
+const GROUP_SIZE: usize = 5;
+const SEED: u64 = 0x5eed;
+const STEP: Duration = Duration::from_millis(20);
+
+/// State machine recording all commands it executed, shared with the test.
+#[derive(Clone, Default)]
+struct History(Arc<Mutex<Vec<u64>>>);
+
+impl ReplicatedStateMachine for History {
+    type Command = u64;
+
+    fn execute(&mut self, v: u64) -> Result<String, ()> {
+        self.0.lock().unwrap().push(v);
+        Ok(String::new())
+    }
+}
+
+/// A member of the group, which is either running or crashed.
+struct Member {
+    id: NodeId,
+    state_file: String,
+    replica: Option<PaxosReplica<u64>>,
+    history: History,
+}
+
+struct Group {
+    network: MemoryNetwork<u64>,
+    clock: Arc<ManualClock>,
+    members: Vec<Member>,
+}
+
+impl Group {
+    fn new() -> Self {
+        let mut group = Self {
+            network: MemoryNetwork::new(),
+            clock: Arc::new(ManualClock::new()),
+            members: Vec::new(),
+        };
+        for id in 1..=GROUP_SIZE {
+            let path = std::env::temp_dir().join(format!("paxos-chaos-{}.bin", id));
+            let _ = std::fs::remove_file(&path);
+            group.members.push(Member {
+                id: NodeId(id),
+                state_file: path.to_str().unwrap().to_owned(),
+                replica: None,
+                history: History::default(),
+            });
+            group.restart(id - 1);
+        }
+        group
+    }
+
+    /// Starts the member from its persisted state, with a fresh state machine.
+    fn restart(&mut self, i: usize) {
+        let ids: Vec<NodeId> = (1..=GROUP_SIZE).map(NodeId).collect();
+        let member = &mut self.members[i];
+        member.history = History::default();
+        let replica = PaxosReplica::with_members(
+            self.network.connect(member.id),
+            member.id,
+            ids,
+            member.history.clone(),
+        )
+        .unwrap()
+        .with_clock(self.clock.clone())
+        .with_state_file(&member.state_file);
+        member.replica = Some(replica);
+    }
+
+    /// Drops the member's replica, losing all state which was not persisted.
+    fn crash(&mut self, i: usize) {
+        self.members[i].replica = None;
+    }
+
+    fn running(&self) -> Vec<usize> {
+        (0..GROUP_SIZE)
+            .filter(|&i| self.members[i].replica.is_some())
+            .collect()
+    }
+
+    fn crashed(&self) -> Vec<usize> {
+        (0..GROUP_SIZE)
+            .filter(|&i| self.members[i].replica.is_none())
+            .collect()
+    }
+
+    /// Lets some time pass and gives every running replica the chance to do its work.
+    fn step(&mut self) {
+        self.clock.advance(STEP);
+        for member in &mut self.members {
+            if let Some(replica) = &mut member.replica {
+                replica.tick();
+            }
+        }
+    }
+}
+
+impl Drop for Group {
+    fn drop(&mut self) {
+        for member in &self.members {
+            let _ = std::fs::remove_file(&member.state_file);
+        }
+    }
+}
+
+#[test]
+fn crash_and_restart_under_load() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let mut group = Group::new();
+
+    let mut next_value = 0;
+    for _ in 0..1000 {
+        if rng.gen_bool(0.3) {
+            let running = group.running();
+            let i = running[rng.gen_range(0..running.len())];
+            let replica = group.members[i].replica.as_mut().unwrap();
+            replica.submit_value(next_value).unwrap();
+            next_value += 1;
+        }
+        // never crash more than a minority at once
+        let crashed = group.crashed();
+        if GROUP_SIZE - crashed.len() > quorum(GROUP_SIZE) && rng.gen_bool(0.01) {
+            let running = group.running();
+            group.crash(running[rng.gen_range(0..running.len())]);
+        } else if !crashed.is_empty() && rng.gen_bool(0.02) {
+            group.restart(crashed[rng.gen_range(0..crashed.len())]);
+        }
+        group.step();
+    }
+
+    // stop the load, recover all replicas and let them catch up
+    for i in group.crashed() {
+        group.restart(i);
+    }
+    for _ in 0..500 {
+        group.step();
+    }
+
+    let histories: Vec<Vec<u64>> = group
+        .members
+        .iter()
+        .map(|member| member.history.0.lock().unwrap().clone())
+        .collect();
+    assert!(!histories[0].is_empty());
+    for (member, history) in group.members.iter().zip(&histories) {
+        assert_eq!(history, &histories[0], "replica {} diverged", member.id);
+    }
+}
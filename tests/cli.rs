@@ -0,0 +1,189 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+const BIN: &str = env!("CARGO_BIN_EXE_paxos_replica");
+
+/// Kills the launched replica when the test ends, even if it fails.
+struct Replica(Child);
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Launches a replica via the default path of the binary and returns its ID.
+fn launch_replica() -> (Replica, String) {
+    let mut child = Command::new(BIN)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let line = stdout.lines().next().unwrap().unwrap();
+    let node_id = line.rsplit(' ').next().unwrap().to_owned();
+    (Replica(child), node_id)
+}
+
+#[test]
+fn submit_and_query_status() {
+    let (_replica, node_id) = launch_replica();
+
+    let submit = Command::new(BIN)
+        .args(["submit", &node_id, "Hello"])
+        .output()
+        .unwrap();
+    assert!(submit.status.success());
+
+    let status = Command::new(BIN)
+        .args(["status", &node_id])
+        .output()
+        .unwrap();
+    assert!(status.status.success());
+    let stdout = String::from_utf8(status.stdout).unwrap();
+    assert!(stdout.contains(&format!("leader: {}", node_id)));
+    assert!(stdout.contains("commit index: 2"));
+}
+
+#[test]
+fn reject_empty_group() {
+    let run = Command::new(BIN)
+        .args(["run", "--group-size", "0"])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    let stderr = String::from_utf8(run.stderr).unwrap();
+    assert!(stderr.contains("group size must be at least 1"));
+}
+
+#[test]
+fn reject_invalid_node_id() {
+    let submit = Command::new(BIN)
+        .args(["submit", "not-a-node", "Hello"])
+        .output()
+        .unwrap();
+    assert!(!submit.status.success());
+}
+
+#[test]
+fn reject_invalid_run_arguments() {
+    for (arg, what) in [
+        ("--group-size", "group size"),
+        ("--peers", "peer ID"),
+        ("--host", "host"),
+        ("--port", "port"),
+    ] {
+        let run = Command::new(BIN).args(["run", arg, "x"]).output().unwrap();
+        // a usage error rather than a panic
+        assert_eq!(run.status.code(), Some(2));
+        let stderr = String::from_utf8(run.stderr).unwrap();
+        assert!(stderr.contains(&format!("invalid {}: x", what)));
+    }
+}
+
+#[test]
+fn reject_group_size_mismatch() {
+    let run = Command::new(BIN)
+        .args(["run", "--group-size", "3"])
+        .output()
+        .unwrap();
+    assert!(!run.status.success());
+    let stderr = String::from_utf8(run.stderr).unwrap();
+    assert!(stderr.contains("group of 3 replicas configured, but 1 are known"));
+}
+
+#[cfg(unix)]
+#[test]
+fn persist_state_on_termination() {
+    use paxos::{load_from_disk_file, BincodeCodec, Command as PaxosCommand, PersistentState};
+
+    let data_dir = std::env::temp_dir().join("paxos-cli-termination");
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let mut child = Command::new(BIN)
+        .args(["run", "--data-dir", data_dir.to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let line = stdout.lines().next().unwrap().unwrap();
+    let node_id = line.rsplit(' ').next().unwrap().to_owned();
+    let mut replica = Replica(child);
+
+    let submit = Command::new(BIN)
+        .args(["submit", &node_id, "Hello"])
+        .output()
+        .unwrap();
+    assert!(submit.status.success());
+    let kill = Command::new("kill")
+        .args(["-TERM", &replica.0.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    assert!(replica.0.wait().unwrap().success());
+
+    let filename = data_dir.join(&node_id).join("state.bin");
+    let state: PersistentState<String> =
+        load_from_disk_file(&BincodeCodec::default(), filename.to_str().unwrap()).unwrap();
+    assert!(state.is_consistent());
+    assert_eq!(state.apply_index, 2);
+    assert_eq!(state.log[1].value, Some(PaxosCommand::App("Hello".to_owned())));
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn bind_to_host_and_port() {
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut child = Command::new(BIN)
+        .args(["run", "--host", "127.0.0.1", "--port", &port.to_string()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let line = stdout.lines().next().unwrap().unwrap();
+    let _replica = Replica(child);
+    let localhost = u32::from(std::net::Ipv4Addr::LOCALHOST) as usize;
+    assert!(line.ends_with(&format!(" {}", localhost * 65536 + port as usize)));
+}
+
+#[test]
+fn inspect_exported_log() {
+    use paxos::{Ballot, Command as PaxosCommand, LogEntry, LogExport, NodeId};
+
+    let entry = |value: Option<&str>, chosen| LogEntry {
+        value: value.map(|v| PaxosCommand::App(v.to_owned())),
+        acceptances: [NodeId(1), NodeId(2)].iter().copied().collect(),
+        accepted_ballot: Ballot::default(),
+        chosen,
+    };
+    let export = LogExport {
+        node_id: NodeId(1),
+        promised_ballot: Ballot::default(),
+        snapshot_index: 0,
+        apply_index: 1,
+        log: vec![entry(Some("Hello"), true), entry(Some("World"), false), entry(None, false)],
+    };
+    let file = std::env::temp_dir().join("paxos-cli-inspect.json");
+    export.store(file.to_str().unwrap()).unwrap();
+
+    let inspect = Command::new(BIN)
+        .args(["inspect", file.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(inspect.status.success());
+    let stdout = String::from_utf8(inspect.stdout).unwrap();
+    assert!(stdout.contains("apply index: 1"));
+    assert!(stdout.contains("[0] chosen Ballot(0, 0) [1, 2] App(\"Hello\")"));
+    assert!(stdout.contains("[1] accepted Ballot(0, 0) [1, 2] App(\"World\")"));
+    assert!(stdout.contains("[2] empty"));
+    std::fs::remove_file(file).unwrap();
+}
@@ -0,0 +1,297 @@
+// Copyright (C) 2020 Quentin M. Kniep <hello@quentinkniep.com>
+// Distributed under terms of the MIT license.
+
+//! Submits interleaved commands of several clients to an in-memory group and checks that all
+//! replicas apply the same sequence of commands, which is a valid ordering of the submissions.
+//! With faults injected, the sequences applied so far must be prefixes of one another at all
+//! times, as replicas never apply different commands at the same log index.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use proptest::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use paxos::{
+    LeaderState, ManualClock, MemoryNetwork, NodeId, PaxosReplica, ReplicatedStateMachine,
+};
+
+const GROUP_SIZE: usize = 5;
+const CLIENTS: u64 = 3;
+const COMMANDS_PER_CLIENT: u64 = 100;
+const SEED: u64 = 0xc0ffee;
+const STEP: Duration = Duration::from_millis(20);
+
+/// A command tagged with the client which sent it, and its sequence number at this client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct Tagged {
+    client: u64,
+    seq: u64,
+}
+
+impl paxos::AppCommand for Tagged {}
+
+/// State machine which ignores all commands, the test inspects the replicas' logs instead.
+struct Noop;
+
+impl ReplicatedStateMachine for Noop {
+    type Command = Tagged;
+
+    fn execute(&mut self, _: Tagged) -> Result<String, ()> {
+        Ok(String::new())
+    }
+}
+
+/// The commands a replica applied so far, with their log index, see `PaxosReplica::history`.
+type History = Vec<(usize, Tagged)>;
+
+/// Collects the replica's `History`.
+fn history(replica: &PaxosReplica<Tagged>) -> History {
+    replica.history().map(|(index, &cmd)| (index, cmd)).collect()
+}
+
+/// Checks that of any two histories, one is a prefix of the other, i.e. that the replicas
+/// applied the same commands at the same log indices, and describes the first divergence.
+fn check_prefixes(histories: &[(NodeId, History)]) -> Result<(), String> {
+    let (longest, reference) = match histories.iter().max_by_key(|(_, h)| h.len()) {
+        Some(longest) => longest,
+        None => return Ok(()),
+    };
+    for (id, history) in histories {
+        let diverged = history.iter().zip(reference).position(|(a, b)| a != b);
+        if let Some(i) = diverged {
+            return Err(format!(
+                "replica {} applied {:?} as command {}, but replica {} applied {:?}",
+                id, history[i], i, longest, reference[i]
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Lets some time pass and gives every replica the chance to do its work.
+fn step(replicas: &mut [PaxosReplica<Tagged>], clock: &ManualClock) {
+    clock.advance(STEP);
+    for replica in replicas {
+        replica.tick();
+    }
+}
+
+#[test]
+fn replicas_apply_identical_logs() {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let clock = Arc::new(ManualClock::new());
+    let network = MemoryNetwork::new();
+    let ids: Vec<NodeId> = (1..=GROUP_SIZE).map(NodeId).collect();
+    let mut replicas: Vec<_> = ids
+        .iter()
+        .map(|&id| {
+            PaxosReplica::with_members(network.connect(id), id, ids.clone(), Noop)
+                .unwrap()
+                .with_clock(clock.clone())
+        })
+        .collect();
+
+    // clients only send their commands once the group agreed on a leader, since replicas only
+    // relay commands to a known leader
+    for _ in 0..1000 {
+        if replicas.iter().all(|r| r.leader_state() != LeaderState::Unknown) {
+            break;
+        }
+        step(&mut replicas, &clock);
+    }
+
+    // each client sends its commands, tagged with its ID and a sequence number, to one replica
+    let mut sent = vec![0; CLIENTS as usize];
+    while sent.iter().any(|&n| n < COMMANDS_PER_CLIENT) {
+        let client = rng.gen_range(0..CLIENTS);
+        let seq = &mut sent[client as usize];
+        if *seq < COMMANDS_PER_CLIENT {
+            replicas[client as usize].submit_value(Tagged { client, seq: *seq }).unwrap();
+            *seq += 1;
+        }
+        if rng.gen_bool(0.5) {
+            step(&mut replicas, &clock);
+        }
+    }
+
+    let total = (CLIENTS * COMMANDS_PER_CLIENT) as usize;
+    for _ in 0..1000 {
+        if replicas.iter().all(|r| r.history().count() == total) {
+            break;
+        }
+        step(&mut replicas, &clock);
+    }
+
+    let histories: Vec<_> = replicas.iter().map(|r| (r.id(), history(r))).collect();
+    check_prefixes(&histories).unwrap();
+    let logs: Vec<Vec<u8>> = replicas
+        .iter()
+        .map(|r| bincode::serialize(&r.history().collect::<Vec<_>>()).unwrap())
+        .collect();
+    for (replica, log) in replicas.iter().zip(&logs) {
+        assert_eq!(log, &logs[0], "replica {} diverged", replica.id());
+    }
+
+    // every command is applied exactly once, and each client's commands in the order sent
+    let applied: Vec<Tagged> = replicas[0].history().map(|(_, &cmd)| cmd).collect();
+    assert_eq!(applied.len(), total);
+    assert_eq!(applied.iter().collect::<HashSet<_>>().len(), total);
+    for client in 0..CLIENTS {
+        let order: Vec<u64> = applied
+            .iter()
+            .filter(|cmd| cmd.client == client)
+            .map(|cmd| cmd.seq)
+            .collect();
+        assert_eq!(order, (0..COMMANDS_PER_CLIENT).collect::<Vec<_>>());
+    }
+}
+
+/// A group whose replicas crash and restart from their state files, and whose links are slowed
+/// down at random, so that messages are reordered across links and lost with crashed replicas.
+struct FaultyGroup {
+    network: MemoryNetwork<Tagged>,
+    clock: Arc<ManualClock>,
+    ids: Vec<NodeId>,
+    state_files: Vec<String>,
+    replicas: Vec<Option<PaxosReplica<Tagged>>>,
+    /// Seeds the replicas' generators, so that failing cases can be reproduced.
+    rng: StdRng,
+}
+
+impl FaultyGroup {
+    fn new(name: &str, seed: u64) -> Self {
+        let ids: Vec<NodeId> = (1..=GROUP_SIZE).map(NodeId).collect();
+        let state_files = ids
+            .iter()
+            .map(|id| {
+                let path = std::env::temp_dir().join(format!("paxos-{}-{}.bin", name, id));
+                let _ = std::fs::remove_file(&path);
+                path.to_str().unwrap().to_owned()
+            })
+            .collect();
+        let clock = Arc::new(ManualClock::new());
+        let mut group = Self {
+            network: MemoryNetwork::with_clock(clock.clone()),
+            clock,
+            ids,
+            state_files,
+            replicas: (0..GROUP_SIZE).map(|_| None).collect(),
+            rng: StdRng::seed_from_u64(seed),
+        };
+        for i in 0..GROUP_SIZE {
+            group.restart(i);
+        }
+        group
+    }
+
+    /// Starts the `i`-th replica from its persisted state, replacing its network node.
+    fn restart(&mut self, i: usize) {
+        let id = self.ids[i];
+        let replica =
+            PaxosReplica::with_members(self.network.connect(id), id, self.ids.clone(), Noop)
+                .unwrap()
+                .with_clock(self.clock.clone())
+                .with_rng(StdRng::seed_from_u64(self.rng.gen()))
+                .with_state_file(&self.state_files[i]);
+        self.replicas[i] = Some(replica);
+    }
+
+    fn histories(&self) -> Vec<(NodeId, History)> {
+        self.replicas
+            .iter()
+            .flatten()
+            .map(|replica| (replica.id(), history(replica)))
+            .collect()
+    }
+
+    fn step(&mut self) {
+        self.clock.advance(STEP);
+        for replica in self.replicas.iter_mut().flatten() {
+            replica.tick();
+        }
+    }
+}
+
+impl Drop for FaultyGroup {
+    fn drop(&mut self) {
+        for file in &self.state_files {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn replicas_never_diverge_under_faults(
+        seed: u64,
+        commands in proptest::collection::vec((0..GROUP_SIZE, 0..CLIENTS), 20..60),
+    ) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut group = FaultyGroup::new(&format!("prefix-{:x}", seed), seed);
+        let mut seqs = vec![0; CLIENTS as usize];
+        let mut commands = commands.into_iter();
+        // like in `replicas_apply_identical_logs`, commands are only sent once there is a leader
+        for _ in 0..1000 {
+            if group.replicas.iter().flatten().all(|r| r.leader_state() != LeaderState::Unknown) {
+                break;
+            }
+            group.step();
+        }
+        for _ in 0..300 {
+            if let Some((target, client)) = commands.next() {
+                if let Some(replica) = &mut group.replicas[target] {
+                    let seq = &mut seqs[client as usize];
+                    let _ = replica.submit_value(Tagged { client, seq: *seq });
+                    *seq += 1;
+                }
+            }
+            if rng.gen_bool(0.05) {
+                let id = group.ids[rng.gen_range(0..GROUP_SIZE)];
+                group.network.set_delay(id, STEP * rng.gen_range(0..5));
+            }
+            // never crash more than a minority at once
+            let crashed: Vec<_> =
+                (0..GROUP_SIZE).filter(|&i| group.replicas[i].is_none()).collect();
+            if crashed.len() < GROUP_SIZE / 2 && rng.gen_bool(0.02) {
+                group.replicas[rng.gen_range(0..GROUP_SIZE)] = None;
+            } else if !crashed.is_empty() && rng.gen_bool(0.05) {
+                group.restart(crashed[rng.gen_range(0..crashed.len())]);
+            }
+            group.step();
+            check_prefixes(&group.histories()).map_err(TestCaseError::fail)?;
+        }
+
+        // heal the group and let all replicas catch up
+        for i in 0..GROUP_SIZE {
+            group.network.set_delay(group.ids[i], Duration::ZERO);
+            if group.replicas[i].is_none() {
+                group.restart(i);
+            }
+        }
+        // commands relayed to a crashed leader are lost, but the healed group commits again
+        let healed = Tagged { client: CLIENTS, seq: 0 };
+        for i in 0..1000 {
+            group.step();
+            // submitted again until applied, as a deposed leader may still drop it
+            if let Some(leader) = group.replicas.iter_mut().flatten().find(|r| r.is_leader()) {
+                if i % 50 == 0 {
+                    let _ = leader.submit_value(healed);
+                }
+            }
+            let histories = group.histories();
+            if histories.iter().all(|(_, history)| history.iter().any(|&(_, cmd)| cmd == healed)) {
+                break;
+            }
+        }
+        let histories = group.histories();
+        check_prefixes(&histories).map_err(TestCaseError::fail)?;
+        for (id, history) in &histories {
+            prop_assert!(history.iter().any(|&(_, cmd)| cmd == healed), "replica {} lags", id);
+        }
+    }
+}